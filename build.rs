@@ -0,0 +1,26 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/log_write.proto");
+        // protox keeps protoc out of the build requirements
+        let fds = protox::compile(["proto/log_write.proto"], ["proto"]).expect("Error compiling log_write.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(fds)
+            .expect("Error generating gRPC service");
+    }
+    // Only on request, a build shouldn't write outside OUT_DIR
+    #[cfg(feature = "ffi-header")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let header = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("log_write.h");
+        let config = cbindgen::Config::from_file("cbindgen.toml").expect("Error reading cbindgen.toml");
+        cbindgen::generate_with_config(&crate_dir, config)
+            .expect("Error generating C bindings")
+            .write_to_file(&header);
+        println!("cargo:warning=C header generated at {}, copy it to include/log_write.h", header.display());
+    }
+}
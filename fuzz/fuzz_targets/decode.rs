@@ -0,0 +1,28 @@
+//! A superblock and an entry header straight from the input: the first 32
+//! bytes are the superblock, the rest the sector holding the header.
+
+#![no_main]
+
+use std::convert::TryInto;
+use libfuzzer_sys::fuzz_target;
+use log_write::format::{parse_super, LogWriteEntry, MemSize};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 32 {
+        return;
+    }
+    let log_super = parse_super(data[..32].try_into().unwrap());
+    if let Ok(log_super) = &log_super {
+        let _ = log_super.validate();
+    }
+    // Readers always hand over a whole sector, never less than a header
+    let header = &data[32..];
+    if header.len() < LogWriteEntry::mem_size() {
+        return;
+    }
+    let entry = LogWriteEntry::from(header.to_vec());
+    let _ = entry.validate();
+    if let Ok(log_super) = log_super {
+        let _ = entry.data_size(log_super.sector_size);
+    }
+});
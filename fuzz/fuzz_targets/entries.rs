@@ -0,0 +1,20 @@
+//! A whole log in memory, walked the way the wasm viewer walks one.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use log_write::format::Entries;
+
+fuzz_target!(|data: &[u8]| {
+    // One to walk, one to look up data with while the walk borrows the other
+    let (Ok(walk), Ok(entries)) = (Entries::new(data), Entries::new(data)) else {
+        return;
+    };
+    for log_entry in walk {
+        let Ok(log_entry) = log_entry else {
+            break;
+        };
+        let _ = log_entry.entry.validate();
+        let _ = entries.data(&log_entry);
+    }
+});
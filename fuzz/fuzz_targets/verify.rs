@@ -0,0 +1,28 @@
+//! A log file read through `LogReader` the way `verify` reads one: every
+//! header and the trailer, then the data of each entry the file holds all of.
+
+#![no_main]
+
+use std::io::Write;
+use libfuzzer_sys::fuzz_target;
+use log_write::log_reader::LogReader;
+
+fuzz_target!(|data: &[u8]| {
+    let path = std::env::temp_dir().join(format!("log-write-fuzz-{}.log", std::process::id()));
+    std::fs::File::create(&path).unwrap().write_all(data).unwrap();
+    if let Ok(mut reader) = LogReader::open(&path) {
+        let _ = reader.trailer();
+        let len = data.len() as u64;
+        let mut buf = Vec::new();
+        while let Ok(Some(log_entry)) = reader.next_entry() {
+            let offset = reader.data_offset(&log_entry);
+            let size = reader.data_size(&log_entry.entry);
+            if offset.checked_add(size).map_or(true, |end| end > len) {
+                break;
+            }
+            buf.resize(size as usize, 0);
+            reader.read_at(&mut buf, offset).unwrap();
+        }
+    }
+    let _ = std::fs::remove_file(&path);
+});
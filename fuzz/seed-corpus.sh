@@ -0,0 +1,11 @@
+#!/bin/sh
+# Seeds the corpora with the synthetic log `log-write selftest` replays, run
+# from this directory before the first cargo fuzz run.
+set -e
+dir=$(mktemp -d)
+cargo run --quiet --manifest-path ../Cargo.toml -- selftest --keep --dir "$dir"
+for target in decode entries verify; do
+    mkdir -p corpus/$target
+    cp "$dir"/log-write-selftest-*.log corpus/$target/selftest.log
+done
+rm -r "$dir"
@@ -0,0 +1,92 @@
+#ifndef LOG_WRITE_H
+#define LOG_WRITE_H
+
+/* Generated from src/ffi.rs by cbindgen, don't edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define LOG_FLUSH_FLAG (1 << 0)
+
+#define LOG_FUA_FLAG (1 << 1)
+
+#define LOG_DISCARD_FLAG (1 << 2)
+
+#define LOG_MARK_FLAG (1 << 3)
+
+#define LOG_METADATA_FLAG (1 << 4)
+
+#define WRITE_LOG_VERSION 1
+
+#define WRITE_LOG_MAGIC 29963231459240050
+
+/*
+ Longest mark name copied into `lw_entry`, including the terminating NUL.
+ */
+#define LW_MARK_LEN 256
+
+/*
+ An open log and replay target.
+ */
+typedef struct lw_log lw_log;
+
+/*
+ The entry just replayed.
+ */
+typedef struct lw_entry {
+  uint64_t index;
+  uint64_t sector;
+  uint64_t nr_sectors;
+  uint64_t flags;
+  /*
+   Name of a mark entry, empty otherwise. Truncated to fit.
+   */
+  char mark[LW_MARK_LEN];
+} lw_entry;
+
+
+
+/*
+ The error behind the last call on this thread that failed, NULL if none
+ has. Valid until the next failing call.
+ */
+const char *lw_last_error(void);
+
+/*
+ Opens `log_path` to replay onto `replay_path`, NULL on error.
+
+ # Safety
+ Both paths must be NUL terminated strings.
+ */
+struct lw_log *lw_open(const char *log_path, const char *replay_path);
+
+/*
+ Replays the next entry and describes it in `entry`, which may be NULL.
+ Returns 1 if an entry was replayed, 0 at the end of the log and -1 on error.
+
+ # Safety
+ `log` must come from `lw_open`, `entry` must be NULL or writable.
+ */
+int lw_next_entry(struct lw_log *log, struct lw_entry *entry);
+
+/*
+ Replays up to and including the mark named `mark`. Returns the number of
+ entries replayed, -1 on error or if the log ends before the mark.
+
+ # Safety
+ `log` must come from `lw_open` and `mark` must be a NUL terminated string.
+ */
+int64_t lw_replay_until_mark(struct lw_log *log, const char *mark);
+
+/*
+ Syncs the replay target and frees `log`. Returns 0, or -1 if the sync
+ failed, `log` is freed either way.
+
+ # Safety
+ `log` must come from `lw_open` and not be used afterwards.
+ */
+int lw_close(struct lw_log *log);
+
+#endif  /* LOG_WRITE_H */
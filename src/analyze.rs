@@ -0,0 +1,279 @@
+use anyhow::{Result, bail};
+use crate::log_reader::LogReader;
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+use crate::state::{ExtentSource, SectorMap, Writer};
+
+/// Two entries between the same pair of barriers that touch the same sectors.
+/// Nothing orders them on the way to the disk, so the filesystem is relying on
+/// the later one winning.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Overlap {
+    pub first: u64,
+    pub second: u64,
+    pub sector: u64,
+    pub nr_sectors: u64,
+}
+
+/// Finds overlapping writes and discards that aren't separated by a FLUSH or FUA.
+pub fn find_overlaps(reader: &mut LogReader) -> Result<Vec<Overlap>> {
+    let mut overlaps: Vec<Overlap> = Vec::new();
+    let mut writers: SectorMap<Writer> = SectorMap::new(reader.sector_size());
+
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            continue;
+        }
+        // A flush completes before its own data is written
+        if (entry.flags & LOG_FLUSH_FLAG) > 0 {
+            writers = SectorMap::new(reader.sector_size());
+        }
+
+        for (sector, nr_sectors, writer) in writers.lookup(entry.sector, entry.nr_sectors) {
+            let Some(Writer(first)) = writer else {
+                continue;
+            };
+            match overlaps.last_mut() {
+                // Adjacent runs of the same pair read better as one range
+                Some(last) if last.first == first && last.second == log_entry.index && last.sector.saturating_add(last.nr_sectors) == sector => {
+                    last.nr_sectors += nr_sectors;
+                }
+                _ => overlaps.push(Overlap { first, second: log_entry.index, sector, nr_sectors }),
+            }
+        }
+        if entry.nr_sectors > 0 {
+            writers.insert(entry.sector, entry.nr_sectors, Writer(log_entry.index));
+        }
+
+        // Anything after a FUA write is submitted once it's durable
+        if (entry.flags & LOG_FUA_FLAG) > 0 {
+            writers = SectorMap::new(reader.sector_size());
+        }
+    }
+    Ok(overlaps)
+}
+
+/// How many times a run of sectors was written.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WriteCount(pub u64);
+
+impl ExtentSource for WriteCount {
+    fn advance(&self, _bytes: u64) -> Self {
+        *self
+    }
+}
+
+/// A run of sectors all written the same number of times.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HotRun {
+    pub sector: u64,
+    pub nr_sectors: u64,
+    pub writes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Amplification {
+    pub bytes_written: u64,
+    /// Bytes of the distinct sectors written, rewrites don't add to it
+    pub unique_bytes: u64,
+    /// The most rewritten runs, most writes first
+    pub hottest: Vec<HotRun>,
+}
+
+impl Amplification {
+    /// Bytes written for every distinct byte, 1.0 when nothing is rewritten.
+    pub fn factor(&self) -> f64 {
+        if self.unique_bytes == 0 {
+            return 0.0;
+        }
+        self.bytes_written as f64 / self.unique_bytes as f64
+    }
+}
+
+/// Counts how often each sector is written, discards aside, and keeps the `top` hottest runs.
+pub fn write_amplification(reader: &mut LogReader, top: usize) -> Result<Amplification> {
+    let sector_size = reader.sector_size() as u64;
+    let mut counts: SectorMap<WriteCount> = SectorMap::new(reader.sector_size());
+    let mut bytes_written: u64 = 0;
+
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        if (entry.flags & (LOG_MARK_FLAG | LOG_DISCARD_FLAG)) > 0 || entry.nr_sectors == 0 {
+            continue;
+        }
+        bytes_written = bytes_written.saturating_add(entry.nr_sectors.saturating_mul(sector_size));
+        for (sector, nr_sectors, count) in counts.lookup(entry.sector, entry.nr_sectors) {
+            let writes = count.map_or(0, |WriteCount(writes)| writes);
+            counts.insert(sector, nr_sectors, WriteCount(writes + 1));
+        }
+    }
+
+    let mut runs: Vec<HotRun> = Vec::new();
+    for (sector, extent) in counts.extents() {
+        let WriteCount(writes) = extent.source;
+        match runs.last_mut() {
+            Some(last) if last.writes == writes && last.sector.saturating_add(last.nr_sectors) == sector => last.nr_sectors += extent.nr_sectors,
+            _ => runs.push(HotRun { sector, nr_sectors: extent.nr_sectors, writes }),
+        }
+    }
+    let unique_bytes = runs.iter().map(|run| run.nr_sectors * sector_size).sum();
+    runs.sort_by(|a, b| b.writes.cmp(&a.writes).then(a.sector.cmp(&b.sector)));
+    runs.truncate(top);
+    Ok(Amplification { bytes_written, unique_bytes, hottest: runs })
+}
+
+/// Whether a run of chunks holds data, written and not discarded since.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Allocated(bool);
+
+impl ExtentSource for Allocated {
+    fn advance(&self, _bytes: u64) -> Self {
+        *self
+    }
+}
+
+/// The allocation when the log reached a mark.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarkAllocation {
+    pub index: u64,
+    pub mark: String,
+    pub bytes: u64,
+}
+
+/// Space a thin provisioned device would have allocated for the log's
+/// writes, given back by its discards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub chunk_size: u64,
+    pub max_bytes: u64,
+    /// The entry after which allocation peaked
+    pub max_entry: u64,
+    pub final_bytes: u64,
+    /// Bytes the discards asked to give back
+    pub discarded_bytes: u64,
+    /// Bytes they gave back, less when they miss whole chunks or hit unallocated ones
+    pub freed_bytes: u64,
+    pub at_marks: Vec<MarkAllocation>,
+}
+
+/// Follows the allocation of a thin device of `chunk_size` chunks through
+/// the log. A write allocates every chunk it touches, a discard frees only
+/// the chunks it covers whole, as dm-thin does.
+pub fn allocation(reader: &mut LogReader, chunk_size: u64) -> Result<Allocation> {
+    let sector_size = reader.sector_size() as u64;
+    if chunk_size < sector_size || !chunk_size.is_multiple_of(sector_size) {
+        bail!("Chunk size {} isn't a multiple of the log's {} byte sectors", chunk_size, sector_size)
+    }
+    let chunk_sectors = chunk_size / sector_size;
+    let mut chunks: SectorMap<Allocated> = SectorMap::new(reader.sector_size());
+    let mut allocated: u64 = 0;
+    let mut result = Allocation { chunk_size, max_bytes: 0, max_entry: 0, final_bytes: 0, discarded_bytes: 0, freed_bytes: 0, at_marks: Vec::new() };
+
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        let end = entry.sector.saturating_add(entry.nr_sectors);
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            result.at_marks.push(MarkAllocation { index: log_entry.index, mark: entry.cmd.clone(), bytes: allocated * chunk_size });
+            continue;
+        }
+        if entry.nr_sectors == 0 {
+            continue;
+        }
+        let discard = (entry.flags & LOG_DISCARD_FLAG) > 0;
+        let (first, last) = match discard {
+            true => (entry.sector.div_ceil(chunk_sectors), end / chunk_sectors),
+            false => (entry.sector / chunk_sectors, end.div_ceil(chunk_sectors)),
+        };
+        if discard {
+            result.discarded_bytes = result.discarded_bytes.saturating_add(entry.nr_sectors.saturating_mul(sector_size));
+        }
+        if first >= last {
+            continue;
+        }
+        for (_, nr_chunks, state) in chunks.lookup(first, last - first) {
+            match (discard, state) {
+                (true, Some(Allocated(true))) => {
+                    allocated -= nr_chunks;
+                    result.freed_bytes += nr_chunks * chunk_size;
+                }
+                (false, None) | (false, Some(Allocated(false))) => allocated += nr_chunks,
+                _ => {}
+            }
+        }
+        chunks.insert(first, last - first, Allocated(!discard));
+        if allocated * chunk_size > result.max_bytes {
+            result.max_bytes = allocated * chunk_size;
+            result.max_entry = log_entry.index;
+        }
+    }
+    result.final_bytes = allocated * chunk_size;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analyze::{allocation, find_overlaps, write_amplification, HotRun, MarkAllocation, Overlap};
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{LOG_DISCARD_FLAG, LOG_FLUSH_FLAG};
+    use crate::testutil::{entry, write, TempPath};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_find_overlaps() {
+        let path = TempPath::new("overlaps.log");
+        let mut writer = LogWriter::create(&path, 512).unwrap();
+        writer.append(&write(0, 4), &[0; 2048]).unwrap();
+        writer.append(&write(2, 4), &[0; 2048]).unwrap();
+        writer.append(&entry(0, 0, LOG_FLUSH_FLAG), &[]).unwrap();
+        writer.append(&write(0, 1), &[0; 512]).unwrap();
+        writer.sync().unwrap();
+
+        let overlaps = find_overlaps(&mut LogReader::open(&path).unwrap()).unwrap();
+        assert_eq!(overlaps, vec![Overlap { first: 0, second: 1, sector: 2, nr_sectors: 2 }]);
+    }
+
+    #[test]
+    fn test_write_amplification() {
+        let path = TempPath::new("amplification.log");
+        let mut writer = LogWriter::create(&path, 512).unwrap();
+        writer.append(&write(0, 4), &[0; 2048]).unwrap();
+        writer.append(&write(2, 4), &[0; 2048]).unwrap();
+        writer.append(&write(3, 1), &[0; 512]).unwrap();
+        writer.append(&entry(0, 8, LOG_DISCARD_FLAG), &[]).unwrap();
+        writer.sync().unwrap();
+
+        let amplification = write_amplification(&mut LogReader::open(&path).unwrap(), 2).unwrap();
+        assert_eq!((amplification.bytes_written, amplification.unique_bytes), (4608, 3072));
+        assert_eq!(amplification.factor(), 1.5);
+        assert_eq!(amplification.hottest, vec![
+            HotRun { sector: 3, nr_sectors: 1, writes: 3 },
+            HotRun { sector: 2, nr_sectors: 1, writes: 2 },
+        ]);
+    }
+
+    #[test]
+    fn test_allocation() {
+        let path = TempPath::new("allocation.log");
+        let mut writer = LogWriter::create(&path, 512).unwrap();
+        writer.append(&write(0, 16), &[1; 8192]).unwrap();
+        writer.append(&write(20, 2), &[1; 1024]).unwrap();
+        writer.mark("written").unwrap();
+        // Covers the first chunk whole but only part of the second
+        writer.append(&entry(0, 12, LOG_DISCARD_FLAG), &[]).unwrap();
+        writer.append(&entry(16, 8, LOG_DISCARD_FLAG), &[]).unwrap();
+        writer.mark("discarded").unwrap();
+        writer.sync().unwrap();
+
+        let by_chunk = allocation(&mut LogReader::open(&path).unwrap(), 4096).unwrap();
+        let by_sector = allocation(&mut LogReader::open(&path).unwrap(), 512).unwrap();
+        let odd_chunk = allocation(&mut LogReader::open(&path).unwrap(), 1000);
+        assert_eq!((by_chunk.max_bytes, by_chunk.max_entry, by_chunk.final_bytes), (12288, 1, 4096));
+        assert_eq!((by_chunk.discarded_bytes, by_chunk.freed_bytes), (10240, 8192));
+        assert_eq!(by_chunk.at_marks, vec![
+            MarkAllocation { index: 2, mark: "written".to_string(), bytes: 12288 },
+            MarkAllocation { index: 5, mark: "discarded".to_string(), bytes: 4096 },
+        ]);
+        assert_eq!((by_sector.max_bytes, by_sector.final_bytes, by_sector.freed_bytes), (9216, 2048, 7168));
+        assert!(odd_chunk.is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Async reading and replay on tokio, for embedding in services that can't
+//! block their executor on log I/O. Mirrors `log_writes::Log`, except that
+//! discards always write zeros since there's no async discard ioctl.
+
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+use std::path::Path;
+use anyhow::{Result, anyhow, bail};
+use futures_util::stream::{self, Stream};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, info};
+use crate::format::{parse_super, LogWriteEntry, LogWriteSuper, LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+use crate::io::{self, ByteOffset};
+
+const ZERO_CHUNK: u64 = 1024 * 1024;
+
+pub struct AsyncLog {
+    log_file: File,
+    /// None when opened read-only
+    replay_file: Option<File>,
+    pub log_super: LogWriteSuper,
+    pub cur_entry: u64,
+}
+
+impl AsyncLog {
+    pub async fn open<P: AsRef<Path>>(log_file_path: P, replay_file_path: P) -> Result<Self> {
+        let replay_file = OpenOptions::new().write(true).open(replay_file_path).await?;
+        Self::open_log(log_file_path, Some(replay_file)).await
+    }
+
+    /// Opens just the log for `entries()`, replaying fails.
+    pub async fn open_read_only<P: AsRef<Path>>(log_file_path: P) -> Result<Self> {
+        Self::open_log(log_file_path, None).await
+    }
+
+    async fn open_log<P: AsRef<Path>>(log_file_path: P, replay_file: Option<File>) -> Result<Self> {
+        let mut log_file = OpenOptions::new().read(true).open(log_file_path).await?;
+
+        let mut buf = [0_u8; 32];
+        log_file.read_exact(&mut buf).await.map_err(|error| {
+            anyhow!("Error reading superblock: {}", error)
+        })?;
+        let log_super = parse_super(buf)?;
+        debug!(?log_super, "opened log");
+
+        // Seek to first log entry
+        log_file.seek(SeekFrom::Start(log_super.sector_size as u64)).await.map_err(|error| {
+            anyhow!("Error seeking to first entry: {}", error)
+        })?;
+
+        Ok(Self {
+            log_file,
+            replay_file,
+            log_super,
+            cur_entry: 0,
+        })
+    }
+
+    fn replay_file(&mut self) -> Result<&mut File> {
+        self.replay_file.as_mut().ok_or_else(|| anyhow!("Log was opened read-only, there's no replay target"))
+    }
+
+    pub async fn fsync_replay_file(&mut self) -> Result<()> {
+        self.replay_file()?.sync_all().await.map_err(|error| {
+            anyhow!("IO Error {}", error)
+        })
+    }
+
+    /// Reads the next entry header, leaving the log positioned at its data.
+    async fn read_entry(&mut self) -> Result<Option<LogWriteEntry>> {
+        if self.cur_entry >= self.log_super.nr_entries {
+            return Ok(None);
+        }
+        let mut header = vec![0_u8; self.log_super.sector_size as usize];
+        self.log_file.read_exact(&mut header).await.map_err(|error| {
+            anyhow!("Error reading entry {}: {}", self.cur_entry, error)
+        })?;
+        self.cur_entry += 1;
+        Ok(Some(LogWriteEntry::from(header)))
+    }
+
+    /// The remaining entries without replaying them, skipping their data.
+    pub fn entries(&mut self) -> impl Stream<Item = Result<LogWriteEntry>> + '_ {
+        stream::try_unfold(self, |log| async move {
+            let Some(entry) = log.read_entry().await? else {
+                return Ok(None);
+            };
+            let size = entry.data_size(log.log_super.sector_size);
+            log.log_file.seek(SeekFrom::Current(i64::try_from(size)?)).await?;
+            Ok(Some((entry, log)))
+        })
+    }
+
+    pub async fn replay_next_entry(&mut self) -> Result<Option<LogWriteEntry>> {
+        self.replay_file()?;
+        let Some(entry) = self.read_entry().await? else {
+            debug!(cur_entry = self.cur_entry, "reached the end of the log");
+            return Ok(None);
+        };
+        let sector_size = self.log_super.sector_size;
+        let size = io::sectors_len(entry.nr_sectors, sector_size)?;
+        let offset = ByteOffset::from_sectors(entry.sector, sector_size)?;
+        info!("replaying {}: sector {}, size {}, flags {}", self.cur_entry - 1, entry.sector, size, entry.flags);
+
+        if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+            self.zero_range(offset.get(), size as u64).await?;
+            return Ok(Some(entry));
+        }
+
+        let mut buf = vec![0_u8; size];
+        self.log_file.read_exact(&mut buf).await.map_err(|error| {
+            anyhow!("Error reading data of entry {}: {}", self.cur_entry - 1, error)
+        })?;
+        let replay_file = self.replay_file()?;
+        replay_file.seek(SeekFrom::Start(offset.get())).await?;
+        replay_file.write_all(&buf).await.map_err(|error| {
+            anyhow!("Error writing entry {}: {}", self.cur_entry - 1, error)
+        })?;
+        Ok(Some(entry))
+    }
+
+    async fn zero_range(&mut self, start: u64, len: u64) -> Result<()> {
+        let zeros = vec![0_u8; ZERO_CHUNK.min(len) as usize];
+        let replay_file = self.replay_file()?;
+        replay_file.seek(SeekFrom::Start(start)).await?;
+        let mut done = 0;
+        while done < len {
+            let chunk = &zeros[..ZERO_CHUNK.min(len - done) as usize];
+            replay_file.write_all(chunk).await.map_err(|error| {
+                anyhow!("Error zeroing range: {}", error)
+            })?;
+            done += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Replays up to and including the mark named `mark`, returning the number of entries replayed.
+    pub async fn replay_until_mark(&mut self, mark: &str) -> Result<u64> {
+        let mut num_entries = 0;
+        while let Some(entry) = self.replay_next_entry().await? {
+            num_entries += 1;
+            if (entry.flags & LOG_MARK_FLAG) > 0 && entry.cmd == mark {
+                return Ok(num_entries);
+            }
+        }
+        bail!("Mark {} not found after {} entries", mark, num_entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+    use crate::async_log::AsyncLog;
+    use crate::format::{LogWriteEntry, LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+    use crate::testutil::{build_log, write, TempPath};
+
+    #[test]
+    fn test_async_replay() {
+        let entries = vec![
+            write(2, 2),
+            LogWriteEntry { sector: 2, nr_sectors: 1, flags: LOG_DISCARD_FLAG, data_len: 0, cmd: String::new() },
+            LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_MARK_FLAG, data_len: 3, cmd: "one".to_string() },
+            write(5, 1),
+        ];
+        let (log_path, replay_path) = (TempPath::new("async.log"), TempPath::new("async.img"));
+        std::fs::write(&log_path, build_log(&entries, |i| i as u8 + 1)).unwrap();
+        std::fs::write(&replay_path, [0xff_u8; 4096]).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut log = AsyncLog::open_read_only(&log_path).await.unwrap();
+            let listed: Vec<LogWriteEntry> = log.entries().try_collect().await.unwrap();
+            assert_eq!(listed.len(), 4);
+            assert_eq!(listed[2].cmd, "one");
+            assert!(log.replay_next_entry().await.is_err());
+
+            let mut log = AsyncLog::open(&log_path, &replay_path).await.unwrap();
+            assert_eq!(log.replay_until_mark("one").await.unwrap(), 3);
+            assert_eq!(log.replay_next_entry().await.unwrap().unwrap().sector, 5);
+            assert!(log.replay_next_entry().await.unwrap().is_none());
+            log.fsync_replay_file().await.unwrap();
+        });
+        let replayed = std::fs::read(&replay_path).unwrap();
+        assert_eq!(&replayed[1024..1536], &[0_u8; 512][..]);
+        assert_eq!(&replayed[1536..2048], &[1_u8; 512][..]);
+        assert_eq!(&replayed[2560..3072], &[4_u8; 512][..]);
+        assert_eq!(&replayed[3072..], &[0xff_u8; 1024][..]);
+    }
+}
@@ -0,0 +1,366 @@
+//! `bench`: replays a log's writes onto targets with each way of writing them
+//! and compares how fast they went, to pick flags for a disk and to notice
+//! when replay gets slower.
+//!
+//! Only the data writes and barriers are timed. Marks and discards are left
+//! out, they cost the same whichever way the data is written, and a FLUSH or
+//! FUA entry waits for everything before it and syncs. Syscalls are the calls
+//! the backend makes itself, reading the log included.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use anyhow::{Result, bail, anyhow};
+use crate::log_reader::LogReader;
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+
+/// O_DIRECT needs buffers, offsets and lengths aligned to the logical block
+/// size, which no disk has bigger than a page.
+const DIRECT_ALIGN: usize = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    /// Read an entry, write it, sync at barriers
+    Sync,
+    /// A thread reading the log ahead of the one writing
+    Pipelined,
+    /// Up to --depth writes in flight on an io_uring
+    IoUring,
+    /// Like sync, with the target opened O_DIRECT
+    Direct,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 4] = [Backend::Sync, Backend::Pipelined, Backend::IoUring, Backend::Direct];
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sync" => Ok(Backend::Sync),
+            "pipelined" => Ok(Backend::Pipelined),
+            "io_uring" => Ok(Backend::IoUring),
+            "direct" => Ok(Backend::Direct),
+            _ => bail!("Unknown backend {}, expected sync, pipelined, io_uring or direct", s),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Sync => "sync",
+            Backend::Pipelined => "pipelined",
+            Backend::IoUring => "io_uring",
+            Backend::Direct => "direct",
+        })
+    }
+}
+
+/// One write of the log: where its data is in the log and where it goes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Write {
+    log_offset: u64,
+    target_offset: u64,
+    len: usize,
+    /// Sync the target once this and everything before it is written
+    barrier: bool,
+}
+
+/// The writes and barriers of the log, in order. Barriers without data are
+/// writes of length 0.
+fn writes(reader: &LogReader) -> Result<Vec<Write>> {
+    let sector_size = reader.sector_size() as u64;
+    let mut writes = Vec::new();
+    for log_entry in reader.clone() {
+        let log_entry = log_entry?;
+        let entry = &log_entry.entry;
+        if (entry.flags & (LOG_MARK_FLAG | LOG_DISCARD_FLAG)) > 0 {
+            continue;
+        }
+        writes.push(Write {
+            log_offset: reader.data_offset(&log_entry),
+            target_offset: entry.sector * sector_size,
+            len: usize::try_from(reader.data_size(entry))?,
+            barrier: (entry.flags & (LOG_FLUSH_FLAG | LOG_FUA_FLAG)) > 0,
+        });
+    }
+    Ok(writes)
+}
+
+/// Reads all the data of the log once, so the first backend run doesn't pay
+/// for getting it from disk and the others find it cached.
+pub fn preload(reader: &LogReader) -> Result<()> {
+    let mut buf = Vec::new();
+    for write in writes(reader)? {
+        buf.resize(write.len, 0);
+        reader.read_at(&mut buf, write.log_offset)?;
+    }
+    Ok(())
+}
+
+/// What one backend did on one target.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Run {
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub syscalls: u64,
+    /// User and system time of every thread of the process
+    pub cpu: Duration,
+}
+
+impl Run {
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / 1e6 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    pub fn cpu_percent(&self) -> f64 {
+        100.0 * self.cpu.as_secs_f64() / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+fn cpu_time() -> Duration {
+    let mut usage: nix::libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { nix::libc::getrusage(nix::libc::RUSAGE_SELF, &mut usage) };
+    let time = |tv: nix::libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// fdatasync, except that /dev/null and the like can't be synced and don't need to.
+fn datasync(file: &File) -> Result<()> {
+    match file.sync_data() {
+        Err(error) if error.raw_os_error() == Some(nix::libc::EINVAL) => Ok(()),
+        result => result.map_err(|error| anyhow!("Error syncing the target: {}", error)),
+    }
+}
+
+/// A `len` byte slice of `buf` starting on a `DIRECT_ALIGN` boundary.
+fn aligned(buf: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    buf.resize(len + DIRECT_ALIGN, 0);
+    let start = buf.as_ptr().align_offset(DIRECT_ALIGN);
+    &mut buf[start..start + len]
+}
+
+fn open_target(target: &Path, direct: bool) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if direct {
+        #[cfg(target_os = "linux")]
+        std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, nix::libc::O_DIRECT);
+        #[cfg(not(target_os = "linux"))]
+        bail!("O_DIRECT needs Linux");
+    }
+    options.open(target).map_err(|error| anyhow!("Error opening {}: {}", target.display(), error))
+}
+
+/// Replays the writes of `reader` onto `target` with `backend`, keeping up
+/// to `depth` writes queued where the backend queues them.
+pub fn run(reader: &LogReader, backend: Backend, target: &Path, depth: usize) -> Result<Run> {
+    let writes = writes(reader)?;
+    let file = open_target(target, backend == Backend::Direct)?;
+    let cpu = cpu_time();
+    let started = Instant::now();
+    let syscalls = match backend {
+        Backend::Sync => write_sync(reader, &writes, &file, false)?,
+        Backend::Direct => write_sync(reader, &writes, &file, true)?,
+        Backend::Pipelined => write_pipelined(reader, &writes, &file, depth)?,
+        Backend::IoUring => write_uring(reader, &writes, &file, depth)?,
+    };
+    datasync(&file)?;
+    Ok(Run {
+        bytes: writes.iter().map(|write| write.len as u64).sum(),
+        elapsed: started.elapsed(),
+        syscalls: syscalls + 1,
+        cpu: cpu_time().saturating_sub(cpu),
+    })
+}
+
+fn write_sync(reader: &LogReader, writes: &[Write], file: &File, direct: bool) -> Result<u64> {
+    let mut syscalls = 0;
+    let mut buf = Vec::new();
+    for write in writes {
+        if write.len > 0 {
+            let data = if direct { aligned(&mut buf, write.len) } else { buf.resize(write.len, 0); &mut buf[..] };
+            reader.read_at(data, write.log_offset)?;
+            file.write_all_at(data, write.target_offset)
+                .map_err(|error| anyhow!("Error writing {} bytes at {}: {}", write.len, write.target_offset, error))?;
+            syscalls += 2;
+        }
+        if write.barrier {
+            datasync(file)?;
+            syscalls += 1;
+        }
+    }
+    Ok(syscalls)
+}
+
+fn write_pipelined(reader: &LogReader, writes: &[Write], file: &File, depth: usize) -> Result<u64> {
+    let (full_tx, full_rx) = mpsc::sync_channel::<(Write, Vec<u8>)>(depth);
+    let (empty_tx, empty_rx) = mpsc::channel::<Vec<u8>>();
+    for _ in 0..depth {
+        empty_tx.send(Vec::new())?;
+    }
+    std::thread::scope(|scope| {
+        let read = scope.spawn(move || -> Result<u64> {
+            let mut syscalls = 0;
+            for write in writes {
+                // The writer hung up after failing, its error is the one to report
+                let Ok(mut buf) = empty_rx.recv() else { break };
+                buf.resize(write.len, 0);
+                if write.len > 0 {
+                    reader.read_at(&mut buf, write.log_offset)?;
+                    syscalls += 1;
+                }
+                if full_tx.send((*write, buf)).is_err() {
+                    break;
+                }
+            }
+            Ok(syscalls)
+        });
+        let mut syscalls = 0;
+        let written = (|| -> Result<()> {
+            for (write, buf) in full_rx.iter() {
+                if write.len > 0 {
+                    file.write_all_at(&buf, write.target_offset)
+                        .map_err(|error| anyhow!("Error writing {} bytes at {}: {}", write.len, write.target_offset, error))?;
+                    syscalls += 1;
+                }
+                if write.barrier {
+                    datasync(file)?;
+                    syscalls += 1;
+                }
+                let _ = empty_tx.send(buf);
+            }
+            Ok(())
+        })();
+        // Wakes the reader whether it waits to send or for a buffer
+        drop(full_rx);
+        drop(empty_tx);
+        let read = read.join().map_err(|_| anyhow!("The log reading thread panicked"))?;
+        written?;
+        Ok(syscalls + read?)
+    })
+}
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+fn write_uring(reader: &LogReader, writes: &[Write], file: &File, depth: usize) -> Result<u64> {
+    use std::os::unix::io::AsRawFd;
+    use io_uring::{opcode, types, IoUring};
+
+    let mut ring = IoUring::new(depth as u32)?;
+    let mut bufs: Vec<Vec<u8>> = (0..depth).map(|_| Vec::new()).collect();
+    let mut free: Vec<usize> = (0..depth).collect();
+    let mut lens = vec![0_usize; depth];
+    let mut syscalls = 0;
+
+    // Waits for at least `wait` writes in flight to finish
+    let reap = |ring: &mut IoUring, free: &mut Vec<usize>, lens: &[usize], syscalls: &mut u64, wait: usize| -> Result<()> {
+        ring.submit_and_wait(wait)?;
+        *syscalls += 1;
+        for cqe in ring.completion() {
+            let index = cqe.user_data() as usize;
+            if cqe.result() < 0 {
+                bail!("Error writing the target: {}", std::io::Error::from_raw_os_error(-cqe.result()))
+            }
+            if cqe.result() as usize != lens[index] {
+                bail!("Short write of {} bytes out of {}", cqe.result(), lens[index])
+            }
+            free.push(index);
+        }
+        Ok(())
+    };
+
+    for write in writes {
+        if write.len > 0 {
+            if free.is_empty() {
+                reap(&mut ring, &mut free, &lens, &mut syscalls, 1)?;
+            }
+            let index = free.pop().unwrap();
+            let buf = &mut bufs[index];
+            buf.resize(write.len, 0);
+            reader.read_at(buf, write.log_offset)?;
+            syscalls += 1;
+            lens[index] = write.len;
+            let len = u32::try_from(write.len).map_err(|_| anyhow!("A {} byte write is too big for io_uring", write.len))?;
+            let sqe = opcode::Write::new(types::Fd(file.as_raw_fd()), buf.as_ptr(), len)
+                .offset(write.target_offset)
+                .build()
+                .user_data(index as u64);
+            unsafe { ring.submission().push(&sqe) }.map_err(|_| anyhow!("The io_uring submission queue is full"))?;
+        }
+        if write.barrier {
+            while free.len() < depth {
+                let in_flight = depth - free.len();
+                reap(&mut ring, &mut free, &lens, &mut syscalls, in_flight)?;
+            }
+            datasync(file)?;
+            syscalls += 1;
+        }
+    }
+    while free.len() < depth {
+        let in_flight = depth - free.len();
+        reap(&mut ring, &mut free, &lens, &mut syscalls, in_flight)?;
+    }
+    Ok(syscalls)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "uring")))]
+fn write_uring(_reader: &LogReader, _writes: &[Write], _file: &File, _depth: usize) -> Result<u64> {
+    bail!("Not built with io_uring, it needs Linux and --features uring")
+}
+
+/// The comparison table, a line per target and backend. Failed runs show why
+/// instead of numbers.
+pub fn table(rows: &[(String, Backend, Result<Run>)]) -> Vec<String> {
+    let width = rows.iter().map(|(target, ..)| target.len()).max().unwrap_or(0).max("TARGET".len());
+    let mut lines = vec![format!("{:<width$}  {:<9}  {:>10}  {:>9}  {:>10}  {:>6}", "TARGET", "BACKEND", "MB/S", "SECONDS", "SYSCALLS", "CPU", width = width)];
+    for (target, backend, run) in rows {
+        lines.push(match run {
+            Ok(run) => format!("{:<width$}  {:<9}  {:>10.1}  {:>9.3}  {:>10}  {:>5.0}%", target, backend.to_string(),
+                               run.mb_per_sec(), run.elapsed.as_secs_f64(), run.syscalls, run.cpu_percent(), width = width),
+            Err(error) => format!("{:<width$}  {:<9}  {}", target, backend.to_string(), error, width = width),
+        });
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bench::{run, Backend};
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{LogWriteEntry, LOG_FLUSH_FLAG};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_backends_write_the_same() {
+        let dir = std::env::temp_dir();
+        let log_path = dir.join(format!("log-write-bench-{}.log", std::process::id()));
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        writer.append(&LogWriteEntry { sector: 8, nr_sectors: 2, flags: 0, data_len: 0, cmd: String::new() }, &[1; 1024]).unwrap();
+        writer.append(&LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_FLUSH_FLAG, data_len: 0, cmd: String::new() }, &[]).unwrap();
+        writer.append(&LogWriteEntry { sector: 1, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() }, &[2; 512]).unwrap();
+        writer.mark("done").unwrap();
+        writer.sync().unwrap();
+        let reader = LogReader::open(&log_path).unwrap();
+
+        let mut images = Vec::new();
+        for backend in [Backend::Sync, Backend::Pipelined] {
+            let image_path = dir.join(format!("log-write-bench-{}-{}.img", std::process::id(), backend));
+            std::fs::write(&image_path, [0; 8192]).unwrap();
+            let bytes = run(&reader, backend, &image_path, 2).unwrap().bytes;
+            images.push((bytes, std::fs::read(&image_path).unwrap()));
+            std::fs::remove_file(&image_path).unwrap();
+        }
+        std::fs::remove_file(&log_path).unwrap();
+        assert_eq!(images[0].0, 1536);
+        assert_eq!(images[0], images[1]);
+        assert_eq!((images[0].1[512], images[0].1[4096]), (2, 1));
+    }
+}
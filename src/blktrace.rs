@@ -0,0 +1,283 @@
+//! Conversion between dm-log-writes logs and the binary blktrace format read by
+//! blkparse, btt and iowatcher. blktrace has no data, so exports only carry the
+//! shape of the I/O and imports take their data from an image of the device.
+//! Marks travel as notify messages, like those from `blk_add_trace_msg`.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use anyhow::{Result, anyhow, bail};
+use bytes::{Buf, BufMut, BytesMut};
+use tracing::debug;
+use crate::io::{self, ByteOffset};
+use crate::log_reader::LogReader;
+use crate::log_writes::{LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG, LOG_METADATA_FLAG};
+use crate::writer::LogWriter;
+
+const MAGIC: u32 = 0x65617400;
+const VERSION: u32 = 0x07;
+const TRACE_SIZE: usize = 48;
+/// blktrace always counts 512 byte sectors
+const TRACE_SECTOR: u64 = 512;
+
+// Categories, in the top half of the action
+const TC_SHIFT: u32 = 16;
+const TC_WRITE: u32 = 1 << 1;
+const TC_FLUSH: u32 = 1 << 2;
+const TC_SYNC: u32 = 1 << 3;
+const TC_QUEUE: u32 = 1 << 4;
+const TC_COMPLETE: u32 = 1 << 7;
+const TC_FS: u32 = 1 << 8;
+const TC_NOTIFY: u32 = 1 << 10;
+const TC_META: u32 = 1 << 12;
+const TC_DISCARD: u32 = 1 << 13;
+const TC_FUA: u32 = 1 << 15;
+
+// Actions, in the bottom half
+const TA_QUEUE: u32 = 1;
+const TA_COMPLETE: u32 = 8;
+const TN_MESSAGE: u32 = 3;
+/// Set on events carrying a cgroup id in front of their payload
+const TA_CGROUP: u32 = 1 << 8;
+const CGROUP_ID_SIZE: usize = 8;
+
+/// One `struct blk_io_trace` and its payload.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Trace {
+    pub sequence: u32,
+    pub time: u64,
+    /// In 512 byte sectors
+    pub sector: u64,
+    pub bytes: u32,
+    pub action: u32,
+    pub pid: u32,
+    pub device: u32,
+    pub cpu: u32,
+    pub error: u16,
+    pub pdu: Vec<u8>,
+}
+
+impl Trace {
+    fn category(&self) -> u32 {
+        self.action >> TC_SHIFT
+    }
+
+    fn act(&self) -> u32 {
+        self.action & 0xff
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(TRACE_SIZE + self.pdu.len());
+        buf.put_u32_le(MAGIC | VERSION);
+        buf.put_u32_le(self.sequence);
+        buf.put_u64_le(self.time);
+        buf.put_u64_le(self.sector);
+        buf.put_u32_le(self.bytes);
+        buf.put_u32_le(self.action);
+        buf.put_u32_le(self.pid);
+        buf.put_u32_le(self.device);
+        buf.put_u32_le(self.cpu);
+        buf.put_u16_le(self.error);
+        buf.put_u16_le(self.pdu.len() as u16);
+        buf.put_slice(&self.pdu);
+        buf.to_vec()
+    }
+
+    /// Reads the next event, None at the end of the trace. Traces from big
+    /// endian machines are recognized by their magic and swapped.
+    pub fn read<R: Read>(input: &mut R) -> Result<Option<Self>> {
+        let mut raw = [0_u8; TRACE_SIZE];
+        match input.read_exact(&mut raw) {
+            Ok(()) => {}
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => bail!("Error reading trace: {}", error),
+        }
+        let big_endian = (u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) & !0xff) == MAGIC;
+        let mut buf = &raw[..];
+        let u32_field = |buf: &mut &[u8]| if big_endian { buf.get_u32() } else { buf.get_u32_le() };
+        let magic = u32_field(&mut buf);
+        if (magic & !0xff) != MAGIC {
+            bail!("Not a blktrace file, bad magic {:#x}", magic)
+        }
+        if (magic & 0xff) != VERSION {
+            bail!("Unsupported blktrace version {}", magic & 0xff)
+        }
+        let sequence = u32_field(&mut buf);
+        let (time, sector) = if big_endian {
+            (buf.get_u64(), buf.get_u64())
+        } else {
+            (buf.get_u64_le(), buf.get_u64_le())
+        };
+        let bytes = u32_field(&mut buf);
+        let action = u32_field(&mut buf);
+        let pid = u32_field(&mut buf);
+        let device = u32_field(&mut buf);
+        let cpu = u32_field(&mut buf);
+        let (error, pdu_len) = if big_endian {
+            (buf.get_u16(), buf.get_u16())
+        } else {
+            (buf.get_u16_le(), buf.get_u16_le())
+        };
+        let mut pdu = vec![0_u8; pdu_len as usize];
+        input.read_exact(&mut pdu).map_err(|error| anyhow!("Error reading trace payload: {}", error))?;
+        Ok(Some(Self { sequence, time, sector, bytes, action, pid, device, cpu, error, pdu }))
+    }
+}
+
+/// Writes a queue and a complete event for every entry, and a message for every
+/// mark. There are no timestamps in the log, entry `n` is timed at `n` microseconds.
+pub fn export<W: Write>(reader: &mut LogReader, out: &mut W) -> Result<u64> {
+    let sector_size = reader.sector_size() as u64;
+    let mut sequence = 0;
+    let mut num_entries = 0;
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        let time = log_entry.index * 1000;
+        let mut traces = Vec::new();
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            traces.push(Trace {
+                time,
+                action: TN_MESSAGE | (TC_NOTIFY << TC_SHIFT),
+                pdu: entry.cmd.as_bytes().to_vec(),
+                ..Default::default()
+            });
+        } else {
+            let mut category = TC_FS;
+            category |= if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+                TC_DISCARD | TC_WRITE
+            } else if entry.nr_sectors > 0 {
+                TC_WRITE
+            } else {
+                0
+            };
+            if (entry.flags & LOG_FLUSH_FLAG) > 0 {
+                category |= TC_FLUSH | TC_SYNC;
+            }
+            if (entry.flags & LOG_FUA_FLAG) > 0 {
+                category |= TC_FUA | TC_SYNC;
+            }
+            if (entry.flags & LOG_METADATA_FLAG) > 0 {
+                category |= TC_META;
+            }
+            let bytes = u32::try_from(entry.nr_sectors.saturating_mul(sector_size))
+                .map_err(|_| anyhow!("Entry {} is too large for blktrace", log_entry.index))?;
+            let trace = Trace {
+                time,
+                sector: entry.sector * sector_size / TRACE_SECTOR,
+                bytes,
+                ..Default::default()
+            };
+            traces.push(Trace { action: TA_QUEUE | ((category | TC_QUEUE) << TC_SHIFT), ..trace.clone() });
+            traces.push(Trace { action: TA_COMPLETE | ((category | TC_COMPLETE) << TC_SHIFT), ..trace });
+        }
+        for mut trace in traces {
+            trace.sequence = sequence;
+            sequence += 1;
+            out.write_all(&trace.to_bytes())?;
+        }
+        num_entries += 1;
+    }
+    Ok(num_entries)
+}
+
+/// Turns the completed writes, discards and flushes of a trace into log entries,
+/// and its messages into marks. Failed requests are left out. Write data is read
+/// from `data`, an image of the traced device, or zeros without one.
+pub fn import<R: Read>(input: &mut R, writer: &mut LogWriter, sector_size: u32, data: Option<&File>) -> Result<u64> {
+    let mut num_entries = 0;
+    while let Some(trace) = Trace::read(input)? {
+        let category = trace.category();
+        if trace.act() == TN_MESSAGE && (category & TC_NOTIFY) > 0 {
+            let pdu = if (trace.action & TA_CGROUP) > 0 {
+                trace.pdu.get(CGROUP_ID_SIZE..).unwrap_or_default()
+            } else {
+                &trace.pdu[..]
+            };
+            let name = String::from_utf8_lossy(pdu);
+            writer.mark(name.trim_end_matches('\0'))?;
+            num_entries += 1;
+            continue;
+        }
+        if trace.act() != TA_COMPLETE || (category & TC_FS) == 0 || (category & (TC_WRITE | TC_DISCARD | TC_FLUSH)) == 0 {
+            continue;
+        }
+        if trace.error != 0 {
+            debug!(sequence = trace.sequence, error = trace.error, "skipping failed request");
+            continue;
+        }
+        let offset = trace.sector * TRACE_SECTOR;
+        if !offset.is_multiple_of(sector_size as u64) || !trace.bytes.is_multiple_of(sector_size) {
+            bail!("Request at sector {} of {} bytes isn't aligned to {} byte sectors", trace.sector, trace.bytes, sector_size)
+        }
+
+        let mut flags = 0;
+        if (category & TC_DISCARD) > 0 {
+            flags |= LOG_DISCARD_FLAG;
+        }
+        if (category & TC_FLUSH) > 0 {
+            flags |= LOG_FLUSH_FLAG;
+        }
+        if (category & TC_FUA) > 0 {
+            flags |= LOG_FUA_FLAG;
+        }
+        if (category & TC_META) > 0 {
+            flags |= LOG_METADATA_FLAG;
+        }
+        let entry = LogWriteEntry {
+            sector: offset / sector_size as u64,
+            nr_sectors: (trace.bytes / sector_size) as u64,
+            flags,
+            data_len: 0,
+            cmd: String::new(),
+        };
+        let mut buf = vec![0_u8; entry.data_size(sector_size) as usize];
+        if let Some(data) = data {
+            // Past the end of the image reads as zeros
+            io::read_full_at(data, &mut buf, ByteOffset::new(offset)?)?;
+        }
+        writer.append(&entry, &buf)?;
+        num_entries += 1;
+    }
+    writer.sync()?;
+    Ok(num_entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blktrace::{export, import, Trace};
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+    use crate::testutil::{entry, TempPath};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_blktrace_round_trip() {
+        let log_path = TempPath::new("blktrace.log");
+        let imported_path = TempPath::new("blktrace-imported.log");
+        let mut writer = LogWriter::create(&log_path, 4096).unwrap();
+        writer.append(&entry(1, 2, LOG_FUA_FLAG), &[7; 8192]).unwrap();
+        writer.append(&entry(8, 4, LOG_DISCARD_FLAG), &[]).unwrap();
+        writer.mark("done").unwrap();
+        writer.append(&entry(0, 0, LOG_FLUSH_FLAG), &[]).unwrap();
+        writer.sync().unwrap();
+
+        let mut trace = Vec::new();
+        assert_eq!(export(&mut LogReader::open(&log_path).unwrap(), &mut trace).unwrap(), 4);
+        let first = Trace::read(&mut &trace[..]).unwrap().unwrap();
+        assert_eq!((first.sector, first.bytes), (8, 8192));
+
+        let mut writer = LogWriter::create(&imported_path, 4096).unwrap();
+        assert_eq!(import(&mut &trace[..], &mut writer, 4096, None).unwrap(), 4);
+        let entries: Vec<(u64, u64, u64, String)> = LogReader::open(&imported_path).unwrap()
+            .map(|entry| entry.unwrap().entry)
+            .map(|entry| (entry.sector, entry.nr_sectors, entry.flags, entry.cmd))
+            .collect();
+        assert_eq!(entries, vec![
+            (1, 2, LOG_FUA_FLAG, String::new()),
+            (8, 4, LOG_DISCARD_FLAG, String::new()),
+            (0, 0, LOG_MARK_FLAG, "done".to_string()),
+            (0, 0, LOG_FLUSH_FLAG, String::new()),
+        ]);
+        assert!(import(&mut &[0_u8; 48][..], &mut LogWriter::create(&imported_path, 512).unwrap(), 512, None).is_err());
+    }
+}
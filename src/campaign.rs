@@ -0,0 +1,248 @@
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, bail};
+use tracing::info;
+use crate::check::Checker;
+use crate::depgraph::DepGraph;
+use crate::io::{self, ByteOffset};
+use crate::log_reader::LogReader;
+use crate::log_writes::{Log, Tear, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_MARK_FLAG};
+
+/// Most of the target a snapshot copies at once
+const COPY_CHUNK: u64 = 1024 * 1024;
+
+/// splitmix64, kept in tree so a seed picks the same crash points in every build.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`, `n` must be greater than 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    pub fn coin(&mut self) -> bool {
+        self.next_u64() & 1 == 1
+    }
+}
+
+/// The device state a power cut right after submitting `crash` could leave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashPoint {
+    pub crash: u64,
+    /// Entries that reached the device, in log order
+    pub durable: Vec<u64>,
+    /// Entry that was in flight when the power went, written last
+    pub torn: Option<(u64, Tear)>,
+}
+
+impl CrashPoint {
+    /// Picks a crash entry, and with `tails` which of the entries since the last FLUSH
+    /// made it and one in flight to tear. An entry only lands if everything the
+    /// dependency graph orders before it landed too.
+    pub fn pick(graph: &DepGraph, rng: &mut Rng, tails: bool, sector_size: u32) -> Self {
+        let crash = rng.below(graph.nodes.len() as u64);
+        if !tails {
+            return Self { crash, durable: (0..=crash).collect(), torn: None };
+        }
+        let flushed = graph.nodes[..=crash as usize].iter()
+            .rposition(|node| (node.flags & LOG_FLUSH_FLAG) > 0)
+            .map_or(0, |index| index as u64 + 1);
+
+        let mut landed = vec![false; crash as usize + 1];
+        let mut in_flight = Vec::new();
+        for index in 0..=crash {
+            let ready = graph.edges.iter()
+                .filter(|edge| edge.to == index)
+                .all(|edge| landed[edge.from as usize]);
+            if index < flushed || (ready && rng.coin()) {
+                landed[index as usize] = true;
+                continue;
+            }
+            let node = &graph.nodes[index as usize];
+            // Tearing needs at least two sectors to leave something of both halves
+            if ready && node.nr_sectors > 1 && (node.flags & (LOG_MARK_FLAG | LOG_DISCARD_FLAG)) == 0 {
+                in_flight.push(index);
+            }
+        }
+        let durable = (0..=crash).filter(|index| landed[*index as usize]).collect();
+
+        let torn = if !in_flight.is_empty() && rng.coin() {
+            let index = in_flight[rng.below(in_flight.len() as u64) as usize];
+            let nr_sectors = graph.nodes[index as usize].nr_sectors;
+            let tear = if rng.coin() {
+                Tear::Garbage
+            } else {
+                Tear::Prefix((1 + rng.below(nr_sectors - 1)) * sector_size as u64)
+            };
+            Some((index, tear))
+        } else {
+            None
+        };
+        Self { crash, durable, torn }
+    }
+}
+
+/// A copy of the replay target from before the first run. Checks like
+/// `fsck -y` and `xfs_repair` write to the target as well as the replay, so
+/// rolling back only what was replayed would leave their repairs for the
+/// next run to find. Restoring costs a copy of the whole target per run.
+struct Snapshot {
+    file: File,
+    size: u64,
+}
+
+fn target_size(file: &File) -> Result<u64> {
+    let metadata = file.metadata()?;
+    if metadata.is_file() {
+        Ok(metadata.len())
+    } else {
+        io::block_device_size(file)
+    }
+}
+
+fn copy(from: &File, to: &File, len: u64) -> Result<()> {
+    let mut buf = vec![0_u8; len.min(COPY_CHUNK) as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut buf[..(len - done).min(COPY_CHUNK) as usize];
+        if io::read_full_at(from, chunk, ByteOffset::new(done)?)? != chunk.len() {
+            bail!("Short read at {}", done)
+        }
+        if io::write_full_at(to, chunk, ByteOffset::new(done)?)? != chunk.len() {
+            bail!("Short write at {}", done)
+        }
+        done += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+impl Snapshot {
+    fn take(replay_path: &Path, path: &Path) -> Result<Self> {
+        let target = File::open(replay_path)?;
+        let size = target_size(&target)?;
+        // Exclusively, the snapshot is in a shared directory and we may be root
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)
+            .map_err(|error| anyhow!("Error creating snapshot {}: {}", path.display(), error))?;
+        copy(&target, &file, size)?;
+        Ok(Self { file, size })
+    }
+
+    fn restore(&self, replay_path: &Path) -> Result<()> {
+        let target = OpenOptions::new().write(true).open(replay_path)?;
+        copy(&self.file, &target, self.size)?;
+        // Writes past the end grow files
+        if target.metadata()?.is_file() {
+            target.set_len(self.size)?;
+        }
+        target.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Checks `runs` random crash points, run `n` seeded with `seed + n` so a failure
+/// can be replayed alone with `--seed` and `--runs 1`. The replay target is
+/// restored from a snapshot after each run, returns the seeds of the failed runs.
+pub fn run(log_path: &str, replay_path: &str, checker: &Checker, seed: u64, runs: u64, tails: bool) -> Result<Vec<u64>> {
+    let mut reader = LogReader::open(log_path)?;
+    let graph = DepGraph::build(&mut reader)?;
+    if graph.nodes.is_empty() {
+        bail!("{} has no entries to crash at", log_path)
+    }
+
+    let snapshot_path = std::env::temp_dir().join(format!("log-write-campaign-{}.img", std::process::id()));
+    let snapshot = Snapshot::take(replay_path.as_ref(), &snapshot_path)?;
+    let _cleanup = RemoveOnDrop(snapshot_path.clone());
+    let mut log = Log::open(log_path, replay_path)?;
+
+    let mut failed = Vec::new();
+    for run in 0..runs {
+        let run_seed = seed.wrapping_add(run);
+        let point = CrashPoint::pick(&graph, &mut Rng::new(run_seed), tails, reader.sector_size());
+        for index in &point.durable {
+            log.replay_entry_at(*index)?;
+        }
+        if let Some((index, tear)) = point.torn {
+            log.tear_entry_at(index, Some(tear))?;
+        }
+        let outcome = checker.run(&log)?;
+        let torn = match point.torn {
+            Some((index, tear)) => format!(", entry {} torn {:?}", index, tear),
+            None => String::new(),
+        };
+        info!("run {} seed {}: crash at entry {}, {} entries landed{}, exit code {}",
+              run, run_seed, point.crash, point.durable.len(), torn, outcome.exit_code);
+        if outcome.exit_code != 0 {
+            failed.push(run_seed);
+        }
+
+        snapshot.restore(replay_path.as_ref())?;
+        info!(run, run_seed, "restored the replay target");
+    }
+    Ok(failed)
+}
+
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::campaign::{CrashPoint, Rng};
+    use crate::depgraph::{DepGraph, Edge, EdgeKind, Node};
+    use crate::log_writes::{Tear, LOG_FLUSH_FLAG};
+
+    fn node(index: u64, sector: u64, flags: u64) -> Node {
+        Node { index, sector, nr_sectors: 4, flags, mark: None }
+    }
+
+    #[test]
+    fn test_pick_crash_point() {
+        // 0 and 1 are flushed by 2, 4 overwrites 3
+        let graph = DepGraph {
+            nodes: vec![node(0, 0, 0), node(1, 8, 0), node(2, 16, LOG_FLUSH_FLAG), node(3, 24, 0), node(4, 24, 0), node(5, 32, 0)],
+            edges: vec![
+                Edge { from: 0, to: 2, kind: EdgeKind::Barrier },
+                Edge { from: 1, to: 2, kind: EdgeKind::Barrier },
+                Edge { from: 2, to: 3, kind: EdgeKind::Barrier },
+                Edge { from: 2, to: 4, kind: EdgeKind::Barrier },
+                Edge { from: 2, to: 5, kind: EdgeKind::Barrier },
+                Edge { from: 3, to: 4, kind: EdgeKind::Overlap },
+            ],
+        };
+        for seed in 0..200 {
+            let point = CrashPoint::pick(&graph, &mut Rng::new(seed), true, 512);
+            assert_eq!(point, CrashPoint::pick(&graph, &mut Rng::new(seed), true, 512));
+            if point.crash >= 2 {
+                assert_eq!(&point.durable[..3], &[0, 1, 2]);
+            }
+            if point.durable.contains(&4) {
+                assert!(point.durable.contains(&3));
+            }
+            if let Some((index, tear)) = point.torn {
+                assert!(!point.durable.contains(&index));
+                if let Tear::Prefix(bytes) = tear {
+                    assert!(bytes > 0 && bytes < 4 * 512 && bytes.is_multiple_of(512));
+                }
+            }
+            let all = CrashPoint::pick(&graph, &mut Rng::new(seed), false, 512);
+            assert_eq!(all.durable, (0..=all.crash).collect::<Vec<u64>>());
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Logs rotated during capture, chained back into one by a manifest listing
+//! the segments in order. Opening the manifest gives a log whose superblock
+//! counts every segment's entries and whose entries run on from one segment
+//! into the next, so numbering and mark lookups don't see the seams.
+//!
+//! A manifest is JSON, segment paths are relative to the manifest:
+//! `{"log_write_chain": 1, "segments": [{"path": "a.log", "nr_entries": 10, "end": 11264}]}`
+//! `end` is the offset right after a segment's last entry.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{Result, anyhow, bail};
+use serde_json::{json, Value};
+use crate::io::{ByteOffset, Whence};
+use crate::log_file::{self, LogFile};
+use crate::log_reader::LogReader;
+
+pub const CHAIN_VERSION: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub path: String,
+    pub nr_entries: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub segments: Vec<Segment>,
+}
+
+/// Whether the start of a file looks like a manifest rather than a log.
+pub fn is_manifest(head: &[u8]) -> bool {
+    head.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{')
+}
+
+fn resolve(path: &str, base: &Path) -> PathBuf {
+    if log_file::is_url(path) || Path::new(path).is_absolute() {
+        PathBuf::from(path)
+    } else {
+        base.join(path)
+    }
+}
+
+impl Manifest {
+    /// Reads the segments at `paths`, in capture order, for a manifest in `base`.
+    pub fn scan(paths: &[&str], base: &Path) -> Result<Self> {
+        let mut segments = Vec::with_capacity(paths.len());
+        let mut sector_size = None;
+        for path in paths {
+            let mut reader = LogReader::open(resolve(path, base))?;
+            if *sector_size.get_or_insert(reader.sector_size()) != reader.sector_size() {
+                bail!("Segment {} has {} byte sectors, the chain {}", path, reader.sector_size(), sector_size.unwrap())
+            }
+            let mut end = reader.sector_size() as u64;
+            let mut nr_entries = 0;
+            while let Some(log_entry) = reader.next_entry()? {
+                end = reader.data_offset(&log_entry) + reader.data_size(&log_entry.entry);
+                nr_entries += 1;
+            }
+            segments.push(Segment { path: path.to_string(), nr_entries, end });
+        }
+        if segments.is_empty() {
+            bail!("A chain needs at least one segment")
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(text).map_err(|error| anyhow!("Error parsing chain manifest: {}", error))?;
+        match value["log_write_chain"].as_u64() {
+            Some(CHAIN_VERSION) => {}
+            Some(version) => bail!("Chain manifest version {} is newer than this log-write", version),
+            None => bail!("Not a chain manifest, log_write_chain is missing"),
+        }
+        let segments = value["segments"].as_array().ok_or_else(|| anyhow!("Chain manifest has no segments"))?
+            .iter()
+            .map(|segment| {
+                let field = |name: &str| segment[name].as_u64().ok_or_else(|| anyhow!("Chain segment without {}", name));
+                Ok(Segment {
+                    path: segment["path"].as_str().ok_or_else(|| anyhow!("Chain segment without a path"))?.to_string(),
+                    nr_entries: field("nr_entries")?,
+                    end: field("end")?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if segments.is_empty() {
+            bail!("Chain manifest has no segments")
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn to_json(&self) -> String {
+        let segments: Vec<Value> = self.segments.iter()
+            .map(|segment| json!({ "path": segment.path, "nr_entries": segment.nr_entries, "end": segment.end }))
+            .collect();
+        serde_json::to_string_pretty(&json!({ "log_write_chain": CHAIN_VERSION, "segments": segments })).unwrap()
+    }
+}
+
+struct Part {
+    file: LogFile,
+    /// Where the segment's entries start in the chained log
+    start: u64,
+    len: u64,
+}
+
+struct Chain {
+    /// The first segment's superblock sector, counting every entry
+    super_sector: Vec<u8>,
+    parts: Vec<Part>,
+    size: u64,
+}
+
+/// An open chain. Clones share the position, like a dup'd file descriptor.
+#[derive(Clone)]
+pub struct ChainFile {
+    chain: Arc<Chain>,
+    pos: Arc<Mutex<u64>>,
+}
+
+impl ChainFile {
+    /// Opens the segments of `manifest`, which is in `base`.
+    pub fn open(manifest: &Manifest, base: &Path) -> Result<Self> {
+        let mut super_sector = Vec::new();
+        let mut parts = Vec::with_capacity(manifest.segments.len());
+        let mut sector_size = 0;
+        let mut nr_entries = 0;
+        for segment in &manifest.segments {
+            let file = LogFile::open(resolve(&segment.path, base))?;
+            let mut buf = [0_u8; 32];
+            if file.read_full_at(&mut buf, ByteOffset::ZERO)? != buf.len() {
+                bail!("Segment {} is too short for a superblock", segment.path)
+            }
+            let log_super = crate::log_writes::parse_super(buf)?;
+            if super_sector.is_empty() {
+                sector_size = log_super.sector_size as u64;
+                super_sector = vec![0_u8; sector_size as usize];
+                file.read_full_at(&mut super_sector, ByteOffset::ZERO)?;
+            } else if log_super.sector_size as u64 != sector_size {
+                bail!("Segment {} has {} byte sectors, the chain {}", segment.path, log_super.sector_size, sector_size)
+            }
+            if log_super.nr_entries < segment.nr_entries || file.size()? < segment.end || segment.end < sector_size {
+                bail!("Segment {} is shorter than the manifest says", segment.path)
+            }
+            let start = parts.last().map(|part: &Part| part.start + part.len).unwrap_or(sector_size);
+            parts.push(Part { file, start, len: segment.end - sector_size });
+            nr_entries += segment.nr_entries;
+        }
+        super_sector[16..24].copy_from_slice(&nr_entries.to_le_bytes());
+        let size = parts.last().map(|part| part.start + part.len).unwrap_or(sector_size);
+        Ok(Self { chain: Arc::new(Chain { super_sector, parts, size }), pos: Arc::new(Mutex::new(0)) })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.chain.size
+    }
+
+    pub fn read_full_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let chain = &self.chain;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let wanted = buf.len() - done;
+            let len = if pos < chain.super_sector.len() as u64 {
+                let len = wanted.min(chain.super_sector.len() - pos as usize);
+                buf[done..done + len].copy_from_slice(&chain.super_sector[pos as usize..pos as usize + len]);
+                len
+            } else {
+                let Some(part) = chain.parts.iter().find(|part| pos < part.start + part.len) else {
+                    break;
+                };
+                let at = pos - part.start;
+                let len = wanted.min((part.len - at) as usize);
+                let offset = ByteOffset::new(chain.super_sector.len() as u64 + at)?;
+                if part.file.read_full_at(&mut buf[done..done + len], offset)? != len {
+                    bail!("Short read from a chain segment at {}", pos)
+                }
+                len
+            };
+            done += len;
+        }
+        Ok(done)
+    }
+
+    pub fn read_full(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut pos = self.pos.lock().unwrap();
+        let done = self.read_full_at(buf, *pos)?;
+        *pos += done as u64;
+        Ok(done)
+    }
+
+    pub fn seek(&self, offset: i64, whence: Whence) -> Result<i64> {
+        let mut pos = self.pos.lock().unwrap();
+        let base = match whence {
+            Whence::SeekSet => 0,
+            Whence::SeekCur => *pos as i64,
+            Whence::SeekEnd => self.chain.size as i64,
+            // nix's Whence also has SEEK_DATA and SEEK_HOLE
+            #[allow(unreachable_patterns)]
+            _ => bail!("Chained logs have no holes to seek to"),
+        };
+        let new = base.checked_add(offset).filter(|new| *new >= 0)
+            .ok_or_else(|| anyhow!("Invalid seek to {} from {}", offset, base))?;
+        *pos = new as u64;
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use crate::chain::{is_manifest, Manifest};
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{Log, LogWriteEntry, LOG_MARK_FLAG};
+    use crate::testutil::{build_log, write, TempPath};
+
+    /// A segment writing `nr_entries` sectors from `first`, then marking `mark`.
+    fn segment(first: u64, nr_entries: u64, mark: &str) -> Vec<u8> {
+        let mut entries: Vec<LogWriteEntry> = (first..first + nr_entries).map(|i| write(i, 1)).collect();
+        entries.push(LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_MARK_FLAG, data_len: mark.len() as u64, cmd: mark.to_string() });
+        let mut log = build_log(&entries, |i| (first + i as u64) as u8);
+        // Preallocated space past the last entry
+        log.extend(vec![0_u8; 4096]);
+        log
+    }
+
+    #[test]
+    fn test_chain() {
+        let dir = TempPath::new("chain");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.log"), segment(0, 3, "one")).unwrap();
+        std::fs::write(dir.join("b.log"), segment(3, 2, "two")).unwrap();
+        let manifest = Manifest::scan(&["a.log", "b.log"], &dir).unwrap();
+        assert_eq!((manifest.segments[0].nr_entries, manifest.segments[0].end), (4, 512 + 3 * 1024 + 512));
+        assert_eq!(Manifest::parse(&manifest.to_json()).unwrap(), manifest);
+        assert!(is_manifest(manifest.to_json().as_bytes()));
+        let manifest_path = dir.join("capture.chain");
+        std::fs::write(&manifest_path, manifest.to_json()).unwrap();
+        let replay_path = dir.join("replay.img");
+        std::fs::write(&replay_path, [0_u8; 4096]).unwrap();
+
+        let mut reader = LogReader::open(&manifest_path).unwrap();
+        let marks: Vec<(u64, String)> = reader.by_ref().map(|entry| entry.unwrap())
+            .filter(|entry| (entry.entry.flags & LOG_MARK_FLAG) > 0)
+            .map(|entry| (entry.index, entry.entry.cmd))
+            .collect();
+        let mut log = Log::open(&manifest_path, &replay_path).unwrap();
+        let progress = log.replay(None, &AtomicBool::new(false)).unwrap();
+        let replayed = std::fs::read(&replay_path).unwrap();
+
+        assert_eq!(reader.nr_entries(), 7);
+        assert_eq!(marks, [(3, "one".to_string()), (6, "two".to_string())]);
+        assert_eq!(progress.entries_replayed, 7);
+        assert_eq!(&replayed[4 * 512..5 * 512], &[4_u8; 512][..]);
+    }
+}
@@ -0,0 +1,205 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow, bail};
+use tracing::{info, info_span, warn};
+use crate::log_writes::{Log, LogWriteEntry, LOG_FLUSH_FLAG, LOG_FUA_FLAG};
+use crate::mount::{Mount, MountOptions};
+use crate::fsprobe;
+use crate::sys;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CheckMode {
+    Number(u64),
+    Flush,
+    Fua,
+}
+
+impl FromStr for CheckMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flush" => Ok(CheckMode::Flush),
+            "fua" => Ok(CheckMode::Fua),
+            _ => {
+                let n: u64 = s.parse().map_err(|_| {
+                    anyhow!("Invalid check mode {}, expected a number, flush or fua", s)
+                })?;
+                if n == 0 {
+                    bail!("Check number must be greater than 0")
+                }
+                Ok(CheckMode::Number(n))
+            }
+        }
+    }
+}
+
+impl CheckMode {
+    pub fn is_checkpoint(&self, entry: &LogWriteEntry, num_entries: u64) -> bool {
+        match self {
+            CheckMode::Number(n) => num_entries.is_multiple_of(*n),
+            CheckMode::Flush => (entry.flags & LOG_FLUSH_FLAG) > 0,
+            CheckMode::Fua => (entry.flags & LOG_FUA_FLAG) > 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CheckOutcome {
+    pub exit_code: i32,
+    pub duration: Duration,
+    /// Combined stdout and stderr of the fsck command
+    pub output: String,
+}
+
+/// Where the fsck command is executed. The replay target is always visible at the
+/// same path it has on the host, so the command line doesn't change between modes.
+#[derive(Debug, Clone)]
+pub enum CheckEnv {
+    Host,
+    Chroot(PathBuf),
+    Container { runtime: String, image: String },
+}
+
+/// `--fsck auto` picks the checker from the filesystem found on the replay target
+pub const FSCK_AUTO: &str = "auto";
+
+#[derive(Debug, Clone)]
+pub struct Checker {
+    pub mode: CheckMode,
+    pub fsck_cmd: String,
+    pub env: CheckEnv,
+    pub replay_path: PathBuf,
+    /// Mount the replay target read-only and pass the mountpoint to the fsck command
+    pub mount: Option<MountOptions>,
+}
+
+/// Bind mount of the replay target inside a chroot, removed on drop.
+struct BindMount {
+    target: PathBuf,
+}
+
+impl BindMount {
+    fn new(source: &Path, root: &Path) -> Result<Self> {
+        let relative = source.strip_prefix("/").unwrap_or(source);
+        let target = root.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if !target.exists() {
+            OpenOptions::new().write(true).create(true).truncate(false).open(&target)?;
+        }
+        sys::bind_mount(source, &target).map_err(|error| {
+            anyhow!("Error bind mounting {} at {}: {}", source.display(), target.display(), error)
+        })?;
+        Ok(Self { target })
+    }
+}
+
+impl Drop for BindMount {
+    fn drop(&mut self) {
+        if let Err(error) = sys::umount(&self.target) {
+            warn!("Error unmounting {}: {}", self.target.display(), error);
+        }
+    }
+}
+
+impl Checker {
+    fn fsck_command(&self) -> Result<String> {
+        if self.fsck_cmd != FSCK_AUTO {
+            return Ok(self.fsck_cmd.clone());
+        }
+        match fsprobe::probe(&self.replay_path)? {
+            Some(fs_type) => {
+                info!("detected {} filesystem on {}", fs_type, self.replay_path.display());
+                Ok(fs_type.check_command(&self.replay_path))
+            }
+            None => bail!("No known filesystem found on {}", self.replay_path.display()),
+        }
+    }
+
+    fn command(&self, fsck_cmd: &str, mountpoint: Option<&Path>) -> Result<Command> {
+        let cmd = match &self.env {
+            CheckEnv::Host => {
+                let mut cmd = Command::new("sh");
+                match mountpoint {
+                    Some(mountpoint) => {
+                        cmd.arg("-c").arg(format!("{} \"$1\"", fsck_cmd)).arg("sh").arg(mountpoint);
+                    }
+                    None => {
+                        cmd.arg("-c").arg(fsck_cmd);
+                    }
+                }
+                cmd
+            }
+            CheckEnv::Chroot(root) => {
+                let mut cmd = Command::new("chroot");
+                cmd.arg(root).arg("sh").arg("-c").arg(fsck_cmd);
+                cmd
+            }
+            CheckEnv::Container { runtime, image } => {
+                let replay_path = fs::canonicalize(&self.replay_path)?;
+                let is_block = fs::metadata(&replay_path)?.file_type().is_block_device();
+                let mapping = format!("{}:{}", replay_path.display(), replay_path.display());
+                let mut cmd = Command::new(runtime);
+                cmd.arg("run").arg("--rm").arg("--privileged");
+                if is_block {
+                    cmd.arg("--device").arg(mapping);
+                } else {
+                    cmd.arg("-v").arg(mapping);
+                }
+                cmd.arg(image).arg("sh").arg("-c").arg(fsck_cmd);
+                cmd
+            }
+        };
+        Ok(cmd)
+    }
+
+    pub fn run(&self, log: &Log) -> Result<CheckOutcome> {
+        let _span = info_span!("fsck", entry = log.cur_entry.saturating_sub(1)).entered();
+        log.fsync_replay_file()?;
+        let fsck_cmd = self.fsck_command()?;
+        let _bind = match &self.env {
+            CheckEnv::Chroot(root) => Some(BindMount::new(&fs::canonicalize(&self.replay_path)?, root)?),
+            _ => None,
+        };
+        let mount = match &self.mount {
+            Some(opts) => Some(Mount::read_only(&self.replay_path, opts)?),
+            None => None,
+        };
+        let start = Instant::now();
+        let output = self.command(&fsck_cmd, mount.as_ref().map(|mount| mount.path()))?.output().map_err(|error| {
+            anyhow!("Error running fsck command {}: {}", fsck_cmd, error)
+        })?;
+        // Captured for reporting, but still shown as the command ran
+        std::io::stdout().write_all(&output.stdout)?;
+        std::io::stderr().write_all(&output.stderr)?;
+        let outcome = CheckOutcome {
+            // Killed by a signal, report it the way the shell would
+            exit_code: output.status.code().unwrap_or(-1),
+            duration: start.elapsed(),
+            output: String::from_utf8_lossy(&[output.stdout, output.stderr].concat()).into_owned(),
+        };
+        info!(cmd = %fsck_cmd, exit_code = outcome.exit_code, duration_ms = outcome.duration.as_millis() as u64, "fsck finished");
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::check::CheckMode;
+
+    #[test]
+    fn test_parse_check_mode() {
+        assert_eq!("flush".parse::<CheckMode>().unwrap(), CheckMode::Flush);
+        assert_eq!("fua".parse::<CheckMode>().unwrap(), CheckMode::Fua);
+        assert_eq!("10".parse::<CheckMode>().unwrap(), CheckMode::Number(10));
+        assert!("0".parse::<CheckMode>().is_err());
+        assert!("barrier".parse::<CheckMode>().is_err());
+    }
+}
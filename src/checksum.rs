@@ -0,0 +1,53 @@
+//! Checksums shared by everything that verifies replays or logs. xxh3 for
+//! content hashes and CRC32C where a format wants a CRC, both use SIMD or the
+//! CPU's CRC instructions where there are some so hashing keeps up with NVMe.
+
+use crate::format::LogWriteEntry;
+
+pub use xxhash_rust::xxh3::Xxh3;
+
+pub fn xxh3(data: &[u8]) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(data)
+}
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c::crc32c(data)
+}
+
+/// Continues `crc` over `data`, for checksums of data read in pieces.
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    crc32c::crc32c_append(crc, data)
+}
+
+/// How hashes are written out in journals, plans and results.
+pub fn hex(digest: u64) -> String {
+    format!("{:016x}", digest)
+}
+
+/// xxh3 of an entry's header, mark name included, and its data. Stable across
+/// versions, so hashes recorded by one can be checked by another.
+pub fn hash_entry(entry: &LogWriteEntry, data: &[u8]) -> u64 {
+    let mut hasher = Xxh3::new();
+    hasher.update(&entry.to_bytes());
+    hasher.update(data);
+    hasher.digest()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::{crc32c, crc32c_append, hash_entry, hex, xxh3};
+    use crate::format::LogWriteEntry;
+
+    #[test]
+    fn test_checksums() {
+        // The check value from the CRC32C spec
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(crc32c_append(crc32c(b"1234"), b"56789"), 0xe3069283);
+        assert_eq!(hex(xxh3(b"")), "2d06800538d394c2");
+
+        let entry = LogWriteEntry { sector: 8, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() };
+        let moved = LogWriteEntry { sector: 9, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() };
+        assert_ne!(hash_entry(&entry, &[1; 512]), hash_entry(&moved, &[1; 512]));
+        assert_ne!(hash_entry(&moved, &[1; 512]), hash_entry(&moved, &[2; 512]));
+    }
+}
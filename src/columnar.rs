@@ -0,0 +1,152 @@
+//! One row per entry in Parquet, for querying thousands of logs at once with
+//! DuckDB or Spark. Only metadata is written, payloads are reduced to a hash.
+
+use std::fs::File;
+use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use crate::checksum;
+use crate::log_reader::LogReader;
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+
+const SCHEMA: &str = "
+message entry {
+    required int64 index (INTEGER(64, false));
+    required int64 sector (INTEGER(64, false));
+    required int64 nr_sectors (INTEGER(64, false));
+    required int64 flags (INTEGER(64, false));
+    required int64 data_len (INTEGER(64, false));
+    optional binary phase (STRING);
+    optional int64 payload_hash (INTEGER(64, false));
+}
+";
+
+/// Rows buffered before a row group is written
+const ROW_GROUP_SIZE: usize = 1 << 20;
+
+#[derive(Default)]
+struct Columns {
+    index: Vec<i64>,
+    sector: Vec<i64>,
+    nr_sectors: Vec<i64>,
+    flags: Vec<i64>,
+    data_len: Vec<i64>,
+    phase: Vec<ByteArray>,
+    phase_levels: Vec<i16>,
+    payload_hash: Vec<i64>,
+    hash_levels: Vec<i16>,
+}
+
+impl Columns {
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn write(&mut self, writer: &mut SerializedFileWriter<File>) -> Result<()> {
+        let mut row_group = writer.next_row_group()?;
+        let required = [&self.index, &self.sector, &self.nr_sectors, &self.flags, &self.data_len];
+        for values in required.iter() {
+            let mut column = row_group.next_column()?.ok_or_else(|| anyhow!("Error writing parquet, schema is short a column"))?;
+            column.typed::<Int64Type>().write_batch(values, None, None)?;
+            column.close()?;
+        }
+        let mut column = row_group.next_column()?.ok_or_else(|| anyhow!("Error writing parquet, schema is short a column"))?;
+        column.typed::<ByteArrayType>().write_batch(&self.phase, Some(&self.phase_levels), None)?;
+        column.close()?;
+        let mut column = row_group.next_column()?.ok_or_else(|| anyhow!("Error writing parquet, schema is short a column"))?;
+        column.typed::<Int64Type>().write_batch(&self.payload_hash, Some(&self.hash_levels), None)?;
+        column.close()?;
+        row_group.close()?;
+        *self = Self::default();
+        Ok(())
+    }
+}
+
+/// Writes a row for every entry in `reader` to `out`. `phase` is the name of the
+/// last mark at or before the entry, `payload_hash` the xxh3 of its data when
+/// `hash` is set. Returns the number of rows.
+pub fn write_parquet(reader: &mut LogReader, hash: bool, out: File) -> Result<u64> {
+    let schema = Arc::new(parse_message_type(SCHEMA)?);
+    let props = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+    let mut writer = SerializedFileWriter::new(out, schema, props)?;
+    let mut columns = Columns::default();
+    let mut phase: Option<ByteArray> = None;
+    let mut num_entries = 0;
+    let mut buf = Vec::new();
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        num_entries += 1;
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            phase = Some(ByteArray::from(entry.cmd.as_str()));
+        }
+        columns.index.push(log_entry.index as i64);
+        columns.sector.push(entry.sector as i64);
+        columns.nr_sectors.push(entry.nr_sectors as i64);
+        columns.flags.push(entry.flags as i64);
+        columns.data_len.push(entry.data_len as i64);
+        match &phase {
+            Some(phase) => {
+                columns.phase.push(phase.clone());
+                columns.phase_levels.push(1);
+            }
+            None => columns.phase_levels.push(0),
+        }
+        if hash && (entry.flags & (LOG_MARK_FLAG | LOG_DISCARD_FLAG)) == 0 && entry.nr_sectors > 0 {
+            buf.resize(reader.data_size(entry) as usize, 0);
+            reader.read_at(&mut buf, reader.data_offset(&log_entry))?;
+            columns.payload_hash.push(checksum::xxh3(&buf) as i64);
+            columns.hash_levels.push(1);
+        } else {
+            columns.hash_levels.push(0);
+        }
+        if columns.len() == ROW_GROUP_SIZE {
+            columns.write(&mut writer)?;
+        }
+    }
+    if columns.len() > 0 {
+        columns.write(&mut writer)?;
+    }
+    writer.close()?;
+    Ok(num_entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+    use crate::checksum;
+    use crate::columnar::write_parquet;
+    use crate::log_reader::LogReader;
+    use crate::log_writes::LOG_DISCARD_FLAG;
+    use crate::testutil::{entry, write, TempPath};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_write_parquet() {
+        let log_path = TempPath::new("columnar.log");
+        let parquet_path = TempPath::new("columnar.parquet");
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        writer.append(&write(8, 1), &[3; 512]).unwrap();
+        writer.mark("mkfs").unwrap();
+        writer.append(&entry(16, 4, LOG_DISCARD_FLAG), &[]).unwrap();
+        writer.sync().unwrap();
+
+        let out = std::fs::File::create(&parquet_path).unwrap();
+        assert_eq!(write_parquet(&mut LogReader::open(&log_path).unwrap(), true, out).unwrap(), 3);
+        let reader = SerializedFileReader::new(std::fs::File::open(&parquet_path).unwrap()).unwrap();
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap()).collect();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!((rows[0].get_ulong(1).unwrap(), rows[0].get_ulong(2).unwrap()), (8, 1));
+        assert!(rows[0].get_string(5).is_err());
+        assert_eq!(rows[0].get_ulong(6).unwrap(), checksum::xxh3(&[3; 512]));
+        assert_eq!(rows[1].get_string(5).unwrap(), "mkfs");
+        assert_eq!((rows[2].get_ulong(0).unwrap(), rows[2].get_ulong(3).unwrap()), (2, LOG_DISCARD_FLAG));
+        assert_eq!(rows[2].get_string(5).unwrap(), "mkfs");
+        assert!(rows[2].get_ulong(6).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Defaults for replay flags, so scripts don't all repeat them. A flag given
+//! on the command line wins, then a `LOG_WRITES_*` variable, then the config
+//! file, then the flag's own default.
+//!
+//! The config is TOML, keys named after the long flags, in
+//! `$XDG_CONFIG_HOME/log-write-rs.toml` or `~/.config/log-write-rs.toml`
+//! unless `--config` names another:
+//! ```toml
+//! fsck = "fsck.ext4 -fn"
+//! check = "fua"
+//! retry = 3
+//! notify-url = "https://ci.example.com/hooks/log-write"
+//! strict = true
+//! ```
+//! The variables are the flag in capitals with underscores,
+//! e.g. `LOG_WRITES_NOTIFY_URL`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, bail};
+use clap::ArgMatches;
+
+pub const ENV_PREFIX: &str = "LOG_WRITES_";
+
+/// The flags a config or variable can set. --force and --yes aren't among
+/// them, overriding the replay target checks takes asking on the command line.
+pub const KEYS: &[&str] = &[
+    "check", "check-chroot", "check-container", "container-runtime", "failure-report", "fsck",
+    "hash-device", "hook", "hook-batch", "hook-payload", "io-class", "keep-going", "max-memory",
+    "metrics-listen", "mount-check", "mount-options", "mount-type", "no-discard", "notify-url",
+    "nowait", "results-db", "resume", "retry", "retry-delay", "skip-zero-writes", "strict",
+];
+
+#[derive(Debug, Default)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+/// The config file used without --config, if there's a home to find it in.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("log-write-rs.toml"))
+}
+
+impl Config {
+    /// Reads `path`, or the default config if there is one, then the environment.
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let mut config = match (path, default_path()) {
+            (Some(path), _) => Self::parse(&read(Path::new(path))?)?,
+            (None, Some(path)) if path.exists() => Self::parse(&read(&path)?)?,
+            _ => Self::default(),
+        };
+        config.merge_env(std::env::vars());
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let table: toml::Table = text.parse().map_err(|error| anyhow!("Error parsing config: {}", error))?;
+        let mut values = HashMap::new();
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => bail!("Config key {} isn't a string, number or boolean", key),
+            };
+            let key = key.replace('_', "-");
+            if !KEYS.contains(&key.as_str()) {
+                bail!("Unknown config key {}", key)
+            }
+            values.insert(key, value);
+        }
+        Ok(Self { values })
+    }
+
+    fn merge_env<I: Iterator<Item = (String, String)>>(&mut self, vars: I) {
+        for (name, value) in vars {
+            // Others share the prefix, like the LOG_WRITES_DEV record sets
+            if let Some(key) = name.strip_prefix(ENV_PREFIX).map(|key| key.to_ascii_lowercase().replace('_', "-")) {
+                if KEYS.contains(&key.as_str()) {
+                    self.values.insert(key, value);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|error| anyhow!("Error reading config {}: {}", path.display(), error))
+}
+
+/// The flags of a command, with the config filling in those not given.
+pub struct Flags<'a> {
+    matches: &'a ArgMatches<'a>,
+    config: &'a Config,
+}
+
+impl<'a> Flags<'a> {
+    pub fn new(matches: &'a ArgMatches<'a>, config: &'a Config) -> Self {
+        Self { matches, config }
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<&'a str> {
+        if self.matches.occurrences_of(name) > 0 {
+            return self.matches.value_of(name);
+        }
+        self.config.get(name).or_else(|| self.matches.value_of(name))
+    }
+
+    /// Whether the flag was given, a config value of false or 0 leaves it off.
+    pub fn is_present(&self, name: &str) -> bool {
+        if self.matches.is_present(name) {
+            return true;
+        }
+        !matches!(self.config.get(name), None | Some("false") | Some("0") | Some(""))
+    }
+
+    /// Fails if `name` is set without `needs`, what clap's requires checks
+    /// before the config is merged in.
+    pub fn require(&self, name: &str, needs: &str) -> Result<()> {
+        // Any value counts for options, `fsck = "false"` is a command
+        if self.is_present(name) && self.value_of(needs).is_none() && !self.is_present(needs) {
+            bail!("--{} needs --{}, on the command line or in the config", name, needs)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{App, Arg};
+    use crate::config::{Config, Flags};
+
+    #[test]
+    fn test_flags() {
+        let mut config = Config::parse("fsck = \"fsck.ext4 -fn\"\nretry = 3\nstrict = true\nno_discard = false\n").unwrap();
+        config.merge_env(vec![
+            ("LOG_WRITES_RETRY".to_string(), "5".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+            ("LOG_WRITES_YES".to_string(), "1".to_string()),
+        ].into_iter());
+        let app = App::new("test")
+            .arg(Arg::with_name("fsck").long("fsck").takes_value(true))
+            .arg(Arg::with_name("retry").long("retry").takes_value(true).default_value("0"))
+            .arg(Arg::with_name("retry-delay").long("retry-delay").takes_value(true).default_value("100"))
+            .arg(Arg::with_name("strict").long("strict"))
+            .arg(Arg::with_name("no-discard").long("no-discard"))
+            .arg(Arg::with_name("check").long("check").takes_value(true));
+        let matches = app.get_matches_from(vec!["test", "--fsck", "xfs_repair -n"]);
+        let flags = Flags::new(&matches, &config);
+
+        assert_eq!(flags.value_of("fsck"), Some("xfs_repair -n"));
+        assert_eq!(flags.value_of("retry"), Some("5"));
+        assert_eq!(flags.value_of("retry-delay"), Some("100"));
+        assert!(flags.is_present("strict"));
+        assert!(!flags.is_present("no-discard"));
+        assert_eq!(config.get("home"), None);
+        assert_eq!(config.get("yes"), None);
+        assert!(flags.require("fsck", "check").is_err());
+        assert!(Flags::new(&matches, &Config::parse("check = \"fua\"\nfsck = \"false\"").unwrap()).require("check", "fsck").is_ok());
+        assert!(Config::parse("hook-on = [\"mark\"]").is_err());
+        assert!(Config::parse("force = true").is_err());
+        assert!(Config::parse("fsk = \"fsck.ext4 -fn\"").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Replays a log with this crate and with the C `replay-log` from xfstests,
+//! then compares the two images to check the port is a drop-in replacement.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use anyhow::{Result, anyhow, bail};
+use tracing::info;
+use crate::log_reader::LogReader;
+use crate::log_writes::{Log, LOG_MARK_FLAG};
+use crate::state::{SectorMap, Writer};
+
+/// Where the two replays first disagree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub sector: u64,
+    /// The last entry writing `sector`, None if no entry wrote it
+    pub entry: Option<u64>,
+    pub rust_image: PathBuf,
+    pub c_image: PathBuf,
+}
+
+/// First sector the two images differ in, an image ending early differs from there.
+pub fn first_difference<A: Read, B: Read>(mut a: A, mut b: B, sector_size: u32) -> Result<Option<u64>> {
+    let mut buf_a = vec![0_u8; sector_size as usize];
+    let mut buf_b = vec![0_u8; sector_size as usize];
+    let mut sector = 0;
+    loop {
+        let len_a = read_sector(&mut a, &mut buf_a)?;
+        let len_b = read_sector(&mut b, &mut buf_b)?;
+        if len_a != len_b || buf_a[..len_a] != buf_b[..len_b] {
+            return Ok(Some(sector));
+        }
+        if len_a < buf_a.len() {
+            return Ok(None);
+        }
+        sector += 1;
+    }
+}
+
+fn read_sector<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut done = 0;
+    while done < buf.len() {
+        match reader.read(&mut buf[done..]) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => bail!("Error reading image: {}", error),
+        }
+    }
+    Ok(done)
+}
+
+/// Replays all of `log_path` into two images in `dir`, one by each implementation.
+/// The images are removed when they match and kept for inspection when they don't.
+pub fn run(log_path: &str, replay_log: &str, dir: &Path) -> Result<Option<Mismatch>> {
+    let mut reader = LogReader::open(log_path)?;
+    let sector_size = reader.sector_size();
+    let mut writers: SectorMap<Writer> = SectorMap::new(sector_size);
+    while let Some(log_entry) = reader.next_entry()? {
+        if (log_entry.entry.flags & LOG_MARK_FLAG) == 0 && log_entry.entry.nr_sectors > 0 {
+            writers.insert(log_entry.entry.sector, log_entry.entry.nr_sectors, Writer(log_entry.index));
+        }
+    }
+    let size = writers.end_sector() * sector_size as u64;
+
+    let rust_image = dir.join(format!("log-write-conformance-{}-rust.img", std::process::id()));
+    let c_image = dir.join(format!("log-write-conformance-{}-c.img", std::process::id()));
+    for image in [&rust_image, &c_image] {
+        OpenOptions::new().write(true).create(true).truncate(true).open(image)?.set_len(size)?;
+    }
+
+    let mut log = Log::open(Path::new(log_path), rust_image.as_path())?;
+    let progress = log.replay(None, &AtomicBool::new(false))?;
+    log.fsync_replay_file()?;
+    info!(entries = progress.entries_replayed, "replayed with log-write");
+
+    let status = Command::new(replay_log).arg("--log").arg(log_path).arg("--replay").arg(&c_image)
+        .status()
+        .map_err(|error| anyhow!("Error running {}: {}", replay_log, error))?;
+    if !status.success() {
+        bail!("{} failed with {}", replay_log, status)
+    }
+    info!("replayed with {}", replay_log);
+
+    match first_difference(File::open(&rust_image)?, File::open(&c_image)?, sector_size)? {
+        Some(sector) => {
+            let entry = writers.lookup(sector, 1).into_iter()
+                .find_map(|(_, _, writer)| writer.map(|Writer(index)| index));
+            Ok(Some(Mismatch { sector, entry, rust_image, c_image }))
+        }
+        None => {
+            std::fs::remove_file(&rust_image)?;
+            std::fs::remove_file(&c_image)?;
+            Ok(None)
+        }
+    }
+}
+
+/// True if `replay_log` can be run, a missing C tool skips the comparison.
+pub fn available(replay_log: &str) -> bool {
+    Command::new(replay_log).arg("--help").output().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conformance::first_difference;
+
+    #[test]
+    fn test_first_difference() {
+        let a = vec![1_u8; 2048];
+        let mut b = a.clone();
+        assert_eq!(first_difference(&a[..], &b[..], 512).unwrap(), None);
+        b[1100] = 0;
+        assert_eq!(first_difference(&a[..], &b[..], 512).unwrap(), Some(2));
+        assert_eq!(first_difference(&a[..], &a[..1024], 512).unwrap(), Some(2));
+        assert_eq!(first_difference(&a[..1000], &a[..1000], 512).unwrap(), None);
+    }
+}
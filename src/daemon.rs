@@ -0,0 +1,192 @@
+// tonic::Status is what every handler has to return
+#![allow(clippy::result_large_err)]
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use tonic::{Request, Response, Status};
+use tracing::info;
+use crate::check::{CheckEnv, CheckMode, Checker};
+use crate::log_writes::{Log, LOG_MARK_FLAG};
+use crate::metrics;
+use crate::results::hash_device;
+
+mod proto {
+    tonic::include_proto!("logwrite");
+}
+
+use proto::replay_server::{Replay, ReplayServer};
+use proto::replay_to_request::Target;
+
+/// A loaded log, the replay runs on a blocking thread while holding `log`.
+struct Job {
+    log: Mutex<Log>,
+    replay_path: PathBuf,
+    nr_entries: u64,
+    cur_entry: AtomicU64,
+    running: AtomicBool,
+    error: Mutex<String>,
+}
+
+impl Job {
+    fn progress(&self, job_id: u64) -> proto::Progress {
+        proto::Progress {
+            job_id,
+            cur_entry: self.cur_entry.load(Ordering::SeqCst),
+            nr_entries: self.nr_entries,
+            running: self.running.load(Ordering::SeqCst),
+            error: self.error.lock().unwrap().clone(),
+        }
+    }
+
+    /// Replays until `target` has been applied, recording why if it couldn't be reached.
+    fn replay_to(&self, target: Target) {
+        let mut log = self.log.lock().unwrap();
+        let error = loop {
+            match log.replay_next_entry(true) {
+                Ok(Some(entry)) => {
+                    self.cur_entry.store(log.cur_entry, Ordering::SeqCst);
+                    metrics::record_entry(&entry, log.sector_size, log.cur_entry);
+                    let reached = match &target {
+                        Target::Entry(index) => log.cur_entry > *index,
+                        Target::Mark(mark) => (entry.flags & LOG_MARK_FLAG) > 0 && entry.cmd == *mark,
+                    };
+                    if reached {
+                        break String::new();
+                    }
+                }
+                Ok(None) => break format!("Reached the end of the log before {:?}", target),
+                Err(error) => break format!("Error replaying entry {}: {}", log.cur_entry, error),
+            }
+        };
+        *self.error.lock().unwrap() = error;
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+#[derive(Default)]
+struct ReplayService {
+    jobs: Mutex<HashMap<u64, Arc<Job>>>,
+    next_job_id: AtomicU64,
+}
+
+fn internal(error: impl std::fmt::Display) -> Status {
+    Status::internal(error.to_string())
+}
+
+impl ReplayService {
+    fn job(&self, job_id: u64) -> Result<Arc<Job>, Status> {
+        self.jobs.lock().unwrap().get(&job_id).cloned()
+            .ok_or_else(|| Status::not_found(format!("No job {}", job_id)))
+    }
+
+    /// Jobs that are replaying can't be snapshotted or verified.
+    fn idle_job(&self, job_id: u64) -> Result<Arc<Job>, Status> {
+        let job = self.job(job_id)?;
+        if job.running.load(Ordering::SeqCst) {
+            return Err(Status::failed_precondition(format!("Job {} is still replaying", job_id)));
+        }
+        Ok(job)
+    }
+}
+
+#[tonic::async_trait]
+impl Replay for ReplayService {
+    async fn load_log(&self, request: Request<proto::LoadLogRequest>) -> Result<Response<proto::Job>, Status> {
+        let request = request.into_inner();
+        let log = Log::open(&request.log_path, &request.replay_path)
+            .map_err(|error| Status::invalid_argument(format!("Error opening {}: {}", request.log_path, error)))?;
+        let job_id = self.next_job_id.fetch_add(1, Ordering::SeqCst);
+        let reply = proto::Job {
+            job_id,
+            nr_entries: log.nr_entries,
+            sector_size: log.sector_size,
+        };
+        let job = Job {
+            replay_path: request.replay_path.into(),
+            nr_entries: log.nr_entries,
+            cur_entry: AtomicU64::new(log.cur_entry),
+            log: Mutex::new(log),
+            running: AtomicBool::new(false),
+            error: Mutex::new(String::new()),
+        };
+        self.jobs.lock().unwrap().insert(job_id, Arc::new(job));
+        info!("job {}: loaded {}", job_id, request.log_path);
+        Ok(Response::new(reply))
+    }
+
+    async fn replay_to(&self, request: Request<proto::ReplayToRequest>) -> Result<Response<proto::Progress>, Status> {
+        let request = request.into_inner();
+        let target = request.target.ok_or_else(|| Status::invalid_argument("No replay target"))?;
+        let job = self.job(request.job_id)?;
+        if let Target::Entry(index) = target {
+            if index < job.cur_entry.load(Ordering::SeqCst) {
+                return Err(Status::failed_precondition(format!("Entry {} has already been replayed", index)));
+            }
+        }
+        if job.running.swap(true, Ordering::SeqCst) {
+            return Err(Status::failed_precondition(format!("Job {} is already replaying", request.job_id)));
+        }
+        info!("job {}: replaying to {:?}", request.job_id, target);
+        let replaying = job.clone();
+        tokio::task::spawn_blocking(move || replaying.replay_to(target));
+        Ok(Response::new(job.progress(request.job_id)))
+    }
+
+    async fn get_progress(&self, request: Request<proto::JobId>) -> Result<Response<proto::Progress>, Status> {
+        let job_id = request.into_inner().job_id;
+        Ok(Response::new(self.job(job_id)?.progress(job_id)))
+    }
+
+    async fn snapshot(&self, request: Request<proto::SnapshotRequest>) -> Result<Response<proto::SnapshotReply>, Status> {
+        let request = request.into_inner();
+        let job = self.idle_job(request.job_id)?;
+        tokio::task::spawn_blocking(move || {
+            let log = job.log.lock().unwrap();
+            log.fsync_replay_file().map_err(internal)?;
+            let bytes = fs::copy(&job.replay_path, &request.dest_path).map_err(|error| {
+                internal(format!("Error copying {} to {}: {}", job.replay_path.display(), request.dest_path, error))
+            })?;
+            Ok(Response::new(proto::SnapshotReply { cur_entry: log.cur_entry, bytes }))
+        }).await.map_err(internal)?
+    }
+
+    async fn verify(&self, request: Request<proto::VerifyRequest>) -> Result<Response<proto::VerifyReply>, Status> {
+        let request = request.into_inner();
+        let job = self.idle_job(request.job_id)?;
+        tokio::task::spawn_blocking(move || {
+            let log = job.log.lock().unwrap();
+            let checker = Checker {
+                mode: CheckMode::Number(1),
+                fsck_cmd: request.fsck_cmd,
+                env: CheckEnv::Host,
+                replay_path: job.replay_path.clone(),
+                mount: None,
+            };
+            let outcome = checker.run(&log).map_err(internal)?;
+            Ok(Response::new(proto::VerifyReply {
+                cur_entry: log.cur_entry,
+                exit_code: outcome.exit_code,
+                duration_secs: outcome.duration.as_secs_f64(),
+                hash: hash_device(&job.replay_path).map_err(internal)?,
+            }))
+        }).await.map_err(internal)?
+    }
+}
+
+/// Serves the gRPC control API on `addr` until the process is killed.
+pub fn serve(addr: SocketAddr) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    info!("serving gRPC on {}", addr);
+    runtime.block_on(async {
+        tonic::transport::Server::builder()
+            .add_service(ReplayServer::new(ReplayService::default()))
+            .serve(addr)
+            .await
+    })?;
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use anyhow::{Result, bail, anyhow};
+use crate::io::{self, ByteOffset};
+use crate::log_writes::{self, Log, LogWriteEntry, LOG_FLUSH_FLAG, LOG_MARK_FLAG};
+use crate::results;
+use crate::undo::UndoLog;
+
+const HELP: &str = "\
+next [N]          replay the next N entries, 1 by default
+until mark [NAME] replay up to and including the next mark, or the mark NAME
+until flush       replay up to and including the next FLUSH
+back [N]          undo the last N entries, 1 by default
+dump sector N     print sector N of the replay target
+hash              print the xxh3 hash of the replay target
+where             print the next entry to replay
+quit              leave the debugger";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Next(u64),
+    UntilMark(Option<String>),
+    UntilFlush,
+    Back(u64),
+    Dump(u64),
+    Hash,
+    Where,
+    Help,
+    Quit,
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let count = |word: Option<&&str>| -> Result<u64> {
+            match word {
+                Some(word) => word.parse().map_err(|error| anyhow!("Invalid count {}: {}", word, error)),
+                None => Ok(1),
+            }
+        };
+        match words.as_slice() {
+            ["next" | "n", rest @ ..] if rest.len() <= 1 => Ok(Command::Next(count(rest.first())?)),
+            ["until" | "u", "mark"] => Ok(Command::UntilMark(None)),
+            ["until" | "u", "mark", name] => Ok(Command::UntilMark(Some(name.to_string()))),
+            ["until" | "u", "flush"] => Ok(Command::UntilFlush),
+            ["back" | "b", rest @ ..] if rest.len() <= 1 => Ok(Command::Back(count(rest.first())?)),
+            ["dump", "sector", sector] => Ok(Command::Dump(sector.parse().map_err(|error| anyhow!("Invalid sector {}: {}", sector, error))?)),
+            ["hash"] => Ok(Command::Hash),
+            ["where" | "w"] => Ok(Command::Where),
+            ["help" | "h" | "?"] => Ok(Command::Help),
+            ["quit" | "q" | "exit"] => Ok(Command::Quit),
+            _ => bail!("Unknown command {:?}, try help", s.trim()),
+        }
+    }
+}
+
+/// Steps a replay interactively, keeping an undo log so it can also step back.
+pub struct Debugger {
+    log: Log,
+    replay_path: String,
+    /// The log only has a write handle on the replay target
+    replay: File,
+    undo_path: PathBuf,
+}
+
+impl Debugger {
+    pub fn open(log_path: &str, replay_path: &str) -> Result<Self> {
+        let mut log = Log::open(log_path, replay_path)?;
+        let undo_path = std::env::temp_dir().join(format!("log-write-debug-{}.undo", std::process::id()));
+        // Never opened through whatever another user left at the name
+        log.undo = Some(UndoLog::create_new(undo_path.as_path(), replay_path.as_ref())?);
+        let replay = OpenOptions::new().read(true).open(replay_path)?;
+        Ok(Self { log, replay_path: replay_path.to_string(), replay, undo_path })
+    }
+
+    fn describe(&self, index: u64, entry: &LogWriteEntry) -> String {
+        let mut flags = String::new();
+        log_writes::entry_flags_to_str(entry.flags, &mut flags);
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            format!("{}: mark {}", index, entry.cmd)
+        } else {
+            format!("{}: sector {}+{}, flags {}", index, entry.sector, entry.nr_sectors, flags)
+        }
+    }
+
+    /// Replays entries until `stop` matches one, printing each, returns false at the end of the log.
+    fn step<W: Write, F: Fn(&LogWriteEntry) -> bool>(&mut self, out: &mut W, limit: u64, stop: F) -> Result<bool> {
+        for _ in 0..limit {
+            let entry = match self.log.replay_next_entry(true)? {
+                Some(entry) => entry,
+                None => {
+                    writeln!(out, "end of the log")?;
+                    return Ok(false);
+                }
+            };
+            writeln!(out, "{}", self.describe(self.log.cur_entry - 1, &entry))?;
+            if stop(&entry) {
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    fn dump<W: Write>(&self, out: &mut W, sector: u64) -> Result<()> {
+        let mut buf = vec![0_u8; self.log.sector_size as usize];
+        let offset = ByteOffset::from_sectors(sector, self.log.sector_size)?;
+        let len = io::read_full_at(&self.replay, &mut buf, offset)?;
+        if len == 0 {
+            bail!("Sector {} is past the end of {}", sector, self.replay_path)
+        }
+        for (i, line) in buf[..len].chunks(16).enumerate() {
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = line.iter().map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' }).collect();
+            writeln!(out, "{:08x}  {:<47}  {}", offset.get() as usize + i * 16, hex.join(" "), text)?;
+        }
+        Ok(())
+    }
+
+    /// Runs one command, returns false once the user quits.
+    pub fn execute<W: Write>(&mut self, out: &mut W, command: Command) -> Result<bool> {
+        match command {
+            Command::Next(count) => {
+                self.step(out, count, |_| false)?;
+            }
+            Command::UntilMark(name) => {
+                self.step(out, u64::MAX, |entry| {
+                    let named = match &name {
+                        Some(name) => *name == entry.cmd,
+                        None => true,
+                    };
+                    (entry.flags & LOG_MARK_FLAG) > 0 && named
+                })?;
+            }
+            Command::UntilFlush => {
+                self.step(out, u64::MAX, |entry| (entry.flags & LOG_FLUSH_FLAG) > 0)?;
+            }
+            Command::Back(count) => {
+                let record = self.log.undo.as_mut().unwrap().step_back(count)?;
+                match record {
+                    Some(record) => {
+                        self.log.seek_to_entry(record.index, record.log_offset)?;
+                        writeln!(out, "back to before entry {}", record.index)?;
+                    }
+                    None => writeln!(out, "at the start of the log")?,
+                }
+            }
+            Command::Dump(sector) => self.dump(out, sector)?,
+            Command::Hash => writeln!(out, "{}", results::hash_device(&self.replay_path)?)?,
+            Command::Where => writeln!(out, "next entry {} of {}", self.log.cur_entry, self.log.nr_entries)?,
+            Command::Help => writeln!(out, "{}", HELP)?,
+            Command::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Reads commands from `input` until it ends or the user quits, an empty line repeats the last command.
+    pub fn run<R: BufRead, W: Write>(&mut self, mut input: R, out: &mut W) -> Result<()> {
+        let mut last: Option<Command> = None;
+        loop {
+            write!(out, "(log-write) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            let command = if line.trim().is_empty() {
+                match &last {
+                    Some(command) => command.clone(),
+                    None => continue,
+                }
+            } else {
+                match line.parse::<Command>() {
+                    Ok(command) => command,
+                    Err(error) => {
+                        writeln!(out, "{}", error)?;
+                        continue;
+                    }
+                }
+            };
+            match self.execute(out, command.clone()) {
+                Ok(true) => last = Some(command),
+                Ok(false) => return Ok(()),
+                Err(error) => writeln!(out, "{}", error)?,
+            }
+        }
+    }
+}
+
+impl Drop for Debugger {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.undo_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug::Command;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("next".parse::<Command>().unwrap(), Command::Next(1));
+        assert_eq!("next 100".parse::<Command>().unwrap(), Command::Next(100));
+        assert_eq!("until mark X".parse::<Command>().unwrap(), Command::UntilMark(Some("X".to_string())));
+        assert_eq!("until flush".parse::<Command>().unwrap(), Command::UntilFlush);
+        assert_eq!("dump sector 8".parse::<Command>().unwrap(), Command::Dump(8));
+        assert_eq!("back 2".parse::<Command>().unwrap(), Command::Back(2));
+        assert!("next a".parse::<Command>().is_err());
+        assert!("jump".parse::<Command>().is_err());
+    }
+}
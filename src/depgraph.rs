@@ -0,0 +1,131 @@
+use std::io::Write;
+use anyhow::Result;
+use crate::log_reader::LogReader;
+use crate::log_writes::{self, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+use crate::state::{SectorMap, Writer};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EdgeKind {
+    /// The later entry overwrites sectors of the earlier one
+    Overlap,
+    /// The earlier entry must be durable before the later one, because of a FLUSH or FUA
+    Barrier,
+}
+
+/// `to` can't be reordered before `from`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Edge {
+    pub from: u64,
+    pub to: u64,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub index: u64,
+    pub sector: u64,
+    pub nr_sectors: u64,
+    pub flags: u64,
+    pub mark: Option<String>,
+}
+
+/// Ordering constraints between the entries of a log.
+#[derive(Debug, Default)]
+pub struct DepGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+impl DepGraph {
+    /// Only the latest overlapping writer and the latest barriers get an edge,
+    /// everything older is implied transitively.
+    pub fn build(reader: &mut LogReader) -> Result<Self> {
+        let mut graph = DepGraph::default();
+        let mut writers: SectorMap<Writer> = SectorMap::new(reader.sector_size());
+        // Entries since the last FLUSH, which the next FLUSH depends on
+        let mut epoch: Vec<u64> = Vec::new();
+        // Barriers every following entry depends on
+        let mut barriers: Vec<u64> = Vec::new();
+
+        while let Some(log_entry) = reader.next_entry()? {
+            let index = log_entry.index;
+            let entry = log_entry.entry;
+            let is_mark = (entry.flags & LOG_MARK_FLAG) > 0;
+            graph.nodes.push(Node {
+                index,
+                sector: entry.sector,
+                nr_sectors: entry.nr_sectors,
+                flags: entry.flags,
+                mark: if is_mark { Some(entry.cmd.clone()) } else { None },
+            });
+            if is_mark {
+                continue;
+            }
+
+            for barrier in &barriers {
+                graph.edges.push(Edge { from: *barrier, to: index, kind: EdgeKind::Barrier });
+            }
+            let mut previous: Vec<u64> = writers.lookup(entry.sector, entry.nr_sectors).into_iter()
+                .filter_map(|(_, _, writer)| writer.map(|Writer(writer)| writer))
+                .collect();
+            previous.sort_unstable();
+            previous.dedup();
+            for writer in previous {
+                if !barriers.contains(&writer) {
+                    graph.edges.push(Edge { from: writer, to: index, kind: EdgeKind::Overlap });
+                }
+            }
+            if entry.nr_sectors > 0 {
+                writers.insert(entry.sector, entry.nr_sectors, Writer(index));
+            }
+
+            if (entry.flags & LOG_FLUSH_FLAG) > 0 {
+                for from in epoch.drain(..) {
+                    graph.edges.push(Edge { from, to: index, kind: EdgeKind::Barrier });
+                }
+                barriers.clear();
+                barriers.push(index);
+            } else if (entry.flags & LOG_FUA_FLAG) > 0 {
+                epoch.push(index);
+                barriers.push(index);
+            } else {
+                epoch.push(index);
+            }
+        }
+        Ok(graph)
+    }
+
+    pub fn write_dot<W: Write>(&self, out: &mut W) -> Result<()> {
+        writeln!(out, "digraph log {{")?;
+        writeln!(out, "    node [shape=box, fontname=monospace];")?;
+        for node in &self.nodes {
+            let mut flags = String::new();
+            log_writes::entry_flags_to_str(node.flags, &mut flags);
+            match &node.mark {
+                Some(mark) => {
+                    let mark = mark.replace('\\', "\\\\").replace('"', "\\\"");
+                    writeln!(out, "    e{} [label=\"{}: mark \\\"{}\\\"\", shape=cds];", node.index, node.index, mark)?;
+                }
+                None => {
+                    let style = if (node.flags & (LOG_FLUSH_FLAG | LOG_FUA_FLAG)) > 0 {
+                        ", style=bold"
+                    } else if (node.flags & LOG_DISCARD_FLAG) > 0 {
+                        ", style=dashed"
+                    } else {
+                        ""
+                    };
+                    writeln!(out, "    e{} [label=\"{}: {}+{}\\n{}\"{}];", node.index, node.index, node.sector, node.nr_sectors, flags, style)?;
+                }
+            }
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Overlap => "color=red",
+                EdgeKind::Barrier => "style=dashed",
+            };
+            writeln!(out, "    e{} -> e{} [{}];", edge.from, edge.to, style)?;
+        }
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+}
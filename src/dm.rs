@@ -0,0 +1,128 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use anyhow::{Result, anyhow, bail};
+use tracing::error;
+use crate::io;
+use crate::loopdev::BlockDevice;
+
+const SECTOR_SIZE: u64 = 512;
+const DM_CONTROL: &str = "/dev/mapper/control";
+const DM_VERSION_MAJOR: u32 = 4;
+const DM_NAME_LEN: usize = 128;
+const DM_UUID_LEN: usize = 129;
+
+#[repr(C)]
+pub struct DmIoctl {
+    version: [u32; 3],
+    data_size: u32,
+    data_start: u32,
+    target_count: u32,
+    open_count: i32,
+    flags: u32,
+    event_nr: u32,
+    padding: u32,
+    dev: u64,
+    name: [u8; DM_NAME_LEN],
+    uuid: [u8; DM_UUID_LEN],
+    data: [u8; 7],
+}
+
+nix::ioctl_readwrite!(dm_target_msg, 0xfd, 14, DmIoctl);
+
+fn dmsetup(args: &[&str]) -> Result<()> {
+    let status = Command::new("dmsetup").args(args).status().map_err(|error| {
+        anyhow!("Error running dmsetup: {}", error)
+    })?;
+    if !status.success() {
+        bail!("dmsetup {} failed: {}", args.join(" "), status)
+    }
+    Ok(())
+}
+
+/// Sends `message` to the target at sector 0 of the device-mapper device `dm_name`,
+/// the DM_TARGET_MSG equivalent of `dmsetup message <dm_name> 0 <message>`.
+pub fn target_message(dm_name: &str, message: &str) -> Result<()> {
+    if dm_name.is_empty() || dm_name.len() >= DM_NAME_LEN {
+        bail!("Invalid device-mapper name {:?}", dm_name)
+    }
+
+    let header_size = std::mem::size_of::<DmIoctl>();
+    // struct dm_target_msg is a u64 sector followed by the NUL terminated message
+    let data_size = header_size + 8 + message.len() + 1;
+    // u64 backing keeps the header properly aligned for the kernel
+    let mut buf = vec![0_u64; data_size.div_ceil(8)];
+
+    let header = unsafe { &mut *(buf.as_mut_ptr() as *mut DmIoctl) };
+    header.version = [DM_VERSION_MAJOR, 0, 0];
+    header.data_size = (buf.len() * 8) as u32;
+    header.data_start = header_size as u32;
+    header.name[..dm_name.len()].copy_from_slice(dm_name.as_bytes());
+
+    let bytes = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf.len() * 8) };
+    let msg_start = header_size + 8;
+    bytes[msg_start..msg_start + message.len()].copy_from_slice(message.as_bytes());
+
+    let control = OpenOptions::new().read(true).write(true).open(DM_CONTROL).map_err(|error| {
+        anyhow!("Error opening {}: {}", DM_CONTROL, error)
+    })?;
+    unsafe { dm_target_msg(control.as_raw_fd(), buf.as_mut_ptr() as *mut DmIoctl) }.map_err(|error| {
+        anyhow!("Error sending message to {}: {}", dm_name, error)
+    })?;
+    Ok(())
+}
+
+/// Sends `mark <name>` to a dm-log-writes target.
+pub fn send_mark(dm_name: &str, mark: &str) -> Result<()> {
+    if mark.is_empty() || mark.contains(char::is_whitespace) {
+        bail!("Invalid mark {:?}, marks can't be empty or contain whitespace", mark)
+    }
+    target_message(dm_name, &format!("mark {}", mark))
+}
+
+/// A dm-log-writes target stacked on `dev`, logging to `log_dev`. The target is
+/// removed on drop, leaving the captured log on `log_dev`.
+pub struct LogWritesTarget {
+    name: String,
+    path: PathBuf,
+    _dev: BlockDevice,
+    _log_dev: BlockDevice,
+}
+
+impl LogWritesTarget {
+    pub fn create(name: &str, dev: &Path, log_dev: &Path) -> Result<Self> {
+        let dev = BlockDevice::open(dev, false)?;
+        let log_dev = BlockDevice::open(log_dev, false)?;
+        let size = io::block_device_size(&File::open(dev.path())?)?;
+        let table = format!(
+            "0 {} log-writes {} {}",
+            size / SECTOR_SIZE,
+            dev.path().display(),
+            log_dev.path().display()
+        );
+        dmsetup(&["create", name, "--table", &table])?;
+        Ok(Self {
+            name: name.to_string(),
+            path: Path::new("/dev/mapper").join(name),
+            _dev: dev,
+            _log_dev: log_dev,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn mark(&self, mark: &str) -> Result<()> {
+        send_mark(&self.name, mark)
+    }
+}
+
+impl Drop for LogWritesTarget {
+    fn drop(&mut self) {
+        if let Err(error) = dmsetup(&["remove", &self.name]) {
+            error!("Error removing {}: {}", self.name, error);
+        }
+    }
+}
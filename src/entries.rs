@@ -0,0 +1,163 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{Result, bail, anyhow};
+use regex::Regex;
+use crate::log_reader::LogReader;
+use crate::log_writes::LOG_MARK_FLAG;
+
+/// Parses an entry list: indices or `first-last` ranges separated by
+/// whitespace or commas, with `#` starting a comment.
+pub fn parse_entry_list(list: &str) -> Result<Vec<u64>> {
+    let mut entries = Vec::new();
+    for line in list.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        for item in line.split(|c: char| c.is_whitespace() || c == ',').filter(|item| !item.is_empty()) {
+            let parse = |index: &str| index.parse::<u64>().map_err(|error| anyhow!("Invalid entry {}: {}", item, error));
+            match item.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse(first)?, parse(last)?);
+                    if first > last {
+                        bail!("Invalid entry range {}", item)
+                    }
+                    entries.extend(first..=last);
+                }
+                None => entries.push(parse(item)?),
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub fn read_entries_file<P: AsRef<Path>>(path: P) -> Result<Vec<u64>> {
+    let list = fs::read_to_string(&path)
+        .map_err(|error| anyhow!("Error reading {}: {}", path.as_ref().display(), error))?;
+    parse_entry_list(&list)
+}
+
+/// Byte offset of every entry header in the log, indexed by entry number.
+pub fn entry_offsets(reader: LogReader) -> Result<Vec<u64>> {
+    reader.map(|entry| entry.map(|entry| entry.offset)).collect()
+}
+
+/// Index and offset of entry `index`, where `--start-entry` starts replaying.
+pub fn entry_position(reader: &mut LogReader, index: u64) -> Result<(u64, u64)> {
+    reader.seek_to_index(index)?;
+    match reader.next_entry()? {
+        Some(log_entry) => Ok((log_entry.index, log_entry.offset)),
+        None => bail!("Entry {} is past the end of the log ({} entries)", index, reader.nr_entries()),
+    }
+}
+
+/// What --start-mark and --end-mark match mark names against: the name itself,
+/// a glob like `fsync-*` where `*` and `?` are wildcards, or a regex after `re:`.
+/// A name after `name:` is taken literally, for marks with `*`, `?` or `re:` in them.
+#[derive(Debug, Clone)]
+pub enum MarkPattern {
+    Name(String),
+    Pattern { source: String, regex: Regex },
+}
+
+impl FromStr for MarkPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        if let Some(name) = pattern.strip_prefix("name:") {
+            return Ok(MarkPattern::Name(name.to_string()));
+        }
+        let regex = if let Some(regex) = pattern.strip_prefix("re:") {
+            regex.to_string()
+        } else if pattern.contains(|c| c == '*' || c == '?') {
+            let glob: String = pattern.chars().map(|c| match c {
+                '*' => ".*".to_string(),
+                '?' => ".".to_string(),
+                c => regex::escape(&c.to_string()),
+            }).collect();
+            format!("^{}$", glob)
+        } else {
+            return Ok(MarkPattern::Name(pattern.to_string()));
+        };
+        let regex = Regex::new(&regex).map_err(|error| anyhow!("Invalid mark pattern {}: {}", pattern, error))?;
+        Ok(MarkPattern::Pattern { source: pattern.to_string(), regex })
+    }
+}
+
+impl fmt::Display for MarkPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarkPattern::Name(name) if name.contains(|c| c == '*' || c == '?') || name.starts_with("re:") || name.starts_with("name:") => {
+                write!(f, "name:{}", name)
+            }
+            MarkPattern::Name(name) => f.write_str(name),
+            MarkPattern::Pattern { source, .. } => f.write_str(source),
+        }
+    }
+}
+
+impl MarkPattern {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            MarkPattern::Name(mark) => mark == name,
+            MarkPattern::Pattern { regex, .. } => regex.is_match(name),
+        }
+    }
+}
+
+/// Index and offset of the entry right after the first mark `mark` matches, where
+/// `--start-mark` starts replaying. Like replay-log, the mark itself isn't replayed.
+pub fn after_mark(reader: &mut LogReader, mark: &MarkPattern) -> Result<(u64, u64)> {
+    while let Some(log_entry) = reader.next_entry()? {
+        if (log_entry.entry.flags & LOG_MARK_FLAG) > 0 && mark.matches(&log_entry.entry.cmd) {
+            return Ok((log_entry.index + 1, reader.data_offset(&log_entry) + reader.data_size(&log_entry.entry)));
+        }
+    }
+    bail!("Start mark {} not found", mark)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entries::{after_mark, entry_position, parse_entry_list, MarkPattern};
+    use crate::log_reader::LogReader;
+    use crate::log_writes::LogWriteEntry;
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_parse_entry_list() {
+        assert_eq!(parse_entry_list("3 1\n# skip the rest\n5-7, 2 # then 2\n").unwrap(), vec![3, 1, 5, 6, 7, 2]);
+        assert!(parse_entry_list("7-5").is_err());
+        assert!(parse_entry_list("one").is_err());
+    }
+
+    #[test]
+    fn test_mark_pattern() {
+        let matches = |pattern: &str, name: &str| pattern.parse::<MarkPattern>().unwrap().matches(name);
+        assert!(matches("fsync-*", "fsync-12") && !matches("fsync-*", "pre-fsync-12"));
+        assert!(matches("run-?", "run-3") && !matches("run-?", "run-12"));
+        assert!(matches("re:^fsync-[0-9]+$", "fsync-12") && !matches("re:^fsync-[0-9]+$", "fsync-x"));
+        assert!(matches("a.b", "a.b") && !matches("a.b", "axb"));
+        assert!("re:(".parse::<MarkPattern>().is_err());
+        assert!(matches("name:fsync-*", "fsync-*") && !matches("name:fsync-*", "fsync-12"));
+        assert!(matches("name:re:(", "re:(") && matches("name:name:x", "name:x"));
+        assert_eq!("name:fsync-*".parse::<MarkPattern>().unwrap().to_string(), "name:fsync-*");
+        assert_eq!("name:fsync".parse::<MarkPattern>().unwrap().to_string(), "fsync");
+    }
+
+    #[test]
+    fn test_start_positions() {
+        let path = std::env::temp_dir().join(format!("log-write-start-{}.log", std::process::id()));
+        let mut writer = LogWriter::create(&path, 512).unwrap();
+        writer.append(&LogWriteEntry { sector: 0, nr_sectors: 2, flags: 0, data_len: 0, cmd: String::new() }, &[0; 1024]).unwrap();
+        writer.mark("start").unwrap();
+        writer.append(&LogWriteEntry { sector: 4, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() }, &[0; 512]).unwrap();
+        writer.sync().unwrap();
+
+        let mut reader = LogReader::open(&path).unwrap();
+        let pattern = |pattern: &str| pattern.parse::<MarkPattern>().unwrap();
+        let positions = (entry_position(&mut reader.clone(), 1).unwrap(), after_mark(&mut reader.clone(), &pattern("start")).unwrap());
+        let missing = (entry_position(&mut reader.clone(), 3).is_err(), after_mark(&mut reader, &pattern("end")).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(positions, ((1, 2048), (2, 2560)));
+        assert_eq!(missing, (true, true));
+    }
+}
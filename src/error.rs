@@ -0,0 +1,106 @@
+//! What kind of failure an error is, for the exit code. Errors stay anyhow
+//! errors, the few that scripts branch on are tagged with an `ErrorKind`
+//! where they happen and keep their message.
+//!
+//! | code | meaning                                                 |
+//! |------|---------------------------------------------------------|
+//! | 0    | success                                                 |
+//! | 1    | any other error, including bad arguments                |
+//! | 2    | the log is malformed or truncated                       |
+//! | 3    | opening or writing the replay target failed             |
+//! | 4    | the check command failed at a checkpoint                |
+//! | 5    | interrupted, `--journal` can resume the replay          |
+//! | 6    | stopped at --limit-bytes or --limit-seconds             |
+
+use std::fmt;
+use anyhow::Result;
+
+/// Exit code of errors without a kind.
+pub const EXIT_FAILURE: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    BadFormat,
+    Target,
+    CheckFailed,
+    Interrupted,
+    LimitReached,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::BadFormat => 2,
+            ErrorKind::Target => 3,
+            ErrorKind::CheckFailed => 4,
+            ErrorKind::Interrupted => 5,
+            ErrorKind::LimitReached => 6,
+        }
+    }
+
+    /// `error`, tagged as this kind.
+    pub fn wrap(self, error: anyhow::Error) -> anyhow::Error {
+        anyhow::Error::new(Tagged { kind: self, error })
+    }
+
+    /// The kind of the first tagged error in the chain of `error`, if any.
+    pub fn of(error: &anyhow::Error) -> Option<ErrorKind> {
+        error.chain().find_map(|cause| cause.downcast_ref::<Tagged>()).map(|tagged| tagged.kind)
+    }
+}
+
+/// The exit code for a run that failed with `error`.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    ErrorKind::of(error).map_or(EXIT_FAILURE, ErrorKind::exit_code)
+}
+
+/// Shows as the error it wraps, so tagging changes no messages.
+#[derive(Debug)]
+struct Tagged {
+    kind: ErrorKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Tagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Tagged {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait WithKind<T> {
+    /// Tags the error, if any, as `kind`.
+    fn with_kind(self, kind: ErrorKind) -> Result<T>;
+}
+
+impl<T> WithKind<T> for Result<T> {
+    fn with_kind(self, kind: ErrorKind) -> Result<T> {
+        self.map_err(|error| kind.wrap(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context, Result};
+    use crate::error::{exit_code, ErrorKind, WithKind};
+
+    #[test]
+    fn test_kind() {
+        let error = ErrorKind::BadFormat.wrap(anyhow!("Magic doesn't match"));
+        assert_eq!(error.to_string(), "Magic doesn't match");
+        assert_eq!(exit_code(&error), 2);
+        // Context added further up doesn't hide the kind
+        let error = Err::<(), _>(error).context("Error opening test.log").unwrap_err();
+        assert_eq!(format!("{:#}", error), "Error opening test.log: Magic doesn't match");
+        assert_eq!(ErrorKind::of(&error), Some(ErrorKind::BadFormat));
+
+        let result: Result<()> = Err(anyhow!("IO error pwrite")).with_kind(ErrorKind::Target);
+        assert_eq!(exit_code(&result.unwrap_err()), 3);
+        assert_eq!(exit_code(&anyhow!("Invalid entry one")), 1);
+    }
+}
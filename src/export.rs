@@ -0,0 +1,117 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use anyhow::{Result, bail};
+use crate::log_writes::{LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG};
+use crate::io::ByteOffset;
+use crate::sys;
+use crate::writer::LogWriter;
+
+/// A block device served to a frontend such as NBD or ublk.
+pub trait BlockExport {
+    fn size(&self) -> u64;
+    fn read_only(&self) -> bool;
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+    fn write(&mut self, offset: u64, data: &[u8], fua: bool) -> Result<()>;
+    fn flush(&mut self) -> Result<()>;
+    fn trim(&mut self, offset: u64, len: u64) -> Result<()>;
+}
+
+/// Forwards I/O to a backing file and records every write, flush and discard
+/// into a dm-log-writes log.
+pub struct RecordingExport {
+    backing: File,
+    size: u64,
+    sector_size: u32,
+    writer: LogWriter,
+}
+
+impl RecordingExport {
+    pub fn open<P: AsRef<Path>>(backing_path: P, writer: LogWriter, sector_size: u32) -> Result<Self> {
+        let backing = OpenOptions::new().read(true).write(true).open(backing_path)?;
+        let size = backing.metadata()?.len();
+        Ok(Self {
+            backing,
+            size,
+            sector_size,
+            writer,
+        })
+    }
+
+    pub fn writer(&mut self) -> &mut LogWriter {
+        &mut self.writer
+    }
+
+    fn sectors(&self, offset: u64, len: u64) -> Result<(u64, u64)> {
+        let sector_size = self.sector_size as u64;
+        if !offset.is_multiple_of(sector_size) || !len.is_multiple_of(sector_size) {
+            bail!("Request at {} of {} bytes isn't sector aligned", offset, len)
+        }
+        Ok((offset / sector_size, len / sector_size))
+    }
+}
+
+impl BlockExport for RecordingExport {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let ret = crate::io::read_full_at(&self.backing, buf, ByteOffset::new(offset)?)?;
+        if ret != buf.len() {
+            bail!("Short read: {}", ret)
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8], fua: bool) -> Result<()> {
+        let (sector, nr_sectors) = self.sectors(offset, data.len() as u64)?;
+        let ret = crate::io::write_full_at(&self.backing, data, ByteOffset::new(offset)?)?;
+        if ret != data.len() {
+            bail!("Short write: {}", ret)
+        }
+        let entry = LogWriteEntry {
+            sector,
+            nr_sectors,
+            flags: if fua { LOG_FUA_FLAG } else { 0 },
+            data_len: 0,
+            cmd: String::new(),
+        };
+        self.writer.append(&entry, data)?;
+        if fua {
+            self.backing.sync_data()?;
+            self.writer.sync()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.backing.sync_all()?;
+        let entry = LogWriteEntry {
+            sector: 0,
+            nr_sectors: 0,
+            flags: LOG_FLUSH_FLAG,
+            data_len: 0,
+            cmd: String::new(),
+        };
+        self.writer.append(&entry, &[])?;
+        self.writer.sync()
+    }
+
+    fn trim(&mut self, offset: u64, len: u64) -> Result<()> {
+        let (sector, nr_sectors) = self.sectors(offset, len)?;
+        sys::punch_hole(&self.backing, offset, len)?;
+        // Discards carry their range but no data
+        let entry = LogWriteEntry {
+            sector,
+            nr_sectors,
+            flags: LOG_DISCARD_FLAG,
+            data_len: 0,
+            cmd: String::new(),
+        };
+        self.writer.append(&entry, &[])
+    }
+}
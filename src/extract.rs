@@ -0,0 +1,113 @@
+//! `extract`: a run of sectors as they were right after an entry, without
+//! replaying the log. Entries are read from that one back, each filling the
+//! sectors no later entry has, until every sector has its last writer.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use anyhow::{Result, bail, anyhow};
+use crate::log_reader::LogReader;
+use crate::log_writes::LOG_DISCARD_FLAG;
+
+/// Where the sectors `extract` wrote came from.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Extracted {
+    /// Written by an entry, with the data it wrote
+    pub from_log: u64,
+    /// Discarded by an entry, written as zeros
+    pub discarded: u64,
+    /// Not written by any entry up to the one asked for, written as zeros
+    pub unwritten: u64,
+}
+
+/// Writes sectors `[sector, sector + count)` as of right after entry
+/// `at_entry` to `path`.
+pub fn extract<P: AsRef<Path>>(reader: &LogReader, at_entry: u64, sector: u64, count: u64, path: P) -> Result<Extracted> {
+    if at_entry >= reader.nr_entries() {
+        bail!("Entry {} is past the end of the log ({} entries)", at_entry, reader.nr_entries())
+    }
+    let end = sector.checked_add(count).ok_or_else(|| anyhow!("Sector range {}+{} overflows", sector, count))?;
+    let sector_size = reader.sector_size() as u64;
+    let len = count.checked_mul(sector_size).and_then(|len| usize::try_from(len).ok())
+        .ok_or_else(|| anyhow!("{} sectors is too much to extract at once", count))?;
+    let mut out = vec![0_u8; len];
+    // Whether a later entry already gave each sector its contents
+    let mut done = vec![false; count as usize];
+    let mut left = count;
+    let mut extracted = Extracted::default();
+
+    for log_entry in reader.entries_rev_from(at_entry)? {
+        if left == 0 {
+            break;
+        }
+        let log_entry = log_entry?;
+        let entry = &log_entry.entry;
+        let (first, last) = (entry.sector.max(sector), entry.sector.saturating_add(entry.nr_sectors).min(end));
+        let mut at = first;
+        while at < last {
+            // The next run of sectors still without a writer
+            if done[(at - sector) as usize] {
+                at += 1;
+                continue;
+            }
+            let run_end = (at..last).find(|s| done[(s - sector) as usize]).unwrap_or(last);
+            let run = (at - sector) as usize..(run_end - sector) as usize;
+            if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+                extracted.discarded += run.len() as u64;
+            } else {
+                let offset = reader.data_offset(&log_entry) + (at - entry.sector) * sector_size;
+                reader.read_at(&mut out[run.start * sector_size as usize..run.end * sector_size as usize], offset)?;
+                extracted.from_log += run.len() as u64;
+            }
+            done[run.clone()].fill(true);
+            left -= run.len() as u64;
+            at = run_end;
+        }
+    }
+    extracted.unwritten = left;
+
+    let mut file = File::create(&path)
+        .map_err(|error| anyhow!("Error creating {}: {}", path.as_ref().display(), error))?;
+    file.write_all(&out)?;
+    file.sync_all()?;
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extract::{extract, Extracted};
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{LogWriteEntry, LOG_DISCARD_FLAG};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_extract() {
+        let dir = std::env::temp_dir();
+        let log_path = dir.join(format!("log-write-extract-{}.log", std::process::id()));
+        let out_path = dir.join(format!("log-write-extract-{}.bin", std::process::id()));
+        let entry = |sector, nr_sectors, flags| LogWriteEntry { sector, nr_sectors, flags, data_len: 0, cmd: String::new() };
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        writer.append(&entry(0, 4, 0), &[1; 2048]).unwrap();
+        writer.append(&entry(2, 1, 0), &[2; 512]).unwrap();
+        writer.append(&entry(3, 1, LOG_DISCARD_FLAG), &[]).unwrap();
+        writer.mark("later").unwrap();
+        writer.append(&entry(1, 1, 0), &[4; 512]).unwrap();
+        writer.sync().unwrap();
+        let reader = LogReader::open(&log_path).unwrap();
+
+        // Sectors 1 to 4 after the discard, before sector 1 is written again
+        let extracted = extract(&reader, 2, 1, 4, &out_path).unwrap();
+        let sectors: Vec<u8> = std::fs::read(&out_path).unwrap().chunks(512).map(|sector| sector[0]).collect();
+        assert_eq!(extracted, Extracted { from_log: 2, discarded: 1, unwritten: 1 });
+        assert_eq!(sectors, [1, 2, 0, 0]);
+
+        let extracted = extract(&reader, 4, 0, 3, &out_path).unwrap();
+        let sectors: Vec<u8> = std::fs::read(&out_path).unwrap().chunks(512).map(|sector| sector[0]).collect();
+        assert_eq!(extracted, Extracted { from_log: 3, discarded: 0, unwritten: 0 });
+        assert_eq!(sectors, [1, 4, 2]);
+        assert!(extract(&reader, 5, 0, 1, &out_path).is_err());
+        std::fs::remove_file(&log_path).unwrap();
+        std::fs::remove_file(&out_path).unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use serde_json::json;
+use tracing::warn;
+use crate::io;
+use crate::log_writes::Log;
+
+/// Failed entries of a `--keep-going` replay, one JSON line each.
+pub struct FailureReport {
+    out: BufWriter<File>,
+    pub failed: u64,
+}
+
+impl FailureReport {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let out = File::create(&path)
+            .map_err(|error| anyhow!("Error creating failure report {}: {}", path.as_ref().display(), error))?;
+        Ok(Self { out: BufWriter::new(out), failed: 0 })
+    }
+
+    /// Records that replaying entry `index`, whose header is at `offset` in the log,
+    /// failed. Returns the log offset of the entry after it, None if the failed
+    /// entry's header can't be read to tell where that is.
+    pub fn record(&mut self, log: &mut Log, index: u64, offset: u64, error: &anyhow::Error) -> Result<Option<u64>> {
+        warn!("entry {} failed, continuing: {}", index, error);
+        let entry = match log.seek_to_entry(index, offset).and_then(|()| log.peek_next_entry()) {
+            Ok(entry) => entry,
+            Err(error) => {
+                warn!("can't read the header of entry {} to skip past it: {}", index, error);
+                None
+            }
+        };
+        let line = json!({
+            "entry": index,
+            "offset": entry.as_ref().and_then(|entry| entry.sector.checked_mul(log.sector_size as u64)),
+            "errno": io::errno(error).map(|errno| errno as i32),
+            "error": error.to_string(),
+        });
+        writeln!(self.out, "{}", line)?;
+        self.out.flush()?;
+        self.failed += 1;
+        Ok(entry.and_then(|entry| offset.checked_add(log.sector_size as u64)?.checked_add(entry.data_size(log.sector_size))))
+    }
+}
@@ -0,0 +1,203 @@
+//! C bindings for replaying logs, so harnesses written against the C
+//! replay-log tool can link against this implementation instead. The header
+//! is `include/log_write.h`, building with `--features ffi-header` regenerates
+//! it into OUT_DIR to copy over it. A panic never unwinds into C, calls that
+//! panic fail like any other error.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::AtomicBool;
+use anyhow::{Result, anyhow};
+use crate::log_writes::{Log, LogWriteEntry, ReplayStop, LOG_MARK_FLAG};
+
+/// Longest mark name copied into `lw_entry`, including the terminating NUL.
+pub const LW_MARK_LEN: usize = 256;
+
+/// An open log and replay target.
+pub struct LwLog {
+    log: Log,
+}
+
+/// The entry just replayed.
+#[repr(C)]
+pub struct LwEntry {
+    pub index: u64,
+    pub sector: u64,
+    pub nr_sectors: u64,
+    pub flags: u64,
+    /// Name of a mark entry, empty otherwise. Truncated to fit.
+    pub mark: [c_char; LW_MARK_LEN],
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(error: anyhow::Error) {
+    let message = CString::new(error.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `call`, turning its error or a panic into `failed` and the last error.
+fn guard<T>(failed: T, call: impl FnOnce() -> Result<T>) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(error)) => {
+            set_error(error);
+            failed
+        }
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_error(anyhow!("Panicked: {}", message));
+            failed
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(arg: *const c_char, name: &str) -> Result<&'a str> {
+    if arg.is_null() {
+        return Err(anyhow!("{} is NULL", name));
+    }
+    CStr::from_ptr(arg).to_str().map_err(|_| anyhow!("{} isn't valid UTF-8", name))
+}
+
+fn fill_entry(out: &mut LwEntry, index: u64, entry: &LogWriteEntry) {
+    out.index = index;
+    out.sector = entry.sector;
+    out.nr_sectors = entry.nr_sectors;
+    out.flags = entry.flags;
+    out.mark = [0; LW_MARK_LEN];
+    if (entry.flags & LOG_MARK_FLAG) > 0 {
+        for (dst, src) in out.mark.iter_mut().zip(entry.cmd.bytes().take(LW_MARK_LEN - 1)) {
+            *dst = src as c_char;
+        }
+    }
+}
+
+/// The error behind the last call on this thread that failed, NULL if none
+/// has. Valid until the next failing call.
+#[no_mangle]
+pub extern "C" fn lw_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Opens `log_path` to replay onto `replay_path`, NULL on error.
+///
+/// # Safety
+/// Both paths must be NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn lw_open(log_path: *const c_char, replay_path: *const c_char) -> *mut LwLog {
+    guard(ptr::null_mut(), || {
+        let log_path = str_arg(log_path, "log_path")?;
+        let replay_path = str_arg(replay_path, "replay_path")?;
+        Ok(Box::into_raw(Box::new(LwLog { log: Log::open(log_path, replay_path)? })))
+    })
+}
+
+/// Replays the next entry and describes it in `entry`, which may be NULL.
+/// Returns 1 if an entry was replayed, 0 at the end of the log and -1 on error.
+///
+/// # Safety
+/// `log` must come from `lw_open`, `entry` must be NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn lw_next_entry(log: *mut LwLog, entry: *mut LwEntry) -> c_int {
+    guard(-1, || {
+        let log = log.as_mut().ok_or_else(|| anyhow!("log is NULL"))?;
+        let index = log.log.cur_entry;
+        Ok(match log.log.replay_next_entry(true)? {
+            Some(replayed) => {
+                if let Some(entry) = entry.as_mut() {
+                    fill_entry(entry, index, &replayed);
+                }
+                1
+            }
+            None => 0,
+        })
+    })
+}
+
+/// Replays up to and including the mark named `mark`. Returns the number of
+/// entries replayed, -1 on error or if the log ends before the mark.
+///
+/// # Safety
+/// `log` must come from `lw_open` and `mark` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lw_replay_until_mark(log: *mut LwLog, mark: *const c_char) -> i64 {
+    guard(-1, || {
+        let log = &mut log.as_mut().ok_or_else(|| anyhow!("log is NULL"))?.log;
+        let mark = str_arg(mark, "mark")?;
+        let progress = log.replay(Some(mark), &AtomicBool::new(false))?;
+        if progress.stop != ReplayStop::Mark {
+            return Err(anyhow!("Mark {} not found after {} entries", mark, progress.entries_replayed));
+        }
+        Ok(progress.entries_replayed as i64)
+    })
+}
+
+/// Syncs the replay target and frees `log`. Returns 0, or -1 if the sync
+/// failed, `log` is freed either way.
+///
+/// # Safety
+/// `log` must come from `lw_open` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lw_close(log: *mut LwLog) -> c_int {
+    if log.is_null() {
+        return 0;
+    }
+    let log = Box::from_raw(log);
+    guard(-1, || {
+        log.log.fsync_replay_file()?;
+        Ok(0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use crate::ffi::{guard, lw_close, lw_last_error, lw_next_entry, lw_open, lw_replay_until_mark, LwEntry, LW_MARK_LEN};
+    use crate::format::{LogWriteEntry, LOG_MARK_FLAG};
+    use crate::testutil::{build_log, write, TempPath};
+
+    #[test]
+    fn test_replay_until_mark() {
+        let entries = vec![
+            write(2, 1),
+            LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_MARK_FLAG, data_len: 3, cmd: "one".to_string() },
+            write(3, 1),
+        ];
+        let (log_path, replay_path) = (TempPath::new("ffi.log"), TempPath::new("ffi.img"));
+        std::fs::write(&log_path, build_log(&entries, |i| i as u8 + 1)).unwrap();
+        std::fs::write(&replay_path, [0_u8; 4096]).unwrap();
+
+        let c_log = CString::new(log_path.to_str().unwrap()).unwrap();
+        let c_replay = CString::new(replay_path.to_str().unwrap()).unwrap();
+        let (one, two) = (CString::new("one").unwrap(), CString::new("two").unwrap());
+        unsafe {
+            let log = lw_open(c_log.as_ptr(), c_replay.as_ptr());
+            assert!(!log.is_null());
+            assert_eq!(lw_replay_until_mark(log, one.as_ptr()), 2);
+            let mut entry = LwEntry { index: 0, sector: 0, nr_sectors: 0, flags: 0, mark: [0; LW_MARK_LEN] };
+            assert_eq!(lw_next_entry(log, &mut entry), 1);
+            assert_eq!((entry.index, entry.sector), (2, 3));
+            assert_eq!(lw_next_entry(log, &mut entry), 0);
+            assert_eq!(lw_replay_until_mark(log, two.as_ptr()), -1);
+            assert!(CStr::from_ptr(lw_last_error()).to_str().unwrap().contains("two"));
+            assert_eq!(lw_close(log), 0);
+        }
+        let replayed = std::fs::read(&replay_path).unwrap();
+        assert_eq!(&replayed[1024..1536], &[1_u8; 512][..]);
+        assert_eq!(&replayed[1536..2048], &[3_u8; 512][..]);
+    }
+
+    #[test]
+    fn test_panic_is_an_error() {
+        assert_eq!(guard(-1, || -> anyhow::Result<i32> { panic!("bad entry") }), -1);
+        let error = unsafe { CStr::from_ptr(lw_last_error()) };
+        assert!(error.to_str().unwrap().contains("bad entry"));
+    }
+}
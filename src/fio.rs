@@ -0,0 +1,83 @@
+//! fio version 2 iologs and job files reproducing the I/O shape of a log, for
+//! running it against other hardware with fio's `read_iolog`.
+
+use std::io::Write;
+use anyhow::Result;
+use crate::log_reader::LogReader;
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+
+/// Writes every entry as iolog actions on `device`: writes, trims for discards, a
+/// sync ahead of FLUSH entries and a datasync after FUA ones. Marks are left out.
+pub fn write_iolog<W: Write>(reader: &mut LogReader, device: &str, out: &mut W) -> Result<u64> {
+    let sector_size = reader.sector_size() as u64;
+    writeln!(out, "fio version 2 iolog")?;
+    writeln!(out, "{} add", device)?;
+    writeln!(out, "{} open", device)?;
+    let mut num_entries = 0;
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        num_entries += 1;
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            continue;
+        }
+        if (entry.flags & LOG_FLUSH_FLAG) > 0 {
+            writeln!(out, "{} sync 0 0", device)?;
+        }
+        if entry.nr_sectors > 0 {
+            let action = if (entry.flags & LOG_DISCARD_FLAG) > 0 { "trim" } else { "write" };
+            writeln!(out, "{} {} {} {}", device, action, entry.sector * sector_size, entry.nr_sectors * sector_size)?;
+        }
+        if (entry.flags & LOG_FUA_FLAG) > 0 {
+            writeln!(out, "{} datasync 0 0", device)?;
+        }
+    }
+    writeln!(out, "{} close", device)?;
+    Ok(num_entries)
+}
+
+/// A job replaying the iolog at `iolog_path`.
+pub fn write_job<W: Write>(log_path: &str, iolog_path: &str, out: &mut W) -> Result<()> {
+    writeln!(out, "; Replays the writes of {} with log-write's iolog", log_path)?;
+    writeln!(out, "[global]")?;
+    writeln!(out, "ioengine=psync")?;
+    writeln!(out, "direct=1")?;
+    writeln!(out)?;
+    writeln!(out, "[log-write-replay]")?;
+    writeln!(out, "read_iolog={}", iolog_path)?;
+    writeln!(out, "; replay_redirect=/dev/other runs the same I/O against another device")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fio::write_iolog;
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG};
+    use crate::testutil::{entry, write, TempPath};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_write_iolog() {
+        let path = TempPath::new("fio.log");
+        let mut writer = LogWriter::create(&path, 512).unwrap();
+        writer.append(&write(8, 2), &[0; 1024]).unwrap();
+        writer.mark("one").unwrap();
+        writer.append(&entry(0, 1, LOG_FLUSH_FLAG | LOG_FUA_FLAG), &[0; 512]).unwrap();
+        writer.append(&entry(16, 4, LOG_DISCARD_FLAG), &[]).unwrap();
+        writer.sync().unwrap();
+
+        let mut iolog = Vec::new();
+        let num_entries = write_iolog(&mut LogReader::open(&path).unwrap(), "/dev/vdb", &mut iolog).unwrap();
+        assert_eq!(num_entries, 4);
+        assert_eq!(String::from_utf8(iolog).unwrap(), "fio version 2 iolog
+/dev/vdb add
+/dev/vdb open
+/dev/vdb write 4096 1024
+/dev/vdb sync 0 0
+/dev/vdb write 0 512
+/dev/vdb datasync 0 0
+/dev/vdb trim 8192 2048
+/dev/vdb close
+");
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::Path;
+use anyhow::Result;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use anyhow::anyhow;
+#[cfg(target_os = "linux")]
+use nix::poll::{poll, PollFd, PollFlags};
+#[cfg(target_os = "linux")]
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use tracing::debug;
+use crate::log_writes::Log;
+
+/// inotify doesn't see writes made by other NFS clients, so look again this often anyway.
+const RECHECK_MS: i32 = 1000;
+
+/// Waits for a log that is still being captured to grow. Without inotify it
+/// only polls.
+pub struct Follower {
+    #[cfg(target_os = "linux")]
+    inotify: Inotify,
+}
+
+impl Follower {
+    #[cfg(target_os = "linux")]
+    pub fn new<P: AsRef<Path>>(log_path: P) -> Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+            .map_err(|error| anyhow!("Error setting up inotify: {}", error))?;
+        inotify.add_watch(log_path.as_ref(), AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_CLOSE_WRITE)
+            .map_err(|error| anyhow!("Error watching {}: {}", log_path.as_ref().display(), error))?;
+        Ok(Self { inotify })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new<P: AsRef<Path>>(_log_path: P) -> Result<Self> {
+        Ok(Self {})
+    }
+
+    /// Blocks until `log` has another entry to replay, whether or not the superblock counts it yet.
+    pub fn wait_for_entry(&self, log: &mut Log) -> Result<()> {
+        loop {
+            if log.refresh_nr_entries()? {
+                return Ok(());
+            }
+            if log.next_entry_complete()? {
+                log.nr_entries = log.cur_entry + 1;
+                return Ok(());
+            }
+            debug!(entry = log.cur_entry, "waiting for the log to grow");
+            self.wait()?;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn wait(&self) -> Result<()> {
+        let mut fds = [PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, RECHECK_MS)? > 0 {
+            // Only the wakeup matters
+            let _ = self.inotify.read_events();
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn wait(&self) -> Result<()> {
+        std::thread::sleep(std::time::Duration::from_millis(RECHECK_MS as u64));
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Follower {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.inotify.as_raw_fd());
+    }
+}
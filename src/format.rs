@@ -0,0 +1,517 @@
+use anyhow::{Result, anyhow, bail};
+use bytes::{Bytes, BytesMut, BufMut};
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::fmt;
+use tracing::warn;
+use crate::error::{ErrorKind, WithKind};
+use crate::reader::Reader;
+use crate::util;
+
+pub const LOG_FLUSH_FLAG: u64 = 1 << 0;
+pub const LOG_FUA_FLAG: u64 = 1 << 1;
+pub const LOG_DISCARD_FLAG: u64 = 1 << 2;
+pub const LOG_MARK_FLAG: u64 = 1 << 3;
+pub const LOG_METADATA_FLAG: u64 = 1 << 4;
+/// Every flag this version understands
+pub const LOG_KNOWN_FLAGS: u64 = LOG_FLUSH_FLAG | LOG_FUA_FLAG | LOG_DISCARD_FLAG | LOG_MARK_FLAG | LOG_METADATA_FLAG;
+
+pub const WRITE_LOG_VERSION: u64 = 1;
+pub const WRITE_LOG_MAGIC: u64 = 0x6a736677736872;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LogWriteSuper {
+    pub magic: u64,
+    pub version: u64,
+    pub nr_entries: u64,
+    pub sector_size: u32,
+}
+
+impl From<[u8; 32]> for LogWriteSuper {
+    fn from(buf: [u8; 32]) -> Self {
+        let mut rdr = Reader::from(buf.to_vec());
+        let magic = rdr.read_u64_le();
+        let version = rdr.read_u64_le();
+        let nr_entries = rdr.read_u64_le();
+        let sector_size = rdr.read_u64_le() as u32;
+        Self {
+            magic,
+            version,
+            nr_entries,
+            sector_size,
+        }
+    }
+}
+
+impl LogWriteSuper {
+    /// Fails on a superblock newer than this version understands.
+    pub fn validate(&self) -> Result<()> {
+        if self.version > WRITE_LOG_VERSION {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Log version {} is newer than the supported {}", self.version, WRITE_LOG_VERSION)))
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(32);
+        buf.put_u64_le(self.magic);
+        buf.put_u64_le(self.version);
+        buf.put_u64_le(self.nr_entries);
+        buf.put_u32_le(self.sector_size);
+        buf.put_u32_le(0);
+        buf.freeze()
+    }
+}
+
+impl Default for LogWriteSuper {
+    fn default() -> Self {
+        Self {
+            magic: 0,
+            version: 0,
+            nr_entries: 0,
+            sector_size: 0,
+        }
+    }
+}
+
+pub struct FlagsToStrEntry {
+    flags: u64,
+    str: String,
+}
+
+macro_rules! log_flags_str_entry {
+    ($f : ident, $s : expr ) => {
+        FlagsToStrEntry {
+            flags : $f,
+            str : $s.to_string()
+        }
+    };
+}
+
+#[inline]
+fn log_flags_table() -> Vec<FlagsToStrEntry> {
+    vec![
+        log_flags_str_entry!(LOG_FLUSH_FLAG, "FLUSH"),
+        log_flags_str_entry!(LOG_FUA_FLAG, "FUA"),
+        log_flags_str_entry!(LOG_DISCARD_FLAG, "DISCARD"),
+        log_flags_str_entry!(LOG_MARK_FLAG, "MARK"),
+        log_flags_str_entry!(LOG_METADATA_FLAG, "METADATA")
+    ]
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogWriteEntry {
+    pub sector: u64,
+    pub nr_sectors: u64,
+    pub flags: u64,
+    pub data_len: u64,
+    pub cmd : String
+}
+
+impl From<Vec<u8>> for LogWriteEntry {
+    fn from(buf: Vec<u8>) -> Self {
+        let mut buf = buf;
+        let header : Vec<_> = buf.drain(..Self::mem_size()).collect();
+        let mut rdr = Reader::from(header);
+        let sector = rdr.read_u64_le();
+        let nr_sectors = rdr.read_u64_le();
+        let flags = rdr.read_u64_le();
+        let data_len = rdr.read_u64_le();
+
+        let mut valid_str = Vec::new();
+
+        for i in buf {
+            if i > 0 {
+                valid_str.push(i)
+            }else {
+                break
+            }
+        }
+        let mut cmd = String::from_utf8(valid_str).unwrap_or_default();
+        Self {
+            sector,
+            nr_sectors,
+            flags,
+            data_len,
+            cmd
+        }
+    }
+}
+impl LogWriteEntry {
+    /// Fails on unknown flags, or fields the kernel never fills in together:
+    /// only marks carry data_len, the length of their name, and they write no sectors.
+    pub fn validate(&self) -> Result<()> {
+        self.check_fields().with_kind(ErrorKind::BadFormat)
+    }
+
+    fn check_fields(&self) -> Result<()> {
+        if (self.flags & !LOG_KNOWN_FLAGS) != 0 {
+            bail!("Unknown flags {:#x}", self.flags & !LOG_KNOWN_FLAGS)
+        }
+        if (self.flags & LOG_MARK_FLAG) > 0 {
+            if self.nr_sectors != 0 {
+                bail!("Mark {} writes {} sectors", self.cmd, self.nr_sectors)
+            }
+            if self.data_len == 0 {
+                bail!("Mark has no name")
+            }
+        } else if self.data_len != 0 {
+            bail!("Entry for sector {} has data_len {} but isn't a mark", self.sector, self.data_len)
+        }
+        Ok(())
+    }
+
+    /// Bytes of data stored in the log after this entry's header.
+    pub fn data_size(&self, sector_size: u32) -> u64 {
+        if (self.flags & LOG_DISCARD_FLAG) > 0 {
+            0
+        } else {
+            // Saturates so a corrupt entry reads as past the end of the log rather than wrapping
+            self.nr_sectors.saturating_mul(sector_size as u64)
+        }
+    }
+
+    /// Encodes the entry header followed by the mark name, if any.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(Self::mem_size() + self.cmd.len());
+        buf.put_u64_le(self.sector);
+        buf.put_u64_le(self.nr_sectors);
+        buf.put_u64_le(self.flags);
+        buf.put_u64_le(self.data_len);
+        buf.put_slice(self.cmd.as_bytes());
+        buf.freeze()
+    }
+}
+
+// memory size of  sector,nr_sector,flags,data_len)
+//  (8 + 8 + 8 + 8) = 32
+const LOG_WRITE_ENTRY_SIZE : usize = 32;
+
+// memory size of magic,version,nr_entries,sector_size + padding
+// (8 + 8 + 8 + 4 + 4) = 32
+const LOG_WRITE_SUPER_SIZE : usize = 32;
+
+impl MemSize for LogWriteSuper {
+    fn mem_size() -> usize {
+        LOG_WRITE_SUPER_SIZE
+    }
+}
+
+impl MemSize for LogWriteEntry {
+    fn mem_size() -> usize {
+        LOG_WRITE_ENTRY_SIZE
+    }
+}
+
+pub const LOG_FLAGS_BUF_SIZE: usize = 128;
+
+pub trait MemSize {
+    fn mem_size() -> usize;
+}
+
+pub fn entry_flags_to_str(flags: u64, buf: &mut String) {
+    let mut flags = flags;
+    let log_flags_table = log_flags_table();
+    let mut empty = true;
+    for i in log_flags_table {
+        if (flags & i.flags) > 0 {
+            if !empty {
+                util::strncat(buf, "|".to_string(), LOG_FLAGS_BUF_SIZE);
+            }
+            empty = false;
+            util::strncat(buf, i.str, LOG_FLAGS_BUF_SIZE);
+            flags &= !i.flags;
+        }
+    }
+
+    if flags > 0 {
+        if !empty {
+            util::strncat(buf, "|".to_string(), LOG_FLAGS_BUF_SIZE);
+        }
+        empty = false;
+        let left_len = LOG_FLAGS_BUF_SIZE - min(buf.len(), LOG_FLAGS_BUF_SIZE);
+        if left_len > 0 {
+            warn!("UNKNOWN.{}", flags)
+        }
+    }
+
+    if empty {
+        buf.clear();
+        buf.push_str("None");
+    }
+}
+
+/// Flags named like "FLUSH,FUA", any case, the other way from `entry_flags_to_str`.
+pub fn parse_flags(names: &str) -> Result<u64> {
+    let table = log_flags_table();
+    names.split(',').map(str::trim).filter(|name| !name.is_empty()).try_fold(0, |flags, name| {
+        table.iter().find(|entry| entry.str.eq_ignore_ascii_case(name))
+            .map(|entry| flags | entry.flags)
+            .ok_or_else(|| anyhow!("Unknown flag {}, expected FLUSH, FUA, DISCARD, MARK or METADATA", name))
+    })
+}
+
+/// Picks entries by LOG_METADATA_FLAG, for metadata-only images or data-only replays.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MetadataFilter {
+    #[default]
+    All,
+    OnlyMetadata,
+    SkipMetadata,
+}
+
+impl MetadataFilter {
+    /// Whether `entry` gets through. Marks and flushes write nothing and always do.
+    pub fn passes(&self, entry: &LogWriteEntry) -> bool {
+        if (entry.flags & LOG_MARK_FLAG) > 0 || entry.nr_sectors == 0 {
+            return true;
+        }
+        let metadata = (entry.flags & LOG_METADATA_FLAG) > 0;
+        match self {
+            MetadataFilter::All => true,
+            MetadataFilter::OnlyMetadata => metadata,
+            MetadataFilter::SkipMetadata => !metadata,
+        }
+    }
+}
+
+/// An entry header together with where it was found in the log.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub index: u64,
+    /// Byte offset of the entry header, its data follows in the next sector
+    pub offset: u64,
+    pub entry: LogWriteEntry,
+}
+
+/// Checks a superblock read from the start of a log.
+pub fn parse_super(buf: [u8; 32]) -> Result<LogWriteSuper> {
+    let log_super = DmLogWrites.read_super(&buf)?;
+    check_sector_size(log_super.sector_size)?;
+    Ok(log_super)
+}
+
+/// Largest sector size a log can have, no block device has a bigger logical block
+pub const MAX_SECTOR_SIZE: u32 = 1 << 16;
+
+/// Fails on sectors too small to hold an entry header, bigger than
+/// `MAX_SECTOR_SIZE` or not a power of two.
+pub fn check_sector_size(sector_size: u32) -> Result<()> {
+    if (sector_size as usize) < LogWriteEntry::mem_size() || sector_size > MAX_SECTOR_SIZE || !sector_size.is_power_of_two() {
+        return Err(ErrorKind::BadFormat.wrap(anyhow!("Invalid sector size {}", sector_size)))
+    }
+    Ok(())
+}
+
+/// Decodes the superblock and entry headers of a log format, so `Entries`,
+/// `LogReader` and `Log` can read formats other than dm-log-writes. A format
+/// is decoded into dm-log-writes terms and laid out like it: a superblock in
+/// the first sector, then each entry's header in a sector of its own
+/// followed by its data.
+pub trait LogFormat: fmt::Debug + Send + Sync {
+    /// Decodes the superblock from the first 32 bytes of the log. The caller
+    /// checks the sector size, which can be overridden.
+    fn read_super(&self, head: &[u8]) -> Result<LogWriteSuper>;
+
+    /// Decodes the header of the next entry from the start of its sector, at
+    /// least its first 32 bytes.
+    fn next_entry(&self, header: &[u8]) -> Result<LogWriteEntry>;
+}
+
+/// Logs written by dm-log-writes or `LogWriter`, every version up to
+/// `WRITE_LOG_VERSION`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DmLogWrites;
+
+impl LogFormat for DmLogWrites {
+    fn read_super(&self, head: &[u8]) -> Result<LogWriteSuper> {
+        let Some(Ok(buf)) = head.get(..LOG_WRITE_SUPER_SIZE).map(<[u8; LOG_WRITE_SUPER_SIZE]>::try_from) else {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Log is too short for a superblock")))
+        };
+        let log_super = LogWriteSuper::from(buf);
+        if log_super.magic != WRITE_LOG_MAGIC {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Magic doesn't match")))
+        }
+        Ok(log_super)
+    }
+
+    fn next_entry(&self, header: &[u8]) -> Result<LogWriteEntry> {
+        if header.len() < LogWriteEntry::mem_size() {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Entry header of {} bytes, at least {} expected", header.len(), LogWriteEntry::mem_size())))
+        }
+        Ok(LogWriteEntry::from(header.to_vec()))
+    }
+}
+
+/// Entries of a whole log already in memory, for when there's no file to
+/// read from, such as a log dropped into a browser.
+pub struct Entries<'a> {
+    log: &'a [u8],
+    format: &'a dyn LogFormat,
+    pub log_super: LogWriteSuper,
+    next_index: u64,
+    next_offset: u64,
+}
+
+impl<'a> Entries<'a> {
+    pub fn new(log: &'a [u8]) -> Result<Self> {
+        Self::with_format(log, &DmLogWrites)
+    }
+
+    /// The entries of `log`, a log in `format`.
+    pub fn with_format(log: &'a [u8], format: &'a dyn LogFormat) -> Result<Self> {
+        let log_super = format.read_super(log)?;
+        check_sector_size(log_super.sector_size)?;
+        Ok(Self {
+            log,
+            format,
+            next_index: 0,
+            next_offset: log_super.sector_size as u64,
+            log_super,
+        })
+    }
+
+    /// The data written by `entry`, none for marks, flushes and discards.
+    pub fn data(&self, entry: &LogEntry) -> Option<&'a [u8]> {
+        let start = entry.offset.checked_add(self.log_super.sector_size as u64)?;
+        let end = start.checked_add(entry.entry.data_size(self.log_super.sector_size))?;
+        self.log.get(usize::try_from(start).ok()?..usize::try_from(end).ok()?)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<LogEntry>> {
+        if self.next_index >= self.log_super.nr_entries {
+            return Ok(None);
+        }
+        let sector_size = self.log_super.sector_size as u64;
+        let header = usize::try_from(self.next_offset).ok()
+            .and_then(|start| self.log.get(start..start.checked_add(sector_size as usize)?));
+        let Some(header) = header else {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Log ends before entry {}", self.next_index)))
+        };
+        let entry = LogEntry {
+            index: self.next_index,
+            offset: self.next_offset,
+            entry: self.format.next_entry(header)?,
+        };
+        self.next_index += 1;
+        self.next_offset = self.next_offset.saturating_add(sector_size).saturating_add(entry.entry.data_size(self.log_super.sector_size));
+        Ok(Some(entry))
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::{check_sector_size, DmLogWrites, Entries, LogFormat, LogWriteEntry, LogWriteSuper, MetadataFilter, log_flags_table, parse_flags, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG, LOG_METADATA_FLAG, MAX_SECTOR_SIZE, WRITE_LOG_VERSION};
+    use anyhow::{Result, bail};
+    use std::convert::TryFrom;
+    use std::fs::OpenOptions;
+    use std::io::Read;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_rust_struct_size() {}
+
+    /// dm-log-writes with a magic of its own, standing in for another format
+    #[derive(Debug)]
+    struct OtherMagic;
+
+    impl LogFormat for OtherMagic {
+        fn read_super(&self, head: &[u8]) -> Result<LogWriteSuper> {
+            let log_super = LogWriteSuper::from(<[u8; 32]>::try_from(&head[..32])?);
+            if log_super.magic != 0x1234 {
+                bail!("Not the other format")
+            }
+            Ok(log_super)
+        }
+
+        fn next_entry(&self, header: &[u8]) -> Result<LogWriteEntry> {
+            DmLogWrites.next_entry(header)
+        }
+    }
+
+    #[test]
+    fn test_other_format() {
+        let log_super = LogWriteSuper { magic: 0x1234, version: WRITE_LOG_VERSION, nr_entries: 1, sector_size: 512 };
+        let entry = LogWriteEntry { sector: 7, nr_sectors: 1, flags: LOG_FUA_FLAG, data_len: 0, cmd: String::new() };
+        let mut log = log_super.to_bytes().to_vec();
+        log.resize(512, 0);
+        log.extend(entry.to_bytes());
+        log.resize(1024, 0);
+        log.extend([9_u8; 512]);
+
+        assert!(Entries::new(&log).is_err());
+        let entries = Entries::with_format(&log, &OtherMagic).unwrap();
+        let read: Vec<LogWriteEntry> = entries.map(|entry| entry.unwrap().entry).collect();
+        assert_eq!(read, [entry]);
+        assert!(DmLogWrites.next_entry(&[0_u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let entry = |flags, nr_sectors, data_len| LogWriteEntry { sector: 8, nr_sectors, flags, data_len, cmd: "one".to_string() };
+        assert!(entry(LOG_FUA_FLAG | LOG_METADATA_FLAG, 8, 0).validate().is_ok());
+        assert!(entry(LOG_MARK_FLAG, 0, 3).validate().is_ok());
+        assert!(entry(1 << 5, 8, 0).validate().is_err());
+        assert!(entry(LOG_MARK_FLAG, 1, 3).validate().is_err());
+        assert!(entry(LOG_MARK_FLAG, 0, 0).validate().is_err());
+        assert!(entry(0, 8, 3).validate().is_err());
+        let mut log_super = LogWriteSuper::default();
+        assert!(log_super.validate().is_ok());
+        log_super.version = WRITE_LOG_VERSION + 1;
+        assert!(log_super.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(parse_flags("FLUSH,fua").unwrap(), LOG_FLUSH_FLAG | LOG_FUA_FLAG);
+        assert_eq!(parse_flags(" MARK ").unwrap(), LOG_MARK_FLAG);
+        assert!(parse_flags("FLUSH,SYNC").is_err());
+    }
+
+    #[test]
+    fn test_metadata_filter() {
+        let entry = |flags, nr_sectors| LogWriteEntry { sector: 8, nr_sectors, flags, data_len: 0, cmd: String::new() };
+        let (metadata, data, flush) = (entry(LOG_METADATA_FLAG, 8), entry(LOG_DISCARD_FLAG, 8), entry(LOG_FLUSH_FLAG, 0));
+        assert!(MetadataFilter::OnlyMetadata.passes(&metadata) && !MetadataFilter::OnlyMetadata.passes(&data));
+        assert!(!MetadataFilter::SkipMetadata.passes(&metadata) && MetadataFilter::SkipMetadata.passes(&data));
+        assert!(MetadataFilter::OnlyMetadata.passes(&flush) && MetadataFilter::SkipMetadata.passes(&flush));
+        assert!(MetadataFilter::All.passes(&metadata) && MetadataFilter::All.passes(&data));
+    }
+
+    #[test]
+    fn test_check_sector_size() {
+        for sector_size in [512, 4096, MAX_SECTOR_SIZE] {
+            assert!(check_sector_size(sector_size).is_ok());
+        }
+        for sector_size in [0, 16, 520, 3000, MAX_SECTOR_SIZE * 2, u32::MAX] {
+            assert!(check_sector_size(sector_size).is_err());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_super_round_trip(magic: u64, version: u64, nr_entries: u64, sector_size: u32) {
+            let log_super = LogWriteSuper { magic, version, nr_entries, sector_size };
+            let bytes = <[u8; 32]>::try_from(&log_super.to_bytes()[..]).unwrap();
+            prop_assert_eq!(LogWriteSuper::from(bytes), log_super);
+        }
+
+        // Names end at the first NUL, the only thing about a header that doesn't round trip
+        #[test]
+        fn prop_entry_round_trip(sector: u64, nr_sectors: u64, flags: u64, data_len: u64, cmd in "[^\\x00]{0,16}", padding in 0_usize..512) {
+            let entry = LogWriteEntry { sector, nr_sectors, flags, data_len, cmd };
+            let mut header = entry.to_bytes().to_vec();
+            header.resize(header.len() + padding, 0);
+            prop_assert_eq!(LogWriteEntry::from(header), entry);
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! Labels for writes into an ext4 (jbd2) journal or an XFS log, so `dump`
+//! and `analyze stats` read as journal commits and checkpoints instead of
+//! raw sector traffic. The journal is given as a sector range or found in
+//! the filesystem superblock as the log leaves it.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+use anyhow::{Result, anyhow};
+use crate::fsprobe::{self, FsType};
+use crate::log_reader::{LogEntry, LogReader};
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+
+const JBD2_MAGIC: u32 = 0xc03b3998;
+const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+const JBD2_COMMIT_BLOCK: u32 = 2;
+const JBD2_SUPERBLOCK_V1: u32 = 3;
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+const JBD2_REVOKE_BLOCK: u32 = 5;
+const XLOG_HEADER_MAGIC: u32 = 0xfeedbabe;
+/// XFS log records start on basic blocks
+const XFS_BB_SIZE: u32 = 512;
+/// Bytes from the start of the device holding the ext4 and XFS superblocks
+const SUPER_BYTES: usize = 2048;
+const EXT4_SUPER_OFFSET: usize = 1024;
+const EXT4_FEATURE_COMPAT_HAS_JOURNAL: u32 = 0x4;
+const EXT4_EXTENT_MAGIC: u16 = 0xf30a;
+/// s_jnl_backup_type saying s_jnl_blocks holds the journal inode's blocks
+const EXT3_JNL_BACKUP_BLOCKS: u8 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JournalKind {
+    Jbd2,
+    Xfs,
+}
+
+/// Where a journal is, `[start, end)` in the log's sectors.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Journal {
+    pub kind: JournalKind,
+    pub start: u64,
+    pub end: u64,
+    /// jbd2 blocks are filesystem blocks, XFS log records start on any basic block
+    pub block_size: u32,
+}
+
+impl fmt::Display for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            JournalKind::Jbd2 => write!(f, "ext4:{}+{}:{}", self.start, self.end - self.start, self.block_size),
+            JournalKind::Xfs => write!(f, "xfs:{}+{}", self.start, self.end - self.start),
+        }
+    }
+}
+
+/// Parses `ext4:SECTOR+SECTORS[:BLOCK_SIZE]`, the block size defaulting to
+/// 4096, or `xfs:SECTOR+SECTORS`.
+impl FromStr for Journal {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid journal {}, expected ext4:SECTOR+SECTORS[:BLOCK_SIZE] or xfs:SECTOR+SECTORS", spec);
+        let mut parts = spec.split(':');
+        let kind = match parts.next() {
+            Some("ext4") | Some("jbd2") => JournalKind::Jbd2,
+            Some("xfs") => JournalKind::Xfs,
+            _ => return Err(invalid()),
+        };
+        let (start, count) = parts.next().and_then(|range| range.split_once('+')).ok_or_else(invalid)?;
+        let (start, count): (u64, u64) = (start.parse().map_err(|_| invalid())?, count.parse().map_err(|_| invalid())?);
+        let block_size = match (kind, parts.next()) {
+            (JournalKind::Jbd2, Some(block_size)) => block_size.parse().map_err(|_| invalid())?,
+            (JournalKind::Jbd2, None) => 4096,
+            (JournalKind::Xfs, None) => XFS_BB_SIZE,
+            (JournalKind::Xfs, Some(_)) => return Err(invalid()),
+        };
+        if count == 0 || parts.next().is_some() || !block_size.is_power_of_two() || block_size < XFS_BB_SIZE {
+            return Err(invalid());
+        }
+        Ok(Self { kind, start, end: start.checked_add(count).ok_or_else(invalid)?, block_size })
+    }
+}
+
+/// What a write into a journal is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Label {
+    Descriptor,
+    Commit,
+    Revoke,
+    /// The jbd2 superblock rewritten, or an XFS log tail moved, after
+    /// metadata was written back to its home
+    Checkpoint,
+    LogRecord,
+    /// Logged blocks, without a header of their own
+    Data,
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Label::Descriptor => "journal descriptor",
+            Label::Commit => "journal commit",
+            Label::Revoke => "journal revoke",
+            Label::Checkpoint => "checkpoint",
+            Label::LogRecord => "log record",
+            Label::Data => "journal data",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// The blocks of an ext4 journal from the copy of its inode's block map in
+/// the superblock `sb`, the first run of contiguous ones.
+fn ext4_journal_blocks(sb: &[u8]) -> Option<(u64, u64)> {
+    if (le32(sb, 0x5c) & EXT4_FEATURE_COMPAT_HAS_JOURNAL) == 0 || le32(sb, 0xe4) != 0 || sb[0xfd] != EXT3_JNL_BACKUP_BLOCKS {
+        return None;
+    }
+    let block_size = 1024_u64.checked_shl(le32(sb, 0x18))?;
+    let i_block = &sb[0x10c..0x10c + 60];
+    let size = (le32(sb, 0x10c + 60) as u64) << 32 | le32(sb, 0x10c + 64) as u64;
+    if le16(i_block, 0) != EXT4_EXTENT_MAGIC {
+        // Block mapped, as ext3 makes them, contiguous when mkfs made it
+        return Some((le32(i_block, 0) as u64, size / block_size)).filter(|(start, len)| *start > 0 && *len > 0);
+    }
+    if le16(i_block, 6) != 0 {
+        return None;
+    }
+    let mut run: Option<(u64, u64)> = None;
+    for extent in 0..(le16(i_block, 2) as usize).min(4) {
+        let extent = &i_block[12 + extent * 12..24 + extent * 12];
+        let start = (le16(extent, 6) as u64) << 32 | le32(extent, 8) as u64;
+        // Lengths over 32768 mark unwritten extents
+        let len = (le16(extent, 4) as u64) & 0x7fff;
+        run = match run {
+            None => Some((start, len)),
+            Some((first, total)) if first.checked_add(total) == Some(start) => Some((first, total + len)),
+            Some(run) => return Some(run),
+        };
+    }
+    run
+}
+
+/// Where the journal of the filesystem whose first `SUPER_BYTES` bytes are
+/// `head` is, none for an external journal or a filesystem without one.
+fn journal_from_super(head: &[u8], sector_size: u32) -> Option<Journal> {
+    let sector_size = sector_size as u64;
+    match fsprobe::probe_buf(head)? {
+        FsType::Ext => {
+            let sb = &head[EXT4_SUPER_OFFSET..];
+            let block_size = 1024_u32.checked_shl(le32(sb, 0x18))?;
+            let (start, len) = ext4_journal_blocks(sb)?;
+            Some(Journal {
+                kind: JournalKind::Jbd2,
+                start: start.checked_mul(block_size as u64)? / sector_size,
+                end: start.checked_add(len)?.checked_mul(block_size as u64)? / sector_size,
+                block_size,
+            })
+        }
+        FsType::Xfs => {
+            let (block_size, log_start, ag_blocks, log_blocks, ag_block_log) =
+                (be32(head, 4) as u64, be64(head, 48), be32(head, 84) as u64, be32(head, 96) as u64, head[124] as u32);
+            if log_start == 0 || ag_block_log >= 64 {
+                return None;
+            }
+            let block = (log_start >> ag_block_log).checked_mul(ag_blocks)?.checked_add(log_start & ((1 << ag_block_log) - 1))?;
+            Some(Journal {
+                kind: JournalKind::Xfs,
+                start: block.checked_mul(block_size)? / sector_size,
+                end: block.checked_add(log_blocks)?.checked_mul(block_size)? / sector_size,
+                block_size: XFS_BB_SIZE,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Finds the journal from the superblock as the whole log leaves it.
+pub fn detect(reader: &LogReader) -> Result<Option<Journal>> {
+    let sector_size = reader.sector_size() as u64;
+    let mut head = vec![0_u8; SUPER_BYTES];
+    let mut reader = reader.clone();
+    reader.seek_to_entry(0, sector_size);
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        if (entry.flags & (LOG_MARK_FLAG | LOG_DISCARD_FLAG)) > 0 || entry.sector.saturating_mul(sector_size) >= SUPER_BYTES as u64 {
+            continue;
+        }
+        let start = (entry.sector * sector_size) as usize;
+        let end = reader.data_size(entry).saturating_add(start as u64).min(SUPER_BYTES as u64) as usize;
+        reader.read_at(&mut head[start..end], reader.data_offset(&log_entry))?;
+    }
+    Ok(journal_from_super(&head, reader.sector_size()))
+}
+
+/// Labels writes into journals, in log order.
+pub struct Annotator {
+    journals: Vec<Journal>,
+    /// The tail of the last XFS log record seen
+    xfs_tail: Option<u64>,
+}
+
+impl Annotator {
+    pub fn new(journals: Vec<Journal>) -> Self {
+        Self { journals, xfs_tail: None }
+    }
+
+    /// From `spec`, as `Journal` parses it or `auto` to detect the journal.
+    pub fn from_spec(spec: &str, reader: &LogReader) -> Result<Self> {
+        let journal = match spec {
+            "auto" => detect(reader)?.ok_or_else(|| anyhow!("Found no ext4 or XFS superblock with an internal journal in the log"))?,
+            spec => spec.parse()?,
+        };
+        Ok(Self::new(vec![journal]))
+    }
+
+    pub fn journals(&self) -> &[Journal] {
+        &self.journals
+    }
+
+    /// What `log_entry` writes into a journal, nothing if it writes outside them.
+    pub fn annotate(&mut self, reader: &LogReader, log_entry: &LogEntry) -> Result<Vec<Label>> {
+        let entry = &log_entry.entry;
+        if (entry.flags & (LOG_MARK_FLAG | LOG_DISCARD_FLAG)) > 0 {
+            return Ok(Vec::new());
+        }
+        let sector_size = reader.sector_size() as u64;
+        let end = entry.sector.saturating_add(entry.nr_sectors);
+        let Some(journal) = self.journals.iter().find(|journal| entry.sector < journal.end && journal.start < end).copied() else {
+            return Ok(Vec::new());
+        };
+        let block_size = journal.block_size as u64;
+        // The first block boundary of the journal inside the write
+        let write_start = entry.sector.max(journal.start) * sector_size;
+        let write_end = end.min(journal.end) * sector_size;
+        let journal_start = journal.start * sector_size;
+        let first = journal_start + (write_start - journal_start + block_size - 1) / block_size * block_size;
+        if first >= write_end {
+            return Ok(vec![Label::Data]);
+        }
+        let mut data = vec![0_u8; (write_end - first) as usize];
+        reader.read_at(&mut data, reader.data_offset(log_entry) + (first - entry.sector * sector_size))?;
+
+        let mut labels = Vec::new();
+        for block in data.chunks(block_size as usize).filter(|block| block.len() >= 32) {
+            let label = match journal.kind {
+                JournalKind::Jbd2 if be32(block, 0) == JBD2_MAGIC => match be32(block, 4) {
+                    JBD2_DESCRIPTOR_BLOCK => Some(Label::Descriptor),
+                    JBD2_COMMIT_BLOCK => Some(Label::Commit),
+                    JBD2_REVOKE_BLOCK => Some(Label::Revoke),
+                    // Rewritten with the new tail once the journal is checkpointed
+                    JBD2_SUPERBLOCK_V1 | JBD2_SUPERBLOCK_V2 => Some(Label::Checkpoint),
+                    _ => None,
+                },
+                JournalKind::Xfs if be32(block, 0) == XLOG_HEADER_MAGIC => {
+                    let tail = be64(block, 24);
+                    let moved = self.xfs_tail.map_or(false, |last| last != tail);
+                    self.xfs_tail = Some(tail);
+                    if moved {
+                        labels.push(Label::Checkpoint);
+                    }
+                    Some(Label::LogRecord)
+                }
+                _ => None,
+            };
+            if let Some(label) = label.filter(|label| !labels.contains(label)) {
+                labels.push(label);
+            }
+        }
+        if labels.is_empty() {
+            labels.push(Label::Data);
+        }
+        Ok(labels)
+    }
+}
+
+/// `labels` as a dump column.
+pub fn describe(labels: &[Label]) -> String {
+    labels.iter().map(Label::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// How many writes of the log have each label.
+pub fn count(reader: &LogReader, annotator: &mut Annotator) -> Result<BTreeMap<Label, u64>> {
+    let mut counts = BTreeMap::new();
+    let mut reader = reader.clone();
+    reader.seek_to_entry(0, reader.sector_size() as u64);
+    while let Some(log_entry) = reader.next_entry()? {
+        for label in annotator.annotate(&reader, &log_entry)? {
+            *counts.entry(label).or_insert(0) += 1;
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fsjournal::{detect, Annotator, Journal, JournalKind, Label};
+    use crate::log_reader::LogReader;
+    use crate::log_writes::LogWriteEntry;
+    use crate::writer::LogWriter;
+
+    fn write(sector: u64, data: &[u8]) -> (LogWriteEntry, Vec<u8>) {
+        (LogWriteEntry { sector, nr_sectors: data.len() as u64 / 512, flags: 0, data_len: 0, cmd: String::new() }, data.to_vec())
+    }
+
+    fn jbd2_block(block_type: u32) -> Vec<u8> {
+        let mut block = vec![0_u8; 4096];
+        block[..4].copy_from_slice(&0xc03b3998_u32.to_be_bytes());
+        block[4..8].copy_from_slice(&block_type.to_be_bytes());
+        block
+    }
+
+    #[test]
+    fn test_parse_journal() {
+        assert_eq!("ext4:2048+65536".parse::<Journal>().unwrap(), Journal { kind: JournalKind::Jbd2, start: 2048, end: 67584, block_size: 4096 });
+        assert_eq!("ext4:8+16:1024".parse::<Journal>().unwrap().block_size, 1024);
+        assert_eq!("xfs:100+50".parse::<Journal>().unwrap(), Journal { kind: JournalKind::Xfs, start: 100, end: 150, block_size: 512 });
+        assert!("xfs:100+50:4096".parse::<Journal>().is_err());
+        assert!("btrfs:0+8".parse::<Journal>().is_err());
+        assert!("ext4:8+0".parse::<Journal>().is_err());
+    }
+
+    #[test]
+    fn test_detect_and_annotate_ext4() {
+        let log_path = std::env::temp_dir().join(format!("log-write-fsjournal-{}.log", std::process::id()));
+        // An ext4 superblock of 4096 byte blocks, its journal in one extent of 16 blocks at block 64
+        let mut sb = vec![0_u8; 1024];
+        sb[0x18..0x1c].copy_from_slice(&2_u32.to_le_bytes());
+        sb[0x38..0x3a].copy_from_slice(&0xef53_u16.to_le_bytes());
+        sb[0x5c..0x60].copy_from_slice(&4_u32.to_le_bytes());
+        sb[0xfd] = 1;
+        let i_block = 0x10c;
+        sb[i_block..i_block + 2].copy_from_slice(&0xf30a_u16.to_le_bytes());
+        sb[i_block + 2..i_block + 4].copy_from_slice(&1_u16.to_le_bytes());
+        sb[i_block + 16..i_block + 18].copy_from_slice(&16_u16.to_le_bytes());
+        sb[i_block + 20..i_block + 24].copy_from_slice(&64_u32.to_le_bytes());
+
+        let mut transaction = jbd2_block(1);
+        transaction.extend(vec![7_u8; 4096]);
+        transaction.extend(jbd2_block(2));
+        let writes = [
+            write(2, &sb),
+            write(512, &transaction),
+            write(512 + 24, &vec![7_u8; 4096]),
+            write(512 + 3 * 8, &[0_u8; 512]),
+            write(512 - 4, &jbd2_block(4)),
+            write(512, &jbd2_block(4)),
+            write(8, &[1_u8; 4096]),
+        ];
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        for (entry, data) in &writes {
+            writer.append(entry, data).unwrap();
+        }
+        writer.sync().unwrap();
+        let reader = LogReader::open(&log_path).unwrap();
+        let journal = detect(&reader).unwrap();
+        let mut annotator = Annotator::from_spec("auto", &reader).unwrap();
+        let labels: Vec<Vec<Label>> = reader.clone().map(|log_entry| annotator.annotate(&reader, &log_entry.unwrap()).unwrap()).collect();
+        std::fs::remove_file(&log_path).unwrap();
+
+        assert_eq!(journal, Some(Journal { kind: JournalKind::Jbd2, start: 512, end: 640, block_size: 4096 }));
+        assert_eq!(labels, [
+            vec![],
+            vec![Label::Descriptor, Label::Commit],
+            vec![Label::Data],
+            vec![Label::Data],
+            vec![Label::Data],
+            vec![Label::Checkpoint],
+            vec![],
+        ]);
+    }
+}
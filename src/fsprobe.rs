@@ -0,0 +1,114 @@
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use anyhow::Result;
+use crate::io::{self, ByteOffset};
+use crate::util;
+
+// Superblock magic locations, as probed by blkid
+const EXT_MAGIC_OFFSET: usize = 1024 + 56;
+const EXT_MAGIC: [u8; 2] = [0x53, 0xef];
+const XFS_MAGIC_OFFSET: usize = 0;
+const XFS_MAGIC: &[u8] = b"XFSB";
+const BTRFS_MAGIC_OFFSET: usize = 65536 + 64;
+const BTRFS_MAGIC: &[u8] = b"_BHRfS_M";
+const F2FS_MAGIC_OFFSET: usize = 1024;
+const F2FS_MAGIC: [u8; 4] = [0x10, 0x20, 0xf5, 0xf2];
+
+const PROBE_SIZE: usize = BTRFS_MAGIC_OFFSET + 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FsType {
+    Ext,
+    Xfs,
+    Btrfs,
+    F2fs,
+}
+
+impl fmt::Display for FsType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FsType::Ext => "ext",
+            FsType::Xfs => "xfs",
+            FsType::Btrfs => "btrfs",
+            FsType::F2fs => "f2fs",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FsType {
+    /// Default read-only consistency check for this filesystem on `device`.
+    pub fn check_command(&self, device: &Path) -> String {
+        let device = util::shell_quote(device.to_string_lossy());
+        match self {
+            FsType::Ext => format!("e2fsck -fn {}", device),
+            FsType::Xfs => format!("xfs_repair -n {}", device),
+            FsType::Btrfs => format!("btrfs check {}", device),
+            FsType::F2fs => format!("fsck.f2fs --dry-run {}", device),
+        }
+    }
+
+    /// The filesystem an fstests FSTYP value names, e.g. ext4.
+    pub fn from_fstyp(fstyp: &str) -> Option<Self> {
+        match fstyp {
+            "ext2" | "ext3" | "ext4" => Some(FsType::Ext),
+            "xfs" => Some(FsType::Xfs),
+            "btrfs" => Some(FsType::Btrfs),
+            "f2fs" => Some(FsType::F2fs),
+            _ => None,
+        }
+    }
+}
+
+fn has_magic(buf: &[u8], offset: usize, magic: &[u8]) -> bool {
+    buf.len() >= offset + magic.len() && &buf[offset..offset + magic.len()] == magic
+}
+
+pub fn probe_buf(buf: &[u8]) -> Option<FsType> {
+    if has_magic(buf, XFS_MAGIC_OFFSET, XFS_MAGIC) {
+        Some(FsType::Xfs)
+    } else if has_magic(buf, EXT_MAGIC_OFFSET, &EXT_MAGIC) {
+        Some(FsType::Ext)
+    } else if has_magic(buf, F2FS_MAGIC_OFFSET, &F2FS_MAGIC) {
+        Some(FsType::F2fs)
+    } else if has_magic(buf, BTRFS_MAGIC_OFFSET, BTRFS_MAGIC) {
+        Some(FsType::Btrfs)
+    } else {
+        None
+    }
+}
+
+pub fn probe<P: AsRef<Path>>(path: P) -> Result<Option<FsType>> {
+    let file = File::open(path)?;
+    let mut buf = vec![0_u8; PROBE_SIZE];
+    let ret = io::read_full_at(&file, &mut buf, ByteOffset::ZERO)?;
+    buf.truncate(ret);
+    Ok(probe_buf(&buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fsprobe::{probe_buf, FsType, EXT_MAGIC_OFFSET, BTRFS_MAGIC_OFFSET, PROBE_SIZE};
+
+    #[test]
+    fn test_probe_buf() {
+        let mut buf = vec![0_u8; PROBE_SIZE];
+        assert_eq!(probe_buf(&buf), None);
+        buf[EXT_MAGIC_OFFSET] = 0x53;
+        buf[EXT_MAGIC_OFFSET + 1] = 0xef;
+        assert_eq!(probe_buf(&buf), Some(FsType::Ext));
+
+        let mut buf = vec![0_u8; PROBE_SIZE];
+        buf[BTRFS_MAGIC_OFFSET..].copy_from_slice(b"_BHRfS_M");
+        assert_eq!(probe_buf(&buf), Some(FsType::Btrfs));
+        assert_eq!(probe_buf(&buf[..1024]), None);
+    }
+
+    #[test]
+    fn test_from_fstyp() {
+        assert_eq!(FsType::from_fstyp("ext4"), Some(FsType::Ext));
+        assert_eq!(FsType::from_fstyp("xfs"), Some(FsType::Xfs));
+        assert_eq!(FsType::from_fstyp("nfs"), None);
+    }
+}
@@ -0,0 +1,119 @@
+//! Presets mirroring the xfstests log-writes recipes, configured the way fstests
+//! is: LOGWRITES_DEV is the log, SCRATCH_DEV the replay target, and FSTYP and
+//! MOUNT_OPTIONS say how to check it.
+
+use std::path::Path;
+use anyhow::{Result, anyhow, bail};
+use log_write::error::ErrorKind;
+use tracing::info;
+use crate::check::{CheckEnv, CheckMode, Checker, FSCK_AUTO};
+use crate::fsprobe::FsType;
+use crate::log_writes::{Log, LOG_FUA_FLAG, LOG_MARK_FLAG};
+use crate::mount::MountOptions;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Preset {
+    /// `_log_writes_replay_log`: replay up to and including the mark
+    Replay(String),
+    /// Replay to the mark, then fsck the scratch device
+    Check(String),
+    /// Replay to the mark, then mount the scratch device with MOUNT_OPTIONS
+    MountCheck(String),
+    /// Replay the whole log, with an fsck after every FUA as generic/482 does
+    CheckFua,
+    /// Replay the whole log, with an fsck after every mark
+    CheckMarks,
+}
+
+impl Preset {
+    fn mark(&self) -> Option<&str> {
+        match self {
+            Preset::Replay(mark) | Preset::Check(mark) | Preset::MountCheck(mark) => Some(mark),
+            Preset::CheckFua | Preset::CheckMarks => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FstestsEnv {
+    pub log: String,
+    pub scratch: String,
+    pub fstyp: Option<String>,
+    pub mount_options: Option<String>,
+}
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+impl FstestsEnv {
+    /// Reads the fstests variables, `log` and `scratch` override LOGWRITES_DEV and SCRATCH_DEV.
+    pub fn from_env(log: Option<&str>, scratch: Option<&str>) -> Result<Self> {
+        let log = log.map(String::from).or_else(|| var("LOGWRITES_DEV"))
+            .ok_or_else(|| anyhow!("LOGWRITES_DEV isn't set, pass --log"))?;
+        let scratch = scratch.map(String::from).or_else(|| var("SCRATCH_DEV"))
+            .ok_or_else(|| anyhow!("SCRATCH_DEV isn't set, pass --scratch"))?;
+        Ok(Self { log, scratch, fstyp: var("FSTYP"), mount_options: var("MOUNT_OPTIONS") })
+    }
+
+    /// The fsck for FSTYP, or the one for whatever is found on the device when
+    /// FSTYP isn't one we know.
+    pub fn checker(&self, mount: bool) -> Checker {
+        let fsck_cmd = match self.fstyp.as_deref().and_then(FsType::from_fstyp) {
+            // Mounting is the check, the mountpoint is passed as an argument
+            _ if mount => "true".to_string(),
+            Some(fs_type) => fs_type.check_command(Path::new(&self.scratch)),
+            None => FSCK_AUTO.to_string(),
+        };
+        Checker {
+            // Checkpoints are picked by the preset
+            mode: CheckMode::Number(1),
+            fsck_cmd,
+            env: CheckEnv::Host,
+            replay_path: self.scratch.clone().into(),
+            mount: if mount {
+                Some(MountOptions { fstype: self.fstyp.clone(), options: self.mount_options.clone() })
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Replays LOGWRITES_DEV onto SCRATCH_DEV as `preset` says, returns the number of entries replayed.
+pub fn run(env: &FstestsEnv, preset: &Preset) -> Result<u64> {
+    let checker = match preset {
+        Preset::Replay(_) => None,
+        Preset::MountCheck(_) => Some(env.checker(true)),
+        _ => Some(env.checker(false)),
+    };
+    let mut log = Log::open(env.log.as_str(), env.scratch.as_str())?;
+    let mut num_entries = 0;
+    let mut found = false;
+    while let Some(entry) = log.replay_next_entry(true)? {
+        num_entries += 1;
+        let is_mark = (entry.flags & LOG_MARK_FLAG) > 0;
+        let at_end = is_mark && preset.mark() == Some(entry.cmd.as_str());
+        let checkpoint = match preset {
+            Preset::CheckFua => (entry.flags & LOG_FUA_FLAG) > 0,
+            Preset::CheckMarks => is_mark,
+            _ => at_end,
+        };
+        if let (true, Some(checker)) = (checkpoint, &checker) {
+            let outcome = checker.run(&log)?;
+            if outcome.exit_code != 0 {
+                return Err(ErrorKind::CheckFailed.wrap(anyhow!("Check of {} failed after entry {}", env.scratch, log.cur_entry - 1)))
+            }
+            info!(entry = log.cur_entry - 1, "check passed");
+        }
+        if at_end {
+            found = true;
+            break;
+        }
+    }
+    if let (false, Some(mark)) = (found, preset.mark()) {
+        bail!("Mark {} not found in {}", mark, env.log)
+    }
+    log.fsync_replay_file()?;
+    Ok(num_entries)
+}
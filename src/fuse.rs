@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use anyhow::Result;
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request};
+use nix::libc::{EIO, ENOENT, EROFS, O_ACCMODE, O_RDONLY};
+use tracing::error;
+use crate::check::CheckMode;
+use crate::export::BlockExport;
+use crate::log_reader::LogReader;
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+use crate::state::{state_size, StateExport};
+
+const ROOT_INO: u64 = 1;
+const TTL: Duration = Duration::from_secs(1);
+
+/// A point in the log's history exposed as an image file.
+struct StateFile {
+    name: String,
+    entry: u64,
+    size: u64,
+}
+
+/// Read-only directory with one image per mark (and per checkpoint if a
+/// check mode is given), each built from the log only while it's open.
+pub struct StatesFs {
+    log_path: PathBuf,
+    base: Option<PathBuf>,
+    size: Option<u64>,
+    files: Vec<StateFile>,
+    open: HashMap<u64, StateExport>,
+    next_fh: u64,
+}
+
+fn file_name(entry: u64, mark: Option<&str>) -> String {
+    match mark {
+        Some(mark) => format!("{:06}-{}.img", entry, mark.replace('/', "_")),
+        None => format!("{:06}.img", entry),
+    }
+}
+
+impl StatesFs {
+    pub fn open<P: AsRef<Path>>(log_path: P, base: Option<&Path>, size: Option<u64>, check: Option<&CheckMode>) -> Result<Self> {
+        let mut reader = LogReader::open(&log_path)?;
+        let sector_size = reader.sector_size() as u64;
+        let base_size = match base {
+            Some(base) => Some(base.metadata()?.len()),
+            None => None,
+        };
+
+        let mut files = Vec::new();
+        let mut end_sector = 0;
+        while let Some(log_entry) = reader.next_entry()? {
+            let entry = &log_entry.entry;
+            if (entry.flags & LOG_MARK_FLAG) == 0 || (entry.flags & LOG_DISCARD_FLAG) > 0 {
+                end_sector = end_sector.max(entry.sector + entry.nr_sectors);
+            }
+            let mark = if (entry.flags & LOG_MARK_FLAG) > 0 {
+                Some(entry.cmd.as_str())
+            } else {
+                None
+            };
+            let checkpoint = check.is_some_and(|mode| mode.is_checkpoint(entry, log_entry.index + 1));
+            if mark.is_some() || checkpoint {
+                files.push(StateFile {
+                    name: file_name(log_entry.index, mark),
+                    entry: log_entry.index,
+                    size: state_size(end_sector * sector_size, base_size, size),
+                });
+            }
+        }
+
+        Ok(Self {
+            log_path: log_path.as_ref().to_path_buf(),
+            base: base.map(Path::to_path_buf),
+            size,
+            files,
+            open: HashMap::new(),
+            next_fh: 1,
+        })
+    }
+
+    fn file(&self, ino: u64) -> Option<&StateFile> {
+        ino.checked_sub(ROOT_INO + 1).and_then(|index| self.files.get(index as usize))
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, size, perm, nlink) = if ino == ROOT_INO {
+            (FileType::Directory, 0, 0o555, 2)
+        } else {
+            (FileType::RegularFile, self.file(ino)?.size, 0o444, 1)
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink,
+            uid: nix::unistd::getuid().as_raw(),
+            gid: nix::unistd::getgid().as_raw(),
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+}
+
+impl Filesystem for StatesFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let found = self.files.iter().position(|file| OsStr::new(&file.name) == name);
+        match found.filter(|_| parent == ROOT_INO).and_then(|index| self.attr(index as u64 + ROOT_INO + 1)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        if ino != ROOT_INO {
+            reply.error(ENOENT);
+            return;
+        }
+        let mut entries = vec![
+            (ROOT_INO, FileType::Directory, "."),
+            (ROOT_INO, FileType::Directory, ".."),
+        ];
+        for (index, file) in self.files.iter().enumerate() {
+            entries.push((index as u64 + ROOT_INO + 1, FileType::RegularFile, file.name.as_str()));
+        }
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        if (flags & O_ACCMODE) != O_RDONLY {
+            reply.error(EROFS);
+            return;
+        }
+        let entry = match self.file(ino) {
+            Some(file) => file.entry,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        match StateExport::open(&self.log_path, entry, self.base.as_deref(), self.size) {
+            Ok(export) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.open.insert(fh, export);
+                reply.opened(fh, 0);
+            }
+            Err(error) => {
+                error!("Error materializing state at entry {}: {}", entry, error);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn read(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        let export = match self.open.get_mut(&fh) {
+            Some(export) => export,
+            None => {
+                reply.error(EIO);
+                return;
+            }
+        };
+        let offset = offset as u64;
+        let len = (size as u64).min(export.size().saturating_sub(offset));
+        let mut buf = vec![0_u8; len as usize];
+        match export.read(offset, &mut buf) {
+            Ok(()) => reply.data(&buf),
+            Err(error) => {
+                error!("Error reading {} bytes at {}: {}", len, offset, error);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        self.open.remove(&fh);
+        reply.ok();
+    }
+}
+
+/// Mounts `fs` on `mountpoint` and serves it until it's unmounted.
+pub fn mount<P: AsRef<Path>>(fs: StatesFs, mountpoint: P) -> Result<()> {
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("log-write".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(fs, mountpoint, &options)?;
+    Ok(())
+}
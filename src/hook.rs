@@ -0,0 +1,157 @@
+use std::io::Write;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+use anyhow::{Result, bail, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::json;
+use crate::io::{self, ByteOffset};
+use crate::log_writes::{self, Log, LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+
+/// Which entries the hook is run for.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HookPoint {
+    Entry,
+    Mark,
+    /// Flush or FUA entries
+    Barrier,
+}
+
+impl FromStr for HookPoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "entry" => Ok(HookPoint::Entry),
+            "mark" => Ok(HookPoint::Mark),
+            "barrier" => Ok(HookPoint::Barrier),
+            _ => bail!("Invalid hook point {}, expected entry, mark or barrier", s),
+        }
+    }
+}
+
+impl HookPoint {
+    fn matches(&self, entry: &LogWriteEntry) -> bool {
+        match self {
+            HookPoint::Entry => true,
+            HookPoint::Mark => (entry.flags & LOG_MARK_FLAG) > 0,
+            HookPoint::Barrier => (entry.flags & (LOG_FLUSH_FLAG | LOG_FUA_FLAG)) > 0,
+        }
+    }
+}
+
+/// The data of the entry `log` just replayed, it sits right before the log's position.
+pub fn entry_payload(log: &Log, entry: &LogWriteEntry) -> Result<Vec<u8>> {
+    if (entry.flags & (LOG_DISCARD_FLAG | LOG_MARK_FLAG)) > 0 {
+        return Ok(Vec::new());
+    }
+    let size = io::sectors_len(entry.nr_sectors, log.sector_size)?;
+    let end = log.log_file.position()?;
+    let mut buf = vec![0_u8; size];
+    if log.log_file.read_full_at(&mut buf, ByteOffset::new(end - size as u64)?)? != buf.len() {
+        bail!("Short read of entry payload")
+    }
+    Ok(buf)
+}
+
+/// Batch mode keeps one hook process running and feeds it a JSON line per entry,
+/// with the entry's data as base64 under `payload` if asked for.
+struct Batch {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+/// A user command run for selected entries with their metadata in LOG_WRITES_* variables.
+pub struct Hook {
+    cmd: String,
+    points: Vec<HookPoint>,
+    payload: bool,
+    replay_path: String,
+    batch: Option<Batch>,
+}
+
+impl Hook {
+    pub fn new(cmd: &str, points: Vec<HookPoint>, payload: bool, batch: bool, replay_path: &str) -> Result<Self> {
+        let batch = if batch {
+            let mut child = Command::new("sh")
+                .arg("-c").arg(cmd)
+                .env("LOG_WRITES_REPLAY", replay_path)
+                .stdin(Stdio::piped())
+                .spawn()
+                .map_err(|error| anyhow!("Error running hook {}: {}", cmd, error))?;
+            let stdin = child.stdin.take().unwrap();
+            Some(Batch { child, stdin })
+        } else {
+            None
+        };
+        Ok(Self {
+            cmd: cmd.to_string(),
+            points,
+            payload,
+            replay_path: replay_path.to_string(),
+            batch,
+        })
+    }
+
+    /// Runs the hook if `entry`, the one `log` just replayed, is at one of its points.
+    pub fn run(&mut self, log: &Log, entry: &LogWriteEntry) -> Result<()> {
+        if !self.points.iter().any(|point| point.matches(entry)) {
+            return Ok(());
+        }
+        let index = log.cur_entry - 1;
+        let mut flags = String::new();
+        log_writes::entry_flags_to_str(entry.flags, &mut flags);
+        let mark = if (entry.flags & LOG_MARK_FLAG) > 0 { Some(entry.cmd.as_str()) } else { None };
+
+        if let Some(batch) = &mut self.batch {
+            let mut line = json!({
+                "entry": index,
+                "sector": entry.sector,
+                "nr_sectors": entry.nr_sectors,
+                "flags": entry.flags,
+                "flags_str": flags,
+                "mark": mark,
+            });
+            if self.payload {
+                line["payload"] = STANDARD.encode(entry_payload(log, entry)?).into();
+            }
+            return writeln!(batch.stdin, "{}", line)
+                .map_err(|error| anyhow!("Error sending entry {} to hook: {}", index, error));
+        }
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(&self.cmd)
+            .env("LOG_WRITES_REPLAY", &self.replay_path)
+            .env("LOG_WRITES_ENTRY", index.to_string())
+            .env("LOG_WRITES_SECTOR", entry.sector.to_string())
+            .env("LOG_WRITES_NR_SECTORS", entry.nr_sectors.to_string())
+            .env("LOG_WRITES_FLAGS", entry.flags.to_string())
+            .env("LOG_WRITES_FLAGS_STR", &flags)
+            .env("LOG_WRITES_MARK", mark.unwrap_or(""))
+            .stdin(if self.payload { Stdio::piped() } else { Stdio::null() });
+        let mut child = cmd.spawn().map_err(|error| anyhow!("Error running hook {}: {}", self.cmd, error))?;
+        if self.payload {
+            let payload = entry_payload(log, entry)?;
+            let mut stdin = child.stdin.take().unwrap();
+            // The hook may not read all of it
+            let _ = stdin.write_all(&payload);
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("Hook failed on entry {}: {}", index, status)
+        }
+        Ok(())
+    }
+
+    /// Closes the batch hook's input and waits for it to exit.
+    pub fn finish(self) -> Result<()> {
+        if let Some(Batch { mut child, stdin }) = self.batch {
+            drop(stdin);
+            let status = child.wait()?;
+            if !status.success() {
+                bail!("Hook {} failed: {}", self.cmd, status)
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+//! How events look on the console: replayed entries as an aligned table
+//! with barriers and marks picked out in color, everything else one line
+//! per event, warnings yellow and errors red. Color is only used on a
+//! terminal, and never with NO_COLOR set.
+
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use log_write::log_writes::{self, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const BLUE: &str = "\x1b[34m";
+const MAGENTA: &str = "\x1b[35m";
+const CYAN: &str = "\x1b[36m";
+
+/// Whether to color what goes to stderr.
+pub fn stderr_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal()
+}
+
+/// Whether to color what goes to stdout.
+pub fn stdout_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+}
+
+/// `text` in `color`, or as it is without color.
+pub fn paint(color: bool, code: &str, text: &str) -> String {
+    if color {
+        format!("{}{}{}", code, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// The fields of one event, those of a replayed entry picked out.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    index: Option<u64>,
+    sector: Option<u64>,
+    size: Option<i64>,
+    flags: Option<u64>,
+    mark: Option<String>,
+    rest: Vec<(&'static str, String)>,
+}
+
+impl Visit for Fields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "index" => self.index = Some(value),
+            "sector" => self.sector = Some(value),
+            "flags" => self.flags = Some(value),
+            "size" => self.size = Some(value as i64),
+            name => self.rest.push((name, value.to_string())),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        match field.name() {
+            "size" => self.size = Some(value),
+            name => self.rest.push((name, value.to_string())),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "mark" => self.mark = Some(value.to_string()),
+            name => self.rest.push((name, value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            "mark" => self.mark = Some(format!("{:?}", value)),
+            name => self.rest.push((name, format!("{:?}", value))),
+        }
+    }
+}
+
+/// An entry's flags, those that order writes colored.
+fn flags_column(flags: u64, color: bool) -> String {
+    if flags == 0 {
+        return paint(color, DIM, "-");
+    }
+    let mut names = String::new();
+    log_writes::entry_flags_to_str(flags, &mut names);
+    names.split('|')
+        .map(|name| match name {
+            "FLUSH" if (flags & LOG_FLUSH_FLAG) > 0 => paint(color, YELLOW, name),
+            "FUA" if (flags & LOG_FUA_FLAG) > 0 => paint(color, MAGENTA, name),
+            "DISCARD" if (flags & LOG_DISCARD_FLAG) > 0 => paint(color, BLUE, name),
+            "MARK" if (flags & LOG_MARK_FLAG) > 0 => paint(color, &format!("{}{}", BOLD, CYAN), name),
+            _ => name.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+/// Column titles for `entry_row`.
+pub fn entry_header(color: bool) -> String {
+    paint(color, DIM, &format!("{:>8}  {:>12}  {:>8}  FLAGS", "ENTRY", "SECTOR", "SIZE"))
+}
+
+/// An entry as a table row, `size` in bytes.
+pub fn entry_row(color: bool, index: u64, sector: u64, size: i64, flags: u64, mark: Option<&str>) -> String {
+    let row = format!("{:>8}  {:>12}  {:>8}  {}", index, sector, size, flags_column(flags, color));
+    match mark {
+        Some(mark) => format!("{}  {}", row, paint(color, &format!("{}{}", BOLD, CYAN), &format!("--- {} ---", mark))),
+        None => row,
+    }
+}
+
+/// The console's event format, see the module doc.
+pub struct Console {
+    color: bool,
+    header_written: AtomicBool,
+}
+
+impl Console {
+    pub fn new(color: bool) -> Self {
+        Self { color, header_written: AtomicBool::new(false) }
+    }
+
+    fn entry_row(&self, writer: &mut Writer<'_>, fields: &Fields, index: u64, flags: u64) -> fmt::Result {
+        if !self.header_written.swap(true, Ordering::Relaxed) {
+            writeln!(writer, "{}", entry_header(self.color))?;
+        }
+        writeln!(writer, "{}", entry_row(self.color, index, fields.sector.unwrap_or(0), fields.size.unwrap_or(0), flags, fields.mark.as_deref()))
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Console
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let level = *event.metadata().level();
+        if let (Level::INFO, Some(index), Some(flags)) = (level, fields.index, fields.flags) {
+            return self.entry_row(&mut writer, &fields, index, flags);
+        }
+        let (code, label) = match level {
+            Level::ERROR => (RED, "error"),
+            Level::WARN => (YELLOW, "warning"),
+            Level::INFO => (CYAN, "info"),
+            Level::DEBUG => (BLUE, "debug"),
+            Level::TRACE => (DIM, "trace"),
+        };
+        write!(writer, "{}  ", paint(self.color, code, &format!("{:>8}", label)))?;
+        match level {
+            Level::ERROR => write!(writer, "{}", paint(self.color, RED, &fields.message))?,
+            _ => write!(writer, "{}", fields.message)?,
+        }
+        for (name, value) in &fields.rest {
+            write!(writer, " {}", paint(self.color, DIM, &format!("{}={}", name, value)))?;
+        }
+        writeln!(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::human::{flags_column, paint};
+    use log_write::log_writes::{LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_METADATA_FLAG};
+
+    #[test]
+    fn test_flags_column() {
+        assert_eq!(flags_column(0, false), "-");
+        assert_eq!(flags_column(LOG_FLUSH_FLAG | LOG_METADATA_FLAG, false), "FLUSH|METADATA");
+        assert_eq!(flags_column(LOG_FUA_FLAG, true), "\x1b[35mFUA\x1b[0m");
+        assert_eq!(paint(false, "\x1b[31m", "failed"), "failed");
+    }
+}
@@ -1,34 +1,356 @@
+use std::convert::TryFrom;
 use std::fs::File;
 use anyhow::{Result, anyhow};
-use std::os::unix::io::{AsRawFd, RawFd};
-use nix::unistd::Whence;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+pub use nix::errno::Errno;
+#[cfg(unix)]
+pub use nix::unistd::Whence;
 
-#[cfg(target_os = "linux")]
+pub use crate::sys::block_device_size;
+
+/// A byte position in a file or device. Always fits the `off_t` the syscalls
+/// take, so sector arithmetic that overflows is caught where it happens.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteOffset(u64);
+
+impl ByteOffset {
+    pub const ZERO: ByteOffset = ByteOffset(0);
+
+    pub fn new(bytes : u64) -> Result<Self> {
+        if bytes > i64::MAX as u64 {
+            return Err(anyhow!("Offset {} is too large", bytes));
+        }
+        Ok(Self(bytes))
+    }
+
+    /// The start of `sector`.
+    pub fn from_sectors(sector : u64, sector_size : u32) -> Result<Self> {
+        sector.checked_mul(sector_size as u64)
+            .ok_or_else(|| anyhow!("Sector {} of {} bytes is past any offset", sector, sector_size))
+            .and_then(Self::new)
+    }
+
+    pub fn checked_add(self, bytes : u64) -> Result<Self> {
+        self.0.checked_add(bytes)
+            .ok_or_else(|| anyhow!("Offset {} + {} overflows", self.0, bytes))
+            .and_then(Self::new)
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    #[cfg(all(unix, not(feature = "std-io")))]
+    fn as_off(self) -> i64 {
+        self.0 as i64
+    }
+}
+
+impl std::fmt::Display for ByteOffset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Bytes in `nr_sectors` sectors.
+pub fn sectors_bytes(nr_sectors : u64, sector_size : u32) -> Result<u64> {
+    nr_sectors.checked_mul(sector_size as u64)
+        .ok_or_else(|| anyhow!("{} sectors of {} bytes overflow", nr_sectors, sector_size))
+}
+
+/// Bytes in `nr_sectors` sectors, as a buffer length.
+pub fn sectors_len(nr_sectors : u64, sector_size : u32) -> Result<usize> {
+    usize::try_from(sectors_bytes(nr_sectors, sector_size)?)
+        .map_err(|_| anyhow!("{} sectors of {} bytes don't fit in memory", nr_sectors, sector_size))
+}
+
+/// Where `lseek` counts from, nix only has these on unix.
+#[cfg(windows)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Whence {
+    SeekSet,
+    SeekCur,
+    SeekEnd,
+}
+
+/// Restarts a syscall interrupted by a signal before it did anything.
+#[cfg(unix)]
+fn retry_eintr<T, F: FnMut() -> nix::Result<T>>(mut f : F) -> nix::Result<T> {
+    loop {
+        match f() {
+            Err(Errno::EINTR) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// The errno behind an error from this module, if there is one.
+#[cfg(unix)]
+pub fn errno(error : &anyhow::Error) -> Option<Errno> {
+    error.downcast_ref::<Errno>().copied()
+}
+
+/// The Windows error code behind an error from this module, if there is one.
+#[cfg(windows)]
+pub fn errno(error : &anyhow::Error) -> Option<i32> {
+    error.downcast_ref::<std::io::Error>().and_then(|error| error.raw_os_error())
+}
+
+#[cfg(all(unix, not(feature = "std-io")))]
 pub fn read(file : &File, buf : &mut [u8]) -> Result<usize>{
-    nix::unistd::read(file.as_raw_fd(), buf).map_err(|e| {
-        anyhow!("IO error read {}", e)
+    retry_eintr(|| nix::unistd::read(file.as_raw_fd(), buf)).map_err(|e| {
+        anyhow::Error::from(e).context(format!("IO error read {}", e))
     })
 }
-#[cfg(target_os = "linux")]
-pub fn read_at(file : &File, buf : &mut [u8], offset : i64) -> Result<usize>{
-    nix::sys::uio::pread(file.as_raw_fd(), buf,offset).map_err(|e| {
-        anyhow!("IO error {}", e)
+#[cfg(all(unix, not(feature = "std-io")))]
+pub fn read_at(file : &File, buf : &mut [u8], offset : ByteOffset) -> Result<usize>{
+    retry_eintr(|| nix::sys::uio::pread(file.as_raw_fd(), buf, offset.as_off())).map_err(|e| {
+        anyhow::Error::from(e).context(format!("IO error {}", e))
     })
 
 }
 
-#[cfg(target_os = "linux")]
-pub fn pwrite(file : &File, buf : &[u8], offset : i64) -> Result<usize>{
-    nix::sys::uio::pwrite(file.as_raw_fd(), buf,offset).map_err(|e| {
-        anyhow!("IO error pwrite {}", e)
+#[cfg(all(unix, not(feature = "std-io")))]
+pub fn pwrite(file : &File, buf : &[u8], offset : ByteOffset) -> Result<usize>{
+    retry_eintr(|| nix::sys::uio::pwrite(file.as_raw_fd(), buf, offset.as_off())).map_err(|e| {
+        anyhow::Error::from(e).context(format!("IO error pwrite {}", e))
+    })
+
+}
+
+/// The std backend reports errors as `std::io::Error`, keep them as `Errno`
+/// so retries and failure reports see the same thing either way.
+#[cfg(all(unix, feature = "std-io"))]
+fn std_errno(result : std::io::Result<usize>) -> nix::Result<usize> {
+    result.map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(0)))
+}
+
+#[cfg(all(unix, feature = "std-io"))]
+pub fn read(file : &File, buf : &mut [u8]) -> Result<usize>{
+    use std::io::Read;
+    let mut file = file;
+    retry_eintr(|| std_errno(file.read(buf))).map_err(|e| {
+        anyhow::Error::from(e).context(format!("IO error read {}", e))
+    })
+}
+
+#[cfg(all(unix, feature = "std-io"))]
+pub fn read_at(file : &File, buf : &mut [u8], offset : ByteOffset) -> Result<usize>{
+    use std::os::unix::fs::FileExt;
+    retry_eintr(|| std_errno(file.read_at(buf, offset.get()))).map_err(|e| {
+        anyhow::Error::from(e).context(format!("IO error {}", e))
+    })
+}
+
+#[cfg(all(unix, feature = "std-io"))]
+pub fn pwrite(file : &File, buf : &[u8], offset : ByteOffset) -> Result<usize>{
+    use std::os::unix::fs::FileExt;
+    retry_eintr(|| std_errno(file.write_at(buf, offset.get()))).map_err(|e| {
+        anyhow::Error::from(e).context(format!("IO error pwrite {}", e))
+    })
+}
+
+/// `seek_read` and `seek_write` move the file position, unlike `pread` and
+/// `pwrite`. Put it back so positional I/O doesn't disturb `read`.
+#[cfg(windows)]
+fn keep_position<T, F: FnOnce() -> std::io::Result<T>>(file : &File, f : F) -> std::io::Result<T> {
+    use std::io::{Seek, SeekFrom};
+    let mut cursor = file;
+    let pos = cursor.stream_position()?;
+    let result = f();
+    cursor.seek(SeekFrom::Start(pos))?;
+    result
+}
+
+#[cfg(windows)]
+pub fn read(file : &File, buf : &mut [u8]) -> Result<usize>{
+    use std::io::Read;
+    let mut file = file;
+    file.read(buf).map_err(|e| {
+        let message = format!("IO error read {}", e);
+        anyhow::Error::from(e).context(message)
+    })
+}
+
+#[cfg(windows)]
+pub fn read_at(file : &File, buf : &mut [u8], offset : ByteOffset) -> Result<usize>{
+    use std::os::windows::fs::FileExt;
+    keep_position(file, || file.seek_read(buf, offset.get())).map_err(|e| {
+        let message = format!("IO error {}", e);
+        anyhow::Error::from(e).context(message)
     })
+}
+
+#[cfg(windows)]
+pub fn pwrite(file : &File, buf : &[u8], offset : ByteOffset) -> Result<usize>{
+    use std::os::windows::fs::FileExt;
+    keep_position(file, || file.seek_write(buf, offset.get())).map_err(|e| {
+        let message = format!("IO error pwrite {}", e);
+        anyhow::Error::from(e).context(message)
+    })
+}
+
+/// Reads until `buf` is full or the file ends, returns how much was read.
+/// Pipes, NFS and signals can all return less than asked for.
+pub fn read_full(file : &File, buf : &mut [u8]) -> Result<usize>{
+    let mut done = 0;
+    while done < buf.len() {
+        let ret = read(file, &mut buf[done..])?;
+        if ret == 0 {
+            break;
+        }
+        done += ret;
+    }
+    Ok(done)
+}
+
+/// `read_full` at `offset`, without moving the file position.
+pub fn read_full_at(file : &File, buf : &mut [u8], offset : ByteOffset) -> Result<usize>{
+    let mut done = 0;
+    while done < buf.len() {
+        let ret = read_at(file, &mut buf[done..], offset.checked_add(done as u64)?)?;
+        if ret == 0 {
+            break;
+        }
+        done += ret;
+    }
+    Ok(done)
+}
 
+/// Writes all of `buf` at `offset`, returns its length.
+pub fn write_full_at(file : &File, buf : &[u8], offset : ByteOffset) -> Result<usize>{
+    let mut done = 0;
+    while done < buf.len() {
+        let ret = pwrite(file, &buf[done..], offset.checked_add(done as u64)?)?;
+        if ret == 0 {
+            return Err(anyhow!("IO error pwrite wrote nothing at {}", offset.get() + done as u64));
+        }
+        done += ret;
+    }
+    Ok(done)
 }
 
+/// Set once RWF_NOWAIT turns out unsupported, as it is for buffered writes on
+/// many filesystems.
+#[cfg(target_os = "linux")]
+static NOWAIT_UNSUPPORTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// How long a nowait write waits for a busy target at most before blocking.
 #[cfg(target_os = "linux")]
+const MAX_NOWAIT_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// `write_full_at` with RWF_NOWAIT: while the target is busy it sleeps,
+/// from `backoff` doubling every time, rather than queue behind other users.
+/// Past a second's wait it blocks after all, so the replay still progresses.
+#[cfg(target_os = "linux")]
+pub fn write_full_at_nowait(file : &File, buf : &[u8], offset : ByteOffset, backoff : std::time::Duration) -> Result<usize>{
+    use std::sync::atomic::Ordering;
+    let mut done = 0;
+    let mut delay = backoff;
+    while done < buf.len() {
+        let at = offset.checked_add(done as u64)?;
+        if NOWAIT_UNSUPPORTED.load(Ordering::Relaxed) || delay > MAX_NOWAIT_BACKOFF {
+            return Ok(done + write_full_at(file, &buf[done..], at)?);
+        }
+        let iov = [std::io::IoSlice::new(&buf[done..])];
+        let ret = unsafe {
+            nix::libc::pwritev2(file.as_raw_fd(), iov.as_ptr() as *const nix::libc::iovec, 1, at.get() as i64, nix::libc::RWF_NOWAIT)
+        };
+        if ret > 0 {
+            done += ret as usize;
+            delay = backoff;
+            continue;
+        }
+        if ret == 0 {
+            return Err(anyhow!("IO error pwritev2 wrote nothing at {}", at.get()));
+        }
+        match Errno::last() {
+            Errno::EINTR => {}
+            Errno::EAGAIN => {
+                std::thread::sleep(delay);
+                delay = delay.max(std::time::Duration::from_millis(1)) * 2;
+            }
+            Errno::EOPNOTSUPP => {
+                tracing::warn!("the replay target doesn't support RWF_NOWAIT, writing normally");
+                NOWAIT_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            errno => return Err(anyhow::Error::from(errno).context(format!("IO error pwritev2 {}", errno))),
+        }
+    }
+    Ok(done)
+}
+
+/// Only Linux has RWF_NOWAIT, elsewhere writes just block.
+#[cfg(not(target_os = "linux"))]
+pub fn write_full_at_nowait(file : &File, buf : &[u8], offset : ByteOffset, _backoff : std::time::Duration) -> Result<usize>{
+    write_full_at(file, buf, offset)
+}
+
+/// Whether nowait writes were actually nowait, none having hit a target without RWF_NOWAIT.
+#[cfg(target_os = "linux")]
+pub fn nowait_supported() -> bool {
+    !NOWAIT_UNSUPPORTED.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn nowait_supported() -> bool {
+    false
+}
+
+#[cfg(unix)]
 pub fn lseek(file : &File, offset : i64, whence : Whence) -> Result<i64>{
     nix::unistd::lseek(file.as_raw_fd(), offset, whence).map_err(|e| {
         anyhow!("IO error pwrite {}", e)
     })
 
-}
\ No newline at end of file
+}
+#[cfg(windows)]
+pub fn lseek(file : &File, offset : i64, whence : Whence) -> Result<i64>{
+    use std::io::{Seek, SeekFrom};
+    let pos = match whence {
+        Whence::SeekSet => SeekFrom::Start(offset as u64),
+        Whence::SeekCur => SeekFrom::Current(offset),
+        Whence::SeekEnd => SeekFrom::End(offset),
+    };
+    let mut file = file;
+    file.seek(pos).map(|pos| pos as i64).map_err(|e| {
+        anyhow!("IO error seek {}", e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::{self, ByteOffset};
+
+    #[test]
+    fn test_byte_offset() {
+        // 3 TiB, past what a 32-bit off_t or sector count can hold
+        let offset = ByteOffset::from_sectors(6 * 1024 * 1024 * 1024, 512).unwrap();
+        assert_eq!(offset.get(), 3 << 40);
+        assert_eq!(ByteOffset::from_sectors((1 << 51) - 1, 4096).unwrap().get(), (1 << 63) - 4096);
+        // Fits a u64 but not an off_t
+        assert!(ByteOffset::from_sectors(1 << 51, 4096).is_err());
+        assert!(ByteOffset::from_sectors(u64::MAX / 512 + 1, 512).is_err());
+        assert!(ByteOffset::new(i64::MAX as u64 + 1).is_err());
+        assert!(ByteOffset::new(i64::MAX as u64).unwrap().checked_add(1).is_err());
+        assert!(io::sectors_len(u64::MAX, 512).is_err());
+    }
+
+    #[test]
+    fn test_io_past_2tib() {
+        let path = std::env::temp_dir().join(format!("log-write-io-{}.img", std::process::id()));
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let offset = ByteOffset::from_sectors(6 * 1024 * 1024 * 1024 + 1, 512).unwrap();
+        let written = io::write_full_at(&file, &[7_u8; 512], offset);
+        let mut buf = [0_u8; 512];
+        let read = written.as_ref().ok().map(|_| io::read_full_at(&file, &mut buf, offset).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        // Filesystems with a smaller maximum file size can't run this
+        if written.is_ok() {
+            assert_eq!(read, Some(512));
+            assert_eq!(buf, [7_u8; 512]);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use anyhow::{Result, bail, anyhow};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use crate::checksum::{self, Xxh3};
+use crate::io::{self, ByteOffset};
+use crate::log_reader::LogReader;
+use crate::log_writes::{Log, LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+
+/// Most of the target hashing a barrier's writes reads at once, unless --max-memory lowers it
+const HASH_CHUNK: usize = 1024 * 1024;
+
+/// A barrier the replay target durably reached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalRecord {
+    pub entry: u64,
+    /// Where the entry after it starts in the log
+    pub next_offset: u64,
+    /// xxh3 of what the entries since the previous barrier left on the target
+    pub hash: String,
+    /// The replay ran to its end, nothing is left to resume
+    pub done: bool,
+}
+
+impl JournalRecord {
+    fn parse(line: &str) -> Option<Self> {
+        let record: Value = serde_json::from_str(line).ok()?;
+        Some(Self {
+            entry: record["entry"].as_u64()?,
+            next_offset: record["next_offset"].as_u64()?,
+            hash: record["hash"].as_str()?.to_string(),
+            done: record["done"].as_bool().unwrap_or(false),
+        })
+    }
+}
+
+/// The ranges the entries after `previous` up to `last` wrote, in order, and
+/// whether any entry after `last` writes over them.
+fn epoch_ranges(log_path: &str, previous: Option<u64>, last: u64) -> Result<(Vec<(u64, u64)>, bool)> {
+    let mut reader = LogReader::open(log_path)?;
+    let mut epoch = Vec::new();
+    let mut rewritten = false;
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        if !is_write(entry) || previous.is_some_and(|previous| log_entry.index <= previous) {
+            continue;
+        }
+        if log_entry.index <= last {
+            epoch.push((entry.sector, entry.nr_sectors));
+        } else if epoch.iter().any(|(sector, nr_sectors)| entry.sector < sector.saturating_add(*nr_sectors) && *sector < entry.sector.saturating_add(entry.nr_sectors)) {
+            rewritten = true;
+            break;
+        }
+    }
+    Ok((epoch, rewritten))
+}
+
+fn is_write(entry: &LogWriteEntry) -> bool {
+    (entry.flags & (LOG_MARK_FLAG | LOG_DISCARD_FLAG)) == 0 && entry.nr_sectors > 0
+}
+
+/// Write-ahead record of replay progress, one JSON line per barrier, appended
+/// only once the target has been synced so a record never runs ahead of the device.
+pub struct Journal {
+    file: File,
+    /// The log only has a write handle on the replay target
+    replay: File,
+    sector_size: u32,
+    /// Sectors written since the last barrier
+    epoch: Vec<(u64, u64)>,
+    /// Most of the target `hash` reads at once
+    chunk: usize,
+}
+
+impl Journal {
+    /// Opens or starts the journal for replaying `log` onto `replay_path`, returns
+    /// the last barrier a previous run reached if its writes are still on the target.
+    /// Hashing the target reads at most `max_memory` at once, like `LogBuilder::max_memory`.
+    pub fn open<P: AsRef<Path>>(path: P, log_path: &str, replay_path: &str, sector_size: u32, max_memory: Option<usize>) -> Result<(Self, Option<JournalRecord>)> {
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        let file = OpenOptions::new().append(true).create(true).open(&path)
+            .map_err(|error| anyhow!("Error opening journal {}: {}", path.as_ref().display(), error))?;
+        let replay = OpenOptions::new().read(true).open(replay_path)?;
+        // A crash can leave the last line torn, it never made it so it's dropped
+        let valid = contents.rfind('\n').map_or(0, |end| end + 1);
+        file.set_len(valid as u64)?;
+        let contents = &contents[..valid];
+        let chunk = max_memory.unwrap_or(HASH_CHUNK).min(HASH_CHUNK).max(1);
+        let mut journal = Self { file, replay, sector_size, epoch: Vec::new(), chunk };
+
+        let mut lines = contents.lines();
+        let header = json!({ "log": log_path, "replay": replay_path, "sector_size": sector_size });
+        match lines.next() {
+            None => {
+                journal.append(&header)?;
+                return Ok((journal, None));
+            }
+            Some(line) if serde_json::from_str::<Value>(line).ok() == Some(header) => {}
+            Some(_) => bail!("Journal {} is for a different replay", path.as_ref().display()),
+        }
+
+        let mut records: Vec<JournalRecord> = lines.map(|line| JournalRecord::parse(line)
+            .ok_or_else(|| anyhow!("Invalid journal record {}", line)))
+            .collect::<Result<_>>()?;
+        let last = match records.pop() {
+            Some(last) => last,
+            None => return Ok((journal, None)),
+        };
+        let previous = records.last().map(|record| record.entry);
+        let (epoch, rewritten) = epoch_ranges(log_path, previous, last.entry)?;
+        if last.done {
+            return Ok((journal, Some(last)));
+        }
+        if rewritten {
+            // Entries after the barrier may have landed before the crash, they're replayed again anyway
+            warn!("journal: later entries rewrite what entry {} synced, resuming without verifying it", last.entry);
+        } else if journal.hash(&epoch)? != last.hash {
+            bail!("The replay target doesn't match the journal at entry {}, it changed since, replay from scratch", last.entry)
+        }
+        info!("journal: resuming after entry {}", last.entry);
+        Ok((journal, Some(last)))
+    }
+
+    fn hash(&self, ranges: &[(u64, u64)]) -> Result<String> {
+        let mut hasher = Xxh3::new();
+        let mut buf = Vec::new();
+        for (sector, nr_sectors) in ranges {
+            let offset = ByteOffset::from_sectors(*sector, self.sector_size)?;
+            let len = io::sectors_bytes(*nr_sectors, self.sector_size)?;
+            let mut done = 0;
+            while done < len {
+                let chunk = (len - done).min(self.chunk as u64) as usize;
+                // Zeroed first, past the end of the target hashes as zeros
+                buf.clear();
+                buf.resize(chunk, 0);
+                io::read_full_at(&self.replay, &mut buf, offset.checked_add(done)?)?;
+                hasher.update(&buf);
+                done += chunk as u64;
+            }
+        }
+        Ok(checksum::hex(hasher.digest()))
+    }
+
+    fn append(&mut self, record: &Value) -> Result<()> {
+        writeln!(self.file, "{}", record)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Tracks `entry`, just replayed, and records progress when it is a barrier.
+    pub fn note(&mut self, log: &Log, entry: &LogWriteEntry) -> Result<()> {
+        if is_write(entry) {
+            self.epoch.push((entry.sector, entry.nr_sectors));
+        }
+        if (entry.flags & (LOG_FLUSH_FLAG | LOG_FUA_FLAG)) > 0 {
+            self.commit(log, false)?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the target and records that everything up to the last replayed entry is on it.
+    fn commit(&mut self, log: &Log, done: bool) -> Result<()> {
+        log.fsync_replay_file()?;
+        let next_offset = log.log_file.position()?;
+        let hash = self.hash(&self.epoch)?;
+        self.append(&json!({ "entry": log.cur_entry - 1, "next_offset": next_offset, "hash": hash, "done": done }))?;
+        self.epoch.clear();
+        Ok(())
+    }
+
+    /// Records progress up to the last replayed entry, for a replay stopped early.
+    pub fn stop(&mut self, log: &Log) -> Result<()> {
+        if log.cur_entry > 0 && !self.epoch.is_empty() {
+            self.commit(log, false)?;
+        }
+        Ok(())
+    }
+
+    /// Records that the replay reached its end.
+    pub fn finish(&mut self, log: &Log) -> Result<()> {
+        if log.cur_entry > 0 {
+            self.commit(log, true)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+//! The library behind log-write: decoding the dm-log-writes format,
+//! summarizing logs and replaying them. The format and stats modules do no
+//! I/O and also build for wasm32, e.g. for a viewer that inspects logs in the
+//! browser without uploading them.
+
+pub mod checksum;
+pub mod error;
+pub mod format;
+pub mod reader;
+pub mod stats;
+pub mod touched;
+pub mod util;
+
+// Replaying needs files, shared with the binary and the C bindings
+#[cfg(not(target_arch = "wasm32"))]
+pub mod io;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod sys;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod retry;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod undo;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod sealed;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod log_file;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod chain;
+#[cfg(all(feature = "http", not(target_arch = "wasm32")))]
+pub mod http;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod log_writes;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod log_reader;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod observer;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod parallel;
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+pub mod async_log;
+#[cfg(all(feature = "ffi", not(target_arch = "wasm32")))]
+pub mod ffi;
+
+#[cfg(test)]
+mod testutil;
@@ -0,0 +1,118 @@
+//! Where a log's bytes come from: a local file, sealed ones decrypted first,
+//! the segments a chain manifest lists, or with the http feature an object
+//! read with range requests. `Log` and `LogReader` only ever read through
+//! this, so all of them replay the same.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::chain::{self, ChainFile, Manifest};
+use crate::io::{self, ByteOffset, Whence};
+use crate::sealed;
+#[cfg(feature = "http")]
+use crate::http::RemoteFile;
+
+enum Source {
+    Local(File),
+    Chain(ChainFile),
+    #[cfg(feature = "http")]
+    Remote(RemoteFile),
+}
+
+/// A log opened for reading. Like a `File` it has a position, shared with its clones.
+pub struct LogFile {
+    source: Source,
+    /// What it was opened as, for `reopen`
+    path: PathBuf,
+}
+
+/// Whether `path` names a remote log rather than a file.
+pub fn is_url(path: &str) -> bool {
+    ["http://", "https://", "s3://"].iter().any(|scheme| path.starts_with(scheme))
+}
+
+impl LogFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(url) = path.to_str().filter(|path| is_url(path)) {
+            #[cfg(feature = "http")]
+            return Ok(Self { source: Source::Remote(RemoteFile::open(url)?), path: path.to_path_buf() });
+            #[cfg(not(feature = "http"))]
+            anyhow::bail!("Can't read {}, log-write was built without the http feature", url)
+        }
+        let file = sealed::open_log(path)?;
+        let mut head = [0_u8; 64];
+        let len = io::read_full_at(&file, &mut head, ByteOffset::ZERO)?;
+        if chain::is_manifest(&head[..len]) {
+            let manifest = Manifest::parse(&std::fs::read_to_string(path)?)?;
+            let base = path.parent().unwrap_or_else(|| Path::new("."));
+            return Ok(Self { source: Source::Chain(ChainFile::open(&manifest, base)?), path: path.to_path_buf() });
+        }
+        Ok(Self { source: Source::Local(file), path: path.to_path_buf() })
+    }
+
+    /// Reads at the position and moves past what was read, returns how much that was.
+    pub fn read_full(&self, buf: &mut [u8]) -> Result<usize> {
+        match &self.source {
+            Source::Local(file) => io::read_full(file, buf),
+            Source::Chain(chain) => chain.read_full(buf),
+            #[cfg(feature = "http")]
+            Source::Remote(remote) => remote.read_full(buf),
+        }
+    }
+
+    /// Reads at `offset` without moving the position. Windows has no pread,
+    /// local reads there do move it.
+    pub fn read_full_at(&self, buf: &mut [u8], offset: ByteOffset) -> Result<usize> {
+        match &self.source {
+            Source::Local(file) => io::read_full_at(file, buf, offset),
+            Source::Chain(chain) => chain.read_full_at(buf, offset.get()),
+            #[cfg(feature = "http")]
+            Source::Remote(remote) => remote.read_full_at(buf, offset.get()),
+        }
+    }
+
+    pub fn seek(&self, offset: i64, whence: Whence) -> Result<i64> {
+        match &self.source {
+            Source::Local(file) => io::lseek(file, offset, whence),
+            Source::Chain(chain) => chain.seek(offset, whence),
+            #[cfg(feature = "http")]
+            Source::Remote(remote) => remote.seek(offset, whence),
+        }
+    }
+
+    /// The position, where the next `read_full` starts.
+    pub fn position(&self) -> Result<u64> {
+        Ok(self.seek(0, Whence::SeekCur)? as u64)
+    }
+
+    pub fn size(&self) -> Result<u64> {
+        match &self.source {
+            Source::Local(file) => Ok(file.metadata()?.len()),
+            Source::Chain(chain) => Ok(chain.size()),
+            #[cfg(feature = "http")]
+            Source::Remote(remote) => Ok(remote.size()),
+        }
+    }
+
+    pub fn try_clone(&self) -> Result<Self> {
+        let source = match &self.source {
+            Source::Local(file) => Source::Local(file.try_clone()?),
+            Source::Chain(chain) => Source::Chain(chain.clone()),
+            #[cfg(feature = "http")]
+            Source::Remote(remote) => Source::Remote(remote.clone()),
+        };
+        Ok(Self { source, path: self.path.clone() })
+    }
+
+    /// Another handle on the log with a position of its own. Windows reads
+    /// at an offset move the position, so a reader beside a replay needs a
+    /// handle of its own there, elsewhere a clone never moves it.
+    pub fn reopen(&self) -> Result<Self> {
+        #[cfg(windows)]
+        if let Source::Local(_) = &self.source {
+            return Self::open(&self.path);
+        }
+        self.try_clone()
+    }
+}
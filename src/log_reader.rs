@@ -0,0 +1,454 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use anyhow::{Result, anyhow, bail};
+use tracing::{debug, warn};
+use crate::io::ByteOffset;
+use crate::log_writes::{self, DmLogWrites, Log, LogFormat, LogWriteSuper, LogWriteEntry, MemSize};
+use crate::log_file::LogFile;
+
+pub use crate::log_writes::LogEntry;
+
+/// What follows the last entry of a log, see `LogReader::trailer`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trailer {
+    /// Not a copy of the superblock, as after logs from the kernel
+    Absent,
+    Matches,
+    /// The copy counts other entries, the capture stopped between writing the two
+    Differs(LogWriteSuper),
+}
+
+/// Entries between checkpoints, a seek reads at most this many headers
+const CHECKPOINT_INTERVAL: u64 = 64;
+
+/// First line of an index sidecar, see `LogReader::write_index`
+const INDEX_MAGIC: &str = "log-write-index 1";
+
+/// The offset of every `CHECKPOINT_INTERVAL`th entry read so far, so going
+/// back to an entry doesn't mean scanning from the start when the log has no
+/// index. 16 bytes per checkpoint, a million entry log takes 250KiB.
+#[derive(Default)]
+struct Checkpoints {
+    offsets: BTreeMap<u64, u64>,
+}
+
+impl Checkpoints {
+    fn insert(&mut self, index: u64, offset: u64) {
+        self.offsets.entry(index).or_insert(offset);
+    }
+
+    /// The checkpoint with the highest index not past `index`.
+    fn at_or_before(&self, index: u64) -> Option<(u64, u64)> {
+        self.offsets.range(..=index).next_back().map(|(index, offset)| (*index, *offset))
+    }
+}
+
+/// Read-only sequential scanner over a log, tracking entry offsets without
+/// replaying anything. Reads at explicit offsets and never moves the file
+/// position, so clones are independent cursors over the same open file that
+/// can scan from different threads, including alongside a replay of it.
+/// Windows reads do move it, which only the replay minds, see `from_log`.
+/// Clones share the checkpoints `seek_to_index` starts from.
+#[derive(Clone)]
+pub struct LogReader {
+    file: Arc<LogFile>,
+    format: Arc<dyn LogFormat>,
+    pub log_super: LogWriteSuper,
+    next_index: u64,
+    next_offset: u64,
+    checkpoints: Arc<Mutex<Checkpoints>>,
+}
+
+impl LogReader {
+    pub fn open<P: AsRef<Path>>(log_file_path: P) -> Result<Self> {
+        Self::open_with_format(log_file_path, Arc::new(DmLogWrites))
+    }
+
+    /// A reader over a log in `format`, with the checkpoints of the index
+    /// sidecar `<log>.idx` if there's one next to it.
+    pub fn open_with_format<P: AsRef<Path>>(log_file_path: P, format: Arc<dyn LogFormat>) -> Result<Self> {
+        let mut reader = Self::from_file(LogFile::open(&log_file_path)?, format)?;
+        reader.load_index_sidecar(log_file_path.as_ref());
+        Ok(reader)
+    }
+
+    /// Without an index seeks only scan further, so one that's missing or
+    /// for another log is passed over.
+    fn load_index_sidecar(&mut self, log_file_path: &Path) {
+        let mut path = log_file_path.as_os_str().to_owned();
+        path.push(".idx");
+        let read = || -> Result<String> {
+            let file = LogFile::open(&path)?;
+            let mut buf = vec![0_u8; file.size()? as usize];
+            if file.read_full_at(&mut buf, ByteOffset::ZERO)? != buf.len() {
+                bail!("Short read")
+            }
+            Ok(String::from_utf8(buf)?)
+        };
+        match read() {
+            Ok(index) => if let Err(error) = self.load_index(&index) {
+                warn!("not using the index {}: {}", Path::new(&path).display(), error);
+            }
+            Err(error) => debug!(path = %Path::new(&path).display(), %error, "no index sidecar"),
+        }
+    }
+
+    /// A reader over the log `log` is replaying, sharing its file. Windows has
+    /// no pread, reads there move the position replay relies on, so there the
+    /// log is opened again instead.
+    pub fn from_log(log: &Log) -> Result<Self> {
+        let mut reader = Self::from_file(log.log_file.reopen()?, log.format.clone())?;
+        // Follows a sector size override given when opening the log
+        reader.override_sector_size(log.sector_size)?;
+        Ok(reader)
+    }
+
+    fn from_file(file: LogFile, format: Arc<dyn LogFormat>) -> Result<Self> {
+        let mut buf = [0_u8; 32];
+        if file.read_full_at(&mut buf, ByteOffset::ZERO)? != buf.len() {
+            bail!("Log is too short for a superblock")
+        }
+        let log_super = format.read_super(&buf)?;
+        log_writes::check_sector_size(log_super.sector_size)?;
+        Ok(Self {
+            file: Arc::new(file),
+            format,
+            next_index: 0,
+            next_offset: log_super.sector_size as u64,
+            log_super,
+            checkpoints: Arc::new(Mutex::new(Checkpoints::default())),
+        })
+    }
+
+    /// Reads the log as if its superblock said `sector_size`, for salvaged
+    /// logs with a bogus one. Only valid before the first entry is read.
+    pub fn override_sector_size(&mut self, sector_size: u32) -> Result<()> {
+        log_writes::check_sector_size(sector_size)?;
+        self.log_super.sector_size = sector_size;
+        self.next_offset = sector_size as u64;
+        *self.checkpoints.lock().unwrap() = Checkpoints::default();
+        Ok(())
+    }
+
+    pub fn sector_size(&self) -> u32 {
+        self.log_super.sector_size
+    }
+
+    pub fn nr_entries(&self) -> u64 {
+        self.log_super.nr_entries
+    }
+
+    /// Bytes in the log, which a truncated one has fewer of than its entries need.
+    pub fn size(&self) -> Result<u64> {
+        self.file.size()
+    }
+
+    /// Compares the superblock with the copy `LogWriter::trailer` leaves after
+    /// the last entry it synced. That can be past the entries the superblock
+    /// counts, when the capture stopped between writing the copy and the
+    /// superblock, so the search carries on through the entries after them.
+    pub fn trailer(&self) -> Result<Trailer> {
+        let sector_size = self.sector_size() as u64;
+        let mut reader = self.clone();
+        reader.seek_to_entry(0, sector_size);
+        let mut end = sector_size;
+        while let Some(log_entry) = reader.next_entry()? {
+            end = reader.data_offset(&log_entry).saturating_add(reader.data_size(&log_entry.entry));
+        }
+        let mut head = [0_u8; 32];
+        if self.file.read_full_at(&mut head, ByteOffset::ZERO)? != head.len() {
+            bail!("Log is too short for a superblock")
+        }
+        // Against the superblock on disk, a sector size override isn't in the copy either
+        let head = LogWriteSuper::from(head);
+        let mut header = vec![0_u8; sector_size as usize];
+        loop {
+            if self.file.read_full_at(&mut header, ByteOffset::new(end)?)? < LogWriteSuper::mem_size() {
+                return Ok(Trailer::Absent);
+            }
+            let tail = LogWriteSuper::from(<[u8; 32]>::try_from(&header[..32]).unwrap());
+            if tail.magic == log_writes::WRITE_LOG_MAGIC {
+                return Ok(if tail == head { Trailer::Matches } else { Trailer::Differs(tail) });
+            }
+            // Zeros past a preallocated end, otherwise an entry appended since the last sync
+            if header.iter().all(|byte| *byte == 0) {
+                return Ok(Trailer::Absent);
+            }
+            let Ok(entry) = self.format.next_entry(&header) else {
+                return Ok(Trailer::Absent);
+            };
+            end = end.saturating_add(sector_size).saturating_add(self.data_size(&entry));
+        }
+    }
+
+    /// Writes the offset of every `CHECKPOINT_INTERVAL`th entry to `out`, an
+    /// index sidecar for `open` to pick up as `<log>.idx`. Seeks then start
+    /// near the entry instead of reading every header before it, which costs
+    /// a request per chunk with a log read over http. Returns the number of entries.
+    pub fn write_index<W: Write>(&self, mut out: W) -> Result<u64> {
+        writeln!(out, "{} {} {} {}", INDEX_MAGIC, self.sector_size(), self.nr_entries(), self.size()?)?;
+        let mut reader = self.clone();
+        reader.seek_to_entry(0, self.sector_size() as u64);
+        let mut nr_entries = 0;
+        while let Some(log_entry) = reader.next_entry()? {
+            if log_entry.index.is_multiple_of(CHECKPOINT_INTERVAL) {
+                writeln!(out, "{} {}", log_entry.index, log_entry.offset)?;
+            }
+            nr_entries += 1;
+        }
+        out.flush()?;
+        Ok(nr_entries)
+    }
+
+    /// Adds the checkpoints of an index `write_index` wrote of this log. The
+    /// log's size and entry count must match, an index of a log that has
+    /// grown since would send seeks to the wrong offsets.
+    pub fn load_index(&mut self, index: &str) -> Result<()> {
+        let mut lines = index.lines();
+        let header = format!("{} {} {} {}", INDEX_MAGIC, self.sector_size(), self.nr_entries(), self.size()?);
+        if lines.next() != Some(header.as_str()) {
+            bail!("Index is of another log, or of this one at another size")
+        }
+        let mut offsets = Vec::new();
+        for line in lines {
+            let (index, offset) = line.split_once(' ')
+                .and_then(|(index, offset)| Some((index.parse::<u64>().ok()?, offset.parse::<u64>().ok()?)))
+                .filter(|(index, _)| *index < self.nr_entries())
+                .ok_or_else(|| anyhow!("Bad index line {:?}", line))?;
+            offsets.push((index, offset));
+        }
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        for (index, offset) in offsets {
+            checkpoints.insert(index, offset);
+        }
+        Ok(())
+    }
+
+    /// Bytes of data stored in the log after the header of `entry`.
+    pub fn data_size(&self, entry: &LogWriteEntry) -> u64 {
+        entry.data_size(self.sector_size())
+    }
+
+    pub fn data_offset(&self, entry: &LogEntry) -> u64 {
+        entry.offset + self.sector_size() as u64
+    }
+
+    /// Makes the entry at `offset` in the log, numbered `index`, the next one read.
+    pub fn seek_to_entry(&mut self, index: u64, offset: u64) {
+        self.next_index = index;
+        self.next_offset = offset;
+    }
+
+    /// Makes entry `index` the next one read, starting from the closest
+    /// checkpoint rather than the start of the log where it can.
+    pub fn seek_to_index(&mut self, index: u64) -> Result<()> {
+        if index >= self.nr_entries() {
+            bail!("Entry {} is past the end of the log ({} entries)", index, self.nr_entries())
+        }
+        let checkpoint = self.checkpoints.lock().unwrap().at_or_before(index);
+        // Scan from the closest checkpoint before it, this cursor's position included
+        let mut from = checkpoint.unwrap_or((0, self.sector_size() as u64));
+        if self.next_index <= index && self.next_index >= from.0 {
+            from = (self.next_index, self.next_offset);
+        }
+        self.seek_to_entry(from.0, from.1);
+        while self.next_index < index {
+            self.next_entry()?;
+        }
+        Ok(())
+    }
+
+    pub fn next_entry(&mut self) -> Result<Option<LogEntry>> {
+        if self.next_index >= self.nr_entries() {
+            return Ok(None);
+        }
+        let mut buf = vec![0_u8; self.sector_size() as usize];
+        let ret = self.file.read_full_at(&mut buf, ByteOffset::new(self.next_offset)?)?;
+        if ret != buf.len() {
+            bail!("Error reading entry {}: {}", self.next_index, ret)
+        }
+        let entry = LogEntry {
+            index: self.next_index,
+            offset: self.next_offset,
+            entry: self.format.next_entry(&buf)?,
+        };
+        if entry.index.is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.checkpoints.lock().unwrap().insert(entry.index, entry.offset);
+        }
+        self.next_index += 1;
+        self.next_offset = self.next_offset.saturating_add(self.sector_size() as u64).saturating_add(self.data_size(&entry.entry));
+        Ok(Some(entry))
+    }
+
+    /// The entries from the last to the first. Entries only lead to the next
+    /// one, so this first scans the headers for their offsets, 8 bytes each.
+    pub fn entries_rev(&self) -> Result<EntriesRev> {
+        self.entries_rev_from(u64::MAX)
+    }
+
+    /// The entries from entry `last` to the first, scanning no further than `last`.
+    pub fn entries_rev_from(&self, last: u64) -> Result<EntriesRev> {
+        let mut reader = self.clone();
+        reader.seek_to_entry(0, self.sector_size() as u64);
+        let mut offsets = Vec::new();
+        while offsets.len() as u64 <= last {
+            let Some(log_entry) = reader.next_entry()? else { break };
+            offsets.push(log_entry.offset);
+        }
+        Ok(EntriesRev { reader, offsets })
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let ret = self.file.read_full_at(buf, ByteOffset::new(offset)?)?;
+        if ret != buf.len() {
+            bail!("Short read of log at {}: {}", offset, ret)
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+/// Entries from the last to the first, from `LogReader::entries_rev`.
+pub struct EntriesRev {
+    reader: LogReader,
+    offsets: Vec<u64>,
+}
+
+impl Iterator for EntriesRev {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offsets.pop()?;
+        self.reader.seek_to_entry(self.offsets.len() as u64, offset);
+        self.reader.next_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{Log, LogWriteEntry};
+    use crate::testutil::{build_log, log_super, sector, write, TempPath};
+
+    #[test]
+    fn test_independent_cursors() {
+        let entries: Vec<LogWriteEntry> = (0..64).map(|i| write(i % 8, 1)).collect();
+        let (log_path, replay_path) = (TempPath::new("cursor.log"), TempPath::new("cursor.img"));
+        std::fs::write(&log_path, build_log(&entries, |i| i as u8)).unwrap();
+        std::fs::write(&replay_path, [0_u8; 4096]).unwrap();
+
+        let mut log = Log::open(&log_path, &replay_path).unwrap();
+        let mut reader = LogReader::from_log(&log).unwrap();
+        let mut second_half = reader.clone();
+        second_half.seek_to_entry(32, 512 + 32 * 1024);
+
+        let scanners: Vec<_> = vec![reader.clone(), second_half].into_iter().map(|reader| {
+            std::thread::spawn(move || reader.map(|entry| entry.unwrap().index).collect::<Vec<u64>>())
+        }).collect();
+        let progress = log.replay(None, &AtomicBool::new(false)).unwrap();
+        let scanned: Vec<Vec<u64>> = scanners.into_iter().map(|scanner| scanner.join().unwrap()).collect();
+
+        assert_eq!(progress.entries_replayed, 64);
+        assert_eq!(scanned[0], (0..64).collect::<Vec<u64>>());
+        assert_eq!(scanned[1], (32..64).collect::<Vec<u64>>());
+        assert_eq!(reader.next_entry().unwrap().unwrap().index, 0);
+        let replayed = std::fs::read(&replay_path).unwrap();
+        assert_eq!(&replayed[..512], &[56_u8; 512][..]);
+    }
+
+    #[test]
+    fn test_seek_to_index() {
+        // Many checkpoints apart, seeks back and forth start from different ones
+        let nr_entries = 1000_u64;
+        let entries: Vec<LogWriteEntry> = (0..nr_entries).map(|i| write(i, 1)).collect();
+        let log_path = TempPath::new("seek.log");
+        std::fs::write(&log_path, build_log(&entries, |i| i as u8)).unwrap();
+
+        let mut reader = LogReader::open(&log_path).unwrap();
+        assert_eq!(reader.by_ref().count(), nr_entries as usize);
+        let mut seeked = Vec::new();
+        for index in [990, 10, 500, 999, 0] {
+            reader.seek_to_index(index).unwrap();
+            let entry = reader.next_entry().unwrap().unwrap();
+            seeked.push((entry.index, entry.offset, entry.entry.sector));
+        }
+
+        let expected: Vec<(u64, u64, u64)> = [990, 10, 500, 999, 0].iter().map(|i| (*i, 512 + i * 1024, *i)).collect();
+        assert_eq!(seeked, expected);
+        assert!(reader.seek_to_index(nr_entries).is_err());
+    }
+
+    #[test]
+    fn test_index_sidecar() {
+        let entries: Vec<LogWriteEntry> = (0..1000).map(|i| write(i, 1)).collect();
+        let mut log = build_log(&entries, |i| i as u8);
+        let (log_path, index_path) = (TempPath::new("index.log"), TempPath::new("index.log.idx"));
+        std::fs::write(&log_path, &log).unwrap();
+        let mut index = Vec::new();
+        assert_eq!(LogReader::open(&log_path).unwrap().write_index(&mut index).unwrap(), 1000);
+        std::fs::write(&index_path, &index).unwrap();
+        // A header early on that no longer leads to the next, only a seek from the index gets past it
+        log[1536..2048].copy_from_slice(&sector(&write(1, 7).to_bytes()));
+        std::fs::write(&log_path, &log).unwrap();
+
+        let mut reader = LogReader::open(&log_path).unwrap();
+        reader.seek_to_index(990).unwrap();
+        let entry = reader.next_entry().unwrap().unwrap();
+        let mut unindexed = LogReader::open(&log_path).unwrap();
+        let stale = unindexed.load_index(&String::from_utf8(index).unwrap().replace(" 1000 ", " 999 "));
+        std::fs::remove_file(&index_path).unwrap();
+        let mut unindexed = LogReader::open(&log_path).unwrap();
+        unindexed.seek_to_index(990).unwrap();
+        let scanned = unindexed.next_entry().unwrap().unwrap();
+
+        assert_eq!((entry.index, entry.offset, entry.entry.sector), (990, 512 + 990 * 1024, 990));
+        assert!(stale.is_err());
+        assert_ne!(scanned.offset, entry.offset);
+    }
+
+    #[test]
+    fn test_entries_rev() {
+        // Every other entry writes two sectors, so the offsets aren't evenly spaced
+        let entries: Vec<LogWriteEntry> = (0..8).map(|i| write(i, 1 + i % 2)).collect();
+        let log_path = TempPath::new("rev.log");
+        std::fs::write(&log_path, build_log(&entries, |i| i as u8)).unwrap();
+
+        let mut reader = LogReader::open(&log_path).unwrap();
+        reader.next_entry().unwrap();
+        let reversed: Vec<(u64, u64)> = reader.entries_rev().unwrap()
+            .map(|entry| entry.map(|entry| (entry.index, entry.entry.sector)).unwrap())
+            .collect();
+        let next = reader.next_entry().unwrap().unwrap().index;
+
+        assert_eq!(reversed, (0..8).rev().map(|i| (i, i)).collect::<Vec<_>>());
+        // The reader's own position is left alone
+        assert_eq!(next, 1);
+    }
+
+    #[test]
+    fn test_huge_entry_length() {
+        // nr_sectors straight from a corrupt header, the entry after it is past any file
+        let mut log = log_super(2);
+        log.extend(sector(&write(0, u64::MAX).to_bytes()));
+        let log_path = TempPath::new("huge.log");
+        std::fs::write(&log_path, &log).unwrap();
+
+        let mut reader = LogReader::open(&log_path).unwrap();
+        let first = reader.next_entry().unwrap().unwrap();
+        let second = reader.next_entry();
+
+        assert_eq!(reader.data_size(&first.entry), u64::MAX);
+        assert!(second.is_err());
+    }
+}
@@ -1,257 +1,425 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Cursor, Seek, SeekFrom};
-use bytes::{Bytes, Buf};
-use crate::reader::Reader;
+use std::io::{Read, Cursor, SeekFrom};
 use anyhow::{Result, bail, anyhow, Error};
-use crate::io;
+use bytes::Bytes;
+use crate::checksum;
+use crate::error::{ErrorKind, WithKind};
+use crate::io::{self, ByteOffset};
+use crate::log_reader::LogReader;
+use crate::observer::{self, ReplayObserver};
+use crate::touched::TouchedSectors;
 use crate::util;
+use crate::undo::UndoLog;
+use crate::retry::RetryPolicy;
+use crate::sys;
+use crate::log_file::LogFile;
 use std::cmp::min;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::convert::TryFrom;
 use derivative::Derivative;
-use nix::unistd::Whence;
+use crate::io::Whence;
 use std::string::FromUtf8Error;
 use std::ffi::CString;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{debug, error, info, info_span, trace, warn};
+use serde_json::{json, Value};
 
-pub const LOG_FLUSH_FLAG: u64 = 1 << 0;
-pub const LOG_FUA_FLAG: u64 = 1 << 1;
-pub const LOG_DISCARD_FLAG: u64 = 1 << 2;
-pub const LOG_MARK_FLAG: u64 = 1 << 3;
-pub const LOG_METADATA_FLAG: u64 = 1 << 4;
+pub use crate::format::*;
 
-pub const WRITE_LOG_VERSION: u64 = 1;
-pub const WRITE_LOG_MAGIC: u64 = 0x6a736677736872;
+pub const LOG_IGNORE_DISCARD: u64 = 1 << 0;
+pub const LOG_DISCARD_NOT_SUPP: u64 = 1 << 1;
+/// Chunk size for copying big entries and zeroing when `max_memory` isn't set
+const DEFAULT_MAX_BUFFER: usize = 64 * 1024 * 1024;
 
-#[derive(Debug, Copy, Clone)]
-pub struct LogWriteSuper {
-    pub magic: u64,
-    pub version: u64,
-    pub nr_entries: u64,
+/// Why `Log::replay` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayStop {
+    EndOfLog,
+    Mark,
+    Cancelled,
+}
+
+/// How far `Log::replay` got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayProgress {
+    pub entries_replayed: u64,
+    /// Index of the entry a later call would replay first
+    pub next_entry: u64,
+    pub stop: ReplayStop,
+}
+
+pub const CURSOR_VERSION: u64 = 2;
+
+/// Where a replay is, for another process to carry on from without reading
+/// the log up to there, see `Log::save_cursor`. Handed over as JSON:
+/// `{"log_write_cursor": 2, "entry": 12, "offset": 13312, "flags": 2, "sector_size": 512,
+/// "nr_entries": 40, "log_size": 41472, "first_header": 9170125383452335561}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayCursor {
+    /// Index of the entry replayed next
+    pub entry: u64,
+    /// Offset of its header in the log
+    pub offset: u64,
+    /// `Log::flags`, so a target already found unable to discard isn't tried again
+    pub flags: u64,
+    /// What the log looked like when the cursor was saved, so it isn't
+    /// restored onto another one. A log still being written only grows.
     pub sector_size: u32,
+    pub nr_entries: u64,
+    pub log_size: u64,
+    /// xxh3 of the first entry's header sector
+    pub first_header: u64,
 }
 
-impl From<[u8; 32]> for LogWriteSuper {
-    fn from(buf: [u8; 32]) -> Self {
-        let mut rdr = Reader::from(buf.to_vec());
-        let magic = rdr.read_u64_le();
-        let version = rdr.read_u64_le();
-        let nr_entries = rdr.read_u64_le();
-        let sector_size = rdr.read_u64_le() as u32;
-        Self {
-            magic,
-            version,
-            nr_entries,
-            sector_size,
+impl ReplayCursor {
+    pub fn parse(text: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(text).map_err(|error| anyhow!("Error parsing replay cursor: {}", error))?;
+        match value["log_write_cursor"].as_u64() {
+            Some(CURSOR_VERSION) => {}
+            Some(version) if version < CURSOR_VERSION => bail!("Replay cursor version {} doesn't identify its log, save it again", version),
+            Some(version) => bail!("Replay cursor version {} is newer than this log-write", version),
+            None => bail!("Not a replay cursor, log_write_cursor is missing"),
         }
+        let field = |name: &str| value[name].as_u64().ok_or_else(|| anyhow!("Replay cursor without {}", name));
+        let sector_size = u32::try_from(field("sector_size")?).map_err(|_| anyhow!("Replay cursor sector_size is out of range"))?;
+        Ok(Self {
+            entry: field("entry")?,
+            offset: field("offset")?,
+            flags: field("flags")?,
+            sector_size,
+            nr_entries: field("nr_entries")?,
+            log_size: field("log_size")?,
+            first_header: field("first_header")?,
+        })
     }
-}
 
-impl Default for LogWriteSuper {
-    fn default() -> Self {
-        Self {
-            magic: 0,
-            version: 0,
-            nr_entries: 0,
-            sector_size: 0,
-        }
+    pub fn to_json(&self) -> String {
+        json!({
+            "log_write_cursor": CURSOR_VERSION,
+            "entry": self.entry,
+            "offset": self.offset,
+            "flags": self.flags,
+            "sector_size": self.sector_size,
+            "nr_entries": self.nr_entries,
+            "log_size": self.log_size,
+            "first_header": self.first_header,
+        }).to_string()
     }
 }
 
-pub struct FlagsToStrEntry {
-    flags: u64,
-    str: String,
+/// Options for opening a `Log`, see `Log::builder`.
+#[derive(Debug, Default)]
+pub struct LogBuilder {
+    log_path: Option<PathBuf>,
+    replay_path: Option<PathBuf>,
+    ignore_discards: bool,
+    max_zero_size: Option<u64>,
+    sector_size: Option<u32>,
+    read_only: bool,
+    strict: bool,
+    metadata_filter: MetadataFilter,
+    skip_zero_writes: bool,
+    exclude_sectors: Vec<(u64, u64)>,
+    sector_offset: u64,
+    max_memory: Option<usize>,
+    format: Option<Arc<dyn LogFormat>>,
 }
 
-macro_rules! log_flags_str_entry {
-    ($f : ident, $s : expr ) => {
-        FlagsToStrEntry {
-            flags : $f,
-            str : $s.to_string()
-        }
-    };
-}
+impl LogBuilder {
+    pub fn log_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.log_path = Some(path.as_ref().to_path_buf());
+        self
+    }
 
-#[inline]
-fn log_flags_table() -> Vec<FlagsToStrEntry> {
-    vec![
-        log_flags_str_entry!(LOG_FLUSH_FLAG, "FLUSH"),
-        log_flags_str_entry!(LOG_FUA_FLAG, "FUA"),
-        log_flags_str_entry!(LOG_DISCARD_FLAG, "DISCARD"),
-        log_flags_str_entry!(LOG_MARK_FLAG, "MARK"),
-        log_flags_str_entry!(LOG_METADATA_FLAG, "METADATA")
-    ]
-}
+    pub fn replay_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.replay_path = Some(path.as_ref().to_path_buf());
+        self
+    }
 
+    /// Skips discard entries instead of discarding or zeroing the range.
+    pub fn ignore_discards(mut self, ignore: bool) -> Self {
+        self.ignore_discards = ignore;
+        self
+    }
 
-#[derive(Debug)]
-pub struct LogWriteEntry {
-    pub sector: u64,
-    pub nr_sectors: u64,
-    pub flags: u64,
-    pub data_len: u64,
-    pub cmd : String
-}
+    /// Largest discard zeroed where the target can't discard, 128MiB by default.
+    pub fn max_zero_size(mut self, size: u64) -> Self {
+        self.max_zero_size = Some(size);
+        self
+    }
 
-impl From<Vec<u8>> for LogWriteEntry {
-    fn from(buf: Vec<u8>) -> Self {
-        let mut buf = buf;
-        let header : Vec<_> = buf.drain(..Self::mem_size()).collect();
-        let mut rdr = Reader::from(header);
-        let sector = rdr.read_u64_le();
-        let nr_sectors = rdr.read_u64_le();
-        let flags = rdr.read_u64_le();
-        let data_len = rdr.read_u64_le();
-
-        let mut valid_str = Vec::new();
-
-        for i in buf {
-            if i > 0 {
-                valid_str.push(i)
-            }else {
-                break
-            }
-        }
-        let mut cmd = String::from_utf8(valid_str).unwrap_or_default();
-        Self {
-            sector,
-            nr_sectors,
-            flags,
-            data_len,
-            cmd
+    /// Sector size to use instead of the one in the superblock, for logs
+    /// whose superblock was written with the wrong one.
+    pub fn sector_size_override(mut self, sector_size: u32) -> Self {
+        self.sector_size = Some(sector_size);
+        self
+    }
+
+    /// Opens only the log, for inspecting it. Replaying fails.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Fails on format drift, a newer superblock version or entries with
+    /// unknown flags or odd fields, instead of replaying them as best it can.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Replays only metadata writes, or only data writes. Skipped entries still count.
+    pub fn metadata_filter(mut self, filter: MetadataFilter) -> Self {
+        self.metadata_filter = filter;
+        self
+    }
+
+    /// Leaves out all-zero writes to sectors nothing has written yet, which
+    /// keeps file targets sparse. Only right for a target that starts zeroed.
+    pub fn skip_zero_writes(mut self, skip: bool) -> Self {
+        self.skip_zero_writes = skip;
+        self
+    }
+
+    /// Leaves the target's `[start, end)` sector ranges alone, writes and
+    /// discards reaching into them are trimmed to the sectors outside.
+    pub fn exclude_sectors(mut self, ranges: Vec<(u64, u64)>) -> Self {
+        self.exclude_sectors = ranges;
+        self
+    }
+
+    /// Where the target starts on the device the log was recorded from, as
+    /// for a partition replayed from a log of its whole disk. Entries land
+    /// this many sectors lower.
+    pub fn sector_offset(mut self, sectors: u64) -> Self {
+        self.sector_offset = sectors;
+        self
+    }
+
+    /// Caps the buffers a replay allocates at `bytes`, bigger entries and
+    /// zeroed ranges are copied a chunk at a time instead. Without a cap they're
+    /// copied in 64MiB chunks, and only entries whose data is asked for are read whole.
+    /// `Log::max_memory` hands the cap on to the undo log and journal.
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Reads the log as `format` instead of as dm-log-writes.
+    pub fn format(mut self, format: Arc<dyn LogFormat>) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn open(self) -> Result<Log> {
+        let Some(log_file_path) = self.log_path else {
+            bail!("No log path to open")
+        };
+        let replay_file = match (self.read_only, self.replay_path) {
+            (true, Some(_)) => bail!("A read-only log has no replay target"),
+            (true, None) => None,
+            (false, Some(path)) => Some(OpenOptions::new().write(true).read(false).open(&path)
+                .map_err(|error| ErrorKind::Target.wrap(anyhow!("Error opening the replay target {}: {}", path.display(), error)))?),
+            (false, None) => bail!("No replay target to open"),
+        };
+        let log_file = LogFile::open(log_file_path)?;
+
+        let format = self.format.unwrap_or_else(|| Arc::new(DmLogWrites));
+        let mut buf = [0_u8; 32];
+        log_file.read_full(&mut buf)?;
+        let log_super = format.read_super(&buf)?;
+
+        debug!(?log_super, "opened log");
+        if self.strict {
+            log_super.validate()?;
         }
+        let sector_size = self.sector_size.unwrap_or(log_super.sector_size);
+        check_sector_size(sector_size)?;
+
+        // Seek to first log entry
+        log_file.seek(sector_size as i64, Whence::SeekSet).map_err(|error| {
+            anyhow!("Error seeking to first entry: {}", error)
+        })?;
+
+        Ok(Log {
+            log_file,
+            format,
+            replay_file,
+            flags: if self.ignore_discards { LOG_IGNORE_DISCARD } else { 0 },
+            nr_entries: log_super.nr_entries,
+            sector_size,
+            cur_entry: 0,
+            max_zero_size: self.max_zero_size.unwrap_or(128 * 1024 * 1024),
+            cur_pos: 0,
+            undo: None,
+            retry: RetryPolicy::default(),
+            nowait: None,
+            entry_offsets: Vec::new(),
+            strict: self.strict,
+            metadata_filter: self.metadata_filter,
+            touched: if self.skip_zero_writes { Some(TouchedSectors::default()) } else { None },
+            sector_offset: self.sector_offset,
+            max_buffer: self.max_memory,
+            excluded: match self.exclude_sectors.is_empty() {
+                true => None,
+                false => {
+                    let mut excluded = TouchedSectors::default();
+                    for (start, end) in self.exclude_sectors {
+                        excluded.insert(start, end);
+                    }
+                    Some(excluded)
+                }
+            },
+        })
     }
 }
-// memory size of  sector,nr_sector,flags,data_len)
-//  (8 + 8 + 8 + 8) = 32
-const LOG_WRITE_ENTRY_SIZE : usize = 32;
 
-impl MemSize for LogWriteEntry {
-    fn mem_size() -> usize {
-        LOG_WRITE_ENTRY_SIZE
+/// How `Log::tear_next_entry` damages the entry it writes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tear {
+    /// Only the first this many bytes land
+    Prefix(u64),
+    /// Every other sector is garbage instead of the data
+    Garbage,
+}
+
+/// Where to tear a replay, parsed from `ENTRY[:BYTES|:garbage]`. Half the
+/// entry's data lands when no tear is given.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TearAt {
+    pub entry: u64,
+    pub tear: Option<Tear>,
+}
+
+impl std::str::FromStr for TearAt {
+    type Err = Error;
+
+    fn from_str(tear_at: &str) -> Result<Self> {
+        let (entry, tear) = match tear_at.split_once(':') {
+            Some((entry, "garbage")) => (entry, Some(Tear::Garbage)),
+            Some((entry, bytes)) => (entry, Some(Tear::Prefix(bytes.parse().map_err(|error| anyhow!("Invalid byte count {}: {}", bytes, error))?))),
+            None => (tear_at, None),
+        };
+        let entry = entry.parse().map_err(|error| anyhow!("Invalid entry {}: {}", entry, error))?;
+        Ok(Self { entry, tear })
     }
 }
 
-pub const LOG_IGNORE_DISCARD: u64 = 1 << 0;
-pub const LOG_DISCARD_NOT_SUPP: u64 = 1 << 1;
-pub const LOG_FLAGS_BUF_SIZE: usize = 128;
+const GARBAGE: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
 
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct Log {
     #[derivative(Debug="ignore")]
-    pub log_file: File,
+    pub log_file: LogFile,
+    /// Decodes the superblock and headers, see `LogBuilder::format`
     #[derivative(Debug="ignore")]
-    pub replay_file: File,
+    pub format: Arc<dyn LogFormat>,
+    /// None when opened read-only
+    #[derivative(Debug="ignore")]
+    replay_file: Option<File>,
     pub flags: u64,
     pub nr_entries: u64,
     pub sector_size: u32,
     pub cur_entry: u64,
     pub max_zero_size: u64,
     pub cur_pos: u64,
+    /// Keeps what each entry overwrote when set, see `UndoLog`
+    #[derivative(Debug="ignore")]
+    pub undo: Option<UndoLog>,
+    /// Applied to writes to the replay target
+    pub retry: RetryPolicy,
+    /// Writes with RWF_NOWAIT, backing off from this delay while the target is busy
+    pub nowait: Option<Duration>,
+    /// Fail on entries that don't validate
+    pub strict: bool,
+    /// Entries it doesn't pass are read past without being applied
+    pub metadata_filter: MetadataFilter,
+    /// Sectors written so far, kept when skipping zero writes
+    #[derivative(Debug="ignore")]
+    touched: Option<TouchedSectors>,
+    /// Sectors of the target nothing is replayed to
+    #[derivative(Debug="ignore")]
+    excluded: Option<TouchedSectors>,
+    /// Subtracted from entry sectors, see `LogBuilder::sector_offset`
+    sector_offset: u64,
+    /// Largest buffer to allocate, see `LogBuilder::max_memory`
+    max_buffer: Option<usize>,
+    /// Offset of each entry header, built on the first random access
+    #[derivative(Debug="ignore")]
+    entry_offsets: Vec<u64>,
 }
 
-pub trait MemSize {
-    fn mem_size() -> usize;
-}
-
-pub fn entry_flags_to_str(flags: u64, buf: &mut String) {
-    let mut flags = flags;
-    let log_flags_table = log_flags_table();
-    let mut empty = true;
-    for i in log_flags_table {
-        if (flags & i.flags) > 0 {
-            if !empty {
-                util::strncat(buf, "|".to_string(), LOG_FLAGS_BUF_SIZE);
-            }
-            empty = false;
-            util::strncat(buf, i.str, LOG_FLAGS_BUF_SIZE);
-            flags &= !i.flags;
-        }
-    }
-
-    if flags > 0 {
-        if !empty {
-            util::strncat(buf, "|".to_string(), LOG_FLAGS_BUF_SIZE);
-        }
-        empty = false;
-        let left_len = LOG_FLAGS_BUF_SIZE - min(buf.len(), LOG_FLAGS_BUF_SIZE);
-        if left_len > 0 {
-            println!("UNKNOWN.{}", flags)
-        }
-    }
-
-    if empty {
-        buf.clear();
-        buf.push_str("None");
-    }
-}
-
-
-
-
 impl Log {
 
     pub fn fsync_replay_file(&self) -> Result<()> {
-        self.replay_file.sync_all().map_err(|error| {
-            anyhow!("IO Error {}", error)
+        self.replay_file()?.sync_all().map_err(|error| {
+            ErrorKind::Target.wrap(anyhow!("IO Error {}", error))
         })
     }
 
     fn discard_range(&mut self, start : u64, len : u64) -> i32 {
-        let range : [u64;2] = [start, len];
-        let ret = unsafe {
-            ioctls::blkdiscard(self.replay_file.as_raw_fd(), &range)
+        let Ok(replay_file) = self.replay_file() else {
+            return 1
         };
-        if ret < 0 {
-            println!("replay device doesn't support discard, switching to writing zeros");
+        if sys::discard(replay_file, start, len).is_err() {
+            warn!("replay device doesn't support discard, switching to writing zeros");
             self.flags |= LOG_DISCARD_NOT_SUPP;
         }
         return 0
     }
     fn zero_range(&mut self, start : u64, len : u64) -> i32 {
-        let mut start = start as usize;
+        let _span = info_span!("zero", start, len).entered();
+        let mut start = start;
         let mut len = len as usize;
         let mut ret : usize = 0;
-        let mut bufsize : usize = len;
+        let bufsize : usize = min(len, self.max_buffer.unwrap_or(DEFAULT_MAX_BUFFER));
+        let Ok(replay_file) = self.replay_file() else {
+            return -1
+        };
         if self.max_zero_size < len as u64{
-            println!("discard len {} larger than max {}", len, self.max_zero_size);
+            warn!("discard len {} larger than max {}", len, self.max_zero_size);
             return 0;
         }
 
-        let mut buf : Vec<u8> = Vec::with_capacity(len);
-        if buf.capacity() != len as usize {
-            eprintln!("Couldn't allocate zero buffer");
+        let mut buf : Vec<u8> = Vec::with_capacity(bufsize);
+        if buf.capacity() != bufsize {
+            error!("Couldn't allocate zero buffer");
             return -1;
         }
 
-        buf.fill(0);
+        buf.resize(bufsize, 0);
 
         while len > 0 {
-            ret = match io::pwrite(&self.replay_file, buf.as_slice(), start as  i64){
+            let chunk = min(len, bufsize);
+            ret = match ByteOffset::new(start).and_then(|offset| io::write_full_at(replay_file, &buf[..chunk], offset)) {
                 Ok(ret) => {
                     ret
                 }
                 Err(error) => {
-                    eprintln!("Error zeroing file {}", error);
+                    error!("Error zeroing file {}", error);
                     return -1
                 }
             };
-            if ret != bufsize {
-                eprintln!("Error zeroing file");
+            if ret != chunk {
+                error!("Error zeroing file");
                 return -1;
             }
             len -= ret;
-            start += ret;
+            start += ret as u64;
         }
         return 0;
     }
 
-    fn discard(&mut self, entry: &LogWriteEntry) -> Result<()> {
-        let mut start = entry.sector * self.sector_size as u64;
-        let mut size = entry.nr_sectors * self.sector_size as u64;
+    fn discard(&mut self, sector: u64, nr_sectors: u64) -> Result<()> {
+        let mut start = ByteOffset::from_sectors(sector, self.sector_size)?.get();
+        let mut size = io::sectors_bytes(nr_sectors, self.sector_size)?;
         let max_chunk: u64 = 1 * 1024 * 1024 * 1024;
+        let _span = info_span!("discard", start, size).entered();
 
-        if (self.flags & LOG_DISCARD_FLAG) != 0 {
+        if (self.flags & LOG_IGNORE_DISCARD) != 0 {
             return Ok(());
         }
 
@@ -265,8 +433,8 @@ impl Log {
                 ret = self.zero_range(start, len)
             }
 
-            if ret > 0 {
-                bail!("Discard error")
+            if ret != 0 {
+                return Err(ErrorKind::Target.wrap(anyhow!("Error discarding {} bytes at {}", len, start)))
             }
 
             size -= len;
@@ -276,36 +444,211 @@ impl Log {
     }
 
     pub fn open<P: AsRef<Path>>(log_file_path: P, replay_file_path: P) -> Result<Self> {
-        let mut log_file = OpenOptions::new().read(true).write(false).open(log_file_path)?;
-        let replay_file = OpenOptions::new().write(true).read(false).open(replay_file_path)?;
+        Self::builder().log_path(log_file_path).replay_path(replay_file_path).open()
+    }
+
+    /// Opens just the log for inspecting it, there needn't be a replay target.
+    pub fn open_read_only<P: AsRef<Path>>(log_file_path: P) -> Result<Self> {
+        Self::builder().log_path(log_file_path).read_only(true).open()
+    }
+
+    pub fn builder() -> LogBuilder {
+        LogBuilder::default()
+    }
+
+    /// The cap on buffers set with `LogBuilder::max_memory`, for whatever else
+    /// reads entry data alongside the replay.
+    pub fn max_memory(&self) -> Option<usize> {
+        self.max_buffer
+    }
+
+    fn replay_file(&self) -> Result<&File> {
+        self.replay_file.as_ref().ok_or_else(|| anyhow!("Log was opened read-only, there's no replay target"))
+    }
 
+    /// Makes the entry at `offset` in the log, numbered `index`, the next one replayed.
+    /// Seeking to the end of the log is kept for when it grows.
+    pub fn seek_to_entry(&mut self, index: u64, offset: u64) -> Result<()> {
+        if index > self.nr_entries {
+            bail!("Entry {} is past the end of the log ({} entries)", index, self.nr_entries)
+        }
+        self.log_file.seek(offset as i64, Whence::SeekSet)?;
+        self.cur_entry = index;
+        Ok(())
+    }
+
+    /// Where the replay is, for `restore_cursor` on another `Log` of the same
+    /// log to carry on from.
+    pub fn save_cursor(&self) -> Result<ReplayCursor> {
+        Ok(ReplayCursor {
+            entry: self.cur_entry,
+            offset: self.log_file.position()?,
+            flags: self.flags,
+            sector_size: self.sector_size,
+            nr_entries: self.nr_entries,
+            log_size: self.log_file.size()?,
+            first_header: self.first_header_hash()?,
+        })
+    }
+
+    fn first_header_hash(&self) -> Result<u64> {
+        let mut header = vec![0_u8; self.sector_size as usize];
+        let len = self.log_file.read_full_at(&mut header, ByteOffset::new(self.sector_size as u64)?)?;
+        Ok(checksum::xxh3(&header[..len]))
+    }
+
+    /// Carries on from where the `Log` that saved `cursor` was, a cursor at
+    /// the end of the log is kept for when it grows. Refused for a log other
+    /// than the one it was saved from, and with `skip_zero_writes`, whose
+    /// record of touched sectors doesn't travel with the cursor.
+    pub fn restore_cursor(&mut self, cursor: &ReplayCursor) -> Result<()> {
+        if self.touched.is_some() {
+            bail!("Can't carry on from a cursor while skipping zero writes, it doesn't know which sectors were written")
+        }
+        if cursor.sector_size != self.sector_size || cursor.nr_entries > self.nr_entries
+            || cursor.log_size > self.log_file.size()? || cursor.first_header != self.first_header_hash()? {
+            bail!("Cursor was saved from another log ({} entries of {} byte sectors, {} bytes)", cursor.nr_entries, cursor.sector_size, cursor.log_size)
+        }
+        if cursor.entry > self.nr_entries {
+            bail!("Cursor is at entry {}, past the end of the log ({} entries)", cursor.entry, self.nr_entries)
+        }
+        // Headers start on a sector after the superblock's
+        if cursor.offset < self.sector_size as u64 || !cursor.offset.is_multiple_of(self.sector_size as u64) {
+            bail!("Cursor offset {} isn't where an entry of a log of {} byte sectors can start", cursor.offset, self.sector_size)
+        }
+        self.log_file.seek(cursor.offset as i64, Whence::SeekSet)?;
+        self.cur_entry = cursor.entry;
+        self.flags = cursor.flags;
+        Ok(())
+    }
+
+    /// Offset of the header of entry `index`, indexing the log the first time
+    /// and again once it has grown past the index.
+    fn entry_offset(&mut self, index: u64) -> Result<u64> {
+        if index >= self.nr_entries {
+            bail!("Entry {} is past the end of the log ({} entries)", index, self.nr_entries)
+        }
+        if index >= self.entry_offsets.len() as u64 {
+            debug!(nr_entries = self.nr_entries, "indexing log");
+            self.entry_offsets = LogReader::from_log(self)?
+                .take(self.nr_entries as usize)
+                .map(|entry| entry.map(|entry| entry.offset))
+                .collect::<Result<_>>()?;
+        }
+        self.entry_offsets.get(index as usize).copied()
+            .ok_or_else(|| anyhow!("Entry {} is missing from the log", index))
+    }
+
+    /// Applies entry `index` alone, wherever the replay was. Replay carries on
+    /// from the entry after it.
+    pub fn replay_entry_at(&mut self, index: u64) -> Result<LogWriteEntry> {
+        let offset = self.entry_offset(index)?;
+        self.seek_to_entry(index, offset)?;
+        self.replay_next_entry(true)?
+            .ok_or_else(|| anyhow!("Entry {} is missing from the log", index))
+    }
+
+    /// Seeks to entry `index` and writes it torn, see `tear_next_entry`.
+    pub fn tear_entry_at(&mut self, index: u64, tear: Option<Tear>) -> Result<LogWriteEntry> {
+        let offset = self.entry_offset(index)?;
+        self.seek_to_entry(index, offset)?;
+        self.tear_next_entry(tear)
+    }
+
+    /// The header and data of entry `index`, without replaying it or moving
+    /// the replay position.
+    pub fn read_entry_at(&mut self, index: u64) -> Result<(LogWriteEntry, Vec<u8>)> {
+        let offset = ByteOffset::new(self.entry_offset(index)?)?;
+        let mut header = vec![0_u8; self.sector_size as usize];
+        if self.log_file.read_full_at(&mut header, offset)? != header.len() {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Error reading entry {}", index)))
+        }
+        let entry = self.format.next_entry(&header)?;
+        let size = if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+            0
+        } else {
+            io::sectors_len(entry.nr_sectors, self.sector_size)?
+        };
+        let data_offset = offset.checked_add(self.sector_size as u64)?;
+        let left = self.log_file.size()?.saturating_sub(data_offset.get());
+        if size as u64 > left {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Entry {} has {} bytes of data but the log ends {} bytes on", index, size, left)))
+        }
+        let mut data = vec![0_u8; size];
+        if self.log_file.read_full_at(&mut data, data_offset)? != data.len() {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Error reading data of entry {}", index)))
+        }
+        Ok((entry, data))
+    }
+
+    /// Re-reads the entry count from the superblock, returns true if the log grew.
+    pub fn refresh_nr_entries(&mut self) -> Result<bool> {
         let mut buf = [0_u8; 32];
-        io::read(&log_file, &mut buf)?;
+        if self.log_file.read_full_at(&mut buf, ByteOffset::ZERO)? != buf.len() {
+            bail!("Error re-reading the superblock")
+        }
         let log_super = LogWriteSuper::from(buf);
-
-        println!("{:?}", log_super);
-        if log_super.magic != WRITE_LOG_MAGIC {
-            bail!("Magic doesn't match")
+        if log_super.nr_entries > self.nr_entries {
+            debug!(nr_entries = log_super.nr_entries, "log grew");
+            self.nr_entries = log_super.nr_entries;
+            return Ok(true);
         }
+        Ok(false)
+    }
 
-        // Seek to first log entry
-        io::lseek(&log_file, (log_super.sector_size as i64 - std::mem::size_of_val(&log_super) as i64), Whence::SeekCur).map_err(|error| {
-            anyhow!("Error seeking to first entry: {}", error)
-        })?;
+    /// Decodes the entry `replay_next_entry` would replay, without replaying it
+    /// or moving past it. None at the end of the log.
+    pub fn peek_next_entry(&self) -> Result<Option<LogWriteEntry>> {
+        if self.cur_entry >= self.nr_entries {
+            return Ok(None);
+        }
+        let pos = self.log_file.position()?;
+        let mut header = vec![0_u8; self.sector_size as usize];
+        if self.log_file.read_full_at(&mut header, ByteOffset::new(pos)?)? != header.len() {
+            bail!("Error reading entry {}", self.cur_entry)
+        }
+        Ok(Some(self.format.next_entry(&header)?))
+    }
 
-        Ok(Self {
-            log_file,
-            replay_file,
-            flags: 0,
-            nr_entries: log_super.nr_entries,
-            sector_size: log_super.sector_size,
-            cur_entry: 0,
-            max_zero_size: 128 * 1024 * 1024,
-            cur_pos: 0,
-        })
+    /// Whether a whole entry is already in the log after the last one replayed,
+    /// the superblock of a log still being written is updated late.
+    pub fn next_entry_complete(&self) -> Result<bool> {
+        let pos = self.log_file.position()?;
+        let mut header = vec![0_u8; LogWriteEntry::mem_size()];
+        if self.log_file.read_full_at(&mut header, ByteOffset::new(pos)?)? != header.len() {
+            return Ok(false);
+        }
+        // Space past the end of a preallocated log reads as zeros
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(false);
+        }
+        // The copy of the superblock a capture leaves after the last entry it synced
+        if LogWriteSuper::from(<[u8; 32]>::try_from(&header[..32]).unwrap()).magic == WRITE_LOG_MAGIC {
+            return Ok(false);
+        }
+        let entry = self.format.next_entry(&header)?;
+        let data = if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+            0
+        } else {
+            io::sectors_bytes(entry.nr_sectors, self.sector_size)?
+        };
+        let len = self.log_file.size()?;
+        Ok(len >= pos + self.sector_size as u64 + data)
     }
 
     pub fn replay_next_entry(&mut self, read_data: bool) -> Result<Option<LogWriteEntry>> {
+        Ok(self.replay_entry(read_data, false)?.map(|(entry, _)| entry))
+    }
+
+    /// Like `replay_next_entry`, also handing back the data written to the
+    /// replay target so it can be checksummed or sent elsewhere. Empty for
+    /// marks, flushes and discards.
+    pub fn replay_next_entry_with_data(&mut self) -> Result<Option<(LogWriteEntry, Bytes)>> {
+        self.replay_entry(true, true)
+    }
+
+    fn replay_entry(&mut self, read_data: bool, keep_data: bool) -> Result<Option<(LogWriteEntry, Bytes)>> {
+        self.replay_file()?;
         let read_size = if read_data {
             self.sector_size as usize
         } else {
@@ -315,21 +658,29 @@ impl Log {
         let mut raw_log_entry = vec![0_u8; read_size];
 
         if self.cur_entry >= self.nr_entries {
-            println!("{:?}", self);
+            debug!(log = ?self, "reached the end of the log");
             return Ok(None);
         }
 
-        let mut ret = io::read(&self.log_file, &mut raw_log_entry)?;
+        let log_offset = if self.undo.is_some() {
+            self.log_file.position()?
+        } else {
+            0
+        };
+        let mut ret = self.log_file.read_full(&mut raw_log_entry)?;
         if ret != read_size as usize {
-            bail!("Error reading entry: {}", ret)
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Log ends in the header of entry {}, {} of its {} bytes are there", self.cur_entry, ret, read_size)))
+        }
+        let entry = self.format.next_entry(&raw_log_entry)?;
+        if self.strict {
+            entry.validate().map_err(|error| anyhow!("Entry {}: {}", self.cur_entry, error))?;
         }
-        let entry = LogWriteEntry::from(raw_log_entry);
         self.cur_entry += 1;
 
-        let size = (entry.nr_sectors * self.sector_size as u64) as usize;
+        let size = io::sectors_len(entry.nr_sectors, self.sector_size)?;
         if read_size < self.sector_size as usize {
-            println!("Seeking..");
-            io::lseek(&self.log_file, (self.sector_size as i64 - LogWriteEntry::mem_size() as i64), Whence::SeekCur)?;
+            trace!("seeking past the rest of the entry sector");
+            self.log_file.seek((self.sector_size as i64 - LogWriteEntry::mem_size() as i64), Whence::SeekCur)?;
             //self.log_file.seek(SeekFrom::Current(LogWriteEntry::mem_size() as i64))?;
         }
 
@@ -337,15 +688,58 @@ impl Log {
         let flags = entry.flags;
         entry_flags_to_str(flags, &mut flag_buf);
 
-        println!("replaying {}: sector {}, size {}, flags {}({})", self.cur_entry - 1, entry.sector, size, flags, flag_buf);
+        let _span = info_span!("entry", index = self.cur_entry - 1, sector = entry.sector, size, flags = %flag_buf).entered();
+        // The fields are for the console's entry table, the message for plain logs
+        if (flags & LOG_MARK_FLAG) > 0 {
+            info!(index = self.cur_entry - 1, sector = entry.sector, size, flags, mark = %entry.cmd,
+                  "replaying {}: sector {}, size {}, flags {}({})", self.cur_entry - 1, entry.sector, size, flags, flag_buf);
+        } else {
+            info!(index = self.cur_entry - 1, sector = entry.sector, size, flags,
+                  "replaying {}: sector {}, size {}, flags {}({})", self.cur_entry - 1, entry.sector, size, flags, flag_buf);
+        }
 
         if size < 0 {
             return Ok(None);
         }
 
+        if !self.metadata_filter.passes(&entry) {
+            debug!("skipped by the metadata filter");
+            if (flags & LOG_DISCARD_FLAG) == 0 {
+                self.log_file.seek(size as i64, Whence::SeekCur)?;
+            }
+            return Ok(Some((entry, Bytes::new())));
+        }
+
+        let sector = self.target_sector(&entry)?;
+        let (start, end) = (sector, sector.saturating_add(entry.nr_sectors));
         if (flags & LOG_DISCARD_FLAG) > 0 {
-            self.discard(&entry);
-            return Ok(Some(entry))
+            self.save_undo(flags, log_offset, sector, size)?;
+            if let Some(touched) = &mut self.touched {
+                touched.insert(start, end);
+            }
+            for (start, end) in self.gaps(start, end) {
+                self.discard(start, end - start)?;
+            }
+            return Ok(Some((entry, Bytes::new())))
+        }
+
+        // A bogus data_len shouldn't allocate or write a log's worth of garbage first
+        let left = self.log_file.size()?.saturating_sub(self.log_file.position()?);
+        if size as u64 > left {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Entry {} has {} bytes of data but the log ends {} bytes on", self.cur_entry - 1, size, left)))
+        }
+        self.save_undo(flags, log_offset, sector, size)?;
+        match self.max_buffer {
+            Some(max_buffer) if size > max_buffer && keep_data => {
+                bail!("Entry {} has {} bytes of data, more than the {} allowed in memory", self.cur_entry - 1, size, max_buffer)
+            }
+            // Without a cap, data that's asked for is only bounded by the log
+            None if keep_data => {}
+            max_buffer if size > max_buffer.unwrap_or(DEFAULT_MAX_BUFFER) => {
+                self.replay_chunked(sector, size)?;
+                return Ok(Some((entry, Bytes::new())));
+            }
+            _ => {}
         }
 
         let mut buf: Vec<u8> = Vec::with_capacity(size);
@@ -356,29 +750,378 @@ impl Log {
             buf.set_len(size);
         }
 
-        ret = io::read(&self.log_file, &mut buf).unwrap();
+        ret = self.log_file.read_full(&mut buf).map_err(|error| {
+            ErrorKind::BadFormat.wrap(anyhow!("Error reading the data of entry {}: {}", self.cur_entry - 1, error))
+        })?;
         if ret != size as usize {
-            println!("{:?}", buf);
-            bail!("Error reading data[X]: {}", ret)
+            trace!(?buf, "short data read");
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Log ends in the data of entry {}, {} of its {} bytes are there", self.cur_entry - 1, ret, size)))
         }
 
-        let offset = entry.sector * self.sector_size as u64;
-        ret = io::pwrite(&self.replay_file, buf.as_slice(), offset as i64)?;
-        drop(buf);
-        if ret != size as usize {
-            bail!("Error reading data[Y]: {}", ret)
+        if let Some(touched) = &mut self.touched {
+            if !touched.overlaps(start, end) && util::is_zero(&buf) {
+                debug!("skipping zero write to untouched sectors");
+                return Ok(Some((entry, Bytes::from(buf))));
+            }
+            touched.insert(start, end);
+        }
+
+        self.write_at(sector, &buf)?;
+        Ok(Some((entry, Bytes::from(buf))))
+    }
+
+    /// Keeps what the entry replayed next overwrites at `sector`, with an undo log.
+    fn save_undo(&mut self, flags: u64, log_offset: u64, sector: u64, size: usize) -> Result<()> {
+        if let Some(undo) = &mut self.undo {
+            let len = if (flags & LOG_MARK_FLAG) > 0 { 0 } else { size as u64 };
+            undo.save(self.cur_entry - 1, log_offset, ByteOffset::from_sectors(sector, self.sector_size)?.get(), len)?;
+        }
+        Ok(())
+    }
+
+    /// Where `entry` starts on the replay target.
+    fn target_sector(&self, entry: &LogWriteEntry) -> Result<u64> {
+        if entry.nr_sectors == 0 {
+            return Ok(entry.sector.saturating_sub(self.sector_offset));
+        }
+        entry.sector.checked_sub(self.sector_offset).ok_or_else(|| {
+            ErrorKind::Target.wrap(anyhow!("Sector {} is before the target, which starts at sector {}", entry.sector, self.sector_offset))
+        })
+    }
+
+    /// Copies `size` bytes of entry data from the log to `sector` on the target
+    /// in chunks of at most `max_buffer`.
+    fn replay_chunked(&mut self, sector: u64, size: usize) -> Result<()> {
+        let sector_size = self.sector_size as usize;
+        let chunk = (self.max_buffer.unwrap_or(DEFAULT_MAX_BUFFER) / sector_size).max(1) * sector_size;
+        debug!(size, chunk, "copying the entry in chunks");
+        let mut buf = vec![0_u8; min(chunk, size)];
+        let mut done = 0;
+        while done < size {
+            let len = min(chunk, size - done);
+            let data = &mut buf[..len];
+            let read = self.log_file.read_full(data)?;
+            if read != len {
+                return Err(ErrorKind::BadFormat.wrap(anyhow!("Log ends in the data of entry {}, {} of its {} bytes are there", self.cur_entry - 1, done + read, size)))
+            }
+            let start = sector + (done / sector_size) as u64;
+            let end = start + len.div_ceil(sector_size) as u64;
+            if let Some(touched) = &mut self.touched {
+                if !touched.overlaps(start, end) && util::is_zero(data) {
+                    done += len;
+                    continue;
+                }
+                touched.insert(start, end);
+            }
+            self.write_at(start, data)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// The runs of sectors in `[start, end)` that aren't excluded.
+    fn gaps(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        match &self.excluded {
+            Some(excluded) => excluded.gaps(start, end),
+            None => vec![(start, end)],
+        }
+    }
+
+    /// Writes `data` to the target at `sector`, less the excluded sectors.
+    fn write_at(&self, sector: u64, data: &[u8]) -> Result<()> {
+        let sector_size = self.sector_size as u64;
+        let len = data.len() as u64;
+        let end = sector.saturating_add(len.div_ceil(sector_size));
+        let gaps = self.gaps(sector, end);
+        if gaps != [(sector, end)] {
+            debug!(sector, ?gaps, "trimming the write to sectors outside the excluded ranges");
+        }
+        let replay_file = self.replay_file()?;
+        for (start, end) in gaps {
+            let from = min((start - sector) * sector_size, len) as usize;
+            let to = min((end - sector) * sector_size, len) as usize;
+            let offset = ByteOffset::from_sectors(start, self.sector_size)?;
+            let ret = self.retry.run("write to the replay target", || match self.nowait {
+                Some(backoff) => io::write_full_at_nowait(replay_file, &data[from..to], offset, backoff),
+                None => io::write_full_at(replay_file, &data[from..to], offset),
+            }).with_kind(ErrorKind::Target)?;
+            if ret != to - from {
+                return Err(ErrorKind::Target.wrap(anyhow!("Error reading data[Y]: {}", ret)))
+            }
         }
-        Ok(Some(entry))
+        Ok(())
+    }
+
+    /// Writes the next entry torn, as a power cut in the middle of it would
+    /// leave it, then moves past it. Only entries with data can be torn.
+    pub fn tear_next_entry(&mut self, tear: Option<Tear>) -> Result<LogWriteEntry> {
+        let index = self.cur_entry;
+        let (entry, mut data) = self.read_entry_at(index)?;
+        if data.is_empty() {
+            bail!("Entry {} writes no data to tear", index)
+        }
+        let sector_size = self.sector_size as usize;
+        match tear.unwrap_or(Tear::Prefix(data.len() as u64 / 2)) {
+            Tear::Prefix(bytes) => data.truncate(min(bytes, data.len() as u64) as usize),
+            Tear::Garbage => {
+                for sector in data.chunks_mut(sector_size).skip(1).step_by(2) {
+                    for (i, byte) in sector.iter_mut().enumerate() {
+                        *byte = GARBAGE[i % GARBAGE.len()];
+                    }
+                }
+            }
+        }
+        warn!(index, bytes = data.len(), "tearing entry");
+        let sector = self.target_sector(&entry)?;
+        let offset = ByteOffset::from_sectors(sector, self.sector_size)?;
+        let log_offset = self.entry_offset(index)?;
+        if let Some(undo) = &mut self.undo {
+            undo.save(index, log_offset, offset.get(), data.len() as u64)?;
+        }
+        if let Some(touched) = &mut self.touched {
+            touched.insert(sector, sector.saturating_add(entry.nr_sectors));
+        }
+        self.write_at(sector, &data)?;
+
+        let next = ByteOffset::new(log_offset)?.checked_add(self.sector_size as u64 + entry.data_size(self.sector_size))?;
+        self.log_file.seek(next.get() as i64, Whence::SeekSet)?;
+        self.cur_entry = index + 1;
+        Ok(entry)
+    }
+
+    /// Replays until the mark named `end_mark`, or the end of the log without one.
+    /// `cancel` is checked before each entry so another thread can stop a long
+    /// replay between entries, the target is left consistent up to `next_entry`.
+    pub fn replay(&mut self, end_mark: Option<&str>, cancel: &AtomicBool) -> Result<ReplayProgress> {
+        self.replay_observed(end_mark, cancel, &mut ())
+    }
+
+    /// `replay`, telling `observer` about each entry replayed and the error it stops on.
+    pub fn replay_observed(&mut self, end_mark: Option<&str>, cancel: &AtomicBool, observer: &mut dyn ReplayObserver) -> Result<ReplayProgress> {
+        let mut entries_replayed = 0;
+        let stop = loop {
+            if cancel.load(Ordering::SeqCst) {
+                info!(cur_entry = self.cur_entry, "replay cancelled");
+                break ReplayStop::Cancelled;
+            }
+            let index = self.cur_entry;
+            let entry = match self.replay_next_entry(true) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break ReplayStop::EndOfLog,
+                Err(error) => {
+                    observer.on_error(index, &error);
+                    return Err(error)
+                }
+            };
+            entries_replayed += 1;
+            observer::notify(observer, index, &entry);
+            if (entry.flags & LOG_MARK_FLAG) > 0 && end_mark == Some(entry.cmd.as_str()) {
+                break ReplayStop::Mark;
+            }
+        };
+        Ok(ReplayProgress { entries_replayed, next_entry: self.cur_entry, stop })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::log_writes::{LogWriteSuper, log_flags_table};
-    use std::fs::OpenOptions;
-    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::log_writes::{Log, LogWriteEntry, ReplayCursor, ReplayStop, Tear, TearAt, LOG_DISCARD_NOT_SUPP, LOG_MARK_FLAG};
+    use crate::testutil::{build_log, write, TempPath};
+
+    /// A write to sector 2, the mark "one" and a write to sector 3, the data
+    /// of entry `i` all `i + 1`, beside a zeroed replay image.
+    fn mark_log(name: &str) -> (TempPath, TempPath) {
+        let entries = vec![
+            write(2, 1),
+            LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_MARK_FLAG, data_len: 3, cmd: "one".to_string() },
+            write(3, 1),
+        ];
+        let (log_path, replay_path) = (TempPath::new(&format!("{}.log", name)), TempPath::new(&format!("{}.img", name)));
+        std::fs::write(&log_path, build_log(&entries, |i| i as u8 + 1)).unwrap();
+        std::fs::write(&replay_path, [0_u8; 4096]).unwrap();
+        (log_path, replay_path)
+    }
 
     #[test]
-    fn test_rust_struct_size() {}
-}
+    fn test_replay_cancelled() {
+        let (log_path, replay_path) = mark_log("cancel");
+
+        let mut log = Log::open(&log_path, &replay_path).unwrap();
+        let cancel = AtomicBool::new(true);
+        let progress = log.replay(None, &cancel).unwrap();
+        assert_eq!((progress.entries_replayed, progress.next_entry, progress.stop), (0, 0, ReplayStop::Cancelled));
+
+        cancel.store(false, Ordering::SeqCst);
+        let progress = log.replay(Some("one"), &cancel).unwrap();
+        assert_eq!((progress.entries_replayed, progress.next_entry, progress.stop), (2, 2, ReplayStop::Mark));
+        let progress = log.replay(Some("one"), &cancel).unwrap();
+        assert_eq!((progress.entries_replayed, progress.next_entry, progress.stop), (1, 3, ReplayStop::EndOfLog));
+    }
 
+    #[test]
+    fn test_entry_at() {
+        let (log_path, replay_path) = mark_log("entry-at");
+
+        let mut log = Log::open(&log_path, &replay_path).unwrap();
+        let (entry, data) = log.read_entry_at(2).unwrap();
+        assert_eq!((entry.sector, data), (3, vec![3_u8; 512]));
+        let (entry, data) = log.read_entry_at(1).unwrap();
+        assert_eq!((entry.cmd.as_str(), data.len()), ("one", 0));
+        assert!(log.read_entry_at(3).is_err());
+        assert_eq!(log.cur_entry, 0);
+        assert_eq!(log.replay_entry_at(2).unwrap().sector, 3);
+        assert_eq!(log.cur_entry, 3);
+        let image = std::fs::read(&replay_path).unwrap();
+        assert_eq!((image[1024], image[1536]), (0, 3));
+    }
+
+    #[test]
+    fn test_peek_next_entry() {
+        let (log_path, replay_path) = mark_log("peek");
+
+        let mut log = Log::open(&log_path, &replay_path).unwrap();
+        log.seek_to_entry(1, 1536).unwrap();
+        assert_eq!(log.peek_next_entry().unwrap().unwrap().cmd, "one");
+        assert_eq!(log.peek_next_entry().unwrap().unwrap().cmd, "one");
+        assert_eq!(log.replay_next_entry(true).unwrap().unwrap().cmd, "one");
+        assert_eq!(log.peek_next_entry().unwrap().unwrap().sector, 3);
+        log.replay_next_entry(true).unwrap();
+        assert!(log.peek_next_entry().unwrap().is_none());
+
+        // The end of the log can be sought to, past it can't
+        log.seek_to_entry(3, 3072).unwrap();
+        assert!(log.replay_next_entry(true).unwrap().is_none());
+        assert!(log.seek_to_entry(4, 3072).is_err());
+    }
+
+    #[test]
+    fn test_replay_next_entry_with_data() {
+        let (log_path, replay_path) = mark_log("with-data");
+
+        let mut log = Log::open(&log_path, &replay_path).unwrap();
+        log.seek_to_entry(2, 2048).unwrap();
+        let (entry, data) = log.replay_next_entry_with_data().unwrap().unwrap();
+        assert_eq!((entry.sector, &data[..]), (3, &[3_u8; 512][..]));
+        assert!(log.replay_next_entry_with_data().unwrap().is_none());
+        assert_eq!(std::fs::read(&replay_path).unwrap()[1536], 3);
+    }
+
+    #[test]
+    fn test_builder() {
+        let (log_path, replay_path) = mark_log("builder");
+
+        assert!(Log::builder().log_path(&log_path).open().is_err());
+        assert!(Log::builder().log_path(&log_path).sector_size_override(16).read_only(true).open().is_err());
+        let mut log = Log::builder().log_path(&log_path).replay_path(&replay_path).open().unwrap();
+        log.replay(None, &AtomicBool::new(false)).unwrap();
+        let image = std::fs::read(&replay_path).unwrap();
+        assert_eq!((image[1024], image[1536]), (1, 3));
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let (log_path, replay_path) = mark_log("read-only");
+        drop(replay_path);
+
+        let mut log = Log::open_read_only(&log_path).unwrap();
+        assert_eq!(log.read_entry_at(2).unwrap().1, vec![3_u8; 512]);
+        assert_eq!(log.peek_next_entry().unwrap().unwrap().sector, 2);
+        assert!(log.replay_next_entry(true).is_err());
+    }
+
+    #[test]
+    fn test_sector_offset() {
+        let (log_path, replay_path) = mark_log("offset");
+
+        // Two sectors lower, as into a partition starting at sector 2
+        let mut log = Log::builder().log_path(&log_path).replay_path(&replay_path).sector_offset(2).open().unwrap();
+        log.replay(None, &AtomicBool::new(false)).unwrap();
+        let image = std::fs::read(&replay_path).unwrap();
+        assert_eq!((image[0], image[512], image[1024]), (1, 3, 0));
+        // Sector 2 comes before a partition starting at sector 3
+        let mut log = Log::builder().log_path(&log_path).replay_path(&replay_path).sector_offset(3).open().unwrap();
+        assert!(log.replay_next_entry(true).is_err());
+    }
+
+    #[test]
+    fn test_cursor_handoff() {
+        let entries: Vec<LogWriteEntry> = (0..4).map(|i| write(i, 1 + i % 2)).collect();
+        let (log_path, replay_path) = (TempPath::new("handoff.log"), TempPath::new("handoff.img"));
+        std::fs::write(&log_path, build_log(&entries, |i| i as u8 + 1)).unwrap();
+        std::fs::write(&replay_path, [0_u8; 4096]).unwrap();
+
+        let mut log = Log::open(&log_path, &replay_path).unwrap();
+        log.replay_next_entry(true).unwrap();
+        log.replay_next_entry(true).unwrap();
+        log.flags |= LOG_DISCARD_NOT_SUPP;
+        let json = log.save_cursor().unwrap().to_json();
+        let cursor = ReplayCursor::parse(&json).unwrap();
+        assert_eq!((cursor.entry, cursor.offset, cursor.flags), (2, 512 + 1024 + 1536, LOG_DISCARD_NOT_SUPP));
+        assert_eq!((cursor.sector_size, cursor.nr_entries, cursor.log_size), (512, 4, 512 * 5 + 512 * 6));
+
+        // As a worker handed the cursor would
+        let mut worker = Log::open(&log_path, &replay_path).unwrap();
+        worker.restore_cursor(&cursor).unwrap();
+        assert_eq!(worker.flags & LOG_DISCARD_NOT_SUPP, LOG_DISCARD_NOT_SUPP);
+        assert_eq!(worker.replay_next_entry(true).unwrap().unwrap().sector, 2);
+        assert_eq!(worker.replay_next_entry(true).unwrap().unwrap().sector, 3);
+        assert!(worker.replay_next_entry(true).unwrap().is_none());
+        let image = std::fs::read(&replay_path).unwrap();
+        assert_eq!((image[0], image[512], image[1024], image[1536], image[2048]), (1, 2, 3, 4, 4));
+
+        assert!(worker.restore_cursor(&ReplayCursor { entry: 5, ..cursor }).is_err());
+        assert!(worker.restore_cursor(&ReplayCursor { offset: 700, ..cursor }).is_err());
+        assert!(ReplayCursor::parse(r#"{"log_write_cursor": 1, "entry": 0, "offset": 512, "flags": 0}"#).is_err());
+        assert!(ReplayCursor::parse(r#"{"log_write_cursor": 3, "entry": 0, "offset": 512, "flags": 0}"#).is_err());
+        assert!(ReplayCursor::parse(r#"{"entry": 0, "offset": 512, "flags": 0}"#).is_err());
+
+        // Another log of as many entries, or one that skips zero writes
+        let (other_path, _) = mark_log("handoff-other");
+        let mut other = Log::open(&other_path, &replay_path).unwrap();
+        assert!(other.restore_cursor(&ReplayCursor { nr_entries: 3, log_size: 0, ..cursor }).is_err());
+        let mut skipping = Log::builder().log_path(&log_path).replay_path(&replay_path).skip_zero_writes(true).open().unwrap();
+        assert!(skipping.restore_cursor(&cursor).is_err());
+    }
+
+    #[test]
+    fn test_skip_zero_writes() {
+        let writes = [(2, 0_u8), (3, 1), (3, 0), (4, 0)];
+        let entries: Vec<LogWriteEntry> = writes.iter().map(|(sector, _)| write(*sector, 1)).collect();
+        let (log_path, replay_path) = (TempPath::new("zero.log"), TempPath::new("zero.img"));
+        std::fs::write(&log_path, build_log(&entries, |i| writes[i].1)).unwrap();
+        // Not zeroed, so skipped writes show
+        std::fs::write(&replay_path, [0xff_u8; 4096]).unwrap();
+
+        let mut log = Log::builder().log_path(&log_path).replay_path(&replay_path).skip_zero_writes(true).open().unwrap();
+        log.replay(None, &AtomicBool::new(false)).unwrap();
+        let replayed = std::fs::read(&replay_path).unwrap();
+        assert_eq!(&replayed[1024..1536], &[0xff_u8; 512][..]);
+        assert_eq!(&replayed[1536..2048], &[0_u8; 512][..]);
+        assert_eq!(&replayed[2048..2560], &[0xff_u8; 512][..]);
+    }
+
+    #[test]
+    fn test_tear_next_entry() {
+        assert_eq!("3".parse::<TearAt>().unwrap(), TearAt { entry: 3, tear: None });
+        assert_eq!("3:100".parse::<TearAt>().unwrap(), TearAt { entry: 3, tear: Some(Tear::Prefix(100)) });
+        assert_eq!("3:garbage".parse::<TearAt>().unwrap(), TearAt { entry: 3, tear: Some(Tear::Garbage) });
+        assert!("3:lots".parse::<TearAt>().is_err());
+
+        let (log_path, replay_path) = (TempPath::new("tear.log"), TempPath::new("tear.img"));
+        std::fs::write(&log_path, build_log(&[write(0, 4), write(0, 4), write(0, 4)], |i| i as u8 + 1)).unwrap();
+        std::fs::write(&replay_path, [0_u8; 2048]).unwrap();
+
+        let mut log = Log::open(&log_path, &replay_path).unwrap();
+        log.replay_next_entry(true).unwrap();
+        log.tear_next_entry(Some(Tear::Prefix(700))).unwrap();
+        let replayed = std::fs::read(&replay_path).unwrap();
+        assert_eq!(&replayed[..700], &[2_u8; 700][..]);
+        assert_eq!(&replayed[700..], &[1_u8; 1348][..]);
+        log.tear_next_entry(Some(Tear::Garbage)).unwrap();
+        let replayed = std::fs::read(&replay_path).unwrap();
+        assert_eq!((&replayed[..512], &replayed[1024..1536]), (&[3_u8; 512][..], &[3_u8; 512][..]));
+        assert_eq!(&replayed[512..516], &[0xde, 0xad, 0xbe, 0xef]);
+        assert!(log.replay_next_entry(true).unwrap().is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, bail};
+use nix::errno::Errno;
+use tracing::warn;
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+const LO_NAME_SIZE: usize = 64;
+const LO_KEY_SIZE: usize = 32;
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+// Another process can grab the device between GET_FREE and SET_FD
+const ATTACH_RETRIES: usize = 8;
+
+#[repr(C)]
+pub struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; LO_KEY_SIZE],
+    lo_init: [u64; 2],
+}
+
+nix::ioctl_none_bad!(loop_ctl_get_free, 0x4C82);
+nix::ioctl_write_int_bad!(loop_set_fd, 0x4C00);
+nix::ioctl_none_bad!(loop_clr_fd, 0x4C01);
+nix::ioctl_write_ptr_bad!(loop_set_status64, 0x4C04, LoopInfo64);
+
+/// A loop device bound to a regular file, detached again on drop.
+pub struct LoopDevice {
+    path: PathBuf,
+    device: File,
+}
+
+impl LoopDevice {
+    pub fn attach(backing: &Path, read_only: bool) -> Result<Self> {
+        let backing_file = OpenOptions::new().read(true).write(!read_only).open(backing)?;
+        let control = File::open(LOOP_CONTROL).map_err(|error| {
+            anyhow!("Error opening {}: {}", LOOP_CONTROL, error)
+        })?;
+
+        for _ in 0..ATTACH_RETRIES {
+            let index = unsafe { loop_ctl_get_free(control.as_raw_fd()) }.map_err(|error| {
+                anyhow!("Error getting free loop device: {}", error)
+            })?;
+            let path = PathBuf::from(format!("/dev/loop{}", index));
+            let device = OpenOptions::new().read(true).write(!read_only).open(&path)?;
+            match unsafe { loop_set_fd(device.as_raw_fd(), backing_file.as_raw_fd()) } {
+                Ok(_) => {}
+                Err(Errno::EBUSY) => continue,
+                Err(error) => bail!("Error attaching {} to {}: {}", backing.display(), path.display(), error),
+            }
+
+            let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+            info.lo_flags = LO_FLAGS_AUTOCLEAR;
+            if read_only {
+                info.lo_flags |= LO_FLAGS_READ_ONLY;
+            }
+            let name = backing.as_os_str().to_string_lossy();
+            let len = name.len().min(LO_NAME_SIZE - 1);
+            info.lo_file_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+            let loop_dev = Self { path, device };
+            unsafe { loop_set_status64(loop_dev.device.as_raw_fd(), &info) }.map_err(|error| {
+                anyhow!("Error setting loop status on {}: {}", loop_dev.path.display(), error)
+            })?;
+            return Ok(loop_dev);
+        }
+        bail!("Couldn't find a free loop device for {}", backing.display())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        if let Err(error) = unsafe { loop_clr_fd(self.device.as_raw_fd()) } {
+            warn!("Error detaching {}: {}", self.path.display(), error);
+        }
+    }
+}
+
+/// A block device path for `target`, backed by a loop device when `target` is a
+/// regular file. Keep it alive for as long as the device is in use.
+pub struct BlockDevice {
+    path: PathBuf,
+    _loop_dev: Option<LoopDevice>,
+}
+
+impl BlockDevice {
+    pub fn open(target: &Path, read_only: bool) -> Result<Self> {
+        if fs::metadata(target)?.file_type().is_block_device() {
+            return Ok(Self {
+                path: target.to_path_buf(),
+                _loop_dev: None,
+            });
+        }
+        let loop_dev = LoopDevice::attach(target, read_only)?;
+        Ok(Self {
+            path: loop_dev.path().to_path_buf(),
+            _loop_dev: Some(loop_dev),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
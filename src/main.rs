@@ -1,45 +1,1992 @@
-#![feature(cstring_from_vec_with_nul)]
-
 use std::fs::OpenOptions;
 use crate::log_writes::{LogWriteEntry, Log};
-use clap::{App, Arg};
-use anyhow::Result;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+#[cfg(unix)]
+use crate::check::{CheckMode, CheckEnv, Checker, CheckOutcome, FSCK_AUTO};
+#[cfg(unix)]
+use crate::notify::Notifier;
+use crate::hook::{Hook, HookPoint};
+#[cfg(unix)]
+use crate::results::{ResultsStore, CheckpointResult};
+#[cfg(unix)]
+use crate::mount::MountOptions;
+#[cfg(unix)]
+use crate::dm::LogWritesTarget;
+use crate::export::RecordingExport;
+use crate::writer::LogWriter;
+use crate::state::StateExport;
+use anyhow::{Result, anyhow, bail};
+#[cfg(unix)]
+use tracing::info;
+use tracing_subscriber::{EnvFilter, Layer};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use std::result::Result::Ok;
+use std::sync::atomic::{AtomicBool, Ordering};
+use log_write::error::ErrorKind;
+use log_write::{chain, checksum, error, io, log_file, log_reader, log_writes, parallel, retry, stats, sys, undo, util};
+
+#[cfg(unix)]
+mod check;
+#[cfg(unix)]
+mod campaign;
+#[cfg(unix)]
+mod fstests;
+// Only checkpoints record results, and those need unix
+#[cfg_attr(not(unix), allow(dead_code))]
+mod results;
+#[cfg(unix)]
+mod mount;
+#[cfg(unix)]
+mod loopdev;
+#[cfg(unix)]
+mod fsprobe;
+#[cfg(unix)]
+mod dm;
+mod writer;
+mod export;
+mod nbd;
+mod state;
+mod remote;
+#[cfg_attr(not(unix), allow(dead_code))]
+mod metrics;
+#[cfg(unix)]
+mod notify;
+mod hook;
+mod entries;
+mod human;
+mod config;
+mod depgraph;
+mod conformance;
+mod blktrace;
+mod fio;
+mod script;
+mod pb;
+mod qcow2;
+mod analyze;
+mod watch;
+mod fsjournal;
+mod debug;
+mod follow;
+mod spool;
+mod plan;
+mod journal;
+mod safety;
+mod failures;
+mod until;
+mod partition;
+mod target;
+mod selftest;
+mod sidecar;
+mod salvage;
+mod extract;
+#[cfg(unix)]
+mod bench;
+#[cfg(feature = "lua")]
+mod plugin;
+#[cfg(feature = "ublk")]
+mod ublk;
+#[cfg(feature = "fuse")]
+mod fuse;
+#[cfg(feature = "grpc")]
+mod daemon;
+#[cfg(feature = "parquet")]
+mod columnar;
+// The library's fixtures, not every test here needs all of them
+#[cfg(test)]
+#[path = "testutil.rs"]
+#[allow(dead_code)]
+mod testutil;
+
+fn metadata_filter(matches : &ArgMatches) -> log_writes::MetadataFilter {
+    if matches.is_present("only-metadata") {
+        log_writes::MetadataFilter::OnlyMetadata
+    } else if matches.is_present("skip-metadata") {
+        log_writes::MetadataFilter::SkipMetadata
+    } else {
+        log_writes::MetadataFilter::All
+    }
+}
+
+/// Entry flags the replay stops after, those of --stop-flags and replay-log's
+/// --end-mark, --next-flush and --next-fua.
+fn stop_flags(matches : &ArgMatches) -> Result<u64> {
+    let mut stop_flags : u64 = match matches.value_of("stop-flags") {
+        Some(names) => log_writes::parse_flags(names)?,
+        None => 0
+    };
+    if matches.is_present("end-mark") {
+        stop_flags |= log_writes::LOG_MARK_FLAG;
+    }
+    if matches.is_present("next-flush") {
+        stop_flags |= log_writes::LOG_FLUSH_FLAG;
+    }
+    if matches.is_present("next-fua") {
+        stop_flags |= log_writes::LOG_FUA_FLAG;
+    }
+    Ok(stop_flags)
+}
 
-mod log_writes;
-mod reader;
-mod io;
-mod util;
+/// Set by SIGINT or SIGTERM during a replay, which then stops between entries.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-fn should_stop(entry : &LogWriteEntry, stop_flags : u64, mark : &str) -> i32 {
-    let flags = entry.flags;
-    let check_mark: i64 = (stop_flags & log_writes::LOG_MARK_FLAG) as i64;
-    if (flags & stop_flags) > 0 {
-        if check_mark <= 0 {
-            return 1
+#[cfg(unix)]
+extern "C" fn interrupted(_signal : nix::libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes the first SIGINT or SIGTERM stop the replay cleanly, a second one kills it.
+fn catch_interrupts() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+        let action = SigAction::new(SigHandler::Handler(interrupted), SaFlags::SA_RESETHAND | SaFlags::SA_RESTART, SigSet::empty());
+        for signal in [Signal::SIGINT, Signal::SIGTERM] {
+            unsafe { sigaction(signal, &action)?; }
         }
-        if (flags & log_writes::LOG_MARK_FLAG) > 0 && entry.cmd == mark {
-            return 1
+    }
+    Ok(())
+}
+
+/// The entry --start-entry or --start-mark start at, if given.
+fn start_position(matches : &ArgMatches, reader : &mut log_reader::LogReader) -> Result<Option<(u64, u64)>> {
+    if let Some(mark) = matches.value_of("start-mark") {
+        return Ok(Some(entries::after_mark(reader, &mark.parse()?)?));
+    }
+    match matches.value_of("start-entry") {
+        Some(index) => Ok(Some(entries::entry_position(reader, index.parse()?)?)),
+        None => Ok(None)
+    }
+}
+
+/// --salvage: the log to replay, a copy of `log_path` trimmed to its complete
+/// entries at `trimmed_path` if it is truncated.
+fn salvage<'a>(matches : &ArgMatches, log_path : &'a str, trimmed_path : &'a str) -> Result<&'a str> {
+    let mut reader = log_reader::LogReader::open(log_path)?;
+    if let Some(sector_size) = matches.value_of("sector-size") {
+        reader.override_sector_size(sector_size.parse()?)?;
+    }
+    match salvage::find_truncation(&reader)? {
+        Some(truncation) => {
+            salvage::write_trimmed(&reader, &truncation, trimmed_path)?;
+            tracing::warn!("{}, replaying the {} entries before it from {}", truncation, truncation.entry, trimmed_path);
+            Ok(trimmed_path)
         }
+        None => Ok(log_path)
+    }
+}
+
+/// replay-log's --find: walks the log without replaying it and prints where the
+/// replay would stop, as the entry number and the sector the next entry starts at.
+fn find(matches : &ArgMatches) -> Result<()> {
+    let mut reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+    if let Some(sector_size) = matches.value_of("sector-size") {
+        reader.override_sector_size(sector_size.parse()?)?;
+    }
+    if let Some((index, offset)) = start_position(matches, &mut reader.clone())? {
+        reader.seek_to_entry(index, offset);
+    }
+    let run_limit : u64 = matches.value_of("limit").unwrap().parse()?;
+    let end_mark : Option<entries::MarkPattern> = matches.value_of("end-mark").map(str::parse).transpose()?;
+    let stop_flags = stop_flags(matches)?;
+    let mut until = matches.value_of("until").map(until::Until::parse).transpose()?;
+    let mut num_entries : u64 = 0;
+    while let Some(log_entry) = reader.next_entry()? {
+        num_entries += 1;
+        let until_stop = until.as_mut().is_some_and(|until| until.stop(&log_entry.entry, log_entry.index));
+        if until_stop || (run_limit > 0 && num_entries == run_limit) || should_stop(&log_entry.entry, stop_flags, end_mark.as_ref()) > 0 {
+            let next = reader.data_offset(&log_entry) + reader.data_size(&log_entry.entry);
+            println!("{}@{}", log_entry.index, next / reader.sector_size() as u64);
+            return Ok(())
+        }
+    }
+    bail!("Nothing to stop at found in {} entries", num_entries)
+}
+
+/// Whether the replay stops after `entry`: it has one of `stop_flags`, and if
+/// that's only MARK, it's the mark `mark` matches or there's no `mark`.
+fn should_stop(entry : &LogWriteEntry, stop_flags : u64, mark : Option<&entries::MarkPattern>) -> i32 {
+    let flags = entry.flags & stop_flags;
+    if (flags & !log_writes::LOG_MARK_FLAG) > 0 {
+        return 1
+    }
+    if flags > 0 && mark.map_or(true, |mark| mark.matches(&entry.cmd)) {
+        return 1
     }
 
     return 0
 }
 
-#[cfg(target_os = "linux")]
-fn main() -> Result<()>{
-    let matches = App::new("Log Writer").version("1.0")
+#[cfg(unix)]
+/// Runs the check for the entry just replayed, returns None if a previous run already passed it.
+fn run_checkpoint(log : &Log, entry : &LogWriteEntry, checker : &Checker, results : Option<&ResultsStore>, hash_device : bool) -> Result<Option<CheckOutcome>> {
+    let entry_idx = log.cur_entry - 1;
+    if let Some(results) = results {
+        if results.is_done(entry_idx)? {
+            info!("checkpoint at entry {} already passed, skipping", entry_idx);
+            return Ok(None)
+        }
+    }
+
+    let outcome = checker.run(log)?;
+    metrics::record_checkpoint(outcome.exit_code == 0);
+
+    if let Some(results) = results {
+        let device_hash = if hash_device {
+            Some(results::hash_device(&checker.replay_path)?)
+        } else {
+            None
+        };
+        let mark = if (entry.flags & log_writes::LOG_MARK_FLAG) > 0 {
+            Some(entry.cmd.clone())
+        } else {
+            None
+        };
+        results.record(&CheckpointResult {
+            entry: entry_idx,
+            mark,
+            exit_code: outcome.exit_code,
+            duration_ms: outcome.duration.as_millis() as u64,
+            device_hash,
+        })?;
+    }
+
+    if outcome.exit_code != 0 {
+        if let Some(results) = results {
+            let history = results.history(entry_idx)?;
+            if history.passed > 0 {
+                info!("entry {} passed {} times and failed {} times in previous runs", entry_idx, history.passed, history.failed);
+            } else if history.failed > 0 {
+                info!("entry {} failed in all {} previous runs", entry_idx, history.failed);
+            } else {
+                info!("entry {} has no previous results", entry_idx);
+            }
+        }
+    }
+    Ok(Some(outcome))
+}
+
+#[cfg(unix)]
+fn record(matches : &ArgMatches) -> Result<()> {
+    let dev = matches.value_of("dev").unwrap();
+    let log_dev = matches.value_of("log").unwrap();
+    let name = matches.value_of("name").unwrap();
+    let start_mark = matches.value_of("start-mark").unwrap();
+    let end_mark = matches.value_of("end-mark").unwrap();
+    let command : Vec<&str> = matches.values_of("command").unwrap().collect();
+
+    let target = LogWritesTarget::create(name, dev.as_ref(), log_dev.as_ref())?;
+    println!("recording writes to {} into {}", target.path().display(), log_dev);
+    target.mark(start_mark)?;
+    // Run as given, joining the words for sh would split and expand them again
+    let status = std::process::Command::new(command[0])
+        .args(&command[1..])
+        .env("LOG_WRITES_DEV", target.path())
+        .status()?;
+    target.mark(end_mark)?;
+    drop(target);
+
+    if !status.success() {
+        bail!("Recorded command failed: {}", status)
+    }
+    println!("log recorded to {}", log_dev);
+    seal_capture(matches, log_dev)
+}
+
+#[cfg(unix)]
+fn mark(matches : &ArgMatches) -> Result<()> {
+    let name = matches.value_of("name").unwrap();
+    let mark = matches.value_of("mark").unwrap();
+    dm::send_mark(name, mark)
+}
+
+fn record_nbd(matches : &ArgMatches) -> Result<()> {
+    let backing = matches.value_of("backing").unwrap();
+    let log_path = matches.value_of("log").unwrap();
+    let listen = matches.value_of("listen").unwrap();
+    let sector_size : u32 = matches.value_of("sector-size").unwrap().parse()?;
+
+    let mut writer = LogWriter::create(log_path, sector_size)?;
+    writer.trailer = true;
+    let mut export = RecordingExport::open(backing, writer, sector_size)?;
+    if let Some(mark) = matches.value_of("start-mark") {
+        export.writer().mark(mark)?;
+    }
+    let listener = std::net::TcpListener::bind(listen)?;
+    println!("recording writes to {} on nbd://{}", backing, listen);
+    nbd::serve(&listener, &mut export)?;
+    if let Some(mark) = matches.value_of("end-mark") {
+        export.writer().mark(mark)?;
+    }
+    export.writer().sync()?;
+    println!("log recorded to {}, {} entries", log_path, export.writer().nr_entries());
+    drop(export);
+    seal_capture(matches, log_path)
+}
+
+fn serve_nbd(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let at_entry : u64 = matches.value_of("at-entry").unwrap().parse()?;
+    let listen = matches.value_of("listen").unwrap();
+    let size = match matches.value_of("size") {
+        Some(size) => Some(size.parse::<u64>()?),
+        None => None
+    };
+    let base = matches.value_of("base").map(std::path::Path::new);
+
+    let mut export = StateExport::open(log_path, at_entry, base, size)?;
+    let listener = std::net::TcpListener::bind(listen)?;
+    println!("serving state at entry {} of {} on nbd://{}", at_entry, log_path, listen);
+    loop {
+        nbd::serve(&listener, &mut export)?;
+    }
+}
+
+fn export_image(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let at_entry = match (matches.value_of("at-mark"), matches.value_of("at-entry")) {
+        (Some(mark), _) => entries::after_mark(&mut log_reader::LogReader::open(log_path)?, &mark.parse()?)?.0 - 1,
+        (None, Some(entry)) => entry.parse()?,
+        (None, None) => unreachable!()
+    };
+    let size = match matches.value_of("size") {
+        Some(size) => Some(size.parse::<u64>()?),
+        None => None
+    };
+
+    let mut export = StateExport::open(log_path, at_entry, None, size)?;
+    let num_clusters = qcow2::write_image(&mut export, output)?;
+    println!("wrote the state at entry {} of {} to {}, {} data clusters", at_entry, log_path, output, num_clusters);
+    Ok(())
+}
+
+fn extract(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let at_entry : u64 = matches.value_of("at-entry").unwrap().parse()?;
+    let sector : u64 = matches.value_of("sector").unwrap().parse()?;
+    let count : u64 = matches.value_of("count").unwrap().parse()?;
+
+    let reader = log_reader::LogReader::open(log_path)?;
+    let extracted = extract::extract(&reader, at_entry, sector, count, output)?;
+    println!("wrote sectors {}..{} as of entry {} to {}: {} from the log, {} discarded, {} never written",
+             sector, sector + count, at_entry, output, extracted.from_log, extracted.discarded, extracted.unwritten);
+    Ok(())
+}
+
+fn seal(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let output = match matches.value_of("output") {
+        Some(output) => output.to_string(),
+        None => format!("{}.age", log_path)
+    };
+    let recipients : Vec<String> = matches.values_of("recipient").unwrap().map(String::from).collect();
+    #[cfg(feature = "age")]
+    {
+        log_write::sealed::seal(log_path, output.as_str(), &recipients)?;
+        println!("sealed {} to {} for {} recipients", log_path, output, recipients.len());
+        Ok(())
+    }
+    #[cfg(not(feature = "age"))]
+    bail!("Can't seal {} for {} recipients, log-write was built without the age feature", output, recipients.len())
+}
+
+/// Seals a just captured log for the --seal-to recipients, a log file written
+/// here is removed once it is, the log device `record` used can't be.
+fn seal_capture(matches : &ArgMatches, log_path : &str) -> Result<()> {
+    let Some(recipients) = matches.values_of("seal-to") else {
+        return Ok(())
+    };
+    let recipients : Vec<String> = recipients.map(String::from).collect();
+    let output = match matches.value_of("sealed-output") {
+        Some(output) => output.to_string(),
+        None => format!("{}.age", log_path)
+    };
+    #[cfg(feature = "age")]
+    {
+        log_write::sealed::seal(log_path, output.as_str(), &recipients)?;
+        if std::fs::metadata(log_path)?.is_file() {
+            std::fs::remove_file(log_path)?;
+        }
+        println!("sealed the log to {} for {} recipients", output, recipients.len());
+        Ok(())
+    }
+    #[cfg(not(feature = "age"))]
+    bail!("Can't seal {} for {} recipients, log-write was built without the age feature", output, recipients.len())
+}
+
+fn chain(matches : &ArgMatches) -> Result<()> {
+    let output = std::path::Path::new(matches.value_of("output").unwrap());
+    let base = match output.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        Some(parent) => std::fs::canonicalize(parent)?,
+        None => std::env::current_dir()?
+    };
+    // Segment paths are kept relative to the manifest where they can be
+    let segments = matches.values_of("segment").unwrap()
+        .map(|segment| {
+            if log_file::is_url(segment) {
+                return Ok(segment.to_string());
+            }
+            let path = std::fs::canonicalize(segment)?;
+            Ok(path.strip_prefix(&base).unwrap_or(&path).to_string_lossy().into_owned())
+        })
+        .collect::<Result<Vec<String>>>()?;
+    let manifest = chain::Manifest::scan(&segments.iter().map(String::as_str).collect::<Vec<_>>(), &base)?;
+    std::fs::write(output, manifest.to_json() + "\n")?;
+    let nr_entries : u64 = manifest.segments.iter().map(|segment| segment.nr_entries).sum();
+    println!("chained {} segments, {} entries, to {}", manifest.segments.len(), nr_entries, output.display());
+    Ok(())
+}
+
+/// Lists entries the way the replay shows them.
+fn dump(matches : &ArgMatches) -> Result<()> {
+    let mut reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+    let head : Option<u64> = matches.value_of("head").map(str::parse).transpose()?;
+    let tail : Option<usize> = matches.value_of("tail").map(str::parse).transpose()?;
+    let mut annotator = matches.value_of("journal").map(|spec| fsjournal::Annotator::from_spec(spec, &reader)).transpose()?;
+    let color = human::stdout_color();
+    let mut print = |reader : &log_reader::LogReader, log_entry : &log_reader::LogEntry| -> Result<()> {
+        let entry = &log_entry.entry;
+        let mark = if (entry.flags & log_writes::LOG_MARK_FLAG) > 0 { Some(entry.cmd.as_str()) } else { None };
+        let size = entry.nr_sectors.saturating_mul(reader.sector_size() as u64) as i64;
+        let row = human::entry_row(color, log_entry.index, entry.sector, size, entry.flags, mark);
+        let labels = match annotator.as_mut() {
+            Some(annotator) => annotator.annotate(reader, log_entry)?,
+            None => Vec::new()
+        };
+        match labels.is_empty() {
+            true => println!("{}", row),
+            false => println!("{}  [{}]", row, fsjournal::describe(&labels)),
+        }
+        Ok(())
+    };
+    println!("{}", human::entry_header(color));
+    match tail {
+        Some(tail) => {
+            let mut last = reader.entries_rev()?.take(tail).collect::<Result<Vec<_>>>()?;
+            last.reverse();
+            for log_entry in &last {
+                print(&reader, log_entry)?;
+            }
+        }
+        None => {
+            let limit = head.unwrap_or(u64::MAX);
+            while let Some(log_entry) = reader.next_entry()?.filter(|log_entry| log_entry.index < limit) {
+                print(&reader, &log_entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe_trailer(trailer : log_reader::Trailer) -> String {
+    match trailer {
+        log_reader::Trailer::Absent => "none, the log came from the kernel or a capture that stopped between syncs".to_string(),
+        log_reader::Trailer::Matches => "matches the superblock".to_string(),
+        log_reader::Trailer::Differs(copy) => format!("counts {} entries, the capture stopped while syncing", copy.nr_entries),
+    }
+}
+
+fn info(matches : &ArgMatches) -> Result<()> {
+    let reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+    println!("version      {}", reader.log_super.version);
+    println!("entries      {}", reader.nr_entries());
+    println!("sector size  {}", reader.sector_size());
+    println!("size         {}", reader.size()?);
+    match salvage::find_truncation(&reader)? {
+        Some(truncation) => println!("truncated    {}", truncation),
+        None => println!("truncated    no"),
+    }
+    println!("trailer      {}", describe_trailer(reader.trailer()?));
+    Ok(())
+}
+
+/// Fails if the log is truncated or its trailer disagrees with its superblock.
+fn verify(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let nr_entries = spool::verify_log(log_path).map_err(|error| ErrorKind::BadFormat.wrap(error))?;
+    let trailer = log_reader::LogReader::open(log_path)?.trailer()?;
+    println!("{} entries, all complete, trailer {}", nr_entries, describe_trailer(trailer));
+    Ok(())
+}
+
+/// Prints just the number, for shell pipelines.
+fn count(matches : &ArgMatches) -> Result<()> {
+    let reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+    let flags = match matches.value_of("flags") {
+        Some(names) => Some(log_writes::parse_flags(names)?),
+        None => None
+    };
+    let mut num_entries : u64 = 0;
+    for log_entry in reader {
+        let log_entry = log_entry?;
+        if flags.map_or(true, |flags| (log_entry.entry.flags & flags) > 0) {
+            num_entries += 1;
+        }
+    }
+    println!("{}", num_entries);
+    Ok(())
+}
+
+/// Prints just the entry number of the mark, failing if there's none.
+fn locate(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let mark = matches.value_of("mark").unwrap();
+    let mut reader = log_reader::LogReader::open(log_path)?;
+    while let Some(log_entry) = reader.next_entry()? {
+        if (log_entry.entry.flags & log_writes::LOG_MARK_FLAG) > 0 && log_entry.entry.cmd == mark {
+            println!("{}", log_entry.index);
+            return Ok(());
+        }
+    }
+    bail!("Mark {} not found in {}", mark, log_path)
+}
+
+fn graph(matches : &ArgMatches) -> Result<()> {
+    let mut reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+    let graph = depgraph::DepGraph::build(&mut reader)?;
+    match matches.value_of("output") {
+        Some(path) => {
+            let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+            graph.write_dot(&mut out)?;
+            out.into_inner().map_err(|error| anyhow::anyhow!("Error writing {}: {}", path, error))?;
+        }
+        None => graph.write_dot(&mut std::io::stdout().lock())?
+    }
+    Ok(())
+}
+
+fn export(matches : &ArgMatches) -> Result<()> {
+    let mut reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+    if matches.value_of("format") == Some("parquet") {
+        // Parquet is written in row groups with a footer, it needs a file
+        let path = matches.value_of("output").ok_or_else(|| anyhow::anyhow!("--format parquet needs --output"))?;
+        #[cfg(feature = "parquet")]
+        {
+            let num_entries = columnar::write_parquet(&mut reader, matches.is_present("hash"), std::fs::File::create(path)?)?;
+            eprintln!("exported {} entries", num_entries);
+            return Ok(());
+        }
+        #[cfg(not(feature = "parquet"))]
+        bail!("Can't write {}, log-write was built without the parquet feature", path)
+    }
+    let mut out : Box<dyn std::io::Write> = match matches.value_of("output") {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout().lock())
+    };
+    let device = || matches.value_of("device").ok_or_else(|| anyhow::anyhow!("fio exports need --device"));
+    let num_entries = match matches.value_of("format").unwrap() {
+        "blktrace" => blktrace::export(&mut reader, &mut out)?,
+        "fio-iolog" => fio::write_iolog(&mut reader, device()?, &mut out)?,
+        "pb" => pb::write_records(&mut reader, &mut out)?,
+        "sh" => script::write_script(&mut reader, matches.value_of("log").unwrap(), matches.value_of("end-mark").map(str::parse).transpose()?.as_ref(), &mut out)?,
+        "fio" => {
+            // The job refers to its iolog, so both need a path
+            let job_path = matches.value_of("output").ok_or_else(|| anyhow::anyhow!("--format fio needs --output"))?;
+            let iolog_path = format!("{}.iolog", job_path);
+            let mut iolog = std::io::BufWriter::new(std::fs::File::create(&iolog_path)?);
+            let num_entries = fio::write_iolog(&mut reader, device()?, &mut iolog)?;
+            iolog.into_inner().map_err(|error| anyhow::anyhow!("Error writing {}: {}", iolog_path, error))?;
+            fio::write_job(matches.value_of("log").unwrap(), &iolog_path, &mut out)?;
+            num_entries
+        }
+        format => bail!("Unknown export format {}", format)
+    };
+    out.flush()?;
+    eprintln!("exported {} entries", num_entries);
+    Ok(())
+}
+
+fn import(matches : &ArgMatches) -> Result<()> {
+    let input_path = matches.value_of("input").unwrap();
+    let mut input = std::io::BufReader::new(std::fs::File::open(input_path)
+        .map_err(|error| anyhow::anyhow!("Error opening {}: {}", input_path, error))?);
+    let sector_size : u32 = matches.value_of("sector-size").unwrap().parse()?;
+    let mut writer = LogWriter::create(matches.value_of("log").unwrap(), sector_size)?;
+    let data = match matches.value_of("data") {
+        Some(path) => Some(std::fs::File::open(path)?),
+        None => None
+    };
+    let num_entries = match matches.value_of("format").unwrap() {
+        "blktrace" => blktrace::import(&mut input, &mut writer, sector_size, data.as_ref())?,
+        format => bail!("Unknown import format {}", format)
+    };
+    println!("imported {} entries", num_entries);
+    Ok(())
+}
+
+fn analyze(matches : &ArgMatches) -> Result<()> {
+    if let Some(matches) = matches.subcommand_matches("overlaps") {
+        let mut reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+        let overlaps = analyze::find_overlaps(&mut reader)?;
+        for overlap in &overlaps {
+            println!("entries {} and {} overlap at sectors {}+{}", overlap.first, overlap.second, overlap.sector, overlap.nr_sectors);
+        }
+        println!("{} overlapping writes between barriers", overlaps.len());
+    }
+    if let Some(matches) = matches.subcommand_matches("watch") {
+        let regions = watch::parse_regions(matches.value_of("sectors").unwrap())?;
+        let mut reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+        let hits = watch::find_hits(&mut reader, &regions)?;
+        for (index, region) in &hits {
+            println!("entry {} writes into sectors {}", index, region);
+        }
+        println!("{} entries write into watched sectors", hits.len());
+    }
+    if let Some(matches) = matches.subcommand_matches("amplification") {
+        let mut reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+        let amplification = analyze::write_amplification(&mut reader, matches.value_of("top").unwrap().parse()?)?;
+        println!("bytes written: {}", amplification.bytes_written);
+        println!("unique bytes: {}", amplification.unique_bytes);
+        println!("write amplification: {:.2}", amplification.factor());
+        for run in &amplification.hottest {
+            println!("sectors {}+{} written {} times", run.sector, run.nr_sectors, run.writes);
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("allocation") {
+        let mut reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+        let allocation = analyze::allocation(&mut reader, matches.value_of("chunk-size").unwrap().parse()?)?;
+        println!("chunk size: {}", allocation.chunk_size);
+        println!("max allocation: {} bytes after entry {}", allocation.max_bytes, allocation.max_entry);
+        println!("final allocation: {} bytes", allocation.final_bytes);
+        println!("discarded: {} bytes, {} of them freed", allocation.discarded_bytes, allocation.freed_bytes);
+        for point in &allocation.at_marks {
+            println!("mark {} at entry {}: {} bytes", point.mark, point.index, point.bytes);
+        }
+    }
+    if let Some(matches) = matches.subcommand_matches("stats") {
+        let reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+        let entries : Vec<log_reader::LogEntry> = reader.clone().collect::<Result<_>>()?;
+        let filter = metadata_filter(matches);
+        if matches.is_present("by-mark") {
+            let kept = entries.iter().map(|log_entry| &log_entry.entry).filter(|entry| filter.passes(entry));
+            for phase in stats::phases(kept, reader.sector_size()) {
+                println!("{}", phase);
+            }
+        } else {
+            println!("{}", parallel::stats(&reader, &entries, filter));
+        }
+        if let Some(spec) = matches.value_of("journal") {
+            let mut annotator = fsjournal::Annotator::from_spec(spec, &reader)?;
+            let counts = fsjournal::count(&reader, &mut annotator)?;
+            println!("journal {}", annotator.journals()[0]);
+            for (label, count) in &counts {
+                println!("  {}: {}", label, count);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn plan(matches : &ArgMatches) -> Result<()> {
+    let order = match matches.value_of("entries-file") {
+        Some(path) => Some(entries::read_entries_file(path)?),
+        None => None
+    };
+    let limit : u64 = matches.value_of("limit").unwrap().parse()?;
+    let end_mark : Option<entries::MarkPattern> = matches.value_of("end-mark").map(str::parse).transpose()?;
+    let plan = plan::Plan::create(matches.value_of("log").unwrap(), matches.value_of("replay").unwrap(), order, limit, end_mark.as_ref())?;
+    match matches.value_of("output") {
+        Some(path) => plan.save(path)?,
+        None => println!("{}", serde_json::to_string_pretty(&plan.to_json())?)
+    }
+    for check in plan.prechecks.iter().filter(|check| !check.passed) {
+        eprintln!("precheck {} failed: {}", check.name, check.detail);
+    }
+    eprintln!("{} entries planned for {}", plan.entries.len(), plan.replay);
+    Ok(())
+}
+
+fn apply(matches : &ArgMatches) -> Result<()> {
+    let plan = plan::Plan::load(matches.value_of("plan").unwrap())?;
+    let applied = plan.apply(matches.is_present("force"), matches.is_present("yes"))?;
+    println!("applied {} entries to {}", applied, plan.replay);
+    Ok(())
+}
+
+fn spool(matches : &ArgMatches) -> Result<()> {
+    let steps = matches.values_of("step").map(|steps| steps.map(String::from).collect()).unwrap_or_default();
+    let interval : u64 = matches.value_of("interval").unwrap().parse()?;
+    let mut spool = spool::Spool::new(matches.value_of("dir").unwrap(), steps, std::time::Duration::from_secs(interval))?;
+    spool.run()
+}
+
+fn debug(matches : &ArgMatches) -> Result<()> {
+    safety::check_target(matches.value_of("replay").unwrap(), matches.is_present("force"), matches.is_present("yes"))?;
+    let mut debugger = debug::Debugger::open(matches.value_of("log").unwrap(), matches.value_of("replay").unwrap())?;
+    debugger.run(std::io::stdin().lock(), &mut std::io::stdout().lock())
+}
+
+#[cfg(unix)]
+fn campaign(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let replay_path = matches.value_of("replay").unwrap();
+    let seed = match matches.value_of("seed") {
+        Some(seed) => seed.parse::<u64>()?,
+        None => std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_nanos() as u64
+    };
+    let runs : u64 = matches.value_of("runs").unwrap().parse()?;
+    safety::check_target(replay_path, matches.is_present("force"), matches.is_present("yes"))?;
+    let checker = Checker {
+        // Every materialized state is checked, the mode isn't consulted
+        mode: CheckMode::Number(1),
+        fsck_cmd: matches.value_of("fsck").unwrap().to_string(),
+        env: CheckEnv::Host,
+        replay_path: replay_path.into(),
+        mount: None,
+    };
+    println!("campaign seed {}", seed);
+    let failed = campaign::run(log_path, replay_path, &checker, seed, runs, matches.is_present("tails"))?;
+    if !failed.is_empty() {
+        for run_seed in &failed {
+            println!("replay the failure with --seed {} --runs 1", run_seed);
+        }
+        return Err(ErrorKind::CheckFailed.wrap(anyhow!("{} of {} runs failed the check", failed.len(), runs)))
+    }
+    println!("all {} runs passed", runs);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bench(matches : &ArgMatches) -> Result<()> {
+    let reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+    let backends = match matches.value_of("backends") {
+        Some(names) => names.split(',').map(str::parse).collect::<Result<Vec<bench::Backend>>>()?,
+        None => bench::Backend::ALL.to_vec()
+    };
+    let depth : usize = matches.value_of("depth").unwrap().parse()?;
+    if depth == 0 {
+        bail!("--depth must be at least 1")
+    }
+    let targets : Vec<&str> = matches.values_of("target").map_or_else(|| vec!["/dev/null"], |targets| targets.collect());
+    for target in &targets {
+        safety::check_target(target, matches.is_present("force"), matches.is_present("yes"))?;
+    }
+    bench::preload(&reader)?;
+    let mut rows = Vec::new();
+    for target in &targets {
+        for backend in &backends {
+            rows.push((target.to_string(), *backend, bench::run(&reader, *backend, std::path::Path::new(target), depth)));
+        }
+    }
+    for line in bench::table(&rows) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn run_fstests(matches : &ArgMatches) -> Result<()> {
+    let env = fstests::FstestsEnv::from_env(matches.value_of("log"), matches.value_of("scratch"))?;
+    let preset = match matches.subcommand() {
+        ("replay", Some(sub)) => fstests::Preset::Replay(sub.value_of("mark").unwrap().to_string()),
+        ("check", Some(sub)) => fstests::Preset::Check(sub.value_of("mark").unwrap().to_string()),
+        ("mount-check", Some(sub)) => fstests::Preset::MountCheck(sub.value_of("mark").unwrap().to_string()),
+        ("check-fua", _) => fstests::Preset::CheckFua,
+        ("check-marks", _) => fstests::Preset::CheckMarks,
+        _ => bail!("Missing preset, see fstests --help")
+    };
+    // fstests runs unattended, SCRATCH_DEV is meant to be overwritten
+    safety::check_target(&env.scratch, false, true)?;
+    let num_entries = fstests::run(&env, &preset)?;
+    println!("replayed {} entries onto {}", num_entries, env.scratch);
+    Ok(())
+}
+
+fn conformance(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let replay_log = matches.value_of("replay-log").unwrap();
+    if !conformance::available(replay_log) {
+        println!("{} not found, skipping the comparison", replay_log);
+        return Ok(());
+    }
+    let dir = match matches.value_of("dir") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => std::env::temp_dir()
+    };
+    match conformance::run(log_path, replay_log, &dir)? {
+        None => {
+            println!("{} and log-write replay {} identically", replay_log, log_path);
+            Ok(())
+        }
+        Some(mismatch) => {
+            match mismatch.entry {
+                Some(entry) => println!("images differ at sector {}, last written by entry {}", mismatch.sector, entry),
+                None => println!("images differ at sector {}, which no entry wrote", mismatch.sector),
+            }
+            println!("kept {} and {}", mismatch.rust_image.display(), mismatch.c_image.display());
+            bail!("{} and log-write disagree", replay_log)
+        }
+    }
+}
+
+fn checksum(matches : &ArgMatches) -> Result<()> {
+    let reader = log_reader::LogReader::open(matches.value_of("log").unwrap())?;
+    let path = matches.value_of("emit").unwrap();
+    let nr_entries = sidecar::emit(reader, path)?;
+    println!("wrote the CRCs of {} entries to {}", nr_entries, path);
+    Ok(())
+}
+
+fn index(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let output = match matches.value_of("output") {
+        Some(output) => output.to_string(),
+        None if log_file::is_url(log_path) => bail!("--output is needed to index a remote log, upload the index next to it"),
+        None => format!("{}.idx", log_path)
+    };
+    let reader = log_reader::LogReader::open(log_path)?;
+    let mut out = std::io::BufWriter::new(std::fs::File::create(&output)?);
+    let nr_entries = reader.write_index(&mut out)?;
+    out.into_inner().map_err(|error| anyhow!("Error writing {}: {}", output, error))?.sync_all()?;
+    println!("indexed {} entries of {} in {}", nr_entries, log_path, output);
+    Ok(())
+}
+
+fn selftest(matches : &ArgMatches) -> Result<()> {
+    let dir = match matches.value_of("dir") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => std::env::temp_dir()
+    };
+    let steps = selftest::run(&dir, matches.value_of("device").map(std::path::Path::new), matches.is_present("keep"));
+    let mut failed = 0;
+    for step in &steps {
+        match &step.result {
+            Ok(detail) => println!("ok      {:<8}  {}", step.name, detail),
+            Err(error) => {
+                failed += 1;
+                println!("FAILED  {:<8}  {}", step.name, error)
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} self test steps failed", failed)
+    }
+    Ok(())
+}
+
+fn step_back(matches : &ArgMatches) -> Result<()> {
+    let count : u64 = matches.value_of("count").unwrap().parse()?;
+    let mut undo = undo::UndoLog::open(matches.value_of("undo-log").unwrap(), matches.value_of("replay").unwrap())?;
+    match undo.step_back(count)? {
+        Some(record) => println!("{} is back to before entry {}", matches.value_of("replay").unwrap(), record.index),
+        None => println!("nothing left to undo")
+    }
+    Ok(())
+}
+
+fn receive(matches : &ArgMatches) -> Result<()> {
+    let replay_path = matches.value_of("replay").unwrap();
+    // Under --stdio stdin is the stream, not someone to ask, so a block
+    // device needs the --yes the sender forwards
+    safety::check_target(replay_path, matches.is_present("force"), matches.is_present("yes"))?;
+    // With --stdio our stdout is the protocol stream, so report on stderr
+    let num_entries = if matches.is_present("stdio") {
+        remote::receive(std::io::stdin(), std::io::stdout(), replay_path)?
+    } else {
+        let listen = matches.value_of("listen").unwrap();
+        let listener = std::net::TcpListener::bind(listen)?;
+        eprintln!("waiting for a sender on {}", listen);
+        remote::serve(&listener, replay_path)?
+    };
+    eprintln!("replayed {} entries onto {}", num_entries, replay_path);
+    Ok(())
+}
+
+fn replay_remote(matches : &ArgMatches, target : &str, run_limit : u64) -> Result<()> {
+    if matches.is_present("check") {
+        bail!("--check can't be combined with --remote")
+    }
+    let log_file_path = matches.value_of("log").unwrap();
+    let end_mark : Option<entries::MarkPattern> = matches.value_of("end-mark").map(str::parse).transpose()?;
+    let stop_flags = stop_flags(matches)?;
+    let target = remote::RemoteTarget::parse(target);
+    // Passed on, the receiver can't ask over the stream
+    let mut receive_args = Vec::new();
+    if matches.is_present("force") {
+        receive_args.push("--force");
+    }
+    if matches.is_present("yes") {
+        receive_args.push("--yes");
+    }
+    let sink = remote::RemoteSink::connect(&target, matches.value_of("remote-command").unwrap(), &receive_args)?;
+    let mut until = matches.value_of("until").map(until::Until::parse).transpose()?;
+    let mut reader = log_reader::LogReader::open(log_file_path)?;
+    let num_entries = remote::send(&mut reader, sink, |entry, num_entries| {
+        until.as_mut().is_some_and(|until| until.stop(entry, num_entries - 1))
+            || (run_limit > 0 && num_entries == run_limit)
+            || should_stop(entry, stop_flags, end_mark.as_ref()) > 0
+    })?;
+    println!("replayed {} entries on {:?}", num_entries, target);
+    Ok(())
+}
+
+#[cfg(feature = "ublk")]
+fn record_ublk(matches : &ArgMatches) -> Result<()> {
+    let backing = matches.value_of("backing").unwrap();
+    let log_path = matches.value_of("log").unwrap();
+    let sector_size : u32 = matches.value_of("sector-size").unwrap().parse()?;
+
+    let mut writer = LogWriter::create(log_path, sector_size)?;
+    writer.trailer = true;
+    let mut export = RecordingExport::open(backing, writer, sector_size)?;
+    if let Some(mark) = matches.value_of("start-mark") {
+        export.writer().mark(mark)?;
+    }
+    println!("recording writes to {}", backing);
+    ublk::serve(&mut export, sector_size)?;
+    if let Some(mark) = matches.value_of("end-mark") {
+        export.writer().mark(mark)?;
+    }
+    export.writer().sync()?;
+    println!("log recorded to {}, {} entries", log_path, export.writer().nr_entries());
+    drop(export);
+    seal_capture(matches, log_path)
+}
+
+#[cfg(feature = "ublk")]
+fn serve_ublk(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let at_entry : u64 = matches.value_of("at-entry").unwrap().parse()?;
+    let size = match matches.value_of("size") {
+        Some(size) => Some(size.parse::<u64>()?),
+        None => None
+    };
+    let base = matches.value_of("base").map(std::path::Path::new);
+
+    let mut export = StateExport::open(log_path, at_entry, base, size)?;
+    println!("serving state at entry {} of {}", at_entry, log_path);
+    let sector_size = export.sector_size();
+    ublk::serve(&mut export, sector_size)
+}
+
+#[cfg(feature = "fuse")]
+fn mount_states(matches : &ArgMatches) -> Result<()> {
+    let log_path = matches.value_of("log").unwrap();
+    let mountpoint = matches.value_of("mountpoint").unwrap();
+    let size = match matches.value_of("size") {
+        Some(size) => Some(size.parse::<u64>()?),
+        None => None
+    };
+    let base = matches.value_of("base").map(std::path::Path::new);
+    let check = match matches.value_of("check") {
+        Some(check) => Some(check.parse::<CheckMode>()?),
+        None => None
+    };
+
+    let fs = fuse::StatesFs::open(log_path, base, size, check.as_ref())?;
+    println!("mounting states of {} on {}", log_path, mountpoint);
+    fuse::mount(fs, mountpoint)
+}
+
+/// The fsck run at checkpoints, if the replay was given --check.
+#[cfg(unix)]
+fn checker(flags : &config::Flags, replay_file_path : &str) -> Result<Option<Checker>> {
+    let check_env = if let Some(root) = flags.value_of("check-chroot") {
+        CheckEnv::Chroot(root.into())
+    } else if let Some(image) = flags.value_of("check-container") {
+        CheckEnv::Container {
+            runtime: flags.value_of("container-runtime").unwrap().to_string(),
+            image: image.to_string(),
+        }
+    } else {
+        CheckEnv::Host
+    };
+    if flags.value_of("fsck") == Some(FSCK_AUTO) && flags.is_present("mount-check") {
+        bail!("--fsck auto can't be combined with --mount-check")
+    }
+    Ok(match (flags.value_of("check"), flags.value_of("fsck")) {
+        (Some(check), Some(fsck_cmd)) => Some(Checker {
+            mode: check.parse::<CheckMode>()?,
+            fsck_cmd: fsck_cmd.to_string(),
+            env: check_env,
+            replay_path: replay_file_path.into(),
+            mount: if flags.is_present("mount-check") {
+                Some(MountOptions {
+                    fstype: flags.value_of("mount-type").map(String::from),
+                    options: flags.value_of("mount-options").map(String::from),
+                })
+            } else {
+                None
+            },
+        }),
+        _ => None
+    })
+}
+
+#[cfg(feature = "grpc")]
+fn run_daemon(matches : &ArgMatches) -> Result<()> {
+    let listen = matches.value_of("listen").unwrap();
+    let addr = listen.parse().map_err(|error| anyhow::anyhow!("Invalid address {}: {}", listen, error))?;
+    if let Some(metrics_listen) = matches.value_of("metrics-listen") {
+        metrics::spawn_server(metrics_listen)?;
+    }
+    daemon::serve(addr)
+}
+
+/// Exits with the code for the kind of error, see log_write::error.
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("{}", human::paint(human::stderr_color(), "\x1b[31m", &format!("Error: {:?}", error)));
+        std::process::exit(error::exit_code(&error));
+    }
+}
+
+fn run() -> Result<()>{
+    let app = App::new("Log Writer").version("1.0")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .after_help("EXIT CODES:
+    0    success
+    1    any other error, including bad arguments
+    2    the log is malformed or truncated
+    3    opening or writing the replay target failed
+    4    the check command failed at a checkpoint
+    5    interrupted, --journal can resume the replay
+    6    stopped at --limit-bytes or --limit-seconds")
+        .subcommand(SubCommand::with_name("record-nbd")
+            .about("Capture a log by serving a backing file over NBD and recording its writes")
+            .arg(Arg::with_name("backing")
+                .long("backing")
+                .value_name("BACKING_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .takes_value(true)
+                .default_value("127.0.0.1:10809")
+            )
+            .arg(Arg::with_name("sector-size")
+                .long("sector-size")
+                .value_name("SECTOR_SIZE")
+                .takes_value(true)
+                .default_value("512")
+            )
+            .arg(Arg::with_name("start-mark")
+                .long("start-mark")
+                .value_name("START_MARK")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("end-mark")
+                .long("end-mark")
+                .value_name("END_MARK")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("seal-to")
+                .long("seal-to")
+                .value_name("AGE_PUBLIC_KEY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Seal the log for this recipient once it's recorded, and remove the plaintext log file")
+            )
+            .arg(Arg::with_name("sealed-output")
+                .long("sealed-output")
+                .value_name("SEALED_PATH")
+                .takes_value(true)
+                .requires("seal-to")
+                .help("Where to write the sealed log, LOG_PATH.age by default")
+            )
+        )
+        .subcommand(SubCommand::with_name("serve-nbd")
+            .about("Serve the device state as of an entry read-only over NBD")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("at-entry")
+                .long("at-entry")
+                .value_name("ENTRY")
+                .takes_value(true)
+                .required(true)
+                .help("Serve the state right after this entry was written")
+            )
+            .arg(Arg::with_name("base")
+                .long("base")
+                .value_name("BASE_PATH")
+                .takes_value(true)
+                .help("Image providing the contents of sectors the log never wrote")
+            )
+            .arg(Arg::with_name("size")
+                .long("size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Export size, defaults to the base image size or the highest written sector")
+            )
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .takes_value(true)
+                .default_value("127.0.0.1:10809")
+            )
+        )
+        .subcommand(SubCommand::with_name("seal")
+            .about("Encrypt a log with age, sealed logs are read with the identity file LOG_WRITE_AGE_IDENTITY names")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("recipient")
+                .long("recipient")
+                .short("r")
+                .value_name("AGE_PUBLIC_KEY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(true)
+                .help("age1... key that can open the log, can be given more than once")
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("SEALED_PATH")
+                .takes_value(true)
+                .help("Defaults to LOG_PATH.age")
+            )
+        )
+        .subcommand(SubCommand::with_name("chain")
+            .about("Write a manifest chaining logs rotated during capture, --log takes it like a single log")
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("MANIFEST_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("segment")
+                .value_name("SEGMENT")
+                .multiple(true)
+                .required(true)
+                .help("Segments in capture order")
+            )
+        )
+        .subcommand(SubCommand::with_name("dump")
+            .about("List the entries of a log, or only the first or last few")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("head")
+                .long("head")
+                .value_name("N")
+                .takes_value(true)
+                .help("Only the first N entries")
+            )
+            .arg(Arg::with_name("tail")
+                .long("tail")
+                .value_name("N")
+                .takes_value(true)
+                .conflicts_with("head")
+                .help("Only the last N entries, e.g. the writes before a crash mark")
+            )
+            .arg(Arg::with_name("journal")
+                .long("journal")
+                .value_name("JOURNAL")
+                .takes_value(true)
+                .help("Label writes into an ext4 or XFS journal: ext4:SECTOR+SECTORS[:BLOCK_SIZE], xfs:SECTOR+SECTORS, or auto to find it in the superblock the log writes")
+            )
+        )
+        .subcommand(SubCommand::with_name("info")
+            .about("Print the superblock, whether the log is truncated and whether its trailer matches")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("verify")
+            .about("Check every entry is complete and the trailer, if any, matches the superblock")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("count")
+            .about("Print how many entries the log has, or how many have the given flags")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("flags")
+                .long("flags")
+                .value_name("FLAGS")
+                .takes_value(true)
+                .help("Only count entries with any of these, e.g. FLUSH,FUA")
+            )
+        )
+        .subcommand(SubCommand::with_name("locate")
+            .about("Print the number of the first mark with the given name")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("mark")
+                .long("mark")
+                .value_name("MARK")
+                .takes_value(true)
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("graph")
+            .about("Write the ordering constraints between entries as a Graphviz graph")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("DOT_PATH")
+                .takes_value(true)
+                .help("Defaults to stdout")
+            )
+        )
+        .subcommand(SubCommand::with_name("export")
+            .about("Convert a log for other tools")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["blktrace", "fio", "fio-iolog", "sh", "parquet", "pb"])
+                .required(true)
+                .help("blktrace: binary events for blkparse, btt and iowatcher, fio-iolog: a read_iolog trace, fio: a job file replaying OUTPUT.iolog, sh: a dd and blkdiscard script taking the device as $1, parquet: a row of metadata per entry, pb: length-delimited Records of proto/log_entries.proto")
+            )
+            .arg(Arg::with_name("hash")
+                .long("hash")
+                .help("Add the xxh3 of each entry's data to parquet rows")
+            )
+            .arg(Arg::with_name("end-mark")
+                .long("end-mark")
+                .value_name("END_MARK")
+                .takes_value(true)
+                .help("Stop the sh script after this mark")
+            )
+            .arg(Arg::with_name("device")
+                .long("device")
+                .value_name("DEV_PATH")
+                .takes_value(true)
+                .help("Device the fio formats write to")
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("Defaults to stdout")
+            )
+        )
+        .subcommand(SubCommand::with_name("export-image")
+            .about("Write the device state as of a mark or entry to a disk image")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["qcow2"])
+                .default_value("qcow2")
+                .help("qcow2: only clusters the log wrote are allocated")
+            )
+            .arg(Arg::with_name("at-mark")
+                .long("at-mark")
+                .value_name("MARK")
+                .takes_value(true)
+                .required_unless("at-entry")
+                .conflicts_with("at-entry")
+                .help("Write the state right after this mark")
+            )
+            .arg(Arg::with_name("at-entry")
+                .long("at-entry")
+                .value_name("ENTRY")
+                .takes_value(true)
+                .help("Write the state right after this entry was written")
+            )
+            .arg(Arg::with_name("size")
+                .long("size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Virtual disk size, defaults to the highest written sector")
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("IMAGE_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("extract")
+            .about("Write a run of sectors as they were right after an entry, without replaying the log")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("at-entry")
+                .long("at-entry")
+                .value_name("ENTRY")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("sector")
+                .long("sector")
+                .value_name("SECTOR")
+                .takes_value(true)
+                .required(true)
+                .help("First sector, in the log's sector size")
+            )
+            .arg(Arg::with_name("count")
+                .long("count")
+                .value_name("SECTORS")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("OUTPUT_PATH")
+                .takes_value(true)
+                .required(true)
+                .help("Sectors no entry wrote, or that were discarded, are zeros")
+            )
+        )
+        .subcommand(SubCommand::with_name("import")
+            .about("Build a replayable log from another tool's trace")
+            .arg(Arg::with_name("input")
+                .long("input")
+                .value_name("TRACE_PATH")
+                .takes_value(true)
+                .required(true)
+                .help("For blktrace, the per-cpu files merged with blkparse -d")
+            )
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["blktrace"])
+                .required(true)
+            )
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+                .help("Log to create")
+            )
+            .arg(Arg::with_name("data")
+                .long("data")
+                .value_name("IMAGE_PATH")
+                .takes_value(true)
+                .help("Image of the traced device to take write data from, traces have none so it's zeros otherwise")
+            )
+            .arg(Arg::with_name("sector-size")
+                .long("sector-size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .default_value("512")
+            )
+        )
+        .subcommand(SubCommand::with_name("analyze")
+            .about("Inspect a log without replaying it")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("overlaps")
+                .about("Report writes to the same sectors that no FLUSH or FUA orders")
+                .arg(Arg::with_name("log")
+                    .long("log")
+                    .value_name("LOG_PATH")
+                    .takes_value(true)
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("watch")
+                .about("Report entries writing into sensitive sectors, such as superblocks")
+                .arg(Arg::with_name("log")
+                    .long("log")
+                    .value_name("LOG_PATH")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("sectors")
+                    .long("sectors")
+                    .value_name("RANGES")
+                    .takes_value(true)
+                    .required(true)
+                    .help("Comma separated FIRST-LAST or START+COUNT sector ranges")
+                )
+            )
+            .subcommand(SubCommand::with_name("amplification")
+                .about("Report how often sectors are rewritten and the write amplification")
+                .arg(Arg::with_name("log")
+                    .long("log")
+                    .value_name("LOG_PATH")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("top")
+                    .long("top")
+                    .value_name("N")
+                    .takes_value(true)
+                    .default_value("10")
+                    .help("How many of the most rewritten sector ranges to list")
+                )
+            )
+            .subcommand(SubCommand::with_name("allocation")
+                .about("Follow the space a thin provisioned device would allocate, peak, final and at each mark")
+                .arg(Arg::with_name("log")
+                    .long("log")
+                    .value_name("LOG_PATH")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("chunk-size")
+                    .long("chunk-size")
+                    .value_name("BYTES")
+                    .takes_value(true)
+                    .default_value("65536")
+                    .help("Allocation unit of the thin device, discards only free chunks they cover whole")
+                )
+            )
+            .subcommand(SubCommand::with_name("stats")
+                .about("Count the writes, flushes, discards and marks in a log")
+                .arg(Arg::with_name("log")
+                    .long("log")
+                    .value_name("LOG_PATH")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("only-metadata")
+                    .long("only-metadata")
+                    .conflicts_with("skip-metadata")
+                    .help("Only count writes tagged as filesystem metadata")
+                )
+                .arg(Arg::with_name("skip-metadata")
+                    .long("skip-metadata")
+                    .help("Don't count writes tagged as filesystem metadata")
+                )
+                .arg(Arg::with_name("by-mark")
+                    .long("by-mark")
+                    .help("Count each phase between marks separately")
+                )
+                .arg(Arg::with_name("journal")
+                    .long("journal")
+                    .value_name("JOURNAL")
+                    .takes_value(true)
+                    .help("Label writes into an ext4 or XFS journal: ext4:SECTOR+SECTORS[:BLOCK_SIZE], xfs:SECTOR+SECTORS, or auto to find it in the superblock the log writes")
+                )
+            )
+        )
+        .subcommand(SubCommand::with_name("plan")
+            .about("Write down what a replay would do, for review before apply")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("replay")
+                .long("replay")
+                .value_name("REPLAY_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("limit")
+                .long("limit")
+                .value_name("LIMIT")
+                .takes_value(true)
+                .default_value("0")
+            )
+            .arg(Arg::with_name("end-mark")
+                .long("end-mark")
+                .value_name("END_MARK")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("entries-file")
+                .long("entries-file")
+                .value_name("PATH")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .value_name("PLAN_PATH")
+                .takes_value(true)
+                .help("Defaults to stdout")
+            )
+        )
+        .subcommand(SubCommand::with_name("apply")
+            .about("Replay exactly what a plan lists")
+            .arg(Arg::with_name("plan")
+                .value_name("PLAN_PATH")
+                .required(true)
+            )
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Write to the block device even if it is mounted or in use")
+            )
+            .arg(Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Don't ask before overwriting a block device")
+            )
+        )
+        .subcommand(SubCommand::with_name("spool")
+            .about("Watch a directory for new logs and run a pipeline on each")
+            .arg(Arg::with_name("dir")
+                .long("dir")
+                .value_name("SPOOL_DIR")
+                .takes_value(true)
+                .required(true)
+                .help("Logs are *.log files here, they are moved to done/ or failed/ once processed")
+            )
+            .arg(Arg::with_name("step")
+                .long("step")
+                .value_name("COMMAND")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Shell command run per log, in order, with $LOG_WRITES_LOG and $LOG_WRITE (this binary) set")
+            )
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .default_value("10")
+            )
+        )
+        .subcommand(SubCommand::with_name("debug")
+            .about("Step through a replay interactively, type help for the commands")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("replay")
+                .long("replay")
+                .value_name("REPLAY_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Write to the block device even if it is mounted or in use")
+            )
+            .arg(Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Don't ask before overwriting a block device")
+            )
+        )
+        .subcommand(SubCommand::with_name("campaign")
+            .about("Check randomly picked crash states of the log, reproducible from the printed seed")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("replay")
+                .long("replay")
+                .value_name("REPLAY_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("fsck")
+                .long("fsck")
+                .value_name("FSCK_CMD")
+                .takes_value(true)
+                .required(true)
+                .help("Command checking each crash state, it must exit 0 on a consistent one. It may repair, the target is restored from a snapshot after every run")
+            )
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .takes_value(true)
+                .help("Seed of the first run, picked from the clock if not given")
+            )
+            .arg(Arg::with_name("runs")
+                .long("runs")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("100")
+            )
+            .arg(Arg::with_name("tails")
+                .long("tails")
+                .help("Also drop or tear entries written since the last FLUSH, as the ordering rules allow")
+            )
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Write to the block device even if it is mounted or in use")
+            )
+            .arg(Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Don't ask before overwriting a block device")
+            )
+        )
+        .subcommand(SubCommand::with_name("conformance")
+            .about("Replay the log with both log-write and the C replay-log and compare the images")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("replay-log")
+                .long("replay-log")
+                .value_name("PATH")
+                .takes_value(true)
+                .default_value("replay-log")
+                .help("The C replay-log from xfstests, the comparison is skipped if it can't be run")
+            )
+            .arg(Arg::with_name("dir")
+                .long("dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Where to write the two images, the temp dir by default")
+            )
+        )
+        .subcommand(SubCommand::with_name("checksum")
+            .about("Write a CRC of every entry to a sidecar, for replaying with --verify-crc once the log has been copied")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("emit")
+                .long("emit")
+                .value_name("SIDECAR_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("index")
+            .about("Write an index sidecar of entry offsets, LOG_PATH.idx is used to seek without reading every header")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("output")
+                .long("output")
+                .value_name("INDEX_PATH")
+                .takes_value(true)
+                .help("Where to write the index, LOG_PATH.idx by default")
+            )
+        )
+        .subcommand(SubCommand::with_name("selftest")
+            .about("Write a synthetic log, replay it into a file and check the result, to test this platform")
+            .arg(Arg::with_name("dir")
+                .long("dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Where to write the log and the image, the temp dir by default")
+            )
+            .arg(Arg::with_name("keep")
+                .long("keep")
+                .help("Keep the log and the image instead of removing them")
+            )
+            .arg(Arg::with_name("device")
+                .long("device")
+                .value_name("DEVICE")
+                .takes_value(true)
+                .help("Also check the size and sector size of this block device can be read, without writing to it")
+            )
+        )
+        .subcommand(SubCommand::with_name("step-back")
+            .about("Roll a replay target back using the undo log of its replay")
+            .arg(Arg::with_name("undo-log")
+                .long("undo-log")
+                .value_name("UNDO_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("replay")
+                .long("replay")
+                .value_name("REPLAY_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("count")
+                .value_name("N")
+                .default_value("1")
+                .help("Number of entries to undo")
+            )
+        )
+        .subcommand(SubCommand::with_name("receive")
+            .about("Apply a log streamed by --remote onto a local device")
+            .arg(Arg::with_name("replay")
+                .long("replay")
+                .value_name("REPLAY_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .takes_value(true)
+                .default_value("127.0.0.1:10810")
+                .help("Anyone who can connect writes to the target, give an address other hosts reach only on a trusted network")
+            )
+            .arg(Arg::with_name("stdio")
+                .long("stdio")
+                .help("Read the stream from stdin and reply on stdout, as run over ssh")
+            )
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Write to the block device even if it is mounted or in use")
+            )
+            .arg(Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Don't ask before overwriting a block device, replay --remote passes on its own --yes")
+            )
+        )
         .arg(Arg::with_name("log")
             .long("log")
             .value_name("LOG_PATH")
             .takes_value(true)
             .required(true)
+            .help("A file, or with the http feature an http(s):// or s3://bucket/key URL")
         )
         .arg(Arg::with_name("replay")
             .long("replay")
             .value_name("REPLAY_PATH")
             .takes_value(true)
-            .required(true)
+            .required_unless_one(&["remote", "find", "num-entries", "target-partition"])
+            .conflicts_with("remote")
+        )
+        .arg(Arg::with_name("truncate-target")
+            .long("truncate-target")
+            .conflicts_with_all(&["remote", "target-partition"])
+            .help("Start from an empty replay file, creating it if needed")
+        )
+        .arg(Arg::with_name("grow-target")
+            .long("grow-target")
+            .conflicts_with_all(&["remote", "target-partition"])
+            .help("Extend the replay file to the furthest byte the log writes or discards")
+        )
+        .arg(Arg::with_name("target-partition")
+            .long("target-partition")
+            .value_name("PARTITION")
+            .takes_value(true)
+            .conflicts_with_all(&["replay", "remote"])
+            .help("Replay a log of a whole disk into this partition of it, like /dev/sdb3, shifting entries by where it starts")
+        )
+        .arg(Arg::with_name("remote")
+            .long("remote")
+            .value_name("[USER@]HOST:/DEV|HOST:PORT")
+            .takes_value(true)
+            .help("Replay on another machine, over ssh or to a running receive --listen")
+        )
+        .arg(Arg::with_name("sector-size")
+            .long("sector-size")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("Sector size to use instead of the one in the log's superblock, for salvaged logs")
+        )
+        .arg(Arg::with_name("only-metadata")
+            .long("only-metadata")
+            .conflicts_with("skip-metadata")
+            .help("Only replay writes tagged as filesystem metadata")
+        )
+        .arg(Arg::with_name("skip-metadata")
+            .long("skip-metadata")
+            .help("Don't replay writes tagged as filesystem metadata")
+        )
+        .arg(Arg::with_name("tear-at")
+            .long("tear-at")
+            .value_name("ENTRY[:BYTES|:garbage]")
+            .takes_value(true)
+            .help("Replay up to ENTRY, then write only its first BYTES, half by default, or every other sector as garbage, and stop")
+        )
+        .arg(Arg::with_name("skip-zero-writes")
+            .long("skip-zero-writes")
+            .help("Don't write all-zero data to sectors not yet written, for targets that start zeroed, keeping files sparse")
+        )
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("Fail on unknown flags, odd entries or a newer log version instead of replaying them anyway")
+        )
+        .arg(Arg::with_name("metrics-listen")
+            .long("metrics-listen")
+            .value_name("ADDR")
+            .takes_value(true)
+            .help("Serve Prometheus metrics on http://ADDR/metrics while replaying")
+        )
+        .arg(Arg::with_name("watch-sectors")
+            .long("watch-sectors")
+            .value_name("RANGES")
+            .takes_value(true)
+            .help("Warn about entries writing into these FIRST-LAST or START+COUNT sector ranges")
+        )
+        .arg(Arg::with_name("exclude-sectors")
+            .long("exclude-sectors")
+            .value_name("RANGES_FILE")
+            .takes_value(true)
+            .help("Leave the target's sectors in this file's ranges alone, e.g. a partition table, trimming writes reaching into them")
+        )
+        .arg(Arg::with_name("stop-on-watch")
+            .long("stop-on-watch")
+            .requires("watch-sectors")
+            .help("Stop right before the first entry writing into a watched range")
+        )
+        .arg(Arg::with_name("force")
+            .long("force")
+            .help("Write to the block device even if it is mounted or in use")
+        )
+        .arg(Arg::with_name("yes")
+            .long("yes")
+            .short("y")
+            .help("Don't ask before overwriting a block device")
+        )
+        .arg(Arg::with_name("retry")
+            .long("retry")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("0")
+            .help("Retry writes to the replay target failing with EIO, ENOSPC or EAGAIN up to N times")
+        )
+        .arg(Arg::with_name("retry-delay")
+            .long("retry-delay")
+            .value_name("MS")
+            .takes_value(true)
+            .default_value("100")
+            .help("Delay before the first retry, doubled for every following one")
+        )
+        .arg(Arg::with_name("max-memory")
+            .long("max-memory")
+            .value_name("MB")
+            .takes_value(true)
+            .help("Copy entries and zeroed ranges bigger than this in chunks rather than holding them in memory whole")
+        )
+        .arg(Arg::with_name("io-class")
+            .long("io-class")
+            .value_name("CLASS")
+            .takes_value(true)
+            .help("Replay with this io priority, idle or best-effort:PRIO from 0 to 7, as ionice would")
+        )
+        .arg(Arg::with_name("nowait")
+            .long("nowait")
+            .help("Write with RWF_NOWAIT, backing off while the target is busy so other users of it go first")
+        )
+        .arg(Arg::with_name("keep-going")
+            .long("keep-going")
+            .short("k")
+            .conflicts_with("remote")
+            .help("Record entries that fail to replay and carry on with the next one")
+        )
+        .arg(Arg::with_name("failure-report")
+            .long("failure-report")
+            .value_name("PATH")
+            .takes_value(true)
+            .default_value("replay-failures.jsonl")
+            .help("Where --keep-going writes a JSON line per failed entry")
+        )
+        .arg(Arg::with_name("follow")
+            .long("follow")
+            .short("f")
+            .conflicts_with_all(&["remote", "entries-file"])
+            .help("Keep replaying entries as they are appended to a log still being captured")
+        )
+        .arg(Arg::with_name("journal")
+            .long("journal")
+            .value_name("JOURNAL_PATH")
+            .takes_value(true)
+            .conflicts_with_all(&["remote", "entries-file"])
+            .help("Record progress at every barrier, and resume from it if a previous replay was interrupted")
+        )
+        .arg(Arg::with_name("undo-log")
+            .long("undo-log")
+            .value_name("UNDO_PATH")
+            .takes_value(true)
+            .conflicts_with("remote")
+            .help("Save what every entry overwrites, for step-back")
+        )
+        .arg(Arg::with_name("salvage")
+            .long("salvage")
+            .value_name("TRIMMED_PATH")
+            .takes_value(true)
+            .conflicts_with_all(&["remote", "follow"])
+            .help("If the log ends mid-entry, write its complete entries to this log and replay that")
+        )
+        .arg(Arg::with_name("verify-crc")
+            .long("verify-crc")
+            .value_name("SIDECAR_PATH")
+            .takes_value(true)
+            .conflicts_with_all(&["remote", "follow"])
+            .help("Check every entry against the CRCs checksum --emit wrote before replaying it")
+        )
+        .arg(Arg::with_name("entries-file")
+            .long("entries-file")
+            .value_name("LIST_PATH")
+            .takes_value(true)
+            .help("Replay only these entries, in this order: indices or first-last ranges")
+        )
+        .arg(Arg::with_name("hook")
+            .long("hook")
+            .value_name("COMMAND")
+            .takes_value(true)
+            .help("Run COMMAND with sh for selected entries, with their metadata in LOG_WRITES_* variables")
+        )
+        .arg(Arg::with_name("hook-on")
+            .long("hook-on")
+            .value_name("entry|mark|barrier")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true)
+            .help("Entries to run the hook for, defaults to mark")
+        )
+        .arg(Arg::with_name("hook-payload")
+            .long("hook-payload")
+            .help("Pass the entry's data to the hook on stdin")
+        )
+        .arg(Arg::with_name("hook-batch")
+            .long("hook-batch")
+            .help("Start the hook once and write a JSON line per entry to its stdin, with --hook-payload the data goes in it as base64")
+        )
+        .arg(Arg::with_name("remote-command")
+            .long("remote-command")
+            .value_name("COMMAND")
+            .takes_value(true)
+            .default_value("log-write")
+            .help("log-write binary to run on an ssh remote")
         )
         .arg(Arg::with_name("limit")
             .long("limit")
@@ -47,35 +1994,961 @@ fn main() -> Result<()>{
             .takes_value(true)
             .default_value("0")
         )
+        .arg(Arg::with_name("limit-bytes")
+            .long("limit-bytes")
+            .value_name("BYTES")
+            .takes_value(true)
+            .default_value("0")
+            .help("Stop once the replayed entries wrote this many bytes, exiting with 6")
+        )
+        .arg(Arg::with_name("limit-seconds")
+            .long("limit-seconds")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .default_value("0")
+            .help("Stop once the replay ran this long, exiting with 6")
+        )
         .arg( Arg::with_name("start-mark")
             .long("start-mark")
             .value_name("START_MARK")
             .takes_value(true)
+            .help("Start replaying after the first mark matching this, a name, a glob like fsync-*, re:REGEX or name:NAME taken literally")
+        )
+        .arg( Arg::with_name("start-entry")
+            .long("start-entry")
+            .value_name("ENTRY")
+            .takes_value(true)
+            .conflicts_with("start-mark")
+            .help("Start replaying at this entry")
         )
         .arg( Arg::with_name("end-mark")
             .long("end-mark")
             .value_name("END_MARK")
             .takes_value(true)
-        ).get_matches();
+            .help("Stop after the first mark matching this, a name, a glob like fsync-*, re:REGEX or name:NAME taken literally, otherwise the whole log is replayed")
+        )
+        .arg( Arg::with_name("until")
+            .long("until")
+            .value_name("EXPR")
+            .takes_value(true)
+            .help("Stop after the first entry where EXPR holds, e.g. 'mark==fsync-* || entry==5000 || flush_count==10', \
+                   names are entry, entries, sector, mark, flush_count, fua_count, discard_count and mark_count")
+        )
+        .arg( Arg::with_name("stop-flags")
+            .long("stop-flags")
+            .value_name("FLAGS")
+            .takes_value(true)
+            .help("Stop after the first entry with any of these flags, e.g. FLUSH,FUA,MARK, with --end-mark only its mark stops at MARK")
+        )
+        // The flags below keep the spelling of replay-log's, so xfstests
+        // helpers can run this binary unchanged
+        .arg( Arg::with_name("next-flush")
+            .long("next-flush")
+            .help("Stop after the next FLUSH")
+        )
+        .arg( Arg::with_name("next-fua")
+            .long("next-fua")
+            .help("Stop after the next FUA")
+        )
+        .arg( Arg::with_name("find")
+            .long("find")
+            .conflicts_with_all(&["replay", "remote"])
+            .help("Don't replay, print ENTRY@SECTOR of where the replay would stop")
+        )
+        .arg( Arg::with_name("num-entries")
+            .long("num-entries")
+            .help("Print the number of entries in the log")
+        )
+        .arg( Arg::with_name("no-discard")
+            .long("no-discard")
+            .help("Don't replay discards")
+        )
+        .arg( Arg::with_name("verbose")
+            .long("verbose")
+            .short("v")
+            .multiple(true)
+            .help("Log more, -v for debug and -vv for trace, unless RUST_LOG is set")
+        )
+        .arg( Arg::with_name("log-output")
+            .long("log-output")
+            .value_name("JSONL_PATH")
+            .takes_value(true)
+            .help("Append every entry and event to this file as JSON lines, the console only shows warnings")
+        )
+        .arg( Arg::with_name("config")
+            .long("config")
+            .value_name("CONFIG_PATH")
+            .takes_value(true)
+            .help("TOML file of replay flag defaults, instead of ~/.config/log-write-rs.toml")
+        );
+    // Capturing with dm-log-writes and checking the target need Linux, or at
+    // least a unix with mount and fsck
+    #[cfg(unix)]
+    let app = app
+        .subcommand(SubCommand::with_name("fstests")
+            .about("xfstests log-writes recipes, reading LOGWRITES_DEV, SCRATCH_DEV, FSTYP and MOUNT_OPTIONS")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .help("Log to replay instead of LOGWRITES_DEV")
+            )
+            .arg(Arg::with_name("scratch")
+                .long("scratch")
+                .value_name("DEV")
+                .takes_value(true)
+                .help("Replay target instead of SCRATCH_DEV")
+            )
+            .subcommand(SubCommand::with_name("replay")
+                .about("Replay up to and including MARK")
+                .arg(Arg::with_name("mark")
+                    .value_name("MARK")
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("check")
+                .about("Replay up to MARK and fsck the result, the fsck picked from FSTYP")
+                .arg(Arg::with_name("mark")
+                    .value_name("MARK")
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("mount-check")
+                .about("Replay up to MARK and mount the result with MOUNT_OPTIONS")
+                .arg(Arg::with_name("mark")
+                    .value_name("MARK")
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("check-fua")
+                .about("Replay the whole log with an fsck after every FUA")
+            )
+            .subcommand(SubCommand::with_name("check-marks")
+                .about("Replay the whole log with an fsck after every mark")
+            )
+        )
+        .subcommand(SubCommand::with_name("record")
+            .about("Capture a log of the writes a command makes, using dm-log-writes")
+            .arg(Arg::with_name("dev")
+                .long("dev")
+                .value_name("DEV_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("name")
+                .long("name")
+                .value_name("DM_NAME")
+                .takes_value(true)
+                .default_value("log-write")
+            )
+            .arg(Arg::with_name("start-mark")
+                .long("start-mark")
+                .value_name("START_MARK")
+                .takes_value(true)
+                .default_value("start")
+            )
+            .arg(Arg::with_name("end-mark")
+                .long("end-mark")
+                .value_name("END_MARK")
+                .takes_value(true)
+                .default_value("end")
+            )
+            .arg(Arg::with_name("seal-to")
+                .long("seal-to")
+                .value_name("AGE_PUBLIC_KEY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("sealed-output")
+                .help("Seal the log for this recipient once it's recorded, the log device itself is left as is")
+            )
+            .arg(Arg::with_name("sealed-output")
+                .long("sealed-output")
+                .value_name("SEALED_PATH")
+                .takes_value(true)
+                .requires("seal-to")
+                .help("Where to write the sealed log, needed with --seal-to")
+            )
+            .arg(Arg::with_name("command")
+                .value_name("COMMAND")
+                .multiple(true)
+                .required(true)
+                .last(true)
+                .help("Command to run, without a shell, the logged device is passed in $LOG_WRITES_DEV")
+            )
+        )
+        .subcommand(SubCommand::with_name("mark")
+            .about("Insert a mark into a running dm-log-writes device")
+            .arg(Arg::with_name("name")
+                .value_name("DM_NAME")
+                .required(true)
+            )
+            .arg(Arg::with_name("mark")
+                .value_name("MARK")
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("bench")
+            .about("Replay the log's writes with each backend and compare MB/s, syscalls and CPU")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("target")
+                .long("target")
+                .value_name("PATH")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Write to this file or device, overwriting it, instead of /dev/null, repeat for several")
+            )
+            .arg(Arg::with_name("backends")
+                .long("backends")
+                .value_name("BACKENDS")
+                .takes_value(true)
+                .help("Only these of sync, pipelined, io_uring and direct, comma separated")
+            )
+            .arg(Arg::with_name("depth")
+                .long("depth")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("32")
+                .help("Writes queued by the pipelined and io_uring backends")
+            )
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Write to the block device even if it is mounted or in use")
+            )
+            .arg(Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Don't ask before overwriting a block device")
+            )
+        )
+        .arg(Arg::with_name("notify-url")
+            .long("notify-url")
+            .value_name("URL")
+            .takes_value(true)
+            .help("POST a JSON summary here on the first failed check or when the replay completes")
+        )
+        .arg( Arg::with_name("check")
+            .long("check")
+            .value_name("NUMBER|flush|fua")
+            .takes_value(true)
+            .help("Run the fsck command every NUMBER entries or at every flush/fua")
+        )
+        .arg( Arg::with_name("fsck")
+            .long("fsck")
+            .value_name("FSCK_CMD")
+            .takes_value(true)
+            .help("Check command to run, or 'auto' to pick one for the filesystem on the replay target")
+        )
+        .arg( Arg::with_name("check-chroot")
+            .long("check-chroot")
+            .value_name("ROOT_DIR")
+            .takes_value(true)
+            .conflicts_with("check-container")
+            .help("Run the fsck command inside a chroot with the replay target bind mounted")
+        )
+        .arg( Arg::with_name("check-container")
+            .long("check-container")
+            .value_name("IMAGE")
+            .takes_value(true)
+            .help("Run the fsck command inside a container with the replay target mapped in")
+        )
+        .arg( Arg::with_name("container-runtime")
+            .long("container-runtime")
+            .value_name("RUNTIME")
+            .takes_value(true)
+            .default_value("docker")
+        )
+        .arg( Arg::with_name("mount-check")
+            .long("mount-check")
+            .conflicts_with_all(&["check-chroot", "check-container"])
+            .help("Mount the replay target read-only and pass the mountpoint to the fsck command")
+        )
+        .arg( Arg::with_name("mount-type")
+            .long("mount-type")
+            .value_name("FSTYPE")
+            .takes_value(true)
+        )
+        .arg( Arg::with_name("mount-options")
+            .long("mount-options")
+            .value_name("OPTIONS")
+            .takes_value(true)
+        )
+        .arg( Arg::with_name("results-db")
+            .long("results-db")
+            .value_name("DB_PATH")
+            .takes_value(true)
+            .help("Record checkpoint results into an SQLite database")
+        )
+        .arg( Arg::with_name("resume")
+            .long("resume")
+            .help("Continue the last run in the results database, skipping passed checkpoints")
+        )
+        .arg( Arg::with_name("hash-device")
+            .long("hash-device")
+            .help("Store a hash of the replay device with every checkpoint result")
+        );
+    #[cfg(feature = "ublk")]
+    let app = app
+        .subcommand(SubCommand::with_name("record-ublk")
+            .about("Capture a log by exposing a backing file as a ublk device and recording its writes")
+            .arg(Arg::with_name("backing")
+                .long("backing")
+                .value_name("BACKING_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("sector-size")
+                .long("sector-size")
+                .value_name("SECTOR_SIZE")
+                .takes_value(true)
+                .default_value("512")
+            )
+            .arg(Arg::with_name("start-mark")
+                .long("start-mark")
+                .value_name("START_MARK")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("end-mark")
+                .long("end-mark")
+                .value_name("END_MARK")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("seal-to")
+                .long("seal-to")
+                .value_name("AGE_PUBLIC_KEY")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Seal the log for this recipient once it's recorded, and remove the plaintext log file")
+            )
+            .arg(Arg::with_name("sealed-output")
+                .long("sealed-output")
+                .value_name("SEALED_PATH")
+                .takes_value(true)
+                .requires("seal-to")
+                .help("Where to write the sealed log, LOG_PATH.age by default")
+            )
+        )
+        .subcommand(SubCommand::with_name("serve-ublk")
+            .about("Expose the device state as of an entry as a read-only ublk device")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("at-entry")
+                .long("at-entry")
+                .value_name("ENTRY")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("base")
+                .long("base")
+                .value_name("BASE_PATH")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("size")
+                .long("size")
+                .value_name("BYTES")
+                .takes_value(true)
+            )
+        );
+    #[cfg(feature = "fuse")]
+    let app = app
+        .subcommand(SubCommand::with_name("mount-states")
+            .about("Mount a read-only directory with an image file for every mark in the log")
+            .arg(Arg::with_name("log")
+                .long("log")
+                .value_name("LOG_PATH")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("mountpoint")
+                .value_name("MOUNTPOINT")
+                .required(true)
+            )
+            .arg(Arg::with_name("check")
+                .long("check")
+                .value_name("NUMBER|flush|fua")
+                .takes_value(true)
+                .help("Also add an image for every checkpoint")
+            )
+            .arg(Arg::with_name("base")
+                .long("base")
+                .value_name("BASE_PATH")
+                .takes_value(true)
+                .help("Image providing the contents of sectors the log never wrote")
+            )
+            .arg(Arg::with_name("size")
+                .long("size")
+                .value_name("BYTES")
+                .takes_value(true)
+                .help("Image size, defaults to the base image size or the highest written sector")
+            )
+        );
+    #[cfg(feature = "lua")]
+    let app = app
+        .arg(Arg::with_name("plugin")
+            .long("plugin")
+            .value_name("SCRIPT.lua")
+            .takes_value(true)
+            .help("Lua script with on_entry, on_mark and should_stop callbacks run for every entry")
+        );
+    #[cfg(feature = "grpc")]
+    let app = app
+        .subcommand(SubCommand::with_name("daemon")
+            .about("Serve a gRPC API for loading logs, replaying, snapshotting and verifying")
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .value_name("ADDR")
+                .takes_value(true)
+                .default_value("127.0.0.1:50051")
+            )
+            .arg(Arg::with_name("metrics-listen")
+                .long("metrics-listen")
+                .value_name("ADDR")
+                .takes_value(true)
+                .help("Serve Prometheus metrics on http://ADDR/metrics")
+            )
+        );
+    let matches = app.get_matches();
 
+    // RUST_LOG picks the verbosity, e.g. RUST_LOG=warn to only see problems,
+    // otherwise -v and -vv raise it like they do for replay-log
+    let level = match matches.occurrences_of("verbose") {
+        0 => "info",
+        1 => "debug",
+        _ => "trace"
+    };
+    // With --log-output every event goes to the file as a JSON line, and the
+    // console only shows warnings unless asked for more
+    let log_output = match matches.value_of("log-output") {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|error| anyhow!("Error opening {}: {}", path, error))?),
+        None => None
+    };
+    let console_level = if log_output.is_some() && matches.occurrences_of("verbose") == 0 { "warn" } else { level };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer()
+            .event_format(human::Console::new(human::stderr_color()))
+            .with_writer(std::io::stderr)
+            .with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(console_level))))
+        .with(log_output.map(|file| tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(std::sync::Mutex::new(file))
+            .with_filter(EnvFilter::new(level))))
+        .init();
+
+    #[cfg(feature = "ublk")]
+    {
+        if let Some(matches) = matches.subcommand_matches("record-ublk") {
+            return record_ublk(matches);
+        }
+        if let Some(matches) = matches.subcommand_matches("serve-ublk") {
+            return serve_ublk(matches);
+        }
+    }
+    #[cfg(feature = "fuse")]
+    if let Some(matches) = matches.subcommand_matches("mount-states") {
+        return mount_states(matches);
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(matches) = matches.subcommand_matches("daemon") {
+        return run_daemon(matches);
+    }
+    #[cfg(unix)]
+    if let Some(matches) = matches.subcommand_matches("record") {
+        return record(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("record-nbd") {
+        return record_nbd(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("serve-nbd") {
+        return serve_nbd(matches);
+    }
+    #[cfg(unix)]
+    if let Some(matches) = matches.subcommand_matches("mark") {
+        return mark(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("seal") {
+        return seal(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("chain") {
+        return chain(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("dump") {
+        return dump(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("info") {
+        return info(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        return verify(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("count") {
+        return count(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("locate") {
+        return locate(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("graph") {
+        return graph(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("export") {
+        return export(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("export-image") {
+        return export_image(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("extract") {
+        return extract(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("import") {
+        return import(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("analyze") {
+        return analyze(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("plan") {
+        return plan(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("apply") {
+        return apply(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("spool") {
+        return spool(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("debug") {
+        return debug(matches);
+    }
+    #[cfg(unix)]
+    if let Some(matches) = matches.subcommand_matches("bench") {
+        return bench(matches);
+    }
+    #[cfg(unix)]
+    if let Some(matches) = matches.subcommand_matches("fstests") {
+        return run_fstests(matches);
+    }
+    #[cfg(unix)]
+    if let Some(matches) = matches.subcommand_matches("campaign") {
+        return campaign(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("conformance") {
+        return conformance(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("checksum") {
+        return checksum(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("index") {
+        return index(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("selftest") {
+        return selftest(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("step-back") {
+        return step_back(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("receive") {
+        return receive(matches);
+    }
+
+    let config = config::Config::load(matches.value_of("config"))?;
+    let flags = config::Flags::new(&matches, &config);
+    if let Some(metrics_listen) = flags.value_of("metrics-listen") {
+        metrics::spawn_server(metrics_listen)?;
+    }
+    if let Some(target) = matches.value_of("remote") {
+        let run_limit : u64 = matches.value_of("limit").unwrap().parse()?;
+        return replay_remote(&matches, target, run_limit);
+    }
     let log_file_path = matches.value_of("log").expect("Log file not provided");
-    let replay_file_path = matches.value_of("replay").expect("Replay file not provided");
+    if matches.is_present("num-entries") {
+        println!("{}", log_reader::LogReader::open(log_file_path)?.nr_entries());
+        return Ok(());
+    }
+    if matches.is_present("find") {
+        return find(&matches);
+    }
+    let log_file_path = match matches.value_of("salvage") {
+        Some(trimmed_path) => salvage(&matches, log_file_path, trimmed_path)?,
+        None => log_file_path
+    };
+    let replay_file_path = matches.value_of("replay").or(matches.value_of("target-partition")).expect("Replay file not provided");
     let limit = matches.value_of("limit").expect("Log file not provided");
     let run_limit : u64 = limit.parse()?;
-    let start_mark = matches.value_of("start-mark");
-    let end_mark = matches.value_of("end-mark").unwrap();
-    let mut stop_flags : u64 = 0;
-    stop_flags |= log_writes::LOG_MARK_FLAG;
+    let end_mark : Option<entries::MarkPattern> = matches.value_of("end-mark").map(str::parse).transpose()?;
+    let stop_flags = stop_flags(&matches)?;
+    let mut until = matches.value_of("until").map(until::Until::parse).transpose()?;
+    let limit_bytes : u64 = matches.value_of("limit-bytes").unwrap().parse()?;
+    let limit_seconds : u64 = matches.value_of("limit-seconds").unwrap().parse()?;
     let mut num_entries : u64 = 0;
+    let mut num_bytes : u64 = 0;
+    // What clap's requires would check, had it seen the config
+    for name in ["hook-on", "hook-payload", "hook-batch"] {
+        flags.require(name, "hook")?;
+    }
+    #[cfg(unix)]
+    {
+        for (name, needs) in [("check", "fsck"), ("check-chroot", "fsck"), ("check-container", "fsck"), ("mount-check", "fsck"),
+                              ("mount-type", "mount-check"), ("mount-options", "mount-check"),
+                              ("resume", "results-db"), ("hash-device", "results-db")] {
+            flags.require(name, needs)?;
+        }
+        // A default fsck or results-db only applies once --check is given
+        for name in ["fsck", "results-db"] {
+            if matches.is_present(name) && !flags.is_present("check") {
+                bail!("--{} needs --check, on the command line or in the config", name)
+            }
+        }
+    }
+    #[cfg(unix)]
+    let checker = checker(&flags, replay_file_path)?;
+    #[cfg(unix)]
+    let hash_device = flags.is_present("hash-device");
+    #[cfg(unix)]
+    let results = match flags.value_of("results-db") {
+        Some(db_path) => Some(ResultsStore::open(db_path, log_file_path, flags.is_present("resume"))?),
+        None => None
+    };
+
+    #[cfg(unix)]
+    let notifier = flags.value_of("notify-url").map(|url| Notifier::new(url, log_file_path, replay_file_path));
+
+    let mut hook = match flags.value_of("hook") {
+        Some(cmd) => {
+            let points = match matches.values_of("hook-on") {
+                Some(points) => points.map(|point| point.parse::<HookPoint>()).collect::<Result<Vec<_>>>()?,
+                None => vec![HookPoint::Mark]
+            };
+            Some(Hook::new(cmd, points, flags.is_present("hook-payload"), flags.is_present("hook-batch"), replay_file_path)?)
+        }
+        None => None
+    };
+
+    #[cfg(feature = "lua")]
+    let plugin = match matches.value_of("plugin") {
+        Some(path) => Some(plugin::Plugin::load(path)?),
+        None => None
+    };
 
-    let mut log = Log::open(log_file_path, replay_file_path)?;
+    let sector_size : Option<u32> = matches.value_of("sector-size").map(str::parse).transpose()?;
+    let open_reader = || -> Result<log_reader::LogReader> {
+        let mut reader = log_reader::LogReader::open(log_file_path)?;
+        if let Some(sector_size) = sector_size {
+            reader.override_sector_size(sector_size)?;
+        }
+        Ok(reader)
+    };
 
-    while let Some(entry) = log.replay_next_entry(true).unwrap() {
+    let mut order = match matches.value_of("entries-file") {
+        Some(path) => {
+            let order = entries::read_entries_file(path)?;
+            let offsets = entries::entry_offsets(open_reader()?)?;
+            if let Some(index) = order.iter().find(|index| **index >= offsets.len() as u64) {
+                bail!("Entry {} is past the end of the log ({} entries)", index, offsets.len())
+            }
+            Some(order.into_iter().map(move |index| (index, offsets[index as usize])))
+        }
+        None => None
+    };
+
+    let regions = match matches.value_of("watch-sectors") {
+        Some(regions) => watch::parse_regions(regions)?,
+        None => Vec::new()
+    };
+    let excluded = match matches.value_of("exclude-sectors") {
+        Some(path) => watch::read_regions_file(path)?,
+        None => Vec::new()
+    };
+    // Found up front so the replay can stop before the write lands
+    let tear_at : Option<log_writes::TearAt> = matches.value_of("tear-at").map(str::parse).transpose()?;
+    let stop_before : std::collections::HashSet<u64> = if matches.is_present("stop-on-watch") {
+        let mut reader = open_reader()?;
+        watch::find_hits(&mut reader, &regions)?.into_iter().map(|(index, _)| index).collect()
+    } else {
+        std::collections::HashSet::new()
+    };
+
+    let sector_offset = match matches.value_of("target-partition") {
+        Some(device) => {
+            let mut reader = open_reader()?;
+            let (start, end) = partition::Partition::of(std::path::Path::new(device))?.sectors(reader.sector_size())?;
+            partition::check_fits(&mut reader, (start, end))?;
+            tracing::info!("replaying into {}, sectors {}-{} of its disk", device, start, end - 1);
+            start
+        }
+        None => 0
+    };
+
+    if matches.is_present("truncate-target") {
+        target::truncate(std::path::Path::new(replay_file_path))?;
+    }
+    if matches.is_present("grow-target") {
+        target::grow(std::path::Path::new(replay_file_path), target::max_end(&mut open_reader()?)?)?;
+    }
+
+    // Only ever from the command line, a config can't skip the target checks
+    safety::check_target(replay_file_path, matches.is_present("force"), matches.is_present("yes"))?;
+    let mut builder = Log::builder().log_path(log_file_path).replay_path(replay_file_path)
+        .strict(flags.is_present("strict"))
+        .metadata_filter(metadata_filter(&matches))
+        .skip_zero_writes(flags.is_present("skip-zero-writes"))
+        .exclude_sectors(excluded.iter().map(|region| (region.start, region.end)).collect())
+        .sector_offset(sector_offset)
+        .ignore_discards(flags.is_present("no-discard"));
+    if let Some(sector_size) = sector_size {
+        builder = builder.sector_size_override(sector_size);
+    }
+    if let Some(max_memory) = flags.value_of("max-memory") {
+        let max_memory : usize = max_memory.parse()?;
+        builder = builder.max_memory(max_memory.checked_mul(1024 * 1024).ok_or_else(|| anyhow!("--max-memory {} is too large", max_memory))?);
+    }
+    let mut log = builder.open()?;
+    if let Some((index, offset)) = start_position(&matches, &mut open_reader()?)? {
+        log.seek_to_entry(index, offset)?;
+    }
+    safety::check_sector_size(replay_file_path, log.sector_size)?;
+    log.retry = retry::RetryPolicy::new(flags.value_of("retry").unwrap().parse()?,
+        std::time::Duration::from_millis(flags.value_of("retry-delay").unwrap().parse()?));
+    let io_class : Option<sys::IoClass> = flags.value_of("io-class").map(str::parse).transpose()?;
+    if let Some(io_class) = io_class {
+        sys::set_io_class(io_class)?;
+    }
+    let nowait = flags.is_present("nowait");
+    if nowait {
+        log.nowait = Some(std::time::Duration::from_millis(1));
+    }
+    if let Some(undo_path) = matches.value_of("undo-log") {
+        let undo = undo::UndoLog::create(undo_path, replay_file_path)?;
+        log.undo = Some(match log.max_memory() {
+            Some(bytes) => undo.max_memory(bytes),
+            None => undo,
+        });
+    }
+    let mut journal = match matches.value_of("journal") {
+        Some(path) => {
+            let (journal, last) = journal::Journal::open(path, log_file_path, replay_file_path, log.sector_size, log.max_memory())?;
+            match last {
+                Some(last) if last.done => {
+                    println!("{} says this replay already completed", path);
+                    return Ok(());
+                }
+                // Stopped after the last entry, before noting it was done
+                Some(last) if last.entry + 1 >= log.nr_entries => {
+                    println!("{} says every entry was replayed, this replay already completed", path);
+                    return Ok(());
+                }
+                Some(last) => log.seek_to_entry(last.entry + 1, last.next_offset)?,
+                None => {}
+            }
+            Some(journal)
+        }
+        None => None
+    };
+    let mut failures = if flags.is_present("keep-going") {
+        Some(failures::FailureReport::create(flags.value_of("failure-report").unwrap())?)
+    } else {
+        None
+    };
+    let mut crcs = match matches.value_of("verify-crc") {
+        Some(path) => Some(sidecar::CrcVerifier::open(path, open_reader()?)?),
+        None => None
+    };
+    let follower = if matches.is_present("follow") {
+        Some(follow::Follower::new(log_file_path)?)
+    } else {
+        None
+    };
+    #[cfg(unix)]
+    let mut last_mark : Option<String> = None;
+    #[cfg(unix)]
+    let mut num_checkpoints : u64 = 0;
+    catch_interrupts()?;
+    let mut interrupted = false;
+    let mut limit_reached : Option<String> = None;
+    // Only a replay that ran out of entries is done, other stops can be resumed
+    let mut end_of_log = false;
+    let started = std::time::Instant::now();
+
+    loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            interrupted = true;
+            break
+        }
+        if let Some(order) = &mut order {
+            match order.next() {
+                Some((index, offset)) => log.seek_to_entry(index, offset)?,
+                None => break
+            }
+        }
+        if let Some(tear_at) = &tear_at {
+            if log.cur_entry == tear_at.entry {
+                #[cfg_attr(not(unix), allow(unused_variables))]
+                let entry = log.tear_next_entry(tear_at.tear)?;
+                #[cfg(unix)]
+                if let Some(checker) = &checker {
+                    if let Some(outcome) = run_checkpoint(&log, &entry, checker, results.as_ref(), hash_device)? {
+                        if outcome.exit_code != 0 {
+                            return Err(ErrorKind::CheckFailed.wrap(anyhow!("Fsck errored out after tearing entry {}", tear_at.entry)))
+                        }
+                    }
+                }
+                break
+            }
+        }
+        if stop_before.contains(&log.cur_entry) {
+            tracing::warn!("stopping before entry {}, it writes into watched sectors", log.cur_entry);
+            break
+        }
+        let index = log.cur_entry;
+        let offset = log.log_file.position()?;
+        let verified = match &mut crcs {
+            Some(crcs) => crcs.check(index),
+            None => Ok(())
+        };
+        let entry = match verified.and_then(|()| log.replay_next_entry(true)) {
+            Ok(Some(entry)) => entry,
+            Err(error) => match &mut failures {
+                Some(failures) => {
+                    match failures.record(&mut log, index, offset, &error)? {
+                        Some(next) => log.seek_to_entry(index + 1, next)?,
+                        None => break
+                    }
+                    continue
+                }
+                None => return Err(error)
+            },
+            Ok(None) => match &follower {
+                Some(follower) => {
+                    follower.wait_for_entry(&mut log)?;
+                    continue
+                }
+                None => {
+                    end_of_log = true;
+                    break
+                }
+            }
+        };
         num_entries += 1;
-        if (run_limit > 0 && num_entries == run_limit)  || should_stop(&entry,stop_flags,end_mark.as_ref()) > 0 {
+        if let Some(region) = watch::hit(&entry, &regions) {
+            tracing::warn!("entry {} writes into watched sectors {}", log.cur_entry - 1, region);
+        }
+        metrics::record_entry(&entry, log.sector_size, log.cur_entry);
+        if let Some(journal) = &mut journal {
+            journal.note(&log, &entry)?;
+        }
+        #[cfg(unix)]
+        if (entry.flags & log_writes::LOG_MARK_FLAG) > 0 {
+            last_mark = Some(entry.cmd.clone());
+        }
+        if let Some(hook) = &mut hook {
+            hook.run(&log, &entry)?;
+        }
+        #[cfg(feature = "lua")]
+        let plugin_stop = match &plugin {
+            Some(plugin) => plugin.run(&log, &entry)?,
+            None => false
+        };
+        #[cfg(not(feature = "lua"))]
+        let plugin_stop = false;
+        #[cfg(unix)]
+        if let Some(checker) = &checker {
+            if checker.mode.is_checkpoint(&entry, num_entries) {
+                if let Some(outcome) = run_checkpoint(&log, &entry, checker, results.as_ref(), hash_device)? {
+                    num_checkpoints += 1;
+                    if outcome.exit_code != 0 {
+                        if let Some(notifier) = &notifier {
+                            notifier.failed(log.cur_entry - 1, last_mark.as_deref(), &outcome, num_entries, num_checkpoints);
+                        }
+                        return Err(ErrorKind::CheckFailed.wrap(anyhow!("Fsck errored out on entry {}", log.cur_entry - 1)))
+                    }
+                }
+            }
+        }
+        let until_stop = until.as_mut().is_some_and(|until| until.stop(&entry, index));
+        if plugin_stop || until_stop || (run_limit > 0 && num_entries == run_limit)  || should_stop(&entry,stop_flags,end_mark.as_ref()) > 0 {
+            break
+        }
+        // Discards and marks write no data
+        num_bytes = num_bytes.saturating_add(entry.data_size(log.sector_size));
+        if limit_bytes > 0 && num_bytes >= limit_bytes {
+            limit_reached = Some(format!("--limit-bytes {} reached with {} bytes", limit_bytes, num_bytes));
+            break
+        }
+        if limit_seconds > 0 && started.elapsed().as_secs() >= limit_seconds {
+            limit_reached = Some(format!("--limit-seconds {} reached", limit_seconds));
             break
         }
     }
 
+    if interrupted {
+        if let Some(journal) = &mut journal {
+            journal.stop(&log)?;
+        }
+        let resume = if journal.is_some() { ", run it again to resume" } else { "" };
+        return Err(ErrorKind::Interrupted.wrap(anyhow!("Interrupted before entry {}{}", log.cur_entry, resume)))
+    }
+    if let Some(reason) = limit_reached {
+        if let Some(journal) = &mut journal {
+            journal.stop(&log)?;
+        }
+        println!("replayed {} entries onto {}, stopped before entry {}: {}", num_entries, replay_file_path, log.cur_entry, reason);
+        return Err(ErrorKind::LimitReached.wrap(anyhow!("Stopped before entry {}, {}", log.cur_entry, reason)))
+    }
+    if let Some(journal) = &mut journal {
+        match end_of_log {
+            true => journal.finish(&log)?,
+            false => journal.stop(&log)?,
+        }
+    }
+    if let Some(hook) = hook {
+        hook.finish()?;
+    }
+    #[cfg(unix)]
+    if let Some(notifier) = &notifier {
+        notifier.completed(num_entries, num_checkpoints);
+    }
+    if io_class.is_some() || nowait {
+        let writes = match (nowait, io::nowait_supported()) {
+            (true, true) => "RWF_NOWAIT writes",
+            (true, false) => "blocking writes, the target doesn't support RWF_NOWAIT",
+            (false, _) => "blocking writes",
+        };
+        println!("replayed with io class {} and {}", sys::io_class()?, writes);
+    }
+    if matches.is_present("truncate-target") || matches.is_present("grow-target") {
+        println!("{} is {} bytes", replay_file_path, target::size(std::path::Path::new(replay_file_path))?);
+    }
+    // The per-entry lines went to the file, leave a summary on the console
+    if let Some(path) = matches.value_of("log-output") {
+        println!("replayed {} entries onto {}, details in {}", num_entries, replay_file_path, path);
+    }
+    if let Some(failures) = &failures {
+        if failures.failed > 0 {
+            bail!("{} entries failed to replay, see {}", failures.failed, flags.value_of("failure-report").unwrap())
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,98 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+use anyhow::Result;
+use tracing::{error, info};
+use crate::log_writes::{LogWriteEntry, LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+
+static ENTRIES_REPLAYED: AtomicU64 = AtomicU64::new(0);
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static BYTES_DISCARDED: AtomicU64 = AtomicU64::new(0);
+static CURRENT_ENTRY: AtomicU64 = AtomicU64::new(0);
+static CHECKPOINTS_PASSED: AtomicU64 = AtomicU64::new(0);
+static CHECKPOINTS_FAILED: AtomicU64 = AtomicU64::new(0);
+/// When the first entry was replayed, for the throughput gauge
+static STARTED: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Counts an entry once it has been applied, `cur_entry` is the number replayed so far.
+pub fn record_entry(entry: &LogWriteEntry, sector_size: u32, cur_entry: u64) {
+    STARTED.lock().unwrap().get_or_insert_with(Instant::now);
+    let bytes = entry.nr_sectors * sector_size as u64;
+    if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+        BYTES_DISCARDED.fetch_add(bytes, Ordering::Relaxed);
+    } else if (entry.flags & LOG_MARK_FLAG) == 0 {
+        BYTES_WRITTEN.fetch_add(bytes, Ordering::Relaxed);
+    }
+    ENTRIES_REPLAYED.fetch_add(1, Ordering::Relaxed);
+    CURRENT_ENTRY.store(cur_entry, Ordering::Relaxed);
+}
+
+pub fn record_checkpoint(passed: bool) {
+    if passed {
+        CHECKPOINTS_PASSED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        CHECKPOINTS_FAILED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP log_write_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE log_write_{} {}", name, kind);
+    let _ = writeln!(out, "log_write_{} {}", name, value);
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let bytes_written = BYTES_WRITTEN.load(Ordering::Relaxed);
+    let elapsed = STARTED.lock().unwrap().map_or(0.0, |started| started.elapsed().as_secs_f64());
+    let throughput = if elapsed > 0.0 { bytes_written as f64 / elapsed } else { 0.0 };
+
+    let mut out = String::new();
+    metric(&mut out, "entries_replayed_total", "counter", "Log entries replayed.", ENTRIES_REPLAYED.load(Ordering::Relaxed));
+    metric(&mut out, "bytes_written_total", "counter", "Bytes of entry data written to replay targets.", bytes_written);
+    metric(&mut out, "bytes_discarded_total", "counter", "Bytes discarded on replay targets.", BYTES_DISCARDED.load(Ordering::Relaxed));
+    metric(&mut out, "current_entry", "gauge", "Entries replayed so far by the latest replay.", CURRENT_ENTRY.load(Ordering::Relaxed));
+    metric(&mut out, "checkpoints_passed_total", "counter", "Checkpoints whose fsck passed.", CHECKPOINTS_PASSED.load(Ordering::Relaxed));
+    metric(&mut out, "checkpoints_failed_total", "counter", "Checkpoints whose fsck failed.", CHECKPOINTS_FAILED.load(Ordering::Relaxed));
+    metric(&mut out, "replay_bytes_per_second", "gauge", "Average write throughput since the first replayed entry.", throughput);
+    out
+}
+
+fn handle(mut stream: TcpStream) -> Result<()> {
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, we don't need any of them
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body)?;
+    Ok(())
+}
+
+/// Serves /metrics on `listen` from a background thread for the rest of the process.
+pub fn spawn_server(listen: &str) -> Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    info!("serving metrics on http://{}/metrics", listen);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(error) = handle(stream) {
+                error!("Error serving metrics: {}", error);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::{Result, anyhow, bail};
+use tracing::warn;
+use crate::loopdev::BlockDevice;
+use crate::sys;
+
+static MOUNT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    pub fstype: Option<String>,
+    pub options: Option<String>,
+}
+
+/// Read-only mount of the replay target at a temporary directory. The mount is
+/// removed together with the directory (and any loop device) when dropped.
+pub struct Mount {
+    mountpoint: PathBuf,
+    _device: BlockDevice,
+}
+
+impl Mount {
+    pub fn read_only(target: &Path, opts: &MountOptions) -> Result<Self> {
+        let device = BlockDevice::open(target, true)?;
+        let mountpoint = std::env::temp_dir().join(format!(
+            "log-write-{}-{}",
+            std::process::id(),
+            MOUNT_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&mountpoint)?;
+
+        let mut options = String::from("ro");
+        if let Some(extra) = &opts.options {
+            options.push(',');
+            options.push_str(extra);
+        }
+
+        let mut cmd = Command::new("mount");
+        cmd.arg("-o").arg(&options);
+        if let Some(fstype) = &opts.fstype {
+            cmd.arg("-t").arg(fstype);
+        }
+        let status = cmd.arg(device.path()).arg(&mountpoint).status().map_err(|error| {
+            anyhow!("Error running mount: {}", error)
+        })?;
+        if !status.success() {
+            let _ = fs::remove_dir(&mountpoint);
+            bail!("Error mounting {} at {}", target.display(), mountpoint.display())
+        }
+        Ok(Self {
+            mountpoint,
+            _device: device,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.mountpoint
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if let Err(error) = sys::umount(&self.mountpoint) {
+            warn!("Error unmounting {}: {}", self.mountpoint.display(), error);
+            return;
+        }
+        if let Err(error) = fs::remove_dir(&self.mountpoint) {
+            warn!("Error removing {}: {}", self.mountpoint.display(), error);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use anyhow::{Result, bail};
+use bytes::{BufMut, BytesMut};
+use tracing::{error, info};
+use crate::export::BlockExport;
+
+const NBD_MAGIC: u64 = 0x4e42444d41474943;
+const NBD_IHAVEOPT: u64 = 0x49484156454f5054;
+const NBD_REP_MAGIC: u64 = 0x3e889045565a9;
+const NBD_REQUEST_MAGIC: u32 = 0x25609513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_ABORT: u32 = 2;
+const NBD_OPT_LIST: u32 = 3;
+const NBD_OPT_INFO: u32 = 6;
+const NBD_OPT_GO: u32 = 7;
+
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_SERVER: u32 = 2;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const NBD_INFO_EXPORT: u16 = 0;
+
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+
+const NBD_EPERM: u32 = 1;
+const NBD_EIO: u32 = 5;
+const NBD_EINVAL: u32 = 22;
+
+/// Largest request we're willing to buffer, matches the kernel client's limit
+const NBD_MAX_REQUEST: u32 = 32 * 1024 * 1024;
+
+fn read_u16(stream: &mut TcpStream) -> Result<u16> {
+    let mut buf = [0_u8; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut TcpStream) -> Result<u32> {
+    let mut buf = [0_u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(stream: &mut TcpStream) -> Result<u64> {
+    let mut buf = [0_u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn transmission_flags(export: &dyn BlockExport) -> u16 {
+    let mut flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH;
+    if export.read_only() {
+        flags |= NBD_FLAG_READ_ONLY;
+    } else {
+        flags |= NBD_FLAG_SEND_FUA | NBD_FLAG_SEND_TRIM;
+    }
+    flags
+}
+
+fn send_option_reply(stream: &mut TcpStream, option: u32, reply: u32, data: &[u8]) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(20 + data.len());
+    buf.put_u64(NBD_REP_MAGIC);
+    buf.put_u32(option);
+    buf.put_u32(reply);
+    buf.put_u32(data.len() as u32);
+    buf.put_slice(data);
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+/// Runs the fixed newstyle handshake, returns false if the client aborted.
+fn handshake(stream: &mut TcpStream, export: &dyn BlockExport) -> Result<bool> {
+    let mut buf = BytesMut::with_capacity(18);
+    buf.put_u64(NBD_MAGIC);
+    buf.put_u64(NBD_IHAVEOPT);
+    buf.put_u16(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES);
+    stream.write_all(&buf)?;
+
+    let client_flags = read_u32(stream)?;
+    let no_zeroes = (client_flags & NBD_FLAG_NO_ZEROES as u32) > 0;
+
+    loop {
+        if read_u64(stream)? != NBD_IHAVEOPT {
+            bail!("Bad option magic from client")
+        }
+        let option = read_u32(stream)?;
+        let len = read_u32(stream)?;
+        if len > 4096 {
+            bail!("Option {} too long: {}", option, len)
+        }
+        let mut data = vec![0_u8; len as usize];
+        stream.read_exact(&mut data)?;
+
+        match option {
+            NBD_OPT_EXPORT_NAME => {
+                let mut buf = BytesMut::with_capacity(134);
+                buf.put_u64(export.size());
+                buf.put_u16(transmission_flags(export));
+                if !no_zeroes {
+                    buf.put_slice(&[0_u8; 124]);
+                }
+                stream.write_all(&buf)?;
+                return Ok(true);
+            }
+            NBD_OPT_ABORT => {
+                send_option_reply(stream, option, NBD_REP_ACK, &[])?;
+                return Ok(false);
+            }
+            NBD_OPT_LIST => {
+                // A single unnamed export
+                send_option_reply(stream, option, NBD_REP_SERVER, &[0_u8; 4])?;
+                send_option_reply(stream, option, NBD_REP_ACK, &[])?;
+            }
+            NBD_OPT_INFO | NBD_OPT_GO => {
+                let mut info = BytesMut::with_capacity(12);
+                info.put_u16(NBD_INFO_EXPORT);
+                info.put_u64(export.size());
+                info.put_u16(transmission_flags(export));
+                send_option_reply(stream, option, NBD_REP_INFO, &info)?;
+                send_option_reply(stream, option, NBD_REP_ACK, &[])?;
+                if option == NBD_OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => send_option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+fn send_reply(stream: &mut TcpStream, error: u32, handle: u64, data: &[u8]) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(16 + data.len());
+    buf.put_u32(NBD_SIMPLE_REPLY_MAGIC);
+    buf.put_u32(error);
+    buf.put_u64(handle);
+    buf.put_slice(data);
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+fn transmission(stream: &mut TcpStream, export: &mut dyn BlockExport) -> Result<()> {
+    loop {
+        if read_u32(stream)? != NBD_REQUEST_MAGIC {
+            bail!("Bad request magic from client")
+        }
+        let flags = read_u16(stream)?;
+        let cmd = read_u16(stream)?;
+        let handle = read_u64(stream)?;
+        let offset = read_u64(stream)?;
+        let len = read_u32(stream)?;
+
+        if (cmd == NBD_CMD_READ || cmd == NBD_CMD_WRITE) && len > NBD_MAX_REQUEST {
+            bail!("Request of {} bytes is too large", len)
+        }
+        let in_range = offset.checked_add(len as u64).is_some_and(|end| end <= export.size());
+
+        match cmd {
+            NBD_CMD_READ => {
+                let mut buf = vec![0_u8; len as usize];
+                if !in_range {
+                    send_reply(stream, NBD_EINVAL, handle, &[])?;
+                } else if let Err(error) = export.read(offset, &mut buf) {
+                    error!("Error reading {} bytes at {}: {}", len, offset, error);
+                    send_reply(stream, NBD_EIO, handle, &[])?;
+                } else {
+                    send_reply(stream, 0, handle, &buf)?;
+                }
+            }
+            NBD_CMD_WRITE => {
+                let mut buf = vec![0_u8; len as usize];
+                stream.read_exact(&mut buf)?;
+                let error = if export.read_only() {
+                    NBD_EPERM
+                } else if !in_range {
+                    NBD_EINVAL
+                } else if let Err(error) = export.write(offset, &buf, (flags & NBD_CMD_FLAG_FUA) > 0) {
+                    error!("Error writing {} bytes at {}: {}", len, offset, error);
+                    NBD_EIO
+                } else {
+                    0
+                };
+                send_reply(stream, error, handle, &[])?;
+            }
+            NBD_CMD_FLUSH => {
+                let error = match export.flush() {
+                    Ok(()) => 0,
+                    Err(error) => {
+                        error!("Error flushing: {}", error);
+                        NBD_EIO
+                    }
+                };
+                send_reply(stream, error, handle, &[])?;
+            }
+            NBD_CMD_TRIM => {
+                let error = if export.read_only() {
+                    NBD_EPERM
+                } else if !in_range {
+                    NBD_EINVAL
+                } else if let Err(error) = export.trim(offset, len as u64) {
+                    error!("Error trimming {} bytes at {}: {}", len, offset, error);
+                    NBD_EIO
+                } else {
+                    0
+                };
+                send_reply(stream, error, handle, &[])?;
+            }
+            NBD_CMD_DISC => return Ok(()),
+            _ => send_reply(stream, NBD_EINVAL, handle, &[])?,
+        }
+    }
+}
+
+/// Accepts a single client on `listener` and serves `export` until it disconnects.
+pub fn serve(listener: &TcpListener, export: &mut dyn BlockExport) -> Result<()> {
+    let (mut stream, addr) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    info!("nbd client connected from {}", addr);
+    if handshake(&mut stream, export)? {
+        transmission(&mut stream, export)?;
+    }
+    info!("nbd client {} disconnected", addr);
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use anyhow::{Result, bail, anyhow};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use crate::check::CheckOutcome;
+
+/// Only the tail of the check output is sent, fsck can be very chatty
+const MAX_OUTPUT: usize = 16 * 1024;
+
+/// POSTs replay summaries to a webhook.
+pub struct Notifier {
+    url: String,
+    log: String,
+    replay: String,
+}
+
+fn tail(output: &str) -> &str {
+    let mut start = output.len().saturating_sub(MAX_OUTPUT);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    &output[start..]
+}
+
+impl Notifier {
+    pub fn new(url: &str, log: &str, replay: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            log: log.to_string(),
+            replay: replay.to_string(),
+        }
+    }
+
+    fn post(&self, summary: &Value) -> Result<()> {
+        // curl keeps TLS and proxies out of our dependencies
+        let mut child = Command::new("curl")
+            .arg("-sS").arg("--fail").arg("--max-time").arg("30")
+            .arg("-X").arg("POST")
+            .arg("-H").arg("Content-Type: application/json")
+            .arg("--data-binary").arg("@-")
+            .arg(&self.url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|error| anyhow!("Error running curl: {}", error))?;
+        child.stdin.take().unwrap().write_all(summary.to_string().as_bytes())?;
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("curl exited with {}", status)
+        }
+        Ok(())
+    }
+
+    /// Notification failures are logged, they never fail the replay itself.
+    fn send(&self, summary: Value) {
+        match self.post(&summary) {
+            Ok(()) => info!("notified {}", self.url),
+            Err(error) => warn!("Error notifying {}: {}", self.url, error),
+        }
+    }
+
+    pub fn failed(&self, entry: u64, mark: Option<&str>, outcome: &CheckOutcome, entries_replayed: u64, checkpoints: u64) {
+        self.send(json!({
+            "status": "failed",
+            "log": self.log,
+            "replay": self.replay,
+            "entries_replayed": entries_replayed,
+            "checkpoints": checkpoints,
+            "failure": {
+                "entry": entry,
+                "mark": mark,
+                "exit_code": outcome.exit_code,
+                "duration_ms": outcome.duration.as_millis() as u64,
+                "output": tail(&outcome.output),
+            },
+        }));
+    }
+
+    pub fn completed(&self, entries_replayed: u64, checkpoints: u64) {
+        self.send(json!({
+            "status": "completed",
+            "log": self.log,
+            "replay": self.replay,
+            "entries_replayed": entries_replayed,
+            "checkpoints": checkpoints,
+        }));
+    }
+}
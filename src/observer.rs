@@ -0,0 +1,116 @@
+//! Typed events from a replay, for embedders that follow one, a GUI say,
+//! without parsing the lines it logs, see `Log::replay_observed`.
+
+use std::ops::Range;
+use anyhow::Error;
+use crate::format::{LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+
+/// The ordering an entry asks of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Barrier {
+    Flush,
+    Fua,
+}
+
+/// Told about each entry as it is replayed. Every method does nothing
+/// unless overridden, so an observer implements just the events it wants.
+pub trait ReplayObserver {
+    /// Entry `index` was replayed, called before the calls below for it
+    fn on_entry(&mut self, _index: u64, _entry: &LogWriteEntry) {}
+
+    fn on_mark(&mut self, _index: u64, _name: &str) {}
+
+    /// Once for each of flush and FUA the entry has
+    fn on_barrier(&mut self, _index: u64, _kind: Barrier) {}
+
+    /// The sectors the entry discards, as numbered in the log
+    fn on_discard(&mut self, _index: u64, _sectors: Range<u64>) {}
+
+    /// Replaying entry `index` failed with `error` and the replay stops
+    fn on_error(&mut self, _index: u64, _error: &Error) {}
+}
+
+/// Observes nothing, what `Log::replay` replays with.
+impl ReplayObserver for () {}
+
+/// Makes the calls for entry `index`, just replayed.
+pub(crate) fn notify(observer: &mut dyn ReplayObserver, index: u64, entry: &LogWriteEntry) {
+    observer.on_entry(index, entry);
+    if (entry.flags & LOG_MARK_FLAG) > 0 {
+        observer.on_mark(index, &entry.cmd);
+    }
+    if (entry.flags & LOG_FLUSH_FLAG) > 0 {
+        observer.on_barrier(index, Barrier::Flush);
+    }
+    if (entry.flags & LOG_FUA_FLAG) > 0 {
+        observer.on_barrier(index, Barrier::Fua);
+    }
+    if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+        observer.on_discard(index, entry.sector..entry.sector.saturating_add(entry.nr_sectors));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::sync::atomic::AtomicBool;
+    use anyhow::Error;
+    use crate::log_writes::{Log, LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+    use crate::observer::{Barrier, ReplayObserver};
+    use crate::testutil::{log_super, push_entry, TempPath};
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl ReplayObserver for Recorder {
+        fn on_entry(&mut self, index: u64, entry: &LogWriteEntry) {
+            self.events.push(format!("entry {} sector {}", index, entry.sector));
+        }
+
+        fn on_mark(&mut self, index: u64, name: &str) {
+            self.events.push(format!("mark {} {}", index, name));
+        }
+
+        fn on_barrier(&mut self, index: u64, kind: Barrier) {
+            self.events.push(format!("barrier {} {:?}", index, kind));
+        }
+
+        fn on_discard(&mut self, index: u64, sectors: Range<u64>) {
+            self.events.push(format!("discard {} {:?}", index, sectors));
+        }
+
+        fn on_error(&mut self, index: u64, _error: &Error) {
+            self.events.push(format!("error {}", index));
+        }
+    }
+
+    #[test]
+    fn test_observed_replay() {
+        let entries = [
+            LogWriteEntry { sector: 1, nr_sectors: 1, flags: LOG_FLUSH_FLAG | LOG_FUA_FLAG, data_len: 0, cmd: String::new() },
+            LogWriteEntry { sector: 2, nr_sectors: 4, flags: LOG_DISCARD_FLAG, data_len: 0, cmd: String::new() },
+            LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_MARK_FLAG, data_len: 4, cmd: "done".to_string() },
+        ];
+        // Counts an entry more than there is, the replay fails on it
+        let mut log = log_super(4);
+        for entry in &entries {
+            push_entry(&mut log, entry, 1);
+        }
+        let (log_path, replay_path) = (TempPath::new("observer.log"), TempPath::new("observer.img"));
+        std::fs::write(&log_path, &log).unwrap();
+        std::fs::write(&replay_path, [0_u8; 4096]).unwrap();
+
+        let mut recorder = Recorder::default();
+        let mut log = Log::builder().log_path(&log_path).replay_path(&replay_path).ignore_discards(true).open().unwrap();
+        let result = log.replay_observed(None, &AtomicBool::new(false), &mut recorder);
+        assert!(result.is_err());
+        assert_eq!(recorder.events, [
+            "entry 0 sector 1", "barrier 0 Flush", "barrier 0 Fua",
+            "entry 1 sector 2", "discard 1 2..6",
+            "entry 2 sector 0", "mark 2 done",
+            "error 3",
+        ]);
+    }
+}
@@ -0,0 +1,50 @@
+//! Tallying entries over every core with rayon, for analyze stats. Only the
+//! tally is spread out: finding the entries is sequential, each header says
+//! where the next one is, and nothing here reads entry data.
+
+use rayon::prelude::*;
+use crate::format::{LogEntry, MetadataFilter};
+use crate::log_reader::LogReader;
+use crate::stats::LogStats;
+
+pub fn stats(reader: &LogReader, entries: &[LogEntry], filter: MetadataFilter) -> LogStats {
+    let sector_size = reader.sector_size();
+    entries.par_iter()
+        .filter(|log_entry| filter.passes(&log_entry.entry))
+        .fold(LogStats::default, |mut stats, log_entry| {
+            stats.add(&log_entry.entry, sector_size);
+            stats
+        })
+        .reduce(LogStats::default, |mut stats, other| {
+            stats.merge(&other);
+            stats
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::{LogEntry, LogWriteEntry, MetadataFilter, LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+    use crate::log_reader::LogReader;
+    use crate::parallel;
+    use crate::stats::LogStats;
+    use crate::testutil::{build_log, write, TempPath};
+
+    #[test]
+    fn test_parallel_stats() {
+        let entries: Vec<LogWriteEntry> = (0..1000_u64).map(|i| match i % 10 {
+            0 => LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_MARK_FLAG, data_len: 1, cmd: "m".to_string() },
+            1 => LogWriteEntry { sector: i, nr_sectors: 4, flags: LOG_DISCARD_FLAG, data_len: 0, cmd: String::new() },
+            _ => write(i, i % 3),
+        }).collect();
+        let log_path = TempPath::new("parallel.log");
+        std::fs::write(&log_path, build_log(&entries, |i| i as u8)).unwrap();
+
+        let reader = LogReader::open(&log_path).unwrap();
+        let indexed: Vec<LogEntry> = reader.clone().collect::<anyhow::Result<_>>().unwrap();
+        let mut expected = LogStats::default();
+        for entry in &entries {
+            expected.add(entry, 512);
+        }
+        assert_eq!(parallel::stats(&reader, &indexed, MetadataFilter::All), expected);
+    }
+}
@@ -0,0 +1,90 @@
+//! Replaying a log of a whole disk into one of its partitions: where the
+//! partition sits on the disk, from sysfs, and whether every entry of the
+//! log lands inside it.
+
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, bail, anyhow};
+use crate::log_reader::LogReader;
+use crate::log_writes::LOG_MARK_FLAG;
+
+/// What sysfs counts partition starts and sizes in, whatever the disk's block size.
+const SYSFS_SECTOR: u64 = 512;
+
+/// A partition's place on its disk, in bytes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Partition {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Partition {
+    /// Reads where `device`, a partition like /dev/sda3, sits on its disk.
+    pub fn of(device: &Path) -> Result<Self> {
+        let name = fs::canonicalize(device).ok()
+            .and_then(|device| device.file_name().map(|name| name.to_os_string()))
+            .ok_or_else(|| anyhow!("Error finding the partition {}", device.display()))?;
+        let sys = Path::new("/sys/class/block").join(name);
+        let read = |field: &str| -> Result<u64> {
+            let value = fs::read_to_string(sys.join(field))
+                .map_err(|error| anyhow!("Error reading the {} of {}, is it a partition? {}", field, device.display(), error))?;
+            value.trim().parse().map_err(|error| anyhow!("Invalid {} {} of {}: {}", field, value.trim(), device.display(), error))
+        };
+        Ok(Self { start: read("start")? * SYSFS_SECTOR, size: read("size")? * SYSFS_SECTOR })
+    }
+
+    /// The partition's `[start, end)` in the log's `sector_size` sectors.
+    pub fn sectors(&self, sector_size: u32) -> Result<(u64, u64)> {
+        let sector_size = sector_size as u64;
+        if !self.start.is_multiple_of(sector_size) {
+            bail!("The partition starts at byte {}, not on a {} byte sector of the log", self.start, sector_size)
+        }
+        Ok((self.start / sector_size, (self.start + self.size) / sector_size))
+    }
+}
+
+/// Fails unless every write and discard of the log lands in `[start, end)`.
+pub fn check_fits(reader: &mut LogReader, (start, end): (u64, u64)) -> Result<()> {
+    let mut outside = 0;
+    let mut first = None;
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        if (entry.flags & LOG_MARK_FLAG) > 0 || entry.nr_sectors == 0 {
+            continue;
+        }
+        if entry.sector < start || entry.sector.saturating_add(entry.nr_sectors) > end {
+            outside += 1;
+            first.get_or_insert((log_entry.index, entry.sector));
+        }
+    }
+    if let Some((index, sector)) = first {
+        bail!("{} entries don't fit in the partition's sectors {}-{}, the first is entry {} at sector {}",
+              outside, start, end - 1, index, sector)
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_reader::LogReader;
+    use crate::log_writes::LogWriteEntry;
+    use crate::partition::{check_fits, Partition};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_check_fits() {
+        let path = std::env::temp_dir().join(format!("log-write-partition-{}.log", std::process::id()));
+        let mut writer = LogWriter::create(&path, 4096).unwrap();
+        writer.append(&LogWriteEntry { sector: 256, nr_sectors: 2, flags: 0, data_len: 0, cmd: String::new() }, &[0; 8192]).unwrap();
+        writer.mark("done").unwrap();
+        writer.sync().unwrap();
+
+        let partition = Partition { start: 1024 * 1024, size: 8192 };
+        assert_eq!(partition.sectors(4096).unwrap(), (256, 258));
+        assert!(Partition { start: 512, size: 8192 }.sectors(4096).is_err());
+        let fits = check_fits(&mut LogReader::open(&path).unwrap(), (256, 258)).is_ok();
+        let too_small = check_fits(&mut LogReader::open(&path).unwrap(), (256, 257)).is_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(fits && too_small);
+    }
+}
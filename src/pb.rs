@@ -0,0 +1,144 @@
+//! Streams a log as the Records of proto/log_entries.proto, so anything with
+//! protobuf bindings can read logs. The wire format is simple enough to write
+//! by hand, which keeps this out of the grpc feature.
+
+use std::io::Write;
+use anyhow::Result;
+use crate::log_reader::LogReader;
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+
+const VARINT: u64 = 0;
+const LEN: u64 = 2;
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_tag(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, (field << 3) | wire_type);
+}
+
+/// proto3 leaves out fields with default values
+fn put_u64(buf: &mut Vec<u8>, field: u64, value: u64) {
+    if value != 0 {
+        put_tag(buf, field, VARINT);
+        put_varint(buf, value);
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    if !value.is_empty() {
+        put_tag(buf, field, LEN);
+        put_varint(buf, value.len() as u64);
+        buf.extend_from_slice(value);
+    }
+}
+
+/// Writes `message` as field `field` of a Record, length-delimited.
+fn write_record<W: Write>(out: &mut W, field: u64, message: &[u8]) -> Result<()> {
+    let mut record = Vec::with_capacity(message.len() + 16);
+    put_tag(&mut record, field, LEN);
+    put_varint(&mut record, message.len() as u64);
+    record.extend_from_slice(message);
+    let mut prefix = Vec::with_capacity(10);
+    put_varint(&mut prefix, record.len() as u64);
+    out.write_all(&prefix)?;
+    out.write_all(&record)?;
+    Ok(())
+}
+
+/// Writes the superblock and every entry of `reader`, returns the number of entries.
+pub fn write_records<W: Write>(reader: &mut LogReader, out: &mut W) -> Result<u64> {
+    let mut message = Vec::new();
+    put_u64(&mut message, 1, reader.log_super.magic);
+    put_u64(&mut message, 2, reader.log_super.version);
+    put_u64(&mut message, 3, reader.log_super.nr_entries);
+    put_u64(&mut message, 4, reader.sector_size() as u64);
+    write_record(out, 1, &message)?;
+
+    let mut num_entries = 0;
+    let mut buf = Vec::new();
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        num_entries += 1;
+        message.clear();
+        put_u64(&mut message, 1, log_entry.index);
+        put_u64(&mut message, 2, entry.sector);
+        put_u64(&mut message, 3, entry.nr_sectors);
+        put_u64(&mut message, 4, entry.flags);
+        put_u64(&mut message, 5, entry.data_len);
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            put_bytes(&mut message, 6, entry.cmd.as_bytes());
+        } else if (entry.flags & LOG_DISCARD_FLAG) == 0 && entry.nr_sectors > 0 {
+            buf.resize(reader.data_size(entry) as usize, 0);
+            reader.read_at(&mut buf, reader.data_offset(&log_entry))?;
+            put_bytes(&mut message, 7, &buf);
+        }
+        write_record(out, 2, &message)?;
+    }
+    Ok(num_entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_reader::LogReader;
+    use crate::log_writes::LogWriteEntry;
+    use crate::pb::{put_varint, write_records};
+    use crate::writer::LogWriter;
+
+    /// Splits a stream into its Records, without the length prefixes.
+    fn records(mut stream: &[u8]) -> Vec<&[u8]> {
+        let mut records = Vec::new();
+        while !stream.is_empty() {
+            let (mut len, mut shift, mut i) = (0_usize, 0, 0);
+            loop {
+                len |= ((stream[i] & 0x7f) as usize) << shift;
+                shift += 7;
+                i += 1;
+                if stream[i - 1] < 0x80 {
+                    break;
+                }
+            }
+            records.push(&stream[i..i + len]);
+            stream = &stream[i + len..];
+        }
+        records
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 1);
+        put_varint(&mut buf, 300);
+        assert_eq!(buf, [0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn test_write_records() {
+        let path = std::env::temp_dir().join(format!("log-write-pb-{}.log", std::process::id()));
+        let mut writer = LogWriter::create(&path, 512).unwrap();
+        writer.append(&LogWriteEntry { sector: 1, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() }, &[5; 512]).unwrap();
+        writer.mark("one").unwrap();
+        writer.sync().unwrap();
+
+        let mut stream = Vec::new();
+        assert_eq!(write_records(&mut LogReader::open(&path).unwrap(), &mut stream).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        let records = records(&stream);
+        assert_eq!(records.len(), 3);
+        // superblock: magic, version 1, 2 entries, sector size 512
+        assert_eq!(records[0][0], 0x0a);
+        assert!(records[0].ends_with(&[0x10, 0x01, 0x18, 0x02, 0x20, 0x80, 0x04]));
+        // entry 0: sector 1, one sector, then its data as field 7
+        assert_eq!(records[1][..7], [0x12, 0x87, 0x04, 0x10, 0x01, 0x18, 0x01]);
+        assert_eq!(records[1][7..10], [0x3a, 0x80, 0x04]);
+        assert_eq!(records[1][10..], [5; 512][..]);
+        // entry 1: index 1, the mark flag, the name's length and the name
+        assert_eq!(records[2], [0x12, 0x0b, 0x08, 0x01, 0x20, 0x08, 0x28, 0x03, 0x32, 0x03, b'o', b'n', b'e']);
+    }
+}
@@ -0,0 +1,274 @@
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use anyhow::{Result, bail, anyhow};
+use serde_json::{json, Value};
+use tracing::info;
+use crate::entries::MarkPattern;
+use crate::io;
+use crate::log_reader::LogReader;
+use crate::log_writes::{Log, LOG_DISCARD_FLAG, LOG_DISCARD_NOT_SUPP, LOG_MARK_FLAG};
+use crate::results;
+use crate::safety::{self, mounted};
+use crate::sys;
+
+/// What replaying a planned entry does to the target.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    Write,
+    /// Discarded, or zeroed when the target can't discard, see `Plan::zero_discards`
+    Discard,
+    Mark,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Write => "write",
+            Action::Discard => "discard",
+            Action::Mark => "mark",
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self> {
+        match s {
+            "write" => Ok(Action::Write),
+            "discard" => Ok(Action::Discard),
+            "mark" => Ok(Action::Mark),
+            _ => bail!("Invalid action {} in plan", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedEntry {
+    pub index: u64,
+    /// Offset of the entry header in the log
+    pub offset: u64,
+    pub sector: u64,
+    pub nr_sectors: u64,
+    pub flags: u64,
+    pub action: Action,
+    pub mark: Option<String>,
+}
+
+/// A check on the replay target, made when planning and again before applying.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Precheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Exactly which entries a replay will apply to which target, written out so
+/// it can be reviewed before anything touches the device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub log: String,
+    /// xxh3 of the log, apply refuses a log that changed since planning
+    pub log_hash: String,
+    pub replay: String,
+    pub sector_size: u32,
+    pub zero_discards: bool,
+    pub entries: Vec<PlannedEntry>,
+    pub prechecks: Vec<Precheck>,
+}
+
+/// Checks the target exists, is writable, isn't mounted and is big enough for the planned writes.
+pub fn prechecks(replay: &str, sector_size: u32, entries: &[PlannedEntry]) -> Result<Vec<Precheck>> {
+    let check = |name: &str, passed: bool, detail: String| Precheck { name: name.to_string(), passed, detail };
+    let metadata = match fs::metadata(replay) {
+        Ok(metadata) => metadata,
+        Err(error) => return Ok(vec![check("exists", false, error.to_string())]),
+    };
+    let mut checks = vec![check("exists", true, String::new())];
+    match OpenOptions::new().write(true).open(replay) {
+        Ok(_) => checks.push(check("writable", true, String::new())),
+        Err(error) => checks.push(check("writable", false, error.to_string())),
+    }
+    let is_mounted = mounted(Path::new(replay))?;
+    checks.push(check("not-mounted", !is_mounted, if is_mounted { format!("{} is mounted", replay) } else { String::new() }));
+
+    let needed = entries.iter().filter(|entry| entry.action != Action::Mark)
+        .map(|entry| entry.sector.saturating_add(entry.nr_sectors).saturating_mul(sector_size as u64))
+        .max()
+        .unwrap_or(0);
+    if sys::is_block_device(&metadata) {
+        let file = OpenOptions::new().read(true).open(replay)?;
+        let size = io::block_device_size(&file)?;
+        checks.push(check("size", size >= needed, format!("{} bytes, writes reach {}", size, needed)));
+    } else {
+        // Files grow as needed
+        checks.push(check("size", true, format!("{} bytes, writes reach {}", metadata.len(), needed)));
+    }
+    Ok(checks)
+}
+
+impl Plan {
+    /// Plans replaying `order`, or the whole log, stopping after `limit` entries or the first mark `end_mark` matches.
+    pub fn create(log: &str, replay: &str, order: Option<Vec<u64>>, limit: u64, end_mark: Option<&MarkPattern>) -> Result<Self> {
+        let mut reader = LogReader::open(log)?;
+        let sector_size = reader.sector_size();
+        let mut all = Vec::new();
+        while let Some(log_entry) = reader.next_entry()? {
+            all.push(log_entry);
+        }
+        let order = order.unwrap_or_else(|| (0..all.len() as u64).collect());
+
+        let mut entries = Vec::new();
+        for index in order {
+            let log_entry = all.get(index as usize)
+                .ok_or_else(|| anyhow!("Entry {} is past the end of the log ({} entries)", index, all.len()))?;
+            let entry = &log_entry.entry;
+            let is_mark = (entry.flags & LOG_MARK_FLAG) > 0;
+            let action = if is_mark {
+                Action::Mark
+            } else if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+                Action::Discard
+            } else {
+                Action::Write
+            };
+            entries.push(PlannedEntry {
+                index,
+                offset: log_entry.offset,
+                sector: entry.sector,
+                nr_sectors: entry.nr_sectors,
+                flags: entry.flags,
+                action,
+                mark: if is_mark { Some(entry.cmd.clone()) } else { None },
+            });
+            if (limit > 0 && entries.len() as u64 == limit) || (is_mark && end_mark.is_some_and(|mark| mark.matches(&entry.cmd))) {
+                break;
+            }
+        }
+
+        let is_block = fs::metadata(replay).map(|metadata| sys::is_block_device(&metadata)).unwrap_or(false);
+        Ok(Self {
+            log: log.to_string(),
+            log_hash: results::hash_device(log)?,
+            replay: replay.to_string(),
+            sector_size,
+            zero_discards: !is_block,
+            prechecks: prechecks(replay, sector_size, &entries)?,
+            entries,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "log": self.log,
+            "log_hash": self.log_hash,
+            "replay": self.replay,
+            "sector_size": self.sector_size,
+            "discards": if self.zero_discards { "zero" } else { "discard" },
+            "prechecks": self.prechecks.iter().map(|check| json!({
+                "name": check.name,
+                "passed": check.passed,
+                "detail": check.detail,
+            })).collect::<Vec<_>>(),
+            "entries": self.entries.iter().map(|entry| json!({
+                "index": entry.index,
+                "offset": entry.offset,
+                "sector": entry.sector,
+                "nr_sectors": entry.nr_sectors,
+                "flags": entry.flags,
+                "action": entry.action.as_str(),
+                "mark": entry.mark,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn from_json(plan: &Value) -> Result<Self> {
+        let str_field = |value: &Value, name: &str| value[name].as_str().map(String::from)
+            .ok_or_else(|| anyhow!("Plan is missing {}", name));
+        let u64_field = |value: &Value, name: &str| value[name].as_u64()
+            .ok_or_else(|| anyhow!("Plan is missing {}", name));
+        let list = |name: &str| plan[name].as_array().cloned().ok_or_else(|| anyhow!("Plan is missing {}", name));
+
+        let entries = list("entries")?.iter().map(|entry| Ok(PlannedEntry {
+            index: u64_field(entry, "index")?,
+            offset: u64_field(entry, "offset")?,
+            sector: u64_field(entry, "sector")?,
+            nr_sectors: u64_field(entry, "nr_sectors")?,
+            flags: u64_field(entry, "flags")?,
+            action: Action::parse(&str_field(entry, "action")?)?,
+            mark: entry["mark"].as_str().map(String::from),
+        })).collect::<Result<Vec<_>>>()?;
+        let prechecks = list("prechecks")?.iter().map(|check| Ok(Precheck {
+            name: str_field(check, "name")?,
+            passed: check["passed"].as_bool().unwrap_or(false),
+            detail: str_field(check, "detail")?,
+        })).collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            log: str_field(plan, "log")?,
+            log_hash: str_field(plan, "log_hash")?,
+            replay: str_field(plan, "replay")?,
+            sector_size: u64_field(plan, "sector_size")? as u32,
+            zero_discards: str_field(plan, "discards")? == "zero",
+            entries,
+            prechecks,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(&path, serde_json::to_string_pretty(&self.to_json())? + "\n")
+            .map_err(|error| anyhow!("Error writing plan {}: {}", path.as_ref().display(), error))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let plan = fs::read_to_string(&path)
+            .map_err(|error| anyhow!("Error reading plan {}: {}", path.as_ref().display(), error))?;
+        Self::from_json(&serde_json::from_str(&plan)?)
+    }
+
+    /// Replays the planned entries, after checking neither the log nor the target changed underneath the plan.
+    pub fn apply(&self, force: bool, yes: bool) -> Result<u64> {
+        if results::hash_device(&self.log)? != self.log_hash {
+            bail!("{} changed since the plan was made", self.log)
+        }
+        for check in prechecks(&self.replay, self.sector_size, &self.entries)? {
+            if !check.passed {
+                bail!("Precheck {} failed: {}", check.name, check.detail)
+            }
+        }
+        safety::check_target(&self.replay, force, yes)?;
+
+        let mut log = Log::open(self.log.as_str(), self.replay.as_str())?;
+        if self.zero_discards {
+            log.flags |= LOG_DISCARD_NOT_SUPP;
+        }
+        for planned in &self.entries {
+            log.seek_to_entry(planned.index, planned.offset)?;
+            let entry = log.replay_next_entry(true)?
+                .ok_or_else(|| anyhow!("Entry {} is missing from the log", planned.index))?;
+            if entry.sector != planned.sector || entry.nr_sectors != planned.nr_sectors || entry.flags != planned.flags {
+                bail!("Entry {} doesn't match the plan", planned.index)
+            }
+        }
+        log.fsync_replay_file()?;
+        info!("applied {} entries to {}", self.entries.len(), self.replay);
+        Ok(self.entries.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plan::{Action, Plan, PlannedEntry, Precheck};
+
+    #[test]
+    fn test_plan_json() {
+        let plan = Plan {
+            log: "a.log".to_string(),
+            log_hash: "0123456789abcdef".to_string(),
+            replay: "/dev/sdb".to_string(),
+            sector_size: 512,
+            zero_discards: false,
+            entries: vec![
+                PlannedEntry { index: 0, offset: 512, sector: 8, nr_sectors: 2, flags: 0, action: Action::Write, mark: None },
+                PlannedEntry { index: 1, offset: 2048, sector: 0, nr_sectors: 0, flags: 8, action: Action::Mark, mark: Some("end".to_string()) },
+            ],
+            prechecks: vec![Precheck { name: "exists".to_string(), passed: true, detail: String::new() }],
+        };
+        assert_eq!(Plan::from_json(&plan.to_json()).unwrap(), plan);
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use mlua::{Function, Lua, Table};
+use crate::hook::entry_payload;
+use crate::log_writes::{self, Log, LogWriteEntry, LOG_MARK_FLAG};
+
+/// A Lua script run in-process for every replayed entry. It may define any of
+///
+/// - `on_entry(entry)` for every entry
+/// - `on_mark(entry)` for mark entries
+/// - `should_stop(entry)` returning true to end the replay after this entry
+///
+/// where `entry` is a table with `index`, `sector`, `nr_sectors`, `flags`,
+/// `flags_str`, `mark` and `payload` (the entry's data as a string).
+pub struct Plugin {
+    lua: Lua,
+    name: String,
+    /// Payloads are only read when the script asks for them with `want_payload = true`
+    want_payload: bool,
+}
+
+fn lua_error(name: &str, error: mlua::Error) -> anyhow::Error {
+    anyhow!("Error in plugin {}: {}", name, error)
+}
+
+impl Plugin {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let name = path.as_ref().display().to_string();
+        let source = fs::read_to_string(&path).map_err(|error| anyhow!("Error reading plugin {}: {}", name, error))?;
+        let lua = Lua::new();
+        lua.load(&source).set_name(name.as_str()).exec().map_err(|error| lua_error(&name, error))?;
+        let want_payload = lua.globals().get::<_, Option<bool>>("want_payload")
+            .map_err(|error| lua_error(&name, error))?
+            .unwrap_or(false);
+        Ok(Self { lua, name, want_payload })
+    }
+
+    fn function(&self, name: &str) -> Result<Option<Function<'_>>> {
+        self.lua.globals().get::<_, Option<Function>>(name).map_err(|error| lua_error(&self.name, error))
+    }
+
+    fn entry_table(&self, log: &Log, entry: &LogWriteEntry) -> Result<Table<'_>> {
+        let build = || -> mlua::Result<Table> {
+            let table = self.lua.create_table()?;
+            let mut flags = String::new();
+            log_writes::entry_flags_to_str(entry.flags, &mut flags);
+            table.set("index", log.cur_entry - 1)?;
+            table.set("sector", entry.sector)?;
+            table.set("nr_sectors", entry.nr_sectors)?;
+            table.set("flags", entry.flags)?;
+            table.set("flags_str", flags)?;
+            if (entry.flags & LOG_MARK_FLAG) > 0 {
+                table.set("mark", entry.cmd.as_str())?;
+            }
+            Ok(table)
+        };
+        let table = build().map_err(|error| lua_error(&self.name, error))?;
+        if self.want_payload {
+            let payload = self.lua.create_string(entry_payload(log, entry)?)
+                .map_err(|error| lua_error(&self.name, error))?;
+            table.set("payload", payload).map_err(|error| lua_error(&self.name, error))?;
+        }
+        Ok(table)
+    }
+
+    /// Calls the script's callbacks for `entry`, the one `log` just replayed,
+    /// returns true if the replay should stop.
+    pub fn run(&self, log: &Log, entry: &LogWriteEntry) -> Result<bool> {
+        let table = self.entry_table(log, entry)?;
+        if let Some(on_entry) = self.function("on_entry")? {
+            on_entry.call::<_, ()>(table.clone()).map_err(|error| lua_error(&self.name, error))?;
+        }
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            if let Some(on_mark) = self.function("on_mark")? {
+                on_mark.call::<_, ()>(table.clone()).map_err(|error| lua_error(&self.name, error))?;
+            }
+        }
+        match self.function("should_stop")? {
+            Some(should_stop) => should_stop.call::<_, bool>(table).map_err(|error| lua_error(&self.name, error)),
+            None => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Writes a device state as a qcow2 image, allocating only the clusters the log
+//! wrote so crash states of large devices stay small. Version 2 images with 64k
+//! clusters and 16 bit refcounts, which every QEMU release reads.
+
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use anyhow::{Result, bail};
+use bytes::{BufMut, BytesMut};
+use crate::export::BlockExport;
+use crate::io::{self, ByteOffset};
+use crate::state::StateExport;
+
+const MAGIC: u32 = 0x514649fb;
+const VERSION: u32 = 2;
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+const L2_ENTRIES: u64 = CLUSTER_SIZE / 8;
+const REFCOUNTS_PER_BLOCK: u64 = CLUSTER_SIZE / 2;
+/// The cluster is referenced once, so it can be written in place
+const OFLAG_COPIED: u64 = 1 << 63;
+
+fn clusters(bytes: u64) -> u64 {
+    bytes.div_ceil(CLUSTER_SIZE)
+}
+
+/// Where each part of the image goes, in clusters.
+#[derive(Debug, PartialEq)]
+struct Layout {
+    l1_size: u64,
+    l1_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u64,
+    refcount_blocks_offset: u64,
+    refcount_blocks: u64,
+    l2_offset: u64,
+    data_offset: u64,
+    total: u64,
+}
+
+impl Layout {
+    fn new(size: u64, l2_tables: u64, data_clusters: u64) -> Self {
+        let l1_size = clusters(size).div_ceil(L2_ENTRIES);
+        let l1_clusters = clusters(l1_size * 8).max(1);
+        let mut refcount_blocks = 1;
+        loop {
+            let refcount_table_clusters = clusters(refcount_blocks * 8);
+            let total = 1 + l1_clusters + refcount_table_clusters + refcount_blocks + l2_tables + data_clusters;
+            // The refcount blocks count themselves, grow them until they cover everything
+            if total.div_ceil(REFCOUNTS_PER_BLOCK) <= refcount_blocks {
+                let refcount_table_offset = 1 + l1_clusters;
+                let refcount_blocks_offset = refcount_table_offset + refcount_table_clusters;
+                let l2_offset = refcount_blocks_offset + refcount_blocks;
+                return Self {
+                    l1_size,
+                    l1_offset: 1,
+                    refcount_table_offset,
+                    refcount_table_clusters,
+                    refcount_blocks_offset,
+                    refcount_blocks,
+                    l2_offset,
+                    data_offset: l2_offset + l2_tables,
+                    total,
+                };
+            }
+            refcount_blocks = total.div_ceil(REFCOUNTS_PER_BLOCK);
+        }
+    }
+}
+
+fn write_at(file: &File, buf: &[u8], cluster: u64) -> Result<()> {
+    let offset = ByteOffset::new(cluster * CLUSTER_SIZE)?;
+    if io::write_full_at(file, buf, offset)? != buf.len() {
+        bail!("Short write to the image at cluster {}", cluster)
+    }
+    Ok(())
+}
+
+/// Writes `state` to a new qcow2 image at `path`, returns the number of data clusters.
+pub fn write_image<P: AsRef<Path>>(state: &mut StateExport, path: P) -> Result<u64> {
+    let size = state.size();
+    let sector_size = state.sector_size() as u64;
+    let mut guest_clusters = BTreeSet::new();
+    for (sector, nr_sectors) in state.log_extents() {
+        let start = sector * sector_size;
+        let end = (start + nr_sectors * sector_size).min(size);
+        if start < end {
+            guest_clusters.extend(start / CLUSTER_SIZE..clusters(end));
+        }
+    }
+    let l2_indices: BTreeSet<u64> = guest_clusters.iter().map(|cluster| cluster / L2_ENTRIES).collect();
+    let layout = Layout::new(size, l2_indices.len() as u64, guest_clusters.len() as u64);
+
+    let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+    let mut header = BytesMut::with_capacity(72);
+    header.put_u32(MAGIC);
+    header.put_u32(VERSION);
+    header.put_u64(0); // backing file offset
+    header.put_u32(0); // backing file name length
+    header.put_u32(CLUSTER_BITS);
+    header.put_u64(size);
+    header.put_u32(0); // no encryption
+    header.put_u32(layout.l1_size as u32);
+    header.put_u64(layout.l1_offset * CLUSTER_SIZE);
+    header.put_u64(layout.refcount_table_offset * CLUSTER_SIZE);
+    header.put_u32(layout.refcount_table_clusters as u32);
+    header.put_u32(0); // no snapshots
+    header.put_u64(0);
+    write_at(&file, &header, 0)?;
+
+    let mut l1 = BytesMut::zeroed(layout.l1_size as usize * 8);
+    let mut l2_tables = vec![BytesMut::zeroed(CLUSTER_SIZE as usize); l2_indices.len()];
+    let l2_slots: Vec<u64> = l2_indices.into_iter().collect();
+    for (i, l1_index) in l2_slots.iter().enumerate() {
+        let entry = ((layout.l2_offset + i as u64) * CLUSTER_SIZE) | OFLAG_COPIED;
+        l1[*l1_index as usize * 8..][..8].copy_from_slice(&entry.to_be_bytes());
+    }
+
+    let mut buf = vec![0_u8; CLUSTER_SIZE as usize];
+    for (i, cluster) in guest_clusters.iter().enumerate() {
+        let host = layout.data_offset + i as u64;
+        let offset = cluster * CLUSTER_SIZE;
+        let len = CLUSTER_SIZE.min(size - offset) as usize;
+        buf.iter_mut().for_each(|byte| *byte = 0);
+        state.read(offset, &mut buf[..len])?;
+        write_at(&file, &buf, host)?;
+
+        let slot = l2_slots.binary_search(&(cluster / L2_ENTRIES)).unwrap();
+        let entry = (host * CLUSTER_SIZE) | OFLAG_COPIED;
+        l2_tables[slot][(cluster % L2_ENTRIES) as usize * 8..][..8].copy_from_slice(&entry.to_be_bytes());
+    }
+    write_at(&file, &l1, layout.l1_offset)?;
+    for (i, table) in l2_tables.iter().enumerate() {
+        write_at(&file, table, layout.l2_offset + i as u64)?;
+    }
+
+    let mut refcount_table = BytesMut::with_capacity(layout.refcount_blocks as usize * 8);
+    for block in 0..layout.refcount_blocks {
+        refcount_table.put_u64((layout.refcount_blocks_offset + block) * CLUSTER_SIZE);
+    }
+    write_at(&file, &refcount_table, layout.refcount_table_offset)?;
+    for block in 0..layout.refcount_blocks {
+        let first = block * REFCOUNTS_PER_BLOCK;
+        let mut refcounts = BytesMut::zeroed(CLUSTER_SIZE as usize);
+        for cluster in first..layout.total.min(first + REFCOUNTS_PER_BLOCK) {
+            refcounts[((cluster - first) * 2) as usize..][..2].copy_from_slice(&1_u16.to_be_bytes());
+        }
+        write_at(&file, &refcounts, layout.refcount_blocks_offset + block)?;
+    }
+    // Every cluster is written out in full, but make sure the last one is
+    file.set_len(layout.total * CLUSTER_SIZE)?;
+    file.sync_all()?;
+    Ok(guest_clusters.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use crate::log_writes::LogWriteEntry;
+    use crate::qcow2::{write_image, Layout, CLUSTER_SIZE, L2_ENTRIES};
+    use crate::state::StateExport;
+    use crate::writer::LogWriter;
+
+    fn be64(buf: &[u8], offset: u64) -> u64 {
+        u64::from_be_bytes(buf[offset as usize..][..8].try_into().unwrap()) & !(1 << 63)
+    }
+
+    /// Follows L1 and L2 like QEMU does, unallocated clusters read as zeros.
+    fn read_cluster(image: &[u8], cluster: u64) -> Vec<u8> {
+        let l1_offset = be64(image, 40);
+        let l2_offset = be64(image, l1_offset + cluster / L2_ENTRIES * 8);
+        if l2_offset == 0 {
+            return vec![0; CLUSTER_SIZE as usize];
+        }
+        match be64(image, l2_offset + cluster % L2_ENTRIES * 8) {
+            0 => vec![0; CLUSTER_SIZE as usize],
+            data => image[data as usize..][..CLUSTER_SIZE as usize].to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout::new(1 << 30, 1, 3);
+        assert_eq!((layout.l1_size, layout.refcount_blocks, layout.data_offset, layout.total), (2, 1, 5, 8));
+        // 40000 clusters need a second refcount block
+        assert_eq!(Layout::new(1 << 40, 5, 40000).refcount_blocks, 2);
+    }
+
+    #[test]
+    fn test_write_image() {
+        let dir = std::env::temp_dir();
+        let log_path = dir.join(format!("log-write-qcow2-{}.log", std::process::id()));
+        let image_path = dir.join(format!("log-write-qcow2-{}.qcow2", std::process::id()));
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        writer.append(&LogWriteEntry { sector: 1, nr_sectors: 2, flags: 0, data_len: 0, cmd: String::new() }, &[7; 1024]).unwrap();
+        // Lands in the second L2 table
+        let far = L2_ENTRIES * CLUSTER_SIZE / 512 + 8;
+        writer.append(&LogWriteEntry { sector: far, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() }, &[9; 512]).unwrap();
+        writer.sync().unwrap();
+
+        let mut state = StateExport::open(&log_path, 1, None, None).unwrap();
+        assert_eq!(write_image(&mut state, &image_path).unwrap(), 2);
+        let image = std::fs::read(&image_path).unwrap();
+        std::fs::remove_file(&log_path).unwrap();
+        std::fs::remove_file(&image_path).unwrap();
+
+        assert_eq!(&image[..4], b"QFI\xfb");
+        assert_eq!(be64(&image, 24), (far + 1) * 512);
+        let first = read_cluster(&image, 0);
+        assert_eq!((&first[..512], &first[512..1536], &first[1536..]), (&[0; 512][..], &[7; 1024][..], &vec![0; CLUSTER_SIZE as usize - 1536][..]));
+        assert_eq!(read_cluster(&image, 1), vec![0; CLUSTER_SIZE as usize]);
+        assert_eq!(&read_cluster(&image, L2_ENTRIES)[4096..4608], &[9; 512][..]);
+        // One refcount for every cluster in the file
+        let refcount_block = be64(&image, be64(&image, 48));
+        let nr_clusters = image.len() as u64 / CLUSTER_SIZE;
+        assert!((0..nr_clusters).all(|i| image[(refcount_block + i * 2) as usize..][..2] == [0, 1]));
+        assert_eq!(image[(refcount_block + nr_clusters * 2) as usize..][..2], [0, 0]);
+    }
+}
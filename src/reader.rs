@@ -1,4 +1,3 @@
-#[cfg(target_os = "linux")]
 use std::io::Read;
 use std::fs::File;
 use anyhow::{Result, anyhow};
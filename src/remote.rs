@@ -0,0 +1,324 @@
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use anyhow::{Result, bail, anyhow};
+use bytes::{BufMut, BytesMut};
+use tracing::info;
+use crate::error::ErrorKind;
+use crate::io::{self, ByteOffset};
+use crate::log_reader::LogReader;
+use crate::metrics;
+use crate::log_writes::{LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+use crate::util::shell_quote;
+
+const REMOTE_MAGIC: u64 = 0x4c57_5245_4d4f_5445;
+const REMOTE_VERSION: u32 = 1;
+
+const FRAME_ENTRY: u8 = 1;
+const FRAME_END: u8 = 2;
+
+const REPLY_OK: u8 = 0;
+const REPLY_ERR: u8 = 1;
+
+/// Largest discard we zero in one write on the receiving end
+const ZERO_CHUNK: u64 = 1024 * 1024;
+
+/// Where a remote replay gets applied.
+#[derive(Debug, PartialEq)]
+pub enum RemoteTarget {
+    /// A `receive --listen` already running on this address
+    Tcp(String),
+    /// `[user@]host:/dev/sdX`, reached by running the receiver over ssh
+    Ssh { host: String, path: String },
+}
+
+impl RemoteTarget {
+    pub fn parse(target: &str) -> Self {
+        if let Some(addr) = target.strip_prefix("tcp://") {
+            return RemoteTarget::Tcp(addr.to_string());
+        }
+        match target.split_once(":/") {
+            Some((host, path)) => RemoteTarget::Ssh {
+                host: host.to_string(),
+                path: format!("/{}", path),
+            },
+            None => RemoteTarget::Tcp(target.to_string()),
+        }
+    }
+}
+
+/// Sending side of a connection to a receiver.
+pub struct RemoteSink {
+    reader: Box<dyn Read>,
+    writer: BufWriter<Box<dyn Write>>,
+    child: Option<Child>,
+}
+
+impl RemoteSink {
+    /// `receiver` is the log-write binary to run on the far end of an ssh
+    /// target, `receive_args` more arguments for its `receive`, like `--yes`.
+    pub fn connect(target: &RemoteTarget, receiver: &str, receive_args: &[&str]) -> Result<Self> {
+        match target {
+            RemoteTarget::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)
+                    .map_err(|error| anyhow!("Error connecting to {}: {}", addr, error))?;
+                stream.set_nodelay(true)?;
+                Ok(Self {
+                    reader: Box::new(stream.try_clone()?),
+                    writer: BufWriter::new(Box::new(stream)),
+                    child: None,
+                })
+            }
+            RemoteTarget::Ssh { host, path } => {
+                let mut command = format!("{} receive --stdio --replay {}", receiver, shell_quote(path));
+                for arg in receive_args {
+                    command.push(' ');
+                    command.push_str(&shell_quote(arg));
+                }
+                let mut child = Command::new("ssh")
+                    .arg("-T")
+                    .arg(host)
+                    .arg(command)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|error| anyhow!("Error running ssh {}: {}", host, error))?;
+                let stdin = child.stdin.take().unwrap();
+                let stdout = child.stdout.take().unwrap();
+                Ok(Self {
+                    reader: Box::new(stdout),
+                    writer: BufWriter::new(Box::new(stdin)),
+                    child: Some(child),
+                })
+            }
+        }
+    }
+
+    fn hello(&mut self, sector_size: u32) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(16);
+        buf.put_u64(REMOTE_MAGIC);
+        buf.put_u32(REMOTE_VERSION);
+        buf.put_u32(sector_size);
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn send_entry(&mut self, entry: &LogWriteEntry, data: &[u8]) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(37);
+        buf.put_u8(FRAME_ENTRY);
+        buf.put_u64(entry.sector);
+        buf.put_u64(entry.nr_sectors);
+        buf.put_u64(entry.flags);
+        buf.put_u32(u32::try_from(data.len()).map_err(|_| anyhow!("Entry data of {} bytes is too big for a frame", data.len()))?);
+        self.writer.write_all(&buf)?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    /// Tells the receiver we're done and waits for it to sync the target.
+    fn finish(mut self) -> Result<()> {
+        self.writer.write_all(&[FRAME_END])?;
+        self.writer.flush()?;
+        let mut reply = [0_u8; 1];
+        self.reader.read_exact(&mut reply)
+            .map_err(|error| anyhow!("Error reading reply from receiver: {}", error))?;
+        if let Some(mut child) = self.child.take() {
+            drop(self.writer);
+            child.wait()?;
+        }
+        if reply[0] != REPLY_OK {
+            bail!("Receiver failed to replay the log")
+        }
+        Ok(())
+    }
+}
+
+/// Streams entries of `reader` to `sink` until `stop` returns true for an entry
+/// (given the number of entries sent so far), returns the number sent.
+pub fn send<F>(reader: &mut LogReader, mut sink: RemoteSink, mut stop: F) -> Result<u64>
+    where F: FnMut(&LogWriteEntry, u64) -> bool
+{
+    sink.hello(reader.sector_size())?;
+    let mut num_entries = 0;
+    while let Some(log_entry) = reader.next_entry()? {
+        // Checked before allocating, the header's size is straight from the log
+        let size = reader.data_size(&log_entry.entry);
+        if u32::try_from(size).is_err() {
+            bail!("Entry {} has {} bytes of data, too big for a frame", log_entry.index, size)
+        }
+        let left = reader.size()?.saturating_sub(reader.data_offset(&log_entry));
+        if size > left {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Entry {} has {} bytes of data but the log ends {} bytes on", log_entry.index, size, left)))
+        }
+        let mut data = vec![0_u8; size as usize];
+        reader.read_at(&mut data, reader.data_offset(&log_entry))?;
+        sink.send_entry(&log_entry.entry, &data)
+            .map_err(|error| anyhow!("Error sending entry {}: {}", log_entry.index, error))?;
+        num_entries += 1;
+        metrics::record_entry(&log_entry.entry, reader.sector_size(), num_entries);
+        if stop(&log_entry.entry, num_entries) {
+            break;
+        }
+    }
+    sink.finish()?;
+    Ok(num_entries)
+}
+
+fn read_u8<R: Read>(stream: &mut R) -> Result<u8> {
+    let mut buf = [0_u8; 1];
+    stream.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(stream: &mut R) -> Result<u32> {
+    let mut buf = [0_u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(stream: &mut R) -> Result<u64> {
+    let mut buf = [0_u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn zero(replay: &File, offset: u64, len: u64) -> Result<()> {
+    let zeros = vec![0_u8; ZERO_CHUNK.min(len) as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = &zeros[..ZERO_CHUNK.min(len - done) as usize];
+        if io::write_full_at(replay, chunk, ByteOffset::new(offset + done)?)? != chunk.len() {
+            bail!("Short write zeroing {} bytes at {}", chunk.len(), offset + done)
+        }
+        done += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// Writes the next `len` bytes of `input` at `offset`, a chunk at a time so
+/// the sender can't make us allocate what it likes.
+fn copy<R: Read>(input: &mut R, replay: &File, offset: u64, len: u64) -> Result<()> {
+    let mut buf = vec![0_u8; ZERO_CHUNK.min(len) as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut buf[..ZERO_CHUNK.min(len - done) as usize];
+        input.read_exact(chunk)?;
+        if io::write_full_at(replay, chunk, ByteOffset::new(offset + done)?)? != chunk.len() {
+            bail!("Short write of {} bytes at {}", chunk.len(), offset + done)
+        }
+        done += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+fn apply<R: Read>(input: &mut R, replay: &File) -> Result<u64> {
+    if read_u64(input)? != REMOTE_MAGIC {
+        bail!("Bad magic from sender")
+    }
+    let version = read_u32(input)?;
+    if version != REMOTE_VERSION {
+        bail!("Unsupported protocol version {}", version)
+    }
+    let sector_size = read_u32(input)?;
+
+    let mut num_entries = 0;
+    loop {
+        match read_u8(input)? {
+            FRAME_ENTRY => {}
+            FRAME_END => break,
+            frame => bail!("Unknown frame {} from sender", frame),
+        }
+        let sector = read_u64(input)?;
+        let nr_sectors = read_u64(input)?;
+        let flags = read_u64(input)?;
+        let data_len = read_u32(input)? as u64;
+
+        let offset = ByteOffset::from_sectors(sector, sector_size)?;
+        let expected = if (flags & LOG_DISCARD_FLAG) > 0 { 0 } else { io::sectors_bytes(nr_sectors, sector_size)? };
+        if data_len != expected {
+            bail!("Entry {} has {} bytes of data, its {} sectors need {}", num_entries, data_len, nr_sectors, expected)
+        }
+        if (flags & LOG_DISCARD_FLAG) > 0 {
+            zero(replay, offset.get(), io::sectors_bytes(nr_sectors, sector_size)?)?;
+        } else if (flags & LOG_MARK_FLAG) > 0 {
+            std::io::copy(&mut input.by_ref().take(data_len), &mut std::io::sink())?;
+        } else {
+            copy(input, replay, offset.get(), data_len)
+                .map_err(|error| anyhow!("Error writing entry {} at {}: {}", num_entries, offset, error))?;
+        }
+        // The target is real hardware, so keep the flush ordering the log recorded
+        if (flags & (LOG_FLUSH_FLAG | LOG_FUA_FLAG)) > 0 {
+            replay.sync_data()?;
+        }
+        num_entries += 1;
+    }
+    replay.sync_all()?;
+    Ok(num_entries)
+}
+
+/// Applies one sender's stream to `replay_path`, replying once it's synced.
+pub fn receive<R: Read, W: Write>(input: R, mut output: W, replay_path: &str) -> Result<u64> {
+    let replay = OpenOptions::new().write(true).open(replay_path)
+        .map_err(|error| anyhow!("Error opening {}: {}", replay_path, error))?;
+    let result = apply(&mut BufReader::new(input), &replay);
+    let reply = if result.is_ok() { REPLY_OK } else { REPLY_ERR };
+    // The sender may already be gone if it was the one that failed
+    let _ = output.write_all(&[reply]).and_then(|_| output.flush());
+    result
+}
+
+/// Accepts a single sender on `listener` and replays its entries onto `replay_path`.
+pub fn serve(listener: &TcpListener, replay_path: &str) -> Result<u64> {
+    let (stream, addr) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    info!("sender connected from {}", addr);
+    receive(stream.try_clone()?, stream, replay_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use crate::remote::{apply, RemoteTarget, FRAME_END, FRAME_ENTRY, REMOTE_MAGIC, REMOTE_VERSION};
+
+    fn stream(data_len: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_u64(REMOTE_MAGIC);
+        buf.put_u32(REMOTE_VERSION);
+        buf.put_u32(512);
+        buf.put_u8(FRAME_ENTRY);
+        buf.put_u64(1);
+        buf.put_u64(1);
+        buf.put_u64(0);
+        buf.put_u32(data_len);
+        buf.put_slice(data);
+        buf.put_u8(FRAME_END);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn test_apply_checks_data_len() {
+        let path = std::env::temp_dir().join(format!("log-write-remote-{}.img", std::process::id()));
+        std::fs::write(&path, [0_u8; 2048]).unwrap();
+        let replay = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let applied = apply(&mut &stream(512, &[7; 512])[..], &replay).unwrap();
+        // A length the sector count doesn't need is refused before anything is read into memory
+        let oversized = apply(&mut &stream(u32::MAX, &[])[..], &replay);
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(applied, 1);
+        assert!(oversized.is_err());
+        assert_eq!((image[511], image[512], image[1024]), (0, 7, 0));
+    }
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(RemoteTarget::parse("root@lab1:/dev/sdb"), RemoteTarget::Ssh {
+            host: "root@lab1".to_string(),
+            path: "/dev/sdb".to_string(),
+        });
+        assert_eq!(RemoteTarget::parse("lab1:10810"), RemoteTarget::Tcp("lab1:10810".to_string()));
+        assert_eq!(RemoteTarget::parse("tcp://lab1:10810"), RemoteTarget::Tcp("lab1:10810".to_string()));
+    }
+}
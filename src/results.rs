@@ -0,0 +1,143 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Result, anyhow};
+use rusqlite::{Connection, params, OptionalExtension};
+use crate::checksum::{self, Xxh3};
+use crate::io;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    log_path TEXT NOT NULL,
+    started_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS checkpoints (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    entry INTEGER NOT NULL,
+    mark TEXT,
+    exit_code INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    device_hash TEXT,
+    PRIMARY KEY (run_id, entry)
+);
+";
+
+#[derive(Debug, Clone)]
+pub struct CheckpointResult {
+    pub entry: u64,
+    pub mark: Option<String>,
+    pub exit_code: i32,
+    pub duration_ms: u64,
+    pub device_hash: Option<String>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CheckpointHistory {
+    pub passed: u64,
+    pub failed: u64,
+}
+
+pub struct ResultsStore {
+    conn: Connection,
+    run_id: i64,
+}
+
+impl ResultsStore {
+    /// Opens (or creates) the results database and starts a run for `log_path`.
+    /// When `resume` is set the most recent run for the same log is continued instead.
+    pub fn open<P: AsRef<Path>>(db_path: P, log_path: &str, resume: bool) -> Result<Self> {
+        let conn = Connection::open(db_path).map_err(|error| {
+            anyhow!("Error opening results db: {}", error)
+        })?;
+        conn.execute_batch(SCHEMA)?;
+        let log_path = std::fs::canonicalize(log_path)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| log_path.to_string());
+
+        let last_run: Option<i64> = if resume {
+            conn.query_row(
+                "SELECT id FROM runs WHERE log_path = ?1 ORDER BY id DESC LIMIT 1",
+                params![log_path],
+                |row| row.get(0),
+            ).optional()?
+        } else {
+            None
+        };
+
+        let run_id = match last_run {
+            Some(run_id) => run_id,
+            None => {
+                let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+                conn.execute(
+                    "INSERT INTO runs (log_path, started_at) VALUES (?1, ?2)",
+                    params![log_path, started_at],
+                )?;
+                conn.last_insert_rowid()
+            }
+        };
+
+        Ok(Self { conn, run_id })
+    }
+
+    pub fn record(&self, result: &CheckpointResult) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO checkpoints (run_id, entry, mark, exit_code, duration_ms, device_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                self.run_id,
+                result.entry as i64,
+                result.mark,
+                result.exit_code,
+                result.duration_ms as i64,
+                result.device_hash
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns true if this run already checked `entry` successfully, so a resumed
+    /// campaign can skip it.
+    pub fn is_done(&self, entry: u64) -> Result<bool> {
+        let found: Option<i32> = self.conn.query_row(
+            "SELECT exit_code FROM checkpoints WHERE run_id = ?1 AND entry = ?2",
+            params![self.run_id, entry as i64],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(found == Some(0))
+    }
+
+    /// Outcomes of `entry` across previous runs of the same log, used to tell a new
+    /// failure from a flaky one.
+    pub fn history(&self, entry: u64) -> Result<CheckpointHistory> {
+        let mut stmt = self.conn.prepare(
+            "SELECT c.exit_code FROM checkpoints c JOIN runs r ON c.run_id = r.id
+             WHERE r.log_path = (SELECT log_path FROM runs WHERE id = ?1)
+             AND c.run_id != ?1 AND c.entry = ?2",
+        )?;
+        let mut history = CheckpointHistory::default();
+        let codes = stmt.query_map(params![self.run_id, entry as i64], |row| row.get::<_, i32>(0))?;
+        for code in codes {
+            if code? == 0 {
+                history.passed += 1;
+            } else {
+                history.failed += 1;
+            }
+        }
+        Ok(history)
+    }
+}
+
+pub fn hash_device<P: AsRef<Path>>(path: P) -> Result<String> {
+    let file = File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0_u8; 1024 * 1024];
+    loop {
+        let ret = io::read_full(&file, &mut buf)?;
+        if ret == 0 {
+            break;
+        }
+        hasher.update(&buf[..ret]);
+    }
+    Ok(checksum::hex(hasher.digest()))
+}
@@ -0,0 +1,72 @@
+use std::thread;
+use std::time::Duration;
+use anyhow::Result;
+#[cfg(unix)]
+use crate::io::Errno;
+use tracing::warn;
+use crate::io;
+
+/// How often to retry target I/O failing with an error flaky iSCSI or USB
+/// targets recover from, doubling the delay every time.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+#[cfg(unix)]
+fn transient(error: &anyhow::Error) -> bool {
+    matches!(io::errno(error), Some(Errno::EIO) | Some(Errno::ENOSPC) | Some(Errno::EAGAIN))
+}
+
+/// ERROR_CRC, ERROR_DISK_FULL and ERROR_IO_DEVICE
+#[cfg(windows)]
+fn transient(error: &anyhow::Error) -> bool {
+    matches!(io::errno(error), Some(23) | Some(112) | Some(1117))
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        Self { retries, delay }
+    }
+
+    /// Runs `f` until it succeeds, fails with a permanent error or runs out of retries.
+    pub fn run<T, F: FnMut() -> Result<T>>(&self, what: &str, mut f: F) -> Result<T> {
+        let mut delay = self.delay;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(error) if attempt < self.retries && transient(&error) => {
+                    attempt += 1;
+                    warn!("{} failed: {}, retry {} of {} in {:?}", what, error, attempt, self.retries, delay);
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use anyhow::anyhow;
+    use nix::errno::Errno;
+    use crate::retry::RetryPolicy;
+
+    #[test]
+    fn test_retry() {
+        let policy = RetryPolicy::new(2, Duration::from_millis(1));
+        let mut calls = 0;
+        let result = policy.run("write", || {
+            calls += 1;
+            if calls < 3 { Err(anyhow::Error::from(Errno::EIO)) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        calls = 0;
+        assert!(policy.run("write", || -> anyhow::Result<()> { calls += 1; Err(anyhow!("permanent")) }).is_err());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+use anyhow::{Result, bail};
+use tracing::warn;
+use crate::io;
+use crate::sys;
+
+/// Whether `target` is the source of any mount.
+pub fn mounted(target: &Path) -> Result<bool> {
+    let target = fs::canonicalize(target)?;
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    Ok(mounts.lines().filter_map(|line| line.split_whitespace().next())
+        .filter(|source| source.starts_with('/'))
+        .any(|source| fs::canonicalize(source).is_ok_and(|source| source == target)))
+}
+
+/// The model the kernel reports for `device`, partitions report their disk's.
+fn model(device: &Path) -> Option<String> {
+    let name = fs::canonicalize(device).ok()?.file_name()?.to_string_lossy().to_string();
+    let sys = fs::canonicalize(Path::new("/sys/class/block").join(name)).ok()?;
+    [sys.join("device/model"), sys.join("../device/model")].iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|model| model.trim().to_string())
+}
+
+/// Refuses to write to a block device that is in use unless `force`, then asks
+/// for confirmation on the terminal unless `yes`. Files are always fine.
+pub fn check_target(target: &str, force: bool, yes: bool) -> Result<()> {
+    let path = Path::new(target);
+    let is_block = fs::metadata(path).map(|metadata| sys::is_block_device(&metadata)).unwrap_or(false);
+    if !is_block {
+        return Ok(());
+    }
+    if !force {
+        if mounted(path)? {
+            bail!("{} is mounted, refusing to write to it without --force", target)
+        }
+        if sys::held_open(path) {
+            bail!("{} is in use, refusing to write to it without --force", target)
+        }
+    }
+    if yes {
+        return Ok(());
+    }
+
+    let size = io::block_device_size(&fs::File::open(path)?)?;
+    let model = model(path).unwrap_or_else(|| "unknown model".to_string());
+    if !std::io::stdin().is_terminal() {
+        bail!("Replaying will overwrite {} ({}, {} bytes), pass --yes to confirm", target, model, size)
+    }
+    eprint!("Replaying will overwrite {} ({}, {} bytes). Type yes to continue: ", target, model, size);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    if answer.trim() != "yes" {
+        bail!("Not confirmed, leaving {} alone", target)
+    }
+    Ok(())
+}
+
+/// Warns when `target` is a block device whose logical block size isn't the
+/// log's sector size, a sign of a bogus superblock that shifts every write.
+pub fn check_sector_size(target: &str, sector_size: u32) -> Result<()> {
+    let is_block = fs::metadata(target).map(|metadata| sys::is_block_device(&metadata)).unwrap_or(false);
+    if !is_block {
+        return Ok(());
+    }
+    let block_size = match sys::logical_block_size(&fs::File::open(target)?) {
+        Ok(block_size) => block_size,
+        Err(_) => return Ok(()),
+    };
+    if block_size != sector_size {
+        warn!("{} has {} byte blocks but the log has {} byte sectors, pass --sector-size if its superblock is wrong", target, block_size, sector_size);
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+//! Logs cut short, by a crash during capture or a copy that didn't finish:
+//! finding the entry the log ends in, and `--salvage` writing a log of the
+//! entries before it with the superblock counting only those.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use crate::log_reader::LogReader;
+use crate::log_writes::LogWriteSuper;
+
+/// Entries are copied in pieces this big.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Where a log ends before its superblock says it should.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Truncation {
+    /// The first entry that isn't all there, the entries before it are complete
+    pub entry: u64,
+    /// Where that entry starts in the log
+    pub offset: u64,
+    /// Bytes its header and data take
+    pub expected: u64,
+    /// Bytes of it the log has
+    pub available: u64,
+}
+
+impl fmt::Display for Truncation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the log ends in entry {} at offset {}, which takes {} bytes but only {} are there",
+               self.entry, self.offset, self.expected, self.available)
+    }
+}
+
+/// The entry the log `reader` reads ends in, none if every entry the
+/// superblock counts is complete.
+pub fn find_truncation(reader: &LogReader) -> Result<Option<Truncation>> {
+    let len = reader.size()?;
+    let sector_size = reader.sector_size() as u64;
+    let mut reader = reader.clone();
+    reader.seek_to_entry(0, sector_size);
+    let mut offset = sector_size;
+    for index in 0..reader.nr_entries() {
+        if offset.saturating_add(sector_size) > len {
+            return Ok(Some(Truncation { entry: index, offset, expected: sector_size, available: len.saturating_sub(offset) }));
+        }
+        let Some(log_entry) = reader.next_entry()? else { break };
+        let size = reader.data_size(&log_entry.entry);
+        let end = reader.data_offset(&log_entry).saturating_add(size);
+        if end > len {
+            return Ok(Some(Truncation { entry: index, offset, expected: sector_size.saturating_add(size), available: len - offset }));
+        }
+        offset = end;
+    }
+    Ok(None)
+}
+
+/// Writes the entries of `reader`'s log before `truncation` to `path`, a
+/// valid log that replays everything the truncated one could.
+pub fn write_trimmed<P: AsRef<Path>>(reader: &LogReader, truncation: &Truncation, path: P) -> Result<()> {
+    let file = File::create(&path)
+        .map_err(|error| anyhow!("Error creating {}: {}", path.as_ref().display(), error))?;
+    let mut out = BufWriter::new(file);
+    let mut buf = Vec::new();
+    let mut offset = 0;
+    while offset < truncation.offset {
+        buf.resize((truncation.offset - offset).min(CHUNK_SIZE) as usize, 0);
+        reader.read_at(&mut buf, offset)?;
+        out.write_all(&buf)?;
+        offset += buf.len() as u64;
+    }
+    let log_super = LogWriteSuper { nr_entries: truncation.entry, ..reader.log_super };
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&log_super.to_bytes())?;
+    out.into_inner().map_err(|error| anyhow!("Error writing {}: {}", path.as_ref().display(), error))?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_reader::LogReader;
+    use crate::log_writes::LogWriteEntry;
+    use crate::salvage::{find_truncation, write_trimmed, Truncation};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_salvage_truncated_log() {
+        let dir = std::env::temp_dir();
+        let log_path = dir.join(format!("log-write-truncated-{}.log", std::process::id()));
+        let trimmed_path = dir.join(format!("log-write-trimmed-{}.log", std::process::id()));
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        for sector in 0..3 {
+            writer.append(&LogWriteEntry { sector, nr_sectors: 2, flags: 0, data_len: 0, cmd: String::new() }, &[sector as u8; 1024]).unwrap();
+        }
+        writer.sync().unwrap();
+        assert_eq!(find_truncation(&LogReader::open(&log_path).unwrap()).unwrap(), None);
+
+        // Half of the data of the last entry, which starts after the superblock and two entries of 1536 bytes
+        let log = std::fs::read(&log_path).unwrap();
+        std::fs::write(&log_path, &log[..log.len() - 512]).unwrap();
+        let reader = LogReader::open(&log_path).unwrap();
+        let truncation = find_truncation(&reader).unwrap().unwrap();
+        assert_eq!(truncation, Truncation { entry: 2, offset: 512 + 2 * 1536, expected: 1536, available: 1024 });
+
+        write_trimmed(&reader, &truncation, &trimmed_path).unwrap();
+        let trimmed = LogReader::open(&trimmed_path).unwrap();
+        let sectors: Vec<u64> = trimmed.clone().map(|entry| entry.unwrap().entry.sector).collect();
+        let trimmed_truncation = find_truncation(&trimmed).unwrap();
+        std::fs::remove_file(&log_path).unwrap();
+        std::fs::remove_file(&trimmed_path).unwrap();
+        assert_eq!(sectors, [0, 1]);
+        assert_eq!(trimmed_truncation, None);
+    }
+}
@@ -0,0 +1,128 @@
+//! Self-contained shell scripts reproducing a log with dd and blkdiscard, for
+//! machines log-write can't be installed on. Write data is embedded as base64.
+
+use std::io::Write;
+use anyhow::{Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use crate::entries::MarkPattern;
+use crate::log_reader::LogReader;
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+use crate::util;
+
+const LINE_LEN: usize = 76;
+
+fn header<W: Write>(out: &mut W, log_path: &str, sector_size: u32) -> Result<()> {
+    write!(out, r#"#!/bin/sh
+# Reproduces the writes of {log} on the device or image given as $1.
+set -e
+DEV="$1"
+[ -n "$DEV" ] || {{ echo "usage: $0 DEVICE" >&2; exit 1; }}
+
+# write SECTOR, data as base64 on stdin
+write() {{
+    base64 -d | dd of="$DEV" ibs=65536 obs={ss} seek="$1" conv=notrunc 2>/dev/null
+}}
+
+# zero SECTOR COUNT
+zero() {{
+    dd if=/dev/zero of="$DEV" bs={ss} seek="$1" count="$2" conv=notrunc 2>/dev/null
+}}
+
+# discard SECTOR COUNT, zeroing files and devices blkdiscard can't handle
+discard() {{
+    if [ -b "$DEV" ] && blkdiscard -o $(($1 * {ss})) -l $(($2 * {ss})) "$DEV" 2>/dev/null; then
+        return
+    fi
+    zero "$1" "$2"
+}}
+
+"#, log = log_path.replace('\n', " "), ss = sector_size)?;
+    Ok(())
+}
+
+/// Writes a script replaying `reader` up to and including the first mark `end_mark`
+/// matches, or the whole log. Returns the number of entries it covers.
+pub fn write_script<W: Write>(reader: &mut LogReader, log_path: &str, end_mark: Option<&MarkPattern>, out: &mut W) -> Result<u64> {
+    let sector_size = reader.sector_size();
+    header(out, log_path, sector_size)?;
+    let mut num_entries = 0;
+    let mut buf = Vec::new();
+    let mut found = false;
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        num_entries += 1;
+        writeln!(out, "# entry {}", log_entry.index)?;
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            writeln!(out, "echo mark {}", util::shell_quote(&entry.cmd))?;
+            if end_mark.is_some_and(|mark| mark.matches(&entry.cmd)) {
+                found = true;
+                break;
+            }
+            continue;
+        }
+        if (entry.flags & LOG_FLUSH_FLAG) > 0 {
+            writeln!(out, "sync")?;
+        }
+        if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+            writeln!(out, "discard {} {}", entry.sector, entry.nr_sectors)?;
+        } else if entry.nr_sectors > 0 {
+            buf.resize(reader.data_size(entry) as usize, 0);
+            reader.read_at(&mut buf, reader.data_offset(&log_entry))?;
+            if util::is_zero(&buf) {
+                writeln!(out, "zero {} {}", entry.sector, entry.nr_sectors)?;
+            } else {
+                writeln!(out, "write {} <<'EOF'", entry.sector)?;
+                let encoded = STANDARD.encode(&buf);
+                for line in encoded.as_bytes().chunks(LINE_LEN) {
+                    out.write_all(line)?;
+                    writeln!(out)?;
+                }
+                writeln!(out, "EOF")?;
+            }
+        }
+        if (entry.flags & LOG_FUA_FLAG) > 0 {
+            writeln!(out, "sync")?;
+        }
+    }
+    if let (false, Some(mark)) = (found, end_mark) {
+        bail!("Mark {} not found", mark)
+    }
+    writeln!(out, "sync")?;
+    Ok(num_entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{LOG_DISCARD_FLAG, LOG_FLUSH_FLAG};
+    use crate::script::write_script;
+    use crate::testutil::{entry, write, TempPath};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_write_script() {
+        let path = TempPath::new("script.log");
+        let mut writer = LogWriter::create(&path, 512).unwrap();
+        writer.append(&write(8, 1), &[0xff; 512]).unwrap();
+        writer.append(&entry(0, 2, LOG_FLUSH_FLAG), &[0; 1024]).unwrap();
+        writer.mark("it's").unwrap();
+        writer.append(&entry(4, 4, LOG_DISCARD_FLAG), &[]).unwrap();
+        writer.sync().unwrap();
+
+        let mut script = Vec::new();
+        assert_eq!(write_script(&mut LogReader::open(&path).unwrap(), "a.log", Some(&"it's".parse().unwrap()), &mut script).unwrap(), 3);
+        let mut full = Vec::new();
+        write_script(&mut LogReader::open(&path).unwrap(), "a.log", None, &mut full).unwrap();
+        let missing = write_script(&mut LogReader::open(&path).unwrap(), "a.log", Some(&"none".parse().unwrap()), &mut Vec::new()).is_err();
+
+        let script = String::from_utf8(script).unwrap();
+        let body: Vec<&str> = script.lines().skip_while(|line| !line.starts_with("# entry")).collect();
+        assert_eq!(body[..2], ["# entry 0", "write 8 <<'EOF'"]);
+        assert_eq!(body[2], "/".repeat(76));
+        assert!(script.contains("sync\nzero 0 2\n# entry 2\necho mark 'it'\\''s'\nsync\n"));
+        assert!(!script.contains("discard 4"));
+        assert!(String::from_utf8(full).unwrap().contains("discard 4 4\nsync\n"));
+        assert!(missing);
+    }
+}
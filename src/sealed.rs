@@ -0,0 +1,140 @@
+//! Logs sealed in an age container, for keeping captured filesystem data
+//! encrypted at rest. Replay needs random access the age stream doesn't give
+//! cheaply, so opening one decrypts it into a memfd first, the plaintext never
+//! touches a filesystem. The identity to decrypt with is read from the file
+//! LOG_WRITE_AGE_IDENTITY names, or passed to `open_log_with`.
+
+use std::fs::File;
+use std::path::Path;
+use anyhow::{Result, bail};
+use crate::io::{self, ByteOffset};
+
+/// First line of every binary age file
+pub const AGE_MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+pub const IDENTITY_VAR: &str = "LOG_WRITE_AGE_IDENTITY";
+
+pub fn is_sealed(file: &File) -> Result<bool> {
+    let mut buf = [0_u8; AGE_MAGIC.len()];
+    Ok(io::read_full_at(file, &mut buf, ByteOffset::ZERO)? == buf.len() && buf == AGE_MAGIC)
+}
+
+/// Opens the log at `path` for reading, decrypting it first if it's sealed.
+pub fn open_log<P: AsRef<Path>>(path: P) -> Result<File> {
+    open_log_with(path, None)
+}
+
+/// Opens the log at `path` for reading, a sealed one is decrypted with the
+/// identity file `identity`, or else the one LOG_WRITE_AGE_IDENTITY names.
+pub fn open_log_with<P: AsRef<Path>>(path: P, identity: Option<&Path>) -> Result<File> {
+    let file = File::open(path.as_ref())?;
+    if !is_sealed(&file)? {
+        return Ok(file);
+    }
+    #[cfg(feature = "age")]
+    {
+        let identity = match identity {
+            Some(identity) => identity.to_path_buf(),
+            None => std::env::var_os(IDENTITY_VAR).map(std::path::PathBuf::from).ok_or_else(|| {
+                anyhow::anyhow!("{} is sealed with age, set {} to an identity file", path.as_ref().display(), IDENTITY_VAR)
+            })?
+        };
+        unseal(file, &identity)
+    }
+    #[cfg(not(feature = "age"))]
+    {
+        let _ = identity;
+        bail!("{} is sealed with age, log-write was built without the age feature", path.as_ref().display())
+    }
+}
+
+/// A file that only lives in memory, gone once it's closed.
+#[cfg(all(feature = "age", any(target_os = "linux", target_os = "android")))]
+fn memory_file() -> Result<File> {
+    use std::os::unix::io::FromRawFd;
+    use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+    let fd = memfd_create(std::ffi::CStr::from_bytes_with_nul(b"log-write-unsealed\0")?, MemFdCreateFlag::MFD_CLOEXEC)?;
+    // SAFETY: memfd_create just returned the descriptor, nothing else owns it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Without memfd the plaintext would have to go to a temp file, which is what sealing is meant to avoid.
+#[cfg(all(feature = "age", not(any(target_os = "linux", target_os = "android"))))]
+fn memory_file() -> Result<File> {
+    bail!("Reading sealed logs needs memfd, which only Linux has")
+}
+
+#[cfg(feature = "age")]
+fn unseal(sealed: File, identity_path: &Path) -> Result<File> {
+    use std::io::{BufReader, Seek, SeekFrom};
+    let identities = age::IdentityFile::from_file(identity_path.to_string_lossy().into_owned())
+        .map_err(|error| anyhow::anyhow!("Error reading identity {}: {}", identity_path.display(), error))?
+        .into_identities()?;
+    let decryptor = age::Decryptor::new_buffered(BufReader::new(sealed))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|identity| identity.as_ref()))?;
+    let mut file = memory_file()?;
+    std::io::copy(&mut reader, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Encrypts the log at `input` to `output` for each of `recipients`, age1 public keys.
+#[cfg(feature = "age")]
+pub fn seal<P: AsRef<Path>>(input: P, output: P, recipients: &[String]) -> Result<()> {
+    use std::str::FromStr;
+    if recipients.is_empty() {
+        bail!("Sealing needs at least one recipient")
+    }
+    let recipients = recipients.iter()
+        .map(|recipient| age::x25519::Recipient::from_str(recipient).map_err(|error| anyhow::anyhow!("Invalid recipient {}: {}", recipient, error)))
+        .collect::<Result<Vec<_>>>()?;
+    let encryptor = age::Encryptor::with_recipients(recipients.iter().map(|recipient| recipient as &dyn age::Recipient))?;
+    let mut input = File::open(input)?;
+    if is_sealed(&input)? {
+        bail!("Log is already sealed")
+    }
+    let mut writer = encryptor.wrap_output(std::io::BufWriter::new(File::create(output)?))?;
+    std::io::copy(&mut input, &mut writer)?;
+    let out = writer.finish()?;
+    out.into_inner().map_err(|error| anyhow::anyhow!("Error writing sealed log: {}", error))?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "age"))]
+mod tests {
+    use age::secrecy::ExposeSecret;
+    use crate::io::{self, ByteOffset};
+    use crate::log_writes::WRITE_LOG_MAGIC;
+    use crate::sealed::{is_sealed, open_log_with, seal};
+
+    #[test]
+    fn test_seal_round_trip() {
+        let dir = std::env::temp_dir();
+        let log_path = dir.join(format!("log-write-seal-{}.log", std::process::id()));
+        let sealed_path = dir.join(format!("log-write-seal-{}.log.age", std::process::id()));
+        let identity_path = dir.join(format!("log-write-seal-{}.key", std::process::id()));
+        let mut log = vec![0_u8; 4096];
+        log[..8].copy_from_slice(&WRITE_LOG_MAGIC.to_le_bytes());
+        log[8..16].copy_from_slice(&1_u64.to_le_bytes());
+        log[24..28].copy_from_slice(&512_u32.to_le_bytes());
+        log[1024..].fill(7);
+        std::fs::write(&log_path, &log).unwrap();
+
+        let identity = age::x25519::Identity::generate();
+        std::fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+        seal(&log_path, &sealed_path, &[identity.to_public().to_string()]).unwrap();
+        assert!(is_sealed(&std::fs::File::open(&sealed_path).unwrap()).unwrap());
+        let unsealed = open_log_with(&sealed_path, Some(&identity_path)).unwrap();
+        let mut buf = vec![0_u8; 4096];
+        let read = io::read_full_at(&unsealed, &mut buf, ByteOffset::ZERO).unwrap();
+
+        std::fs::write(&identity_path, age::x25519::Identity::generate().to_string().expose_secret()).unwrap();
+        let wrong_key = open_log_with(&sealed_path, Some(&identity_path)).is_err();
+        for path in [&log_path, &sealed_path, &identity_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(read, 4096);
+        assert_eq!(buf, log);
+        assert!(wrong_key);
+    }
+}
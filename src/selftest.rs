@@ -0,0 +1,173 @@
+//! `selftest`: writes a small synthetic log, replays it into a file and checks
+//! the file holds what the log wrote, a smoke test of the platform before
+//! trusting a real run with a real log.
+//!
+//! The log has writes that overlap, FUA writes, a flush, a discard and marks,
+//! so every path of the replay is taken at least once.
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use anyhow::{Result, bail};
+use crate::conformance;
+use crate::log_reader::LogReader;
+use crate::log_writes::{Log, LogWriteEntry, LOG_DISCARD_FLAG, LOG_DISCARD_NOT_SUPP, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+use crate::sys;
+use crate::writer::LogWriter;
+
+const SECTOR_SIZE: u32 = 512;
+/// Sectors of the replayed image, every write lands inside them
+const IMAGE_SECTORS: u64 = 64;
+const NR_WRITES: u64 = 16;
+
+/// One step of the self test and how it went, what it found or why it failed.
+#[derive(Debug)]
+pub struct Step {
+    pub name: &'static str,
+    pub result: Result<String>,
+}
+
+/// The data written to `sector` by write `write`, different for every pair
+/// so a sector replayed from the wrong entry or to the wrong place shows.
+fn pattern(write: u64, sector: u64) -> Vec<u8> {
+    (0..SECTOR_SIZE as u64).map(|offset| (write * 37 + sector * 11 + offset) as u8 | 1).collect()
+}
+
+/// Writes the synthetic log to `path` and returns the image replaying all
+/// of it onto a zeroed target has to give.
+pub fn generate(path: &Path) -> Result<Vec<u8>> {
+    let sector_size = SECTOR_SIZE as usize;
+    let mut image = vec![0_u8; IMAGE_SECTORS as usize * sector_size];
+    let mut writer = LogWriter::create(path, SECTOR_SIZE)?;
+    writer.mark("selftest-start")?;
+    for write in 0..NR_WRITES {
+        let sector = (write * 5) % (IMAGE_SECTORS - 8);
+        let nr_sectors = 1 + write % 8;
+        let mut data = Vec::new();
+        for sector in sector..sector + nr_sectors {
+            data.extend(pattern(write, sector));
+        }
+        let flags = if write % 4 == 3 { LOG_FUA_FLAG } else { 0 };
+        writer.append(&LogWriteEntry { sector, nr_sectors, flags, data_len: 0, cmd: String::new() }, &data)?;
+        let start = sector as usize * sector_size;
+        image[start..start + data.len()].copy_from_slice(&data);
+
+        if write == 7 {
+            writer.append(&LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_FLUSH_FLAG, data_len: 0, cmd: String::new() }, &[])?;
+        }
+        if write == 11 {
+            writer.append(&LogWriteEntry { sector: 8, nr_sectors: 16, flags: LOG_DISCARD_FLAG, data_len: 0, cmd: String::new() }, &[])?;
+            image[8 * sector_size..24 * sector_size].fill(0);
+        }
+    }
+    writer.mark("selftest-end")?;
+    writer.sync()?;
+    Ok(image)
+}
+
+/// Reads the log back and checks it has the entries `generate` wrote.
+fn read_back(log_path: &Path) -> Result<String> {
+    let reader = LogReader::open(log_path)?;
+    let nr_entries = reader.nr_entries();
+    let (mut read, mut marks, mut flushes, mut discards) = (0, 0, 0, 0);
+    for log_entry in reader {
+        let flags = log_entry?.entry.flags;
+        read += 1;
+        if (flags & LOG_MARK_FLAG) > 0 {
+            marks += 1;
+        }
+        if (flags & LOG_FLUSH_FLAG) > 0 {
+            flushes += 1;
+        }
+        if (flags & LOG_DISCARD_FLAG) > 0 {
+            discards += 1;
+        }
+    }
+    if read != nr_entries || (marks, flushes, discards) != (2, 1, 1) {
+        bail!("Read {} of {} entries, {} marks, {} flushes and {} discards, expected {} entries, 2 marks, a flush and a discard",
+              read, nr_entries, marks, flushes, discards, NR_WRITES + 4)
+    }
+    Ok(format!("{} entries, 2 marks, a flush and a discard", read))
+}
+
+/// Replays all of the log onto a zeroed `image_path`.
+fn replay(log_path: &Path, image_path: &Path) -> Result<String> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(image_path)?
+        .set_len(IMAGE_SECTORS * SECTOR_SIZE as u64)?;
+    let mut log = Log::open(log_path, image_path)?;
+    let progress = log.replay(None, &AtomicBool::new(false))?;
+    log.fsync_replay_file()?;
+    if progress.entries_replayed != log.nr_entries {
+        bail!("Replayed {} of {} entries", progress.entries_replayed, log.nr_entries)
+    }
+    let discards = if (log.flags & LOG_DISCARD_NOT_SUPP) > 0 { "zeroed, the target can't discard" } else { "discarded" };
+    Ok(format!("{} entries onto {}, discards {}", progress.entries_replayed, image_path.display(), discards))
+}
+
+fn verify(image_path: &Path, expected: &[u8]) -> Result<String> {
+    if let Some(sector) = conformance::first_difference(File::open(image_path)?, expected, SECTOR_SIZE)? {
+        bail!("Sector {} of the image isn't what the log wrote there", sector)
+    }
+    Ok(format!("all {} sectors hold what the log wrote", IMAGE_SECTORS))
+}
+
+/// Asks `device` its size and sector size, the ioctls a replay onto it needs,
+/// without writing to it.
+fn probe(device: &Path) -> Result<String> {
+    let file = File::open(device)?;
+    let size = sys::block_device_size(&file)?;
+    let sector_size = sys::logical_block_size(&file)?;
+    Ok(format!("{} is {} bytes with {} byte sectors", device.display(), size, sector_size))
+}
+
+fn step<T>(steps: &mut Vec<Step>, name: &'static str, result: Result<(T, String)>) -> Option<T> {
+    match result {
+        Ok((value, detail)) => {
+            steps.push(Step { name, result: Ok(detail) });
+            Some(value)
+        }
+        Err(error) => {
+            steps.push(Step { name, result: Err(error) });
+            None
+        }
+    }
+}
+
+/// The steps up to and including the first that fails.
+fn log_steps(steps: &mut Vec<Step>, log_path: &Path, image_path: &Path) -> Option<()> {
+    let expected = step(steps, "generate", generate(log_path).map(|image| (image, log_path.display().to_string())))?;
+    step(steps, "read", read_back(log_path).map(|detail| ((), detail)))?;
+    step(steps, "replay", replay(log_path, image_path).map(|detail| ((), detail)))?;
+    step(steps, "verify", verify(image_path, &expected).map(|detail| ((), detail)))
+}
+
+/// Runs the steps in `dir`, stopping at the first that fails, then probes
+/// `device` if given. The log and image are removed unless `keep`.
+pub fn run(dir: &Path, device: Option<&Path>, keep: bool) -> Vec<Step> {
+    let log_path = dir.join(format!("log-write-selftest-{}.log", std::process::id()));
+    let image_path = dir.join(format!("log-write-selftest-{}.img", std::process::id()));
+    let mut steps = Vec::new();
+    log_steps(&mut steps, &log_path, &image_path);
+    if !keep {
+        let _ = std::fs::remove_file(&log_path);
+        let _ = std::fs::remove_file(&image_path);
+    }
+    if let Some(device) = device {
+        steps.push(Step { name: "probe", result: probe(device) });
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selftest::run;
+
+    #[test]
+    fn test_selftest_passes() {
+        let steps = run(&std::env::temp_dir(), None, false);
+        assert_eq!(steps.iter().map(|step| step.name).collect::<Vec<_>>(), ["generate", "read", "replay", "verify"]);
+        for step in &steps {
+            assert!(step.result.is_ok(), "{}: {:?}", step.name, step.result);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! CRC32C sidecars for logs, which carry no checksums of their own.
+//! `checksum --emit` writes one while the log is known good, and replaying
+//! with `--verify-crc` checks each entry against it before writing it, so a
+//! log damaged on its way over NFS or a USB disk fails on the damaged entry
+//! instead of as an fsck failure much later.
+//!
+//! The sidecar is JSON lines, a header with the log's sector size and entry
+//! count, then `{"entry": N, "crc32c": "..."}` per entry, the CRC of its
+//! header, mark name included, and its data.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use anyhow::{Result, bail, anyhow};
+use serde_json::{json, Value};
+use crate::checksum;
+use crate::error::ErrorKind;
+use crate::log_reader::LogReader;
+use crate::log_writes::LogEntry;
+
+/// Data is read in pieces this big, entries can be larger than is wise to hold.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// The CRC of an entry's header and data.
+fn entry_crc(reader: &LogReader, log_entry: &LogEntry) -> Result<u32> {
+    let mut crc = checksum::crc32c(&log_entry.entry.to_bytes());
+    let mut offset = reader.data_offset(log_entry);
+    let end = offset + reader.data_size(&log_entry.entry);
+    let mut buf = Vec::new();
+    while offset < end {
+        buf.resize((end - offset).min(CHUNK_SIZE) as usize, 0);
+        reader.read_at(&mut buf, offset)?;
+        crc = checksum::crc32c_append(crc, &buf);
+        offset += buf.len() as u64;
+    }
+    Ok(crc)
+}
+
+/// Writes the sidecar of the log `reader` reads to `path`, returns the number of entries.
+pub fn emit<P: AsRef<Path>>(mut reader: LogReader, path: P) -> Result<u64> {
+    let file = File::create(&path)
+        .map_err(|error| anyhow!("Error creating {}: {}", path.as_ref().display(), error))?;
+    let mut out = BufWriter::new(file);
+    writeln!(out, "{}", json!({ "sector_size": reader.sector_size(), "nr_entries": reader.nr_entries() }))?;
+    while let Some(log_entry) = reader.next_entry()? {
+        let crc = entry_crc(&reader, &log_entry)?;
+        writeln!(out, "{}", json!({ "entry": log_entry.index, "crc32c": format!("{:08x}", crc) }))?;
+    }
+    out.flush()?;
+    Ok(reader.nr_entries())
+}
+
+/// Checks entries against a sidecar as the replay reaches them.
+pub struct CrcVerifier {
+    reader: LogReader,
+    crcs: Vec<u32>,
+    path: String,
+}
+
+impl CrcVerifier {
+    /// Loads the sidecar at `path`, which has to be for the log `reader` reads.
+    pub fn open(path: &str, reader: LogReader) -> Result<Self> {
+        let file = File::open(path).map_err(|error| anyhow!("Error opening {}: {}", path, error))?;
+        let mut lines = BufReader::new(file).lines();
+        let header: Value = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => bail!("{} is empty", path),
+        };
+        if header["sector_size"].as_u64() != Some(reader.sector_size() as u64) || header["nr_entries"].as_u64() != Some(reader.nr_entries()) {
+            bail!("{} is for a log of {} entries of {} byte sectors, this one has {} of {}",
+                  path, header["nr_entries"], header["sector_size"], reader.nr_entries(), reader.sector_size())
+        }
+        let mut crcs = Vec::new();
+        for line in lines {
+            let line = line?;
+            let record: Value = serde_json::from_str(&line)?;
+            let crc = record["crc32c"].as_str().and_then(|crc| u32::from_str_radix(crc, 16).ok());
+            match (record["entry"].as_u64(), crc) {
+                (Some(index), Some(crc)) if index == crcs.len() as u64 => crcs.push(crc),
+                _ => bail!("Invalid record in {}: {}", path, line),
+            }
+        }
+        if crcs.len() as u64 != reader.nr_entries() {
+            bail!("{} has CRCs of {} entries out of {}", path, crcs.len(), reader.nr_entries())
+        }
+        Ok(Self { reader, crcs, path: path.to_string() })
+    }
+
+    /// Fails unless entry `index` reads back as it did when the sidecar was
+    /// written. Past the end of the log there is nothing to check.
+    pub fn check(&mut self, index: u64) -> Result<()> {
+        let Some(expected) = self.crcs.get(index as usize) else {
+            return Ok(());
+        };
+        self.reader.seek_to_index(index)?;
+        let log_entry = self.reader.next_entry()?.ok_or_else(|| anyhow!("Entry {} is missing from the log", index))?;
+        let crc = entry_crc(&self.reader, &log_entry)?;
+        if crc != *expected {
+            return Err(ErrorKind::BadFormat.wrap(anyhow!("Entry {} has CRC {:08x} where {} says {:08x}, the log changed since it was written",
+                                                          index, crc, self.path, expected)))
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_reader::LogReader;
+    use crate::log_writes::LogWriteEntry;
+    use crate::sidecar::{emit, CrcVerifier};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_sidecar_finds_corrupt_entry() {
+        let dir = std::env::temp_dir();
+        let log_path = dir.join(format!("log-write-sidecar-{}.log", std::process::id()));
+        let sidecar_path = dir.join(format!("log-write-sidecar-{}.crc", std::process::id()));
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        for sector in 0..4 {
+            writer.append(&LogWriteEntry { sector, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() }, &[sector as u8; 512]).unwrap();
+        }
+        writer.mark("done").unwrap();
+        writer.sync().unwrap();
+        assert_eq!(emit(LogReader::open(&log_path).unwrap(), &sidecar_path).unwrap(), 5);
+
+        let sidecar = sidecar_path.to_str().unwrap();
+        let mut verifier = CrcVerifier::open(sidecar, LogReader::open(&log_path).unwrap()).unwrap();
+        assert!((0..6).all(|index| verifier.check(index).is_ok()));
+
+        // A flipped byte in the data of entry 2, headers and data take a sector each
+        let mut log = std::fs::read(&log_path).unwrap();
+        log[512 + 2 * 1024 + 512 + 100] ^= 0xff;
+        std::fs::write(&log_path, &log).unwrap();
+        let mut verifier = CrcVerifier::open(sidecar, LogReader::open(&log_path).unwrap()).unwrap();
+        let checked: Vec<bool> = (0..5).map(|index| verifier.check(index).is_ok()).collect();
+        std::fs::remove_file(&log_path).unwrap();
+        std::fs::remove_file(&sidecar_path).unwrap();
+        assert_eq!(checked, [true, true, false, true, true]);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{Result, bail, anyhow};
+use serde_json::json;
+use tracing::{info, warn};
+use crate::log_reader::{LogReader, Trailer};
+use crate::salvage;
+
+/// Checks that every entry the superblock counts is in the file, and that a
+/// copy of the superblock after them agrees, returns the entry count.
+pub fn verify_log<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let reader = LogReader::open(&path)?;
+    if let Some(truncation) = salvage::find_truncation(&reader)? {
+        bail!("Log is truncated, {}", truncation)
+    }
+    if let Trailer::Differs(copy) = reader.trailer()? {
+        bail!("The superblock counts {} entries but its copy after them counts {}, the capture stopped while syncing",
+              reader.nr_entries(), copy.nr_entries)
+    }
+    Ok(reader.nr_entries())
+}
+
+/// Watches a directory for new logs and runs the pipeline on each, moving them
+/// to `done/` or `failed/` next to a `<log>.result.json`.
+///
+/// NAS mounts don't deliver inotify events, so the directory is polled and a
+/// log is only picked up once its size and mtime stop changing between polls.
+pub struct Spool {
+    dir: PathBuf,
+    steps: Vec<String>,
+    interval: Duration,
+    /// Size and mtime of the logs seen in the last poll
+    seen: HashMap<PathBuf, (u64, SystemTime)>,
+}
+
+impl Spool {
+    pub fn new<P: AsRef<Path>>(dir: P, steps: Vec<String>, interval: Duration) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        for sub in ["done", "failed"] {
+            fs::create_dir_all(dir.join(sub))
+                .map_err(|error| anyhow!("Error creating {}: {}", dir.join(sub).display(), error))?;
+        }
+        Ok(Self { dir, steps, interval, seen: HashMap::new() })
+    }
+
+    /// Logs that haven't changed since the last poll.
+    fn settled(&mut self) -> Result<Vec<PathBuf>> {
+        let mut seen = HashMap::new();
+        let mut settled = Vec::new();
+        for dirent in fs::read_dir(&self.dir)? {
+            let path = dirent?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("log") || !path.is_file() {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            let state = (metadata.len(), metadata.modified()?);
+            if self.seen.get(&path) == Some(&state) {
+                settled.push(path.clone());
+            }
+            seen.insert(path, state);
+        }
+        self.seen = seen;
+        settled.sort();
+        Ok(settled)
+    }
+
+    /// Runs the steps on `log` in order, stopping at the first failing one.
+    fn process(&self, log: &Path) -> Result<bool> {
+        let name = log.file_name().unwrap().to_string_lossy().to_string();
+        let exe = std::env::current_exe()?;
+        let mut steps = Vec::new();
+        let mut passed = true;
+
+        let verified = verify_log(log);
+        if let Err(error) = &verified {
+            warn!("{} failed verification: {}", name, error);
+            passed = false;
+        } else {
+            for step in &self.steps {
+                let started = Instant::now();
+                info!("running {} on {}", step, name);
+                let status = Command::new("sh")
+                    .arg("-c").arg(step)
+                    .env("LOG_WRITE", &exe)
+                    .env("LOG_WRITES_LOG", log)
+                    .status()
+                    .map_err(|error| anyhow!("Error running {}: {}", step, error))?;
+                steps.push(json!({
+                    "command": step,
+                    "exit_code": status.code(),
+                    "seconds": started.elapsed().as_secs_f64(),
+                }));
+                if !status.success() {
+                    warn!("{} failed on {}: {}", step, name, status);
+                    passed = false;
+                    break;
+                }
+            }
+        }
+
+        let folder = self.dir.join(if passed { "done" } else { "failed" });
+        let result = json!({
+            "log": name,
+            "entries": verified.as_ref().ok(),
+            "error": verified.as_ref().err().map(|error| error.to_string()),
+            "steps": steps,
+            "passed": passed,
+        });
+        fs::write(folder.join(format!("{}.result.json", name)), serde_json::to_string_pretty(&result)?)?;
+        fs::rename(log, folder.join(&name))?;
+        Ok(passed)
+    }
+
+    /// Polls the directory forever.
+    pub fn run(&mut self) -> Result<()> {
+        info!("watching {} for logs", self.dir.display());
+        loop {
+            for log in self.settled()? {
+                let passed = self.process(&log)?;
+                info!("{} {}", log.display(), if passed { "passed" } else { "failed" });
+                self.seen.remove(&log);
+            }
+            thread::sleep(self.interval);
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use anyhow::{Result, bail};
+use crate::io::{self, ByteOffset};
+use crate::log_reader::LogReader;
+use crate::log_writes::{LOG_DISCARD_FLAG, LOG_MARK_FLAG};
+use crate::export::BlockExport;
+
+/// Where the current contents of a run of sectors come from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Source {
+    /// Data in the log, starting at this byte offset
+    Log(u64),
+    /// Discarded, reads back as zeros
+    Zero,
+}
+
+/// What a `SectorMap` records for each extent. Splitting an extent hands the
+/// tail a copy advanced by the bytes cut off the front.
+pub trait ExtentSource: Copy {
+    fn advance(&self, bytes: u64) -> Self;
+}
+
+impl ExtentSource for Source {
+    fn advance(&self, bytes: u64) -> Self {
+        match self {
+            Source::Log(offset) => Source::Log(offset + bytes),
+            Source::Zero => Source::Zero,
+        }
+    }
+}
+
+/// The entry that last wrote a sector.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Writer(pub u64);
+
+impl ExtentSource for Writer {
+    fn advance(&self, _bytes: u64) -> Self {
+        *self
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Extent<S = Source> {
+    pub nr_sectors: u64,
+    pub source: S,
+}
+
+/// Non-overlapping map from start sector to the latest writer of each sector.
+#[derive(Debug, Default)]
+pub struct SectorMap<S = Source> {
+    sector_size: u64,
+    extents: BTreeMap<u64, Extent<S>>,
+}
+
+impl<S: ExtentSource> SectorMap<S> {
+    pub fn new(sector_size: u32) -> Self {
+        Self {
+            sector_size: sector_size as u64,
+            extents: BTreeMap::new(),
+        }
+    }
+
+    /// Records `source` as the contents of `[sector, sector + nr_sectors)`,
+    /// splitting whatever it overwrites. Extents stop at sector u64::MAX.
+    pub fn insert(&mut self, sector: u64, nr_sectors: u64, source: S) {
+        let end = sector.saturating_add(nr_sectors);
+        let nr_sectors = end - sector;
+        if nr_sectors == 0 {
+            return;
+        }
+        let overlapping: Vec<(u64, Extent<S>)> = self.extents
+            .range(..end)
+            .rev()
+            .take_while(|(start, extent)| **start + extent.nr_sectors > sector)
+            .map(|(start, extent)| (*start, *extent))
+            .collect();
+
+        for (start, extent) in overlapping {
+            self.extents.remove(&start);
+            let extent_end = start + extent.nr_sectors;
+            if start < sector {
+                self.extents.insert(start, Extent {
+                    nr_sectors: sector - start,
+                    source: extent.source,
+                });
+            }
+            if extent_end > end {
+                self.extents.insert(end, Extent {
+                    nr_sectors: extent_end - end,
+                    source: extent.source.advance((end - start).saturating_mul(self.sector_size)),
+                });
+            }
+        }
+        self.extents.insert(sector, Extent { nr_sectors, source });
+    }
+
+    /// Splits `[sector, sector + nr_sectors)` into runs, `None` for sectors never written.
+    pub fn lookup(&self, sector: u64, nr_sectors: u64) -> Vec<(u64, u64, Option<S>)> {
+        let end = sector.saturating_add(nr_sectors);
+        let mut runs = Vec::new();
+        let mut cur = sector;
+
+        let first = self.extents.range(..=sector).next_back()
+            .filter(|(start, extent)| **start + extent.nr_sectors > sector)
+            .map(|(start, _)| *start)
+            .unwrap_or(sector);
+
+        for (start, extent) in self.extents.range(first..end) {
+            let extent_end = start + extent.nr_sectors;
+            if *start > cur {
+                runs.push((cur, *start - cur, None));
+                cur = *start;
+            }
+            let run_end = extent_end.min(end);
+            let source = extent.source.advance((cur - start).saturating_mul(self.sector_size));
+            runs.push((cur, run_end - cur, Some(source)));
+            cur = run_end;
+        }
+        if cur < end {
+            runs.push((cur, end - cur, None));
+        }
+        runs
+    }
+
+    /// Every extent in sector order.
+    pub fn extents(&self) -> impl Iterator<Item = (u64, &Extent<S>)> {
+        self.extents.iter().map(|(start, extent)| (*start, extent))
+    }
+
+    /// One past the highest sector ever written.
+    pub fn end_sector(&self) -> u64 {
+        self.extents.iter().next_back().map_or(0, |(start, extent)| start + extent.nr_sectors)
+    }
+}
+
+/// Builds the sector map for the device state after `entry` has been applied.
+pub fn scan_to_entry(reader: &mut LogReader, entry: u64) -> Result<SectorMap> {
+    if entry >= reader.nr_entries() {
+        bail!("Entry {} is past the end of the log ({} entries)", entry, reader.nr_entries())
+    }
+    let mut map = SectorMap::new(reader.sector_size());
+    while let Some(log_entry) = reader.next_entry()? {
+        let flags = log_entry.entry.flags;
+        if (flags & LOG_DISCARD_FLAG) > 0 {
+            map.insert(log_entry.entry.sector, log_entry.entry.nr_sectors, Source::Zero);
+        } else if (flags & LOG_MARK_FLAG) == 0 {
+            let offset = reader.data_offset(&log_entry);
+            map.insert(log_entry.entry.sector, log_entry.entry.nr_sectors, Source::Log(offset));
+        }
+        if log_entry.index == entry {
+            break;
+        }
+    }
+    Ok(map)
+}
+
+/// Read-only export of a historical device state, materialized on read.
+pub struct StateExport {
+    reader: LogReader,
+    map: SectorMap,
+    base: Option<File>,
+    size: u64,
+}
+
+impl StateExport {
+    pub fn open<P: AsRef<Path>>(log_file_path: P, entry: u64, base: Option<&Path>, size: Option<u64>) -> Result<Self> {
+        let mut reader = LogReader::open(log_file_path)?;
+        let map = scan_to_entry(&mut reader, entry)?;
+        let base = match base {
+            Some(base) => Some(File::open(base)?),
+            None => None,
+        };
+        let base_size = match &base {
+            Some(base) => Some(base.metadata()?.len()),
+            None => None,
+        };
+        let size = state_size(map.end_sector() * reader.sector_size() as u64, base_size, size);
+        Ok(Self { reader, map, base, size })
+    }
+
+    /// Sector ranges holding data from the log, everything else reads from the
+    /// base image or as zeros.
+    pub fn log_extents(&self) -> Vec<(u64, u64)> {
+        self.map.extents()
+            .filter(|(_, extent)| matches!(extent.source, Source::Log(_)))
+            .map(|(sector, extent)| (sector, extent.nr_sectors))
+            .collect()
+    }
+
+    pub fn sector_size(&self) -> u32 {
+        self.reader.sector_size()
+    }
+}
+
+/// Size of an exported state: the override if given, otherwise the larger of
+/// the base image and the highest written byte.
+pub fn state_size(written_size: u64, base_size: Option<u64>, size: Option<u64>) -> u64 {
+    match (size, base_size) {
+        (Some(size), _) => size,
+        (None, Some(base_size)) => base_size.max(written_size),
+        (None, None) => written_size,
+    }
+}
+
+impl BlockExport for StateExport {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let sector_size = self.reader.sector_size() as u64;
+        let first = offset / sector_size;
+        let last = (offset + buf.len() as u64).div_ceil(sector_size);
+        let mut scratch = vec![0_u8; ((last - first) * sector_size) as usize];
+
+        for (sector, nr_sectors, source) in self.map.lookup(first, last - first) {
+            let start = ((sector - first) * sector_size) as usize;
+            let chunk = &mut scratch[start..start + (nr_sectors * sector_size) as usize];
+            match (source, &self.base) {
+                (Some(Source::Log(log_offset)), _) => self.reader.read_at(chunk, log_offset)?,
+                (None, Some(base)) => {
+                    // Past the end of the base image reads as zeros
+                    io::read_full_at(base, chunk, ByteOffset::from_sectors(sector, sector_size as u32)?)?;
+                }
+                _ => {}
+            }
+        }
+        let skip = (offset - first * sector_size) as usize;
+        buf.copy_from_slice(&scratch[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, _offset: u64, _data: &[u8], _fua: bool) -> Result<()> {
+        bail!("State export is read-only")
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn trim(&mut self, _offset: u64, _len: u64) -> Result<()> {
+        bail!("State export is read-only")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::{SectorMap, Source};
+
+    #[test]
+    fn test_sector_map_overwrite() {
+        let mut map = SectorMap::new(512);
+        map.insert(0, 8, Source::Log(1024));
+        map.insert(2, 2, Source::Zero);
+        map.insert(6, 4, Source::Log(8192));
+
+        assert_eq!(map.lookup(0, 12), vec![
+            (0, 2, Some(Source::Log(1024))),
+            (2, 2, Some(Source::Zero)),
+            (4, 2, Some(Source::Log(1024 + 4 * 512))),
+            (6, 4, Some(Source::Log(8192))),
+            (10, 2, None),
+        ]);
+        assert_eq!(map.lookup(3, 2), vec![
+            (3, 1, Some(Source::Zero)),
+            (4, 1, Some(Source::Log(1024 + 4 * 512))),
+        ]);
+        assert_eq!(map.end_sector(), 10);
+    }
+
+    #[test]
+    fn test_sector_map_end() {
+        // A corrupt entry near the end of the sector space stops at u64::MAX
+        let mut map = SectorMap::new(512);
+        map.insert(u64::MAX - 4, 8, Source::Zero);
+        map.insert(u64::MAX - 2, u64::MAX, Source::Log(1024));
+
+        assert_eq!(map.lookup(u64::MAX - 4, 100), vec![
+            (u64::MAX - 4, 2, Some(Source::Zero)),
+            (u64::MAX - 2, 2, Some(Source::Log(1024))),
+        ]);
+        assert_eq!(map.end_sector(), u64::MAX);
+    }
+}
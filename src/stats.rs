@@ -0,0 +1,187 @@
+use std::fmt;
+use crate::touched::TouchedSectors;
+use crate::format::{LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG, LOG_METADATA_FLAG};
+
+/// Counts of what a log contains, by kind of entry.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LogStats {
+    pub entries: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub fua: u64,
+    pub discards: u64,
+    pub marks: u64,
+    pub metadata: u64,
+    pub bytes_written: u64,
+    pub bytes_discarded: u64,
+    /// One past the highest sector written or discarded
+    pub end_sector: u64,
+}
+
+impl LogStats {
+    pub fn add(&mut self, entry: &LogWriteEntry, sector_size: u32) {
+        let bytes = entry.nr_sectors.saturating_mul(sector_size as u64);
+        self.entries += 1;
+        if (entry.flags & LOG_FLUSH_FLAG) > 0 {
+            self.flushes += 1;
+        }
+        if (entry.flags & LOG_FUA_FLAG) > 0 {
+            self.fua += 1;
+        }
+        if (entry.flags & LOG_METADATA_FLAG) > 0 {
+            self.metadata += 1;
+        }
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            self.marks += 1;
+            return;
+        }
+        if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+            self.discards += 1;
+            self.bytes_discarded = self.bytes_discarded.saturating_add(bytes);
+        } else if entry.nr_sectors > 0 {
+            self.writes += 1;
+            self.bytes_written = self.bytes_written.saturating_add(bytes);
+        }
+        if entry.nr_sectors > 0 {
+            self.end_sector = self.end_sector.max(entry.sector.saturating_add(entry.nr_sectors));
+        }
+    }
+
+    /// Adds the counts of another part of the same log.
+    pub fn merge(&mut self, other: &LogStats) {
+        self.entries += other.entries;
+        self.writes += other.writes;
+        self.flushes += other.flushes;
+        self.fua += other.fua;
+        self.discards += other.discards;
+        self.marks += other.marks;
+        self.metadata += other.metadata;
+        self.bytes_written = self.bytes_written.saturating_add(other.bytes_written);
+        self.bytes_discarded = self.bytes_discarded.saturating_add(other.bytes_discarded);
+        self.end_sector = self.end_sector.max(other.end_sector);
+    }
+}
+
+impl fmt::Display for LogStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "entries: {}", self.entries)?;
+        writeln!(f, "writes: {} ({} bytes)", self.writes, self.bytes_written)?;
+        writeln!(f, "discards: {} ({} bytes)", self.discards, self.bytes_discarded)?;
+        writeln!(f, "flushes: {}", self.flushes)?;
+        writeln!(f, "fua: {}", self.fua)?;
+        writeln!(f, "metadata: {}", self.metadata)?;
+        writeln!(f, "marks: {}", self.marks)?;
+        write!(f, "end sector: {}", self.end_sector)
+    }
+}
+
+/// Stats of the entries from one mark up to the next, the mark included.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PhaseStats {
+    /// Mark the phase starts at, None for the entries before the first mark
+    pub mark: Option<String>,
+    pub stats: LogStats,
+    /// Distinct sectors the phase writes, however many times
+    pub unique_sectors: u64,
+}
+
+/// Splits a log into phases at its marks, e.g. to compare the same phase of
+/// a workload across two kernels.
+pub fn phases<'a, I: IntoIterator<Item = &'a LogWriteEntry>>(entries: I, sector_size: u32) -> Vec<PhaseStats> {
+    let mut phases = vec![PhaseStats::default()];
+    let mut touched = TouchedSectors::default();
+    for entry in entries {
+        if (entry.flags & LOG_MARK_FLAG) > 0 {
+            phases.push(PhaseStats { mark: Some(entry.cmd.clone()), ..PhaseStats::default() });
+            touched = TouchedSectors::default();
+        }
+        let phase = phases.last_mut().unwrap();
+        phase.stats.add(entry, sector_size);
+        if (entry.flags & (LOG_MARK_FLAG | LOG_DISCARD_FLAG)) == 0 {
+            touched.insert(entry.sector, entry.sector.saturating_add(entry.nr_sectors));
+            phase.unique_sectors = touched.sectors();
+        }
+    }
+    // Nothing before the first mark
+    if phases.len() > 1 && phases[0].stats.entries == 0 {
+        phases.remove(0);
+    }
+    phases
+}
+
+impl fmt::Display for PhaseStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.mark {
+            Some(mark) => writeln!(f, "phase {}:", mark)?,
+            None => writeln!(f, "phase before the first mark:")?,
+        }
+        for line in self.stats.to_string().lines() {
+            writeln!(f, "  {}", line)?;
+        }
+        write!(f, "  unique sectors written: {}", self.unique_sectors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::{Entries, LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_MARK_FLAG};
+    use crate::stats::{phases, LogStats};
+    use crate::testutil::{build_log, write};
+
+    #[test]
+    fn test_stats_from_memory() {
+        let entries = vec![
+            write(8, 2),
+            LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_FLUSH_FLAG, data_len: 0, cmd: String::new() },
+            LogWriteEntry { sector: 100, nr_sectors: 4, flags: LOG_DISCARD_FLAG, data_len: 0, cmd: String::new() },
+            LogWriteEntry { sector: 0, nr_sectors: 0, flags: LOG_MARK_FLAG, data_len: 3, cmd: "one".to_string() },
+        ];
+        let log = build_log(&entries, |_| 7);
+
+        let mut parsed = Entries::new(&log).unwrap();
+        let first = parsed.next().unwrap().unwrap();
+        assert_eq!(parsed.data(&first), Some(&[7_u8; 1024][..]));
+        let mut stats = LogStats::default();
+        stats.add(&first.entry, 512);
+        let mut rest = LogStats::default();
+        for entry in parsed {
+            rest.add(&entry.unwrap().entry, 512);
+        }
+        stats.merge(&rest);
+        assert_eq!(stats, LogStats {
+            entries: 4,
+            writes: 1,
+            flushes: 1,
+            fua: 0,
+            discards: 1,
+            marks: 1,
+            metadata: 0,
+            bytes_written: 1024,
+            bytes_discarded: 2048,
+            end_sector: 104,
+        });
+
+        // Cut off in the middle of the data of the first entry
+        assert!(Entries::new(&log[..1024]).unwrap().nth(1).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_phases() {
+        let entry = |sector, nr_sectors, flags, cmd: &str| LogWriteEntry { sector, nr_sectors, flags, data_len: cmd.len() as u64, cmd: cmd.to_string() };
+        let entries = vec![
+            entry(0, 8, 0, ""),
+            entry(0, 0, LOG_MARK_FLAG, "one"),
+            entry(8, 8, 0, ""),
+            entry(12, 8, 0, ""),
+            entry(0, 0, LOG_FLUSH_FLAG, ""),
+            entry(0, 0, LOG_MARK_FLAG, "two"),
+            entry(0, 16, LOG_DISCARD_FLAG, ""),
+        ];
+        let phases = phases(&entries, 512);
+        assert_eq!(phases.iter().map(|phase| phase.mark.as_deref()).collect::<Vec<_>>(), vec![None, Some("one"), Some("two")]);
+        assert_eq!(phases.iter().map(|phase| phase.unique_sectors).collect::<Vec<_>>(), vec![8, 12, 0]);
+        assert_eq!((phases[1].stats.bytes_written, phases[1].stats.flushes, phases[1].stats.marks), (16 * 512, 1, 1));
+        assert_eq!(phases[2].stats.bytes_discarded, 16 * 512);
+        assert_eq!(crate::stats::phases(&entries[1..], 512).len(), 2);
+    }
+}
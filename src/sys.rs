@@ -0,0 +1,300 @@
+use std::fmt;
+use std::fs::{File, Metadata};
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{Result, bail};
+
+/// An I/O scheduling class and priority, 0 highest to 7 lowest, as ionice sets them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoClass {
+    /// Follows the CPU nice level
+    None,
+    Realtime(u8),
+    BestEffort(u8),
+    /// Only gets the disk when nothing else wants it
+    Idle,
+}
+
+impl FromStr for IoClass {
+    type Err = anyhow::Error;
+
+    /// `idle`, `best-effort` or `best-effort:PRIO`.
+    fn from_str(class: &str) -> Result<Self> {
+        let (name, prio) = match class.split_once(':') {
+            Some((name, prio)) => (name, Some(prio)),
+            None => (class, None),
+        };
+        match (name, prio) {
+            ("idle", None) => Ok(IoClass::Idle),
+            ("best-effort", None) => Ok(IoClass::BestEffort(4)),
+            ("best-effort", Some(prio)) => match prio.parse() {
+                Ok(prio) if prio <= 7 => Ok(IoClass::BestEffort(prio)),
+                _ => bail!("Invalid best-effort priority {}, expected 0 to 7", prio),
+            },
+            _ => bail!("Invalid io class {}, expected idle or best-effort:PRIO", class),
+        }
+    }
+}
+
+impl fmt::Display for IoClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoClass::None => write!(f, "none"),
+            IoClass::Realtime(prio) => write!(f, "realtime:{}", prio),
+            IoClass::BestEffort(prio) => write!(f, "best-effort:{}", prio),
+            IoClass::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::{File, Metadata, OpenOptions};
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use anyhow::{Result, anyhow};
+    use nix::errno::Errno;
+    use nix::fcntl::{fallocate, FallocateFlags, OFlag};
+    use nix::mount::{mount, MsFlags};
+    use super::IoClass;
+
+    const IOPRIO_WHO_PROCESS: i32 = 1;
+    const IOPRIO_CLASS_SHIFT: i32 = 13;
+
+    pub fn block_device_size(file : &File) -> Result<u64>{
+        let mut size : u64 = 0;
+        let ret = unsafe {
+            ioctls::blkgetsize64(file.as_raw_fd(), &mut size)
+        };
+        if ret < 0 {
+            return Err(anyhow!("IO error BLKGETSIZE64 {}", Errno::last()))
+        }
+        Ok(size)
+    }
+
+    pub fn logical_block_size(file : &File) -> Result<u32> {
+        let mut size : std::os::raw::c_int = 0;
+        let ret = unsafe {
+            ioctls::blksszget(file.as_raw_fd(), &mut size)
+        };
+        if ret < 0 {
+            return Err(anyhow!("IO error BLKSSZGET {}", Errno::last()))
+        }
+        Ok(size as u32)
+    }
+
+    pub fn discard(file : &File, start : u64, len : u64) -> Result<()> {
+        let range : [u64;2] = [start, len];
+        let ret = unsafe {
+            ioctls::blkdiscard(file.as_raw_fd(), &range)
+        };
+        if ret < 0 {
+            return Err(anyhow!("IO error BLKDISCARD {}", Errno::last()));
+        }
+        Ok(())
+    }
+
+    pub fn is_block_device(metadata : &Metadata) -> bool {
+        metadata.file_type().is_block_device()
+    }
+
+    pub fn held_open(device : &Path) -> bool {
+        OpenOptions::new().read(true).custom_flags(OFlag::O_EXCL.bits()).open(device).is_err()
+    }
+
+    pub fn punch_hole(file : &File, offset : u64, len : u64) -> Result<()> {
+        fallocate(
+            file.as_raw_fd(),
+            FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            offset as i64,
+            len as i64,
+        ).map_err(|error| anyhow!("Error punching hole: {}", error))
+    }
+
+    pub fn bind_mount(source : &Path, target : &Path) -> Result<()> {
+        mount(Some(source), target, None::<&str>, MsFlags::MS_BIND, None::<&str>)
+            .map_err(anyhow::Error::from)
+    }
+
+    pub fn umount(target : &Path) -> Result<()> {
+        nix::mount::umount(target).map_err(anyhow::Error::from)
+    }
+
+    pub fn set_io_class(class : IoClass) -> Result<()> {
+        let (class, prio) = match class {
+            IoClass::None => (0, 0),
+            IoClass::Realtime(prio) => (1, prio),
+            IoClass::BestEffort(prio) => (2, prio),
+            IoClass::Idle => (3, 0),
+        };
+        let ret = unsafe {
+            nix::libc::syscall(nix::libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, (class << IOPRIO_CLASS_SHIFT) | prio as i32)
+        };
+        if ret < 0 {
+            return Err(anyhow!("Error setting the io class: {}", Errno::last()))
+        }
+        Ok(())
+    }
+
+    pub fn io_class() -> Result<IoClass> {
+        let ret = unsafe {
+            nix::libc::syscall(nix::libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0)
+        };
+        if ret < 0 {
+            return Err(anyhow!("Error reading the io class: {}", Errno::last()))
+        }
+        let prio = (ret & ((1 << IOPRIO_CLASS_SHIFT) - 1)) as u8;
+        Ok(match ret >> IOPRIO_CLASS_SHIFT {
+            1 => IoClass::Realtime(prio),
+            2 => IoClass::BestEffort(prio),
+            3 => IoClass::Idle,
+            _ => IoClass::None,
+        })
+    }
+}
+
+/// macOS, the BSDs, Windows and anything else without the Linux block layer.
+/// Replay targets are expected to be regular files, so discards become zeroing.
+#[cfg(not(target_os = "linux"))]
+mod portable {
+    use std::fs::{File, Metadata};
+    use std::path::Path;
+    use anyhow::{Result, anyhow, bail};
+    use crate::io::{self, ByteOffset, Whence};
+    use super::IoClass;
+
+    const ZERO_CHUNK: u64 = 1024 * 1024;
+
+    /// Regular files and the BSD disk devices both report their size by seeking to the end.
+    pub fn block_device_size(file : &File) -> Result<u64>{
+        let pos = io::lseek(file, 0, Whence::SeekCur)?;
+        let size = io::lseek(file, 0, Whence::SeekEnd)?;
+        io::lseek(file, pos, Whence::SeekSet)?;
+        Ok(size as u64)
+    }
+
+    pub fn logical_block_size(_file : &File) -> Result<u32> {
+        bail!("Block sizes can only be queried on Linux")
+    }
+
+    pub fn discard(_file : &File, _start : u64, _len : u64) -> Result<()> {
+        bail!("Discard isn't supported on this platform")
+    }
+
+    #[cfg(unix)]
+    pub fn is_block_device(metadata : &Metadata) -> bool {
+        use std::os::unix::fs::FileTypeExt;
+        metadata.file_type().is_block_device()
+    }
+
+    #[cfg(not(unix))]
+    pub fn is_block_device(_metadata : &Metadata) -> bool {
+        false
+    }
+
+    /// Only Linux fails an O_EXCL open of a device something else holds
+    pub fn held_open(_device : &Path) -> bool {
+        false
+    }
+
+    pub fn punch_hole(file : &File, offset : u64, len : u64) -> Result<()> {
+        let zeros = vec![0_u8; ZERO_CHUNK.min(len) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = &zeros[..ZERO_CHUNK.min(len - done) as usize];
+            io::write_full_at(file, chunk, ByteOffset::new(offset + done)?)
+                .map_err(|error| anyhow!("Error zeroing range: {}", error))?;
+            done += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    pub fn bind_mount(_source : &Path, _target : &Path) -> Result<()> {
+        bail!("Bind mounts need Linux")
+    }
+
+    #[cfg(unix)]
+    pub fn umount(_target : &Path) -> Result<()> {
+        bail!("Unmounting needs Linux")
+    }
+
+    pub fn set_io_class(_class : IoClass) -> Result<()> {
+        bail!("Setting the io class needs Linux")
+    }
+
+    pub fn io_class() -> Result<IoClass> {
+        Ok(IoClass::None)
+    }
+}
+
+#[cfg(target_os = "linux")]
+use linux as imp;
+#[cfg(not(target_os = "linux"))]
+use portable as imp;
+
+/// Size of a block device, or of a regular file where there's no ioctl for it.
+pub fn block_device_size(file : &File) -> Result<u64>{
+    imp::block_device_size(file)
+}
+
+/// Logical block size of the block device behind `file`.
+pub fn logical_block_size(file : &File) -> Result<u32> {
+    imp::logical_block_size(file)
+}
+
+/// Discards `[start, start + len)` on the device behind `file`. Fails where
+/// that isn't possible, callers fall back to zeroing.
+pub fn discard(file : &File, start : u64, len : u64) -> Result<()> {
+    imp::discard(file, start, len)
+}
+
+/// Frees `[offset, offset + len)` of a file, keeping its size. Reads back as zeros.
+pub fn punch_hole(file : &File, offset : u64, len : u64) -> Result<()> {
+    imp::punch_hole(file, offset, len)
+}
+
+pub fn is_block_device(metadata : &Metadata) -> bool {
+    imp::is_block_device(metadata)
+}
+
+/// Whether something, a mount, device-mapper or md, holds `device` open exclusively.
+pub fn held_open(device : &Path) -> bool {
+    imp::held_open(device)
+}
+
+/// Bind mounts are Linux only, an error elsewhere.
+#[cfg(unix)]
+pub fn bind_mount(source : &Path, target : &Path) -> Result<()> {
+    imp::bind_mount(source, target)
+}
+
+#[cfg(unix)]
+pub fn umount(target : &Path) -> Result<()> {
+    imp::umount(target)
+}
+
+/// Sets the io class of this process, and so of the threads it starts after.
+pub fn set_io_class(class : IoClass) -> Result<()> {
+    imp::set_io_class(class)
+}
+
+/// The io class this process has, what the scheduler actually applies.
+pub fn io_class() -> Result<IoClass> {
+    imp::io_class()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sys::IoClass;
+
+    #[test]
+    fn test_io_class() {
+        assert_eq!("idle".parse::<IoClass>().unwrap(), IoClass::Idle);
+        assert_eq!("best-effort:7".parse::<IoClass>().unwrap(), IoClass::BestEffort(7));
+        assert_eq!("best-effort".parse::<IoClass>().unwrap().to_string(), "best-effort:4");
+        assert!("best-effort:8".parse::<IoClass>().is_err());
+        assert!("realtime:0".parse::<IoClass>().is_err());
+    }
+}
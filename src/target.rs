@@ -0,0 +1,84 @@
+//! Sizing a regular file replay target, so its size doesn't depend on what
+//! was there before: --truncate-target starts from an empty file and
+//! --grow-target extends it to the furthest byte the log touches.
+
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use anyhow::{Result, bail, anyhow};
+use crate::log_reader::LogReader;
+use crate::log_writes::LOG_MARK_FLAG;
+
+/// Fails unless `path` is a regular file or doesn't exist yet.
+fn check_file(path: &Path, flag: &str) -> Result<()> {
+    match fs::metadata(path) {
+        Ok(metadata) if !metadata.is_file() => bail!("--{} only works on regular files, {} isn't one", flag, path.display()),
+        _ => Ok(()),
+    }
+}
+
+/// Creates `path` empty, or empties it.
+pub fn truncate(path: &Path) -> Result<()> {
+    check_file(path, "truncate-target")?;
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)
+        .map_err(|error| anyhow!("Error truncating {}: {}", path.display(), error))?;
+    Ok(())
+}
+
+/// One past the last byte any write or discard of the log touches.
+pub fn max_end(reader: &mut LogReader) -> Result<u64> {
+    let sector_size = reader.sector_size() as u64;
+    let mut end = 0;
+    while let Some(log_entry) = reader.next_entry()? {
+        let entry = &log_entry.entry;
+        if (entry.flags & LOG_MARK_FLAG) == 0 {
+            end = end.max(entry.sector.saturating_add(entry.nr_sectors).saturating_mul(sector_size));
+        }
+    }
+    Ok(end)
+}
+
+/// Extends `path` to `size` bytes if it's shorter, leaving the new part sparse.
+pub fn grow(path: &Path, size: u64) -> Result<()> {
+    check_file(path, "grow-target")?;
+    let file = OpenOptions::new().write(true).open(path)
+        .map_err(|error| anyhow!("Error opening {}: {}", path.display(), error))?;
+    if file.metadata()?.len() < size {
+        file.set_len(size).map_err(|error| anyhow!("Error growing {} to {} bytes: {}", path.display(), size, error))?;
+    }
+    Ok(())
+}
+
+/// Size of `path` in bytes.
+pub fn size(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path).map_err(|error| anyhow!("Error reading {}: {}", path.display(), error))?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_reader::LogReader;
+    use crate::log_writes::{LogWriteEntry, LOG_DISCARD_FLAG};
+    use crate::target::{grow, max_end, size, truncate};
+    use crate::writer::LogWriter;
+
+    #[test]
+    fn test_grow_and_truncate() {
+        let dir = std::env::temp_dir();
+        let log_path = dir.join(format!("log-write-target-{}.log", std::process::id()));
+        let image_path = dir.join(format!("log-write-target-{}.img", std::process::id()));
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        writer.append(&LogWriteEntry { sector: 4, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() }, &[1; 512]).unwrap();
+        writer.append(&LogWriteEntry { sector: 16, nr_sectors: 8, flags: LOG_DISCARD_FLAG, data_len: 0, cmd: String::new() }, &[]).unwrap();
+        writer.mark("done").unwrap();
+        writer.sync().unwrap();
+
+        let end = max_end(&mut LogReader::open(&log_path).unwrap()).unwrap();
+        std::fs::write(&image_path, [1; 100]).unwrap();
+        truncate(&image_path).unwrap();
+        let truncated = size(&image_path).unwrap();
+        grow(&image_path, end).unwrap();
+        let grown = size(&image_path).unwrap();
+        std::fs::remove_file(&image_path).unwrap();
+        std::fs::remove_file(&log_path).unwrap();
+        assert_eq!((end, truncated, grown), (24 * 512, 0, 24 * 512));
+    }
+}
@@ -0,0 +1,79 @@
+//! Fixtures shared by the unit tests: logs built in memory in the
+//! dm-log-writes layout with 512 byte sectors, and temp files removed however
+//! the test ends. The binary's tests include this file too, so it only names
+//! the library through paths both crates have, and `LogWriter` stays out of it.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use crate::log_writes::{LogWriteEntry, LogWriteSuper, WRITE_LOG_MAGIC, WRITE_LOG_VERSION};
+
+pub const SECTOR_SIZE: u32 = 512;
+
+/// `bytes` padded with zeros to a sector.
+pub fn sector(bytes: &[u8]) -> Vec<u8> {
+    let mut sector = bytes.to_vec();
+    sector.resize(SECTOR_SIZE as usize, 0);
+    sector
+}
+
+/// The superblock sector of a log of `nr_entries` entries.
+pub fn log_super(nr_entries: u64) -> Vec<u8> {
+    sector(&LogWriteSuper { magic: WRITE_LOG_MAGIC, version: WRITE_LOG_VERSION, nr_entries, sector_size: SECTOR_SIZE }.to_bytes())
+}
+
+/// Appends `entry` to `log`, its data all `byte`.
+pub fn push_entry(log: &mut Vec<u8>, entry: &LogWriteEntry, byte: u8) {
+    log.extend(sector(&entry.to_bytes()));
+    log.extend(vec![byte; entry.data_size(SECTOR_SIZE) as usize]);
+}
+
+/// A log of `entries`, the data of entry `i` all `fill(i)`.
+pub fn build_log(entries: &[LogWriteEntry], fill: impl Fn(usize) -> u8) -> Vec<u8> {
+    let mut log = log_super(entries.len() as u64);
+    for (i, entry) in entries.iter().enumerate() {
+        push_entry(&mut log, entry, fill(i));
+    }
+    log
+}
+
+/// An entry for `nr_sectors` at `sector` with `flags`.
+pub fn entry(sector: u64, nr_sectors: u64, flags: u64) -> LogWriteEntry {
+    LogWriteEntry { sector, nr_sectors, flags, data_len: 0, cmd: String::new() }
+}
+
+/// A write of `nr_sectors` at `sector`.
+pub fn write(sector: u64, nr_sectors: u64) -> LogWriteEntry {
+    entry(sector, nr_sectors, 0)
+}
+
+/// A file or directory in the temp dir named for the process, removed on drop.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("log-write-{}-{}", std::process::id(), name)))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = match self.0.is_dir() {
+            true => std::fs::remove_dir_all(&self.0),
+            false => std::fs::remove_file(&self.0),
+        };
+    }
+}
@@ -0,0 +1,83 @@
+//! Which sectors a replay has written so far, as merged ranges since a
+//! bitmap of a multi-terabyte target wouldn't fit in memory.
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, Clone)]
+pub struct TouchedSectors {
+    /// Start of each range to one past its end, ranges never overlap or touch
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl TouchedSectors {
+    /// Whether any sector in `[start, end)` was written.
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.ranges.range(..end).next_back().is_some_and(|(_, range_end)| *range_end > start)
+    }
+
+    /// Number of sectors written at least once.
+    pub fn sectors(&self) -> u64 {
+        self.ranges.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// The runs of `[start, end)` outside every range, in order.
+    pub fn gaps(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        if start >= end {
+            return Vec::new();
+        }
+        // The range starting at or before `start` may reach into it
+        let first = self.ranges.range(..=start).next_back().map_or(start, |(range_start, _)| *range_start);
+        let mut gaps = Vec::new();
+        let mut from = start;
+        for (&range_start, &range_end) in self.ranges.range(first..end) {
+            if range_start > from {
+                gaps.push((from, range_start));
+            }
+            from = from.max(range_end);
+        }
+        if from < end {
+            gaps.push((from, end));
+        }
+        gaps
+    }
+
+    pub fn insert(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        // Swallow every range overlapping or adjacent to the new one
+        while let Some((&range_start, &range_end)) = self.ranges.range(..=end).next_back() {
+            if range_end < start {
+                break;
+            }
+            start = start.min(range_start);
+            end = end.max(range_end);
+            self.ranges.remove(&range_start);
+        }
+        self.ranges.insert(start, end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::touched::TouchedSectors;
+
+    #[test]
+    fn test_touched_sectors() {
+        let mut touched = TouchedSectors::default();
+        touched.insert(10, 20);
+        touched.insert(30, 40);
+        assert!(!touched.overlaps(0, 10) && !touched.overlaps(20, 30) && !touched.overlaps(40, 50));
+        assert!(touched.overlaps(19, 21) && touched.overlaps(0, 100) && touched.overlaps(35, 36));
+        touched.insert(20, 30);
+        touched.insert(5, 12);
+        assert_eq!(touched.ranges.len(), 1);
+        assert_eq!(touched.ranges.get(&5), Some(&40));
+        assert_eq!(touched.sectors(), 35);
+        touched.insert(50, 60);
+        assert_eq!(touched.gaps(0, 100), vec![(0, 5), (40, 50), (60, 100)]);
+        assert_eq!(touched.gaps(10, 45), vec![(40, 45)]);
+        assert!(touched.gaps(52, 58).is_empty());
+    }
+}
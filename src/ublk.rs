@@ -0,0 +1,347 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use anyhow::{Result, anyhow, bail};
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::signal::{SigSet, Signal};
+use tracing::{error, info};
+use crate::export::BlockExport;
+
+const UBLK_CONTROL: &str = "/dev/ublk-control";
+
+// _IOWR('u', nr, struct ublksrv_ctrl_cmd)
+const UBLK_U_CMD_ADD_DEV: u32 = 0xc020_7504;
+const UBLK_U_CMD_DEL_DEV: u32 = 0xc020_7505;
+const UBLK_U_CMD_START_DEV: u32 = 0xc020_7506;
+const UBLK_U_CMD_STOP_DEV: u32 = 0xc020_7507;
+const UBLK_U_CMD_SET_PARAMS: u32 = 0xc020_7508;
+// _IOWR('u', nr, struct ublksrv_io_cmd)
+const UBLK_U_IO_FETCH_REQ: u32 = 0xc010_7520;
+const UBLK_U_IO_COMMIT_AND_FETCH_REQ: u32 = 0xc010_7521;
+
+const UBLK_IO_OP_READ: u32 = 0;
+const UBLK_IO_OP_WRITE: u32 = 1;
+const UBLK_IO_OP_FLUSH: u32 = 2;
+const UBLK_IO_OP_DISCARD: u32 = 3;
+const UBLK_IO_F_FUA: u32 = 1 << 13;
+
+const UBLK_IO_RES_OK: i32 = 0;
+
+const UBLK_PARAM_TYPE_BASIC: u32 = 1 << 0;
+const UBLK_PARAM_TYPE_DISCARD: u32 = 1 << 1;
+const UBLK_ATTR_READ_ONLY: u32 = 1 << 0;
+const UBLK_ATTR_VOLATILE_CACHE: u32 = 1 << 2;
+const UBLK_ATTR_FUA: u32 = 1 << 3;
+
+const QUEUE_DEPTH: u16 = 64;
+const MAX_IO_BUF_BYTES: u32 = 512 * 1024;
+const SECTOR_SHIFT: u8 = 9;
+const PAGE_SIZE: usize = 4096;
+
+#[repr(C)]
+#[derive(Default)]
+struct CtrlCmd {
+    dev_id: u32,
+    queue_id: u16,
+    len: u16,
+    addr: u64,
+    data: [u64; 1],
+    dev_path_len: u16,
+    pad: u16,
+    reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CtrlDevInfo {
+    nr_hw_queues: u16,
+    queue_depth: u16,
+    state: u16,
+    pad0: u16,
+    max_io_buf_bytes: u32,
+    dev_id: u32,
+    ublksrv_pid: i32,
+    pad1: u32,
+    flags: u64,
+    ublksrv_flags: u64,
+    owner_uid: u32,
+    owner_gid: u32,
+    reserved1: u64,
+    reserved2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ParamBasic {
+    attrs: u32,
+    logical_bs_shift: u8,
+    physical_bs_shift: u8,
+    io_opt_shift: u8,
+    io_min_shift: u8,
+    max_sectors: u32,
+    chunk_sectors: u32,
+    dev_sectors: u64,
+    virt_boundary_mask: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ParamDiscard {
+    discard_alignment: u32,
+    discard_granularity: u32,
+    max_discard_sectors: u32,
+    max_write_zeroes_sectors: u32,
+    max_discard_segments: u16,
+    reserved0: u16,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    len: u32,
+    types: u32,
+    basic: ParamBasic,
+    discard: ParamDiscard,
+}
+
+#[repr(C)]
+struct IoDesc {
+    op_flags: u32,
+    nr_sectors: u32,
+    start_sector: u64,
+    addr: u64,
+}
+
+#[repr(C)]
+struct IoCmd {
+    q_id: u16,
+    tag: u16,
+    result: i32,
+    addr: u64,
+}
+
+fn as_bytes<T, const N: usize>(value: &T) -> [u8; N] {
+    let mut buf = [0_u8; N];
+    let len = std::mem::size_of::<T>().min(N);
+    unsafe { std::ptr::copy_nonoverlapping(value as *const T as *const u8, buf.as_mut_ptr(), len) };
+    buf
+}
+
+/// The ublk control device, talked to through IORING_OP_URING_CMD.
+struct Control {
+    file: File,
+    ring: IoUring<squeue::Entry128, cqueue::Entry>,
+}
+
+impl Control {
+    fn open() -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(UBLK_CONTROL).map_err(|error| {
+            anyhow!("Error opening {} (is ublk_drv loaded?): {}", UBLK_CONTROL, error)
+        })?;
+        let ring = IoUring::builder().build(4)?;
+        Ok(Self { file, ring })
+    }
+
+    fn command(&mut self, op: u32, cmd: &CtrlCmd) -> Result<i32> {
+        let sqe = opcode::UringCmd80::new(types::Fd(self.file.as_raw_fd()), op)
+            .cmd(as_bytes(cmd))
+            .build();
+        unsafe { self.ring.submission().push(&sqe) }.map_err(|_| anyhow!("ublk control ring is full"))?;
+        self.ring.submit_and_wait(1)?;
+        let cqe = self.ring.completion().next().ok_or_else(|| anyhow!("Missing ublk control completion"))?;
+        if cqe.result() < 0 {
+            bail!("ublk control command {:#x} failed: {}", op, nix::errno::Errno::from_i32(-cqe.result()))
+        }
+        Ok(cqe.result())
+    }
+
+    fn dev_command(&mut self, op: u32, dev_id: u32) -> Result<i32> {
+        let cmd = CtrlCmd {
+            dev_id,
+            queue_id: u16::MAX,
+            ..Default::default()
+        };
+        self.command(op, &cmd)
+    }
+}
+
+fn handle_io(export: &mut dyn BlockExport, iod: &IoDesc, buf: &mut [u8]) -> i32 {
+    let offset = iod.start_sector << SECTOR_SHIFT;
+    let len = (iod.nr_sectors as usize) << SECTOR_SHIFT;
+    let ret = match iod.op_flags & 0xff {
+        UBLK_IO_OP_READ => export.read(offset, &mut buf[..len]),
+        UBLK_IO_OP_WRITE => export.write(offset, &buf[..len], (iod.op_flags & UBLK_IO_F_FUA) > 0),
+        UBLK_IO_OP_FLUSH => export.flush(),
+        UBLK_IO_OP_DISCARD => export.trim(offset, len as u64),
+        _ => return -nix::libc::EINVAL,
+    };
+    match ret {
+        Ok(()) => len as i32,
+        Err(error) => {
+            error!("Error handling ublk io at {} of {} bytes: {}", offset, len, error);
+            -nix::libc::EIO
+        }
+    }
+}
+
+/// Serves the single hardware queue until the device is stopped.
+fn run_queue(dev_id: u32, export: &mut (dyn BlockExport + Send)) -> Result<()> {
+    let path = format!("/dev/ublkc{}", dev_id);
+    // udev may take a moment to create the char device
+    let mut char_dev = None;
+    for _ in 0..100 {
+        match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => {
+                char_dev = Some(file);
+                break;
+            }
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    let char_dev = char_dev.ok_or_else(|| anyhow!("Error opening {}", path))?;
+
+    let desc_size = (QUEUE_DEPTH as usize * std::mem::size_of::<IoDesc>()).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let descs = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            desc_size,
+            ProtFlags::PROT_READ,
+            MapFlags::MAP_SHARED | MapFlags::MAP_POPULATE,
+            char_dev.as_raw_fd(),
+            0,
+        )
+    }.map_err(|error| anyhow!("Error mapping io descriptors: {}", error))? as *const IoDesc;
+
+    let mut ring: IoUring<squeue::Entry, cqueue::Entry> = IoUring::new(QUEUE_DEPTH as u32 * 2)?;
+    let mut bufs: Vec<Vec<u8>> = (0..QUEUE_DEPTH).map(|_| vec![0_u8; MAX_IO_BUF_BYTES as usize]).collect();
+
+    let queue_cmd = |ring: &mut IoUring<squeue::Entry, cqueue::Entry>, op: u32, tag: u16, result: i32, addr: u64| -> Result<()> {
+        let cmd = IoCmd { q_id: 0, tag, result, addr };
+        let sqe = opcode::UringCmd16::new(types::Fd(char_dev.as_raw_fd()), op)
+            .cmd(as_bytes(&cmd))
+            .build()
+            .user_data(tag as u64);
+        unsafe { ring.submission().push(&sqe) }.map_err(|_| anyhow!("ublk queue ring is full"))
+    };
+
+    for (tag, buf) in bufs.iter_mut().enumerate() {
+        queue_cmd(&mut ring, UBLK_U_IO_FETCH_REQ, tag as u16, -1, buf.as_mut_ptr() as u64)?;
+    }
+
+    'serve: loop {
+        ring.submit_and_wait(1)?;
+        let completed: Vec<(u16, i32)> = ring.completion().map(|cqe| (cqe.user_data() as u16, cqe.result())).collect();
+        for (tag, res) in completed {
+            if res != UBLK_IO_RES_OK {
+                // The device is being stopped and aborts outstanding fetches
+                break 'serve;
+            }
+            let iod = unsafe { &*descs.add(tag as usize) };
+            let buf = &mut bufs[tag as usize];
+            let result = handle_io(export, iod, buf);
+            queue_cmd(&mut ring, UBLK_U_IO_COMMIT_AND_FETCH_REQ, tag, result, buf.as_mut_ptr() as u64)?;
+        }
+    }
+
+    unsafe { munmap(descs as *mut nix::libc::c_void, desc_size) }?;
+    Ok(())
+}
+
+/// Exposes `export` as /dev/ublkbN until SIGINT or SIGTERM, then removes the device.
+pub fn serve(export: &mut (dyn BlockExport + Send), sector_size: u32) -> Result<()> {
+    if sector_size < 512 || !sector_size.is_power_of_two() {
+        bail!("Invalid sector size {}", sector_size)
+    }
+    let mut control = Control::open()?;
+
+    let mut info = CtrlDevInfo {
+        nr_hw_queues: 1,
+        queue_depth: QUEUE_DEPTH,
+        max_io_buf_bytes: MAX_IO_BUF_BYTES,
+        dev_id: u32::MAX,
+        ublksrv_pid: std::process::id() as i32,
+        ..Default::default()
+    };
+    let cmd = CtrlCmd {
+        dev_id: u32::MAX,
+        queue_id: u16::MAX,
+        len: std::mem::size_of::<CtrlDevInfo>() as u16,
+        addr: &mut info as *mut CtrlDevInfo as u64,
+        ..Default::default()
+    };
+    control.command(UBLK_U_CMD_ADD_DEV, &cmd)?;
+    let dev_id = info.dev_id;
+
+    let result = (|| -> Result<()> {
+        let shift = sector_size.trailing_zeros() as u8;
+        let mut params = Params {
+            len: std::mem::size_of::<Params>() as u32,
+            types: UBLK_PARAM_TYPE_BASIC,
+            basic: ParamBasic {
+                attrs: UBLK_ATTR_VOLATILE_CACHE | UBLK_ATTR_FUA,
+                logical_bs_shift: shift,
+                physical_bs_shift: shift,
+                io_min_shift: shift,
+                io_opt_shift: shift,
+                max_sectors: MAX_IO_BUF_BYTES >> SECTOR_SHIFT,
+                dev_sectors: export.size() >> SECTOR_SHIFT,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        if export.read_only() {
+            params.basic.attrs |= UBLK_ATTR_READ_ONLY;
+        } else {
+            params.types |= UBLK_PARAM_TYPE_DISCARD;
+            params.discard = ParamDiscard {
+                discard_granularity: sector_size,
+                max_discard_sectors: u32::MAX >> SECTOR_SHIFT,
+                max_discard_segments: 1,
+                ..Default::default()
+            };
+        }
+        let cmd = CtrlCmd {
+            dev_id,
+            queue_id: u16::MAX,
+            len: std::mem::size_of::<Params>() as u16,
+            addr: &mut params as *mut Params as u64,
+            ..Default::default()
+        };
+        control.command(UBLK_U_CMD_SET_PARAMS, &cmd)?;
+
+        // Block the signals before spawning so only sigwait below sees them
+        let mut signals = SigSet::empty();
+        signals.add(Signal::SIGINT);
+        signals.add(Signal::SIGTERM);
+        signals.thread_block()?;
+
+        thread::scope(|scope| -> Result<()> {
+            let queue = scope.spawn(|| run_queue(dev_id, export));
+
+            // Completes once the queue has fetched all its tags
+            let cmd = CtrlCmd {
+                dev_id,
+                queue_id: u16::MAX,
+                data: [std::process::id() as u64],
+                ..Default::default()
+            };
+            let started = control.command(UBLK_U_CMD_START_DEV, &cmd);
+            if started.is_ok() {
+                info!("serving on {}", Path::new(&format!("/dev/ublkb{}", dev_id)).display());
+                let signal = signals.wait()?;
+                info!("received {:?}, stopping /dev/ublkb{}", signal, dev_id);
+            }
+            // Stopping aborts the queue's outstanding fetches so it can exit
+            control.dev_command(UBLK_U_CMD_STOP_DEV, dev_id)?;
+            started?;
+            queue.join().map_err(|_| anyhow!("ublk queue thread panicked"))?
+        })?;
+        Ok(())
+    })();
+
+    control.dev_command(UBLK_U_CMD_DEL_DEV, dev_id)?;
+    result
+}
@@ -0,0 +1,260 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use anyhow::{Result, bail, anyhow};
+use bytes::{Buf, BufMut, BytesMut};
+use crate::io::{self, ByteOffset};
+
+const UNDO_MAGIC: &[u8; 8] = b"LWUNDO02";
+/// The magic and where the last whole record ends
+const HEADER_SIZE: u64 = 16;
+const RECORD_SIZE: u64 = 40;
+/// Most of the old data `save` and `step_back` hold in memory at once, unless
+/// `max_memory` lowers it
+const COPY_CHUNK: u64 = 1024 * 1024;
+
+/// One replayed entry: what the replay target held before it was applied.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct UndoRecord {
+    pub index: u64,
+    /// Where the entry starts in the log, to resume replaying from it
+    pub log_offset: u64,
+    /// Byte range of the replay target the entry overwrote, empty for marks and flushes
+    pub offset: u64,
+    pub len: u64,
+    /// Size of the replay target before the entry, writes past the end grow files
+    pub old_size: u64,
+}
+
+impl UndoRecord {
+    fn to_bytes(self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(RECORD_SIZE as usize);
+        buf.put_u64_le(self.index);
+        buf.put_u64_le(self.log_offset);
+        buf.put_u64_le(self.offset);
+        buf.put_u64_le(self.len);
+        buf.put_u64_le(self.old_size);
+        buf
+    }
+
+    fn from_bytes(mut buf: &[u8]) -> Self {
+        Self {
+            index: buf.get_u64_le(),
+            log_offset: buf.get_u64_le(),
+            offset: buf.get_u64_le(),
+            len: buf.get_u64_le(),
+            old_size: buf.get_u64_le(),
+        }
+    }
+}
+
+/// The prior contents of everything a replay overwrote, so the replay target
+/// can be rolled back entry by entry.
+///
+/// Each record is the old data followed by a fixed size `UndoRecord`, which
+/// lets the log be read back to front and truncated as it's undone. The
+/// header says where the last synced record ends, so what a crash left half
+/// written after it is never taken for a record.
+pub struct UndoLog {
+    file: File,
+    /// A separate handle, the replay target is opened write only
+    replay: File,
+    end: u64,
+    chunk: u64,
+}
+
+impl UndoLog {
+    /// Starts a new undo log for replaying onto `replay_path`.
+    pub fn create<P: AsRef<Path>>(path: P, replay_path: P) -> Result<Self> {
+        Self::create_with(OpenOptions::new().create(true).truncate(true), path, replay_path)
+    }
+
+    /// Like `create`, but fails if anything is at `path` already, a symlink
+    /// included, for undo logs in a shared directory like /tmp.
+    pub fn create_new<P: AsRef<Path>>(path: P, replay_path: P) -> Result<Self> {
+        Self::create_with(OpenOptions::new().create_new(true), path, replay_path)
+    }
+
+    fn create_with<P: AsRef<Path>>(options: &mut OpenOptions, path: P, replay_path: P) -> Result<Self> {
+        let file = options.read(true).write(true).open(&path)
+            .map_err(|error| anyhow!("Error creating undo log {}: {}", path.as_ref().display(), error))?;
+        let replay = OpenOptions::new().read(true).write(true).open(replay_path)?;
+        let mut undo = Self { file, replay, end: HEADER_SIZE, chunk: COPY_CHUNK };
+        undo.commit()?;
+        Ok(undo)
+    }
+
+    /// Opens an existing undo log to roll `replay_path` back.
+    pub fn open<P: AsRef<Path>>(path: P, replay_path: P) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(&path)
+            .map_err(|error| anyhow!("Error opening undo log {}: {}", path.as_ref().display(), error))?;
+        let mut header = [0_u8; HEADER_SIZE as usize];
+        if io::read_full_at(&file, &mut header, ByteOffset::ZERO)? != header.len() || &header[..8] != UNDO_MAGIC {
+            bail!("{} is not an undo log", path.as_ref().display())
+        }
+        let end = (&header[8..]).get_u64_le();
+        if end < HEADER_SIZE || end > file.metadata()?.len() {
+            bail!("{} is damaged, its records end at {} but the file is {} bytes", path.as_ref().display(), end, file.metadata()?.len())
+        }
+        let replay = OpenOptions::new().read(true).write(true).open(replay_path)?;
+        Ok(Self { file, replay, end, chunk: COPY_CHUNK })
+    }
+
+    /// Holds at most `bytes` of old data in memory at once, like `LogBuilder::max_memory`.
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.chunk = COPY_CHUNK.min(bytes as u64).max(1);
+        self
+    }
+
+    /// Syncs the records up to `end` and then the header pointing past them.
+    fn commit(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        let mut header = BytesMut::with_capacity(HEADER_SIZE as usize);
+        header.put_slice(UNDO_MAGIC);
+        header.put_u64_le(self.end);
+        if io::write_full_at(&self.file, &header, ByteOffset::ZERO)? != header.len() {
+            bail!("Short write to the undo log header")
+        }
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn replay_size(&self) -> Result<u64> {
+        let metadata = self.replay.metadata()?;
+        if metadata.is_file() {
+            Ok(metadata.len())
+        } else {
+            io::block_device_size(&self.replay)
+        }
+    }
+
+    /// Saves `len` bytes at `offset` of the replay target before entry `index` overwrites them.
+    pub fn save(&mut self, index: u64, log_offset: u64, offset: u64, len: u64) -> Result<()> {
+        let old_size = self.replay_size()?;
+        // Nothing to keep past the end of the target
+        let kept = len.min(old_size.saturating_sub(offset));
+        let mut buf = vec![0_u8; kept.min(self.chunk) as usize];
+        let mut done = 0;
+        while done < kept {
+            let chunk = &mut buf[..(kept - done).min(self.chunk) as usize];
+            if io::read_full_at(&self.replay, chunk, ByteOffset::new(offset + done)?)? != chunk.len() {
+                bail!("Short read saving undo data for entry {}", index)
+            }
+            if io::write_full_at(&self.file, chunk, ByteOffset::new(self.end + done)?)? != chunk.len() {
+                bail!("Short write to undo log for entry {}", index)
+            }
+            done += chunk.len() as u64;
+        }
+        let record = UndoRecord { index, log_offset, offset, len: kept, old_size }.to_bytes();
+        if io::write_full_at(&self.file, &record, ByteOffset::new(self.end + kept)?)? != record.len() {
+            bail!("Short write to undo log for entry {}", index)
+        }
+        self.end += kept + record.len() as u64;
+        self.commit()
+    }
+
+    /// Restores the replay target to before the last `count` entries, returns
+    /// the earliest entry undone, the one to replay next.
+    pub fn step_back(&mut self, count: u64) -> Result<Option<UndoRecord>> {
+        let mut last = None;
+        for _ in 0..count {
+            if self.end <= HEADER_SIZE {
+                break;
+            }
+            if self.end - HEADER_SIZE < RECORD_SIZE {
+                bail!("Undo log ends in a partial record, {} bytes after the header", self.end - HEADER_SIZE)
+            }
+            let mut raw = [0_u8; RECORD_SIZE as usize];
+            if io::read_full_at(&self.file, &mut raw, ByteOffset::new(self.end - RECORD_SIZE)?)? != raw.len() {
+                bail!("Short read of the undo record ending at {}", self.end)
+            }
+            let record = UndoRecord::from_bytes(&raw);
+            if record.len > self.end - HEADER_SIZE - RECORD_SIZE || record.offset.checked_add(record.len).is_none() {
+                bail!("Bad undo record for entry {}: {} bytes at {}, with {} bytes of undo data before it",
+                      record.index, record.len, record.offset, self.end - HEADER_SIZE - RECORD_SIZE)
+            }
+            let start = self.end - RECORD_SIZE - record.len;
+
+            let mut buf = vec![0_u8; record.len.min(self.chunk) as usize];
+            let mut done = 0;
+            while done < record.len {
+                let chunk = &mut buf[..(record.len - done).min(self.chunk) as usize];
+                if io::read_full_at(&self.file, chunk, ByteOffset::new(start + done)?)? != chunk.len() {
+                    bail!("Short read of undo data for entry {}", record.index)
+                }
+                if io::write_full_at(&self.replay, chunk, ByteOffset::new(record.offset + done)?)? != chunk.len() {
+                    bail!("Error restoring entry {}", record.index)
+                }
+                done += chunk.len() as u64;
+            }
+            let metadata = self.replay.metadata()?;
+            if metadata.is_file() && metadata.len() > record.old_size {
+                self.replay.set_len(record.old_size)?;
+            }
+
+            // The target first, a crash before the header is updated only restores the record again
+            self.replay.sync_all()?;
+            self.end = start;
+            self.commit()?;
+            self.file.set_len(self.end)?;
+            last = Some(record);
+        }
+        Ok(last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::{self, ByteOffset};
+    use crate::testutil::TempPath;
+    use crate::undo::UndoLog;
+
+    #[test]
+    fn test_step_back() {
+        let (replay, path) = (TempPath::new("undo.img"), TempPath::new("undo.undo"));
+        std::fs::write(&replay, [1_u8; 1024]).unwrap();
+
+        let mut undo = UndoLog::create(&path, &replay).unwrap();
+        let file = std::fs::OpenOptions::new().write(true).open(&replay).unwrap();
+        undo.save(0, 512, 0, 512).unwrap();
+        io::pwrite(&file, &[2_u8; 512], ByteOffset::ZERO).unwrap();
+        undo.save(1, 1536, 512, 1024).unwrap();
+        io::pwrite(&file, &[3_u8; 1024], ByteOffset::new(512).unwrap()).unwrap();
+        drop(undo);
+
+        // Restored a few bytes at a time
+        let mut undo = UndoLog::open(&path, &replay).unwrap().max_memory(100);
+        assert_eq!(undo.step_back(1).unwrap().unwrap().index, 1);
+        let mut expected = vec![2_u8; 512];
+        expected.extend([1_u8; 512]);
+        assert_eq!(std::fs::read(&replay).unwrap(), expected);
+        assert_eq!(undo.step_back(5).unwrap().unwrap().log_offset, 512);
+        assert_eq!(std::fs::read(&replay).unwrap(), vec![1_u8; 1024]);
+        assert_eq!(undo.step_back(1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_damaged_undo_log() {
+        let (replay, path) = (TempPath::new("damaged.img"), TempPath::new("damaged.undo"));
+        std::fs::write(&replay, [1_u8; 1024]).unwrap();
+        let mut undo = UndoLog::create(&path, &replay).unwrap();
+        undo.save(0, 512, 0, 512).unwrap();
+        drop(undo);
+        // Half of a save a crash cut short, past the end the header records
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, &[0xff_u8; 300]).unwrap();
+
+        let mut undo = UndoLog::open(&path, &replay).unwrap();
+        assert_eq!(undo.step_back(5).unwrap().unwrap().index, 0);
+
+        // A record claiming more undo data than there is
+        let mut undo = UndoLog::create(&path, &replay).unwrap();
+        undo.save(0, 512, 0, 512).unwrap();
+        let len_at = ByteOffset::new(16 + 512 + 24).unwrap();
+        io::pwrite(&std::fs::OpenOptions::new().write(true).open(&path).unwrap(), &u64::MAX.to_le_bytes(), len_at).unwrap();
+        assert!(undo.step_back(1).is_err());
+        std::fs::write(&path, b"LWUNDO02").unwrap();
+        assert!(UndoLog::open(&path, &replay).is_err());
+        assert!(UndoLog::create_new(&path, &replay).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"LWUNDO02");
+    }
+}
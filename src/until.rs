@@ -0,0 +1,288 @@
+//! `--until`: when to stop a replay, as an expression checked after every
+//! entry, e.g. `mark==fsync-* || entry==5000 || flush_count==10`.
+//!
+//! Comparisons are `NAME OP VALUE` with `==`, `!=`, `<`, `<=`, `>` or `>=`,
+//! combined with `&&`, `||`, `!` and parentheses. The names are
+//! - `entry`: index of the entry just replayed
+//! - `entries`: entries replayed so far
+//! - `sector`: sector the entry writes to
+//! - `flush_count`, `fua_count`, `discard_count`, `mark_count`: entries
+//!   replayed so far with that flag
+//! - `mark`: the entry's mark, compared with `==` or `!=` against a name,
+//!   glob, `re:` regex or literal `name:` as --end-mark takes. Quote values
+//!   with spaces.
+
+use anyhow::{Result, bail, anyhow};
+use crate::entries::MarkPattern;
+use crate::log_writes::{LogWriteEntry, LOG_DISCARD_FLAG, LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_MARK_FLAG};
+
+/// Most `(` and `!` an expression may nest, parsing and checking it recurse that far
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Counter {
+    Entry,
+    Entries,
+    Sector,
+    FlushCount,
+    FuaCount,
+    DiscardCount,
+    MarkCount,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Or(Vec<Expr>),
+    And(Vec<Expr>),
+    Not(Box<Expr>),
+    Compare(Counter, Op, u64),
+    Mark(bool, MarkPattern),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let next_is = |chars: &mut std::iter::Peekable<std::str::Chars>, expected: char| chars.next_if_eq(&expected).is_some();
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' if next_is(&mut chars, '&') => Token::And,
+            '|' if next_is(&mut chars, '|') => Token::Or,
+            '=' if next_is(&mut chars, '=') => Token::Op(Op::Eq),
+            '!' if next_is(&mut chars, '=') => Token::Op(Op::Ne),
+            '!' => Token::Not,
+            '<' if next_is(&mut chars, '=') => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if next_is(&mut chars, '=') => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '\'' | '"' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(next) if next == c => break,
+                        Some(next) => word.push(next),
+                        None => bail!("Missing closing {} in --until {}", c, text),
+                    }
+                }
+                Token::Word(word)
+            }
+            c if !"&|=<>".contains(c) => {
+                let mut word = c.to_string();
+                while let Some(next) = chars.next_if(|next| !next.is_whitespace() && !"()&|=!<>".contains(*next)) {
+                    word.push(next);
+                }
+                Token::Word(word)
+            }
+            c => bail!("Unexpected {} in --until {}", c, text),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: Token) -> bool {
+        if self.tokens.get(self.pos) == Some(&token) {
+            self.pos += 1;
+            return true
+        }
+        false
+    }
+
+    fn deeper(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            bail!("--until {} nests deeper than {}", self.text, MAX_DEPTH)
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.and()?];
+        while self.eat(Token::Or) {
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::Or(exprs) })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.unary()?];
+        while self.eat(Token::And) {
+            exprs.push(self.unary()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::And(exprs) })
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let depth = self.depth;
+        let expr = match self.next() {
+            Some(Token::Not) => {
+                self.deeper()?;
+                Expr::Not(Box::new(self.unary()?))
+            }
+            Some(Token::Open) => {
+                self.deeper()?;
+                let expr = self.or()?;
+                if !self.eat(Token::Close) {
+                    bail!("Missing ) in --until {}", self.text)
+                }
+                expr
+            }
+            Some(Token::Word(name)) => self.compare(&name)?,
+            _ => bail!("Expected a comparison in --until {}", self.text),
+        };
+        self.depth = depth;
+        Ok(expr)
+    }
+
+    fn compare(&mut self, name: &str) -> Result<Expr> {
+        let (op, value) = match (self.next(), self.next()) {
+            (Some(Token::Op(op)), Some(Token::Word(value))) => (op, value),
+            _ => bail!("Expected {} OP VALUE in --until {}", name, self.text),
+        };
+        let counter = match name {
+            "mark" => return match op {
+                Op::Eq | Op::Ne => Ok(Expr::Mark(op == Op::Eq, value.parse()?)),
+                _ => bail!("mark can only be compared with == or != in --until {}", self.text),
+            },
+            "entry" => Counter::Entry,
+            "entries" => Counter::Entries,
+            "sector" => Counter::Sector,
+            "flush_count" => Counter::FlushCount,
+            "fua_count" => Counter::FuaCount,
+            "discard_count" => Counter::DiscardCount,
+            "mark_count" => Counter::MarkCount,
+            _ => bail!("Unknown name {} in --until {}", name, self.text),
+        };
+        let value = value.parse().map_err(|error| anyhow!("Invalid number {} in --until {}: {}", value, self.text, error))?;
+        Ok(Expr::Compare(counter, op, value))
+    }
+}
+
+/// A parsed `--until`, counting the flags of the entries it's shown.
+#[derive(Debug)]
+pub struct Until {
+    expr: Expr,
+    counts: [u64; 5],
+}
+
+impl Until {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser { text, tokens: tokenize(text)?, pos: 0, depth: 0 };
+        let expr = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            bail!("Unexpected {:?} in --until {}", parser.tokens[parser.pos], text)
+        }
+        Ok(Self { expr, counts: [0; 5] })
+    }
+
+    /// Counts entry `index`, just replayed, and whether to stop after it.
+    pub fn stop(&mut self, entry: &LogWriteEntry, index: u64) -> bool {
+        self.counts[0] += 1;
+        for (count, flag) in self.counts[1..].iter_mut().zip([LOG_FLUSH_FLAG, LOG_FUA_FLAG, LOG_DISCARD_FLAG, LOG_MARK_FLAG]) {
+            if (entry.flags & flag) > 0 {
+                *count += 1;
+            }
+        }
+        self.eval(&self.expr, entry, index)
+    }
+
+    fn eval(&self, expr: &Expr, entry: &LogWriteEntry, index: u64) -> bool {
+        match expr {
+            Expr::Or(exprs) => exprs.iter().any(|expr| self.eval(expr, entry, index)),
+            Expr::And(exprs) => exprs.iter().all(|expr| self.eval(expr, entry, index)),
+            Expr::Not(expr) => !self.eval(expr, entry, index),
+            Expr::Mark(equal, pattern) => {
+                let is_match = (entry.flags & LOG_MARK_FLAG) > 0 && pattern.matches(&entry.cmd);
+                is_match == *equal
+            }
+            Expr::Compare(counter, op, value) => {
+                let actual = match counter {
+                    Counter::Entry => index,
+                    Counter::Sector => entry.sector,
+                    Counter::Entries => self.counts[0],
+                    Counter::FlushCount => self.counts[1],
+                    Counter::FuaCount => self.counts[2],
+                    Counter::DiscardCount => self.counts[3],
+                    Counter::MarkCount => self.counts[4],
+                };
+                match op {
+                    Op::Eq => actual == *value,
+                    Op::Ne => actual != *value,
+                    Op::Lt => actual < *value,
+                    Op::Le => actual <= *value,
+                    Op::Gt => actual > *value,
+                    Op::Ge => actual >= *value,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_writes::{LogWriteEntry, LOG_FLUSH_FLAG, LOG_MARK_FLAG};
+    use crate::until::Until;
+
+    fn entry(flags: u64, cmd: &str) -> LogWriteEntry {
+        LogWriteEntry { sector: 0, nr_sectors: 0, flags, data_len: 0, cmd: cmd.to_string() }
+    }
+
+    #[test]
+    fn test_until() {
+        let mut until = Until::parse("mark == 'fsync-*' || entry==5000 || (flush_count>=2 && !entries<3)").unwrap();
+        assert!(!until.stop(&entry(LOG_FLUSH_FLAG, ""), 1));
+        assert!(!until.stop(&entry(LOG_FLUSH_FLAG, ""), 2));
+        assert!(until.stop(&entry(0, ""), 3));
+        assert!(Until::parse("mark==fsync-*").unwrap().stop(&entry(LOG_MARK_FLAG, "fsync-3"), 0));
+        assert!(Until::parse("entry==5000").unwrap().stop(&entry(0, ""), 5000));
+
+        assert!(Until::parse("entry==").is_err());
+        assert!(Until::parse("blocks>3").is_err());
+        assert!(Until::parse("(entry==1").is_err());
+        assert!(Until::parse("mark<3").is_err());
+        assert!(Until::parse("entry==1 entry==2").is_err());
+        assert!(Until::parse("mark == 'fsync").is_err());
+        assert!(Until::parse(&format!("{}entry==1", "!".repeat(100_000))).is_err());
+        assert!(Until::parse(&format!("{}entry==1{}", "(".repeat(100_000), ")".repeat(100_000))).is_err());
+        assert!(Until::parse(&vec!["mark==a"; 100_000].join(" || ")).is_ok());
+        assert!(Until::parse(&vec!["entry==1 && entry!=2"; 1000].join(" || ")).is_ok());
+        assert!(Until::parse(&format!("{}entry==1", "!".repeat(100))).is_ok());
+    }
+}
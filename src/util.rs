@@ -12,11 +12,35 @@ pub fn strncat(dest : &mut String, src : String, n : usize ) {
 pub fn strnlen<S : AsRef<str>>(src : S, max_len : usize ) -> usize {
     min(src.as_ref().len(), max_len)
 }
+
+pub fn shell_quote<S : AsRef<str>>(src : S) -> String {
+    format!("'{}'", src.as_ref().replace('\'', "'\\''"))
+}
+
+/// Whether every byte of `buf` is zero, a word at a time so it vectorizes.
+pub fn is_zero(buf : &[u8]) -> bool {
+    // Safe, any bit pattern is a valid u128
+    let (prefix, words, suffix) = unsafe { buf.align_to::<u128>() };
+    prefix.iter().all(|byte| *byte == 0)
+        && words.iter().fold(0, |acc, word| acc | word) == 0
+        && suffix.iter().all(|byte| *byte == 0)
+}
+
 #[test]
 fn test_strncat() {
     let mut hello = "Hello ".to_string();
     let world = "World Micheal";
     strncat(&mut hello, world.to_string(), 5 );
     assert_eq!(hello, "Hello World");
-    println!("{}", hello);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_is_zero() {
+    let mut buf = vec![0_u8; 4099];
+    assert!(is_zero(&buf) && is_zero(&buf[1..]) && is_zero(&[]));
+    buf[4098] = 1;
+    assert!(!is_zero(&buf));
+    buf[4098] = 0;
+    buf[17] = 0x80;
+    assert!(!is_zero(&buf[3..]));
+}
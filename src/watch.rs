@@ -0,0 +1,95 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, bail, anyhow};
+use crate::log_reader::LogReader;
+use crate::log_writes::{LogWriteEntry, LOG_MARK_FLAG};
+
+/// A run of sectors, `[start, end)`, that entries shouldn't normally touch.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end - 1)
+    }
+}
+
+/// Parses comma separated `first-last` (inclusive) or `start+count` sector ranges.
+/// Ranges reaching the end of the sector space stop at u64::MAX.
+pub fn parse_regions(regions: &str) -> Result<Vec<Region>> {
+    regions.split(',').map(str::trim).filter(|region| !region.is_empty()).map(|region| {
+        let parse = |sector: &str| sector.parse::<u64>().map_err(|error| anyhow!("Invalid region {}: {}", region, error));
+        let (start, end) = if let Some((start, count)) = region.split_once('+') {
+            let start = parse(start)?;
+            (start, start.saturating_add(parse(count)?))
+        } else if let Some((first, last)) = region.split_once('-') {
+            (parse(first)?, parse(last)?.saturating_add(1))
+        } else {
+            let sector = parse(region)?;
+            (sector, sector.saturating_add(1))
+        };
+        if start >= end {
+            bail!("Empty region {}", region)
+        }
+        Ok(Region { start, end })
+    }).collect()
+}
+
+/// Reads regions from a file, any mix of lines and commas, `#` starting a comment.
+pub fn read_regions_file<P: AsRef<Path>>(path: P) -> Result<Vec<Region>> {
+    let text = fs::read_to_string(&path)
+        .map_err(|error| anyhow!("Error reading {}: {}", path.as_ref().display(), error))?;
+    let regions: Vec<&str> = text.lines().map(|line| line.split('#').next().unwrap_or("")).collect();
+    parse_regions(&regions.join(","))
+}
+
+/// The first region `entry` writes or discards into.
+pub fn hit(entry: &LogWriteEntry, regions: &[Region]) -> Option<Region> {
+    if (entry.flags & LOG_MARK_FLAG) > 0 {
+        return None;
+    }
+    let end = entry.sector.saturating_add(entry.nr_sectors);
+    regions.iter().find(|region| entry.sector < region.end && region.start < end).copied()
+}
+
+/// Every entry of the log touching one of `regions`, with the region it hit.
+pub fn find_hits(reader: &mut LogReader, regions: &[Region]) -> Result<Vec<(u64, Region)>> {
+    let mut hits = Vec::new();
+    while let Some(log_entry) = reader.next_entry()? {
+        if let Some(region) = hit(&log_entry.entry, regions) {
+            hits.push((log_entry.index, region));
+        }
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::log_writes::LogWriteEntry;
+    use crate::watch::{hit, parse_regions, Region};
+
+    #[test]
+    fn test_regions() {
+        let regions = parse_regions("0-15, 32768+8,100").unwrap();
+        assert_eq!(regions, vec![
+            Region { start: 0, end: 16 },
+            Region { start: 32768, end: 32776 },
+            Region { start: 100, end: 101 },
+        ]);
+        assert!(parse_regions("8-4").is_err());
+
+        let write = |sector, nr_sectors| LogWriteEntry { sector, nr_sectors, flags: 0, data_len: 0, cmd: String::new() };
+        assert_eq!(hit(&write(16, 8), &regions), None);
+        assert_eq!(hit(&write(32760, 9), &regions), Some(regions[1]));
+        assert_eq!(hit(&write(12, 8), &regions), Some(regions[0]));
+
+        let all = parse_regions("0-18446744073709551615").unwrap();
+        assert_eq!(all, vec![Region { start: 0, end: u64::MAX }]);
+        assert_eq!(parse_regions("18446744073709551610+100").unwrap()[0].end, u64::MAX);
+        assert_eq!(hit(&write(u64::MAX - 1, 8), &all), Some(all[0]));
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use anyhow::{Result, bail};
+use crate::io::{self, ByteOffset};
+use crate::log_writes::{LogWriteSuper, LogWriteEntry, MemSize, LOG_DISCARD_FLAG, LOG_MARK_FLAG, WRITE_LOG_MAGIC, WRITE_LOG_VERSION};
+
+/// Produces a log in the dm-log-writes on-disk format: the superblock in the first
+/// sector, then every entry header in its own sector followed by its data.
+pub struct LogWriter {
+    file: File,
+    sector_size: u32,
+    nr_entries: u64,
+    next_offset: u64,
+    /// Also write the superblock in the sector after the last entry on every
+    /// sync, the next entry appended overwrites it. A log whose two copies
+    /// differ was left by a capture that stopped while syncing.
+    pub trailer: bool,
+}
+
+impl LogWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sector_size: u32) -> Result<Self> {
+        if sector_size < LogWriteSuper::mem_size() as u32 || !sector_size.is_power_of_two() {
+            bail!("Invalid sector size {}", sector_size)
+        }
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
+        let mut writer = Self {
+            file,
+            sector_size,
+            nr_entries: 0,
+            next_offset: sector_size as u64,
+            trailer: false,
+        };
+        writer.write_super()?;
+        Ok(writer)
+    }
+
+    pub fn nr_entries(&self) -> u64 {
+        self.nr_entries
+    }
+
+    fn write_super(&mut self) -> Result<()> {
+        self.write_super_at(ByteOffset::ZERO)
+    }
+
+    /// The copy of the superblock after the last entry, see `trailer`.
+    fn write_trailer(&mut self) -> Result<()> {
+        self.write_super_at(ByteOffset::new(self.next_offset)?)
+    }
+
+    fn write_super_at(&mut self, offset: ByteOffset) -> Result<()> {
+        let log_super = LogWriteSuper {
+            magic: WRITE_LOG_MAGIC,
+            version: WRITE_LOG_VERSION,
+            nr_entries: self.nr_entries,
+            sector_size: self.sector_size,
+        };
+        let mut buf = log_super.to_bytes().to_vec();
+        buf.resize(self.sector_size as usize, 0);
+        io::write_full_at(&self.file, &buf, offset)?;
+        Ok(())
+    }
+
+    /// Appends an entry, `data` must be `entry.nr_sectors` sectors long, or empty
+    /// for discards.
+    pub fn append(&mut self, entry: &LogWriteEntry, data: &[u8]) -> Result<()> {
+        let expected = if (entry.flags & LOG_DISCARD_FLAG) > 0 {
+            0
+        } else {
+            entry.nr_sectors * self.sector_size as u64
+        };
+        if data.len() as u64 != expected {
+            bail!("Entry data is {} bytes, expected {}", data.len(), expected)
+        }
+        let mut header = entry.to_bytes().to_vec();
+        if header.len() > self.sector_size as usize {
+            bail!("Entry header doesn't fit in a sector")
+        }
+        header.resize(self.sector_size as usize, 0);
+        header.extend_from_slice(data);
+        let ret = io::write_full_at(&self.file, &header, ByteOffset::new(self.next_offset)?)?;
+        if ret != header.len() {
+            bail!("Short write appending entry {}: {}", self.nr_entries, ret)
+        }
+        self.next_offset += header.len() as u64;
+        self.nr_entries += 1;
+        Ok(())
+    }
+
+    pub fn mark(&mut self, name: &str) -> Result<()> {
+        let entry = LogWriteEntry {
+            sector: 0,
+            nr_sectors: 0,
+            flags: LOG_MARK_FLAG,
+            data_len: name.len() as u64,
+            cmd: name.to_string(),
+        };
+        self.append(&entry, &[])
+    }
+
+    /// Publishes the appended entries by updating the superblock, and syncs the log.
+    pub fn sync(&mut self) -> Result<()> {
+        // The copy goes first, so a superblock it disagrees with is the older one
+        if self.trailer {
+            self.write_trailer()?;
+        }
+        self.write_super()?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use crate::log_reader::{LogReader, Trailer};
+    use crate::log_writes::{Log, LogWriteEntry, LOG_DISCARD_FLAG};
+    use crate::writer::LogWriter;
+
+    /// An entry with its data. Flags are any bits, unknown ones included, and
+    /// discards may span every sector since they carry no data.
+    fn entry(sector_size: u32) -> impl Strategy<Value = (LogWriteEntry, Vec<u8>)> {
+        (any::<u64>(), 0_u64..8, any::<bool>(), any::<u64>(), any::<u64>(), "[^\\x00]{0,8}", any::<u8>())
+            .prop_map(move |(sector, nr_sectors, all_sectors, flags, data_len, cmd, fill)| {
+                let discard = (flags & LOG_DISCARD_FLAG) > 0;
+                let nr_sectors = if discard && all_sectors { u64::MAX } else { nr_sectors };
+                let data = if discard { Vec::new() } else { vec![fill; (nr_sectors * sector_size as u64) as usize] };
+                (LogWriteEntry { sector, nr_sectors, flags, data_len, cmd }, data)
+            })
+    }
+
+    fn log() -> impl Strategy<Value = (u32, Vec<(LogWriteEntry, Vec<u8>)>)> {
+        prop_oneof![Just(512_u32), Just(1024), Just(4096)]
+            .prop_flat_map(|sector_size| (Just(sector_size), proptest::collection::vec(entry(sector_size), 0..16)))
+    }
+
+    #[test]
+    fn test_trailer() {
+        let log_path = std::env::temp_dir().join(format!("log-write-trailer-{}.log", std::process::id()));
+        let entry = LogWriteEntry { sector: 0, nr_sectors: 1, flags: 0, data_len: 0, cmd: String::new() };
+        let trailer = || LogReader::open(&log_path).unwrap().trailer().unwrap();
+        let mut writer = LogWriter::create(&log_path, 512).unwrap();
+        writer.append(&entry, &[1; 512]).unwrap();
+        writer.sync().unwrap();
+        let without = trailer();
+
+        writer.trailer = true;
+        writer.sync().unwrap();
+        let synced = trailer();
+        // Appended over the copy, not synced yet
+        writer.append(&entry, &[2; 512]).unwrap();
+        let appended = trailer();
+        // Stopped in sync after the copy, the superblock on disk still counts one entry
+        writer.append(&entry, &[3; 512]).unwrap();
+        writer.write_trailer().unwrap();
+        let interrupted = trailer();
+        let counted = LogReader::open(&log_path).unwrap().nr_entries();
+        writer.sync().unwrap();
+        let finished = trailer();
+        std::fs::remove_file(&log_path).unwrap();
+
+        assert_eq!(without, Trailer::Absent);
+        assert_eq!(synced, Trailer::Matches);
+        assert_eq!(appended, Trailer::Absent);
+        assert_eq!(counted, 1);
+        assert!(matches!(interrupted, Trailer::Differs(copy) if copy.nr_entries == 3));
+        assert_eq!(finished, Trailer::Matches);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_written_log_decodes((sector_size, entries) in log()) {
+            let log_path = std::env::temp_dir().join(format!("log-write-prop-{}.log", std::process::id()));
+            let mut writer = LogWriter::create(&log_path, sector_size).unwrap();
+            for (entry, data) in &entries {
+                writer.append(entry, data).unwrap();
+            }
+            writer.sync().unwrap();
+
+            let reader = LogReader::open(&log_path).unwrap();
+            let mut read = Vec::new();
+            for log_entry in reader.clone() {
+                let log_entry = log_entry.unwrap();
+                let mut data = vec![0_u8; reader.data_size(&log_entry.entry) as usize];
+                reader.read_at(&mut data, reader.data_offset(&log_entry)).unwrap();
+                read.push((log_entry.entry, data));
+            }
+            let mut log = Log::open_read_only(&log_path).unwrap();
+            let replayed: Vec<_> = (0..entries.len() as u64).map(|index| log.read_entry_at(index).unwrap()).collect();
+            std::fs::remove_file(&log_path).unwrap();
+
+            prop_assert_eq!((reader.sector_size(), reader.nr_entries()), (sector_size, entries.len() as u64));
+            prop_assert_eq!(&read, &entries);
+            prop_assert_eq!(&replayed, &entries);
+        }
+    }
+}
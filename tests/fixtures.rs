@@ -0,0 +1,69 @@
+//! Decodes and replays the logs in tests/fixtures and checks them against
+//! fixtures.toml, so decoder changes are held to logs we didn't write with
+//! our own writer.
+
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use log_write::checksum::crc32c;
+use log_write::format::Entries;
+use log_write::log_reader::LogReader;
+use log_write::log_writes::{parse_flags, Log, LogWriteEntry};
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures")
+}
+
+fn int(table: &toml::Table, key: &str) -> u64 {
+    table[key].as_integer().unwrap_or_else(|| panic!("{} isn't an integer", key)) as u64
+}
+
+fn expected_entries(fixture: &toml::Table) -> Vec<LogWriteEntry> {
+    fixture["entries"].as_array().unwrap().iter().map(|entry| {
+        let entry = entry.as_table().unwrap();
+        let cmd = entry.get("mark").and_then(|mark| mark.as_str()).unwrap_or("").to_string();
+        LogWriteEntry {
+            sector: int(entry, "sector"),
+            nr_sectors: int(entry, "nr_sectors"),
+            flags: parse_flags(entry["flags"].as_str().unwrap()).unwrap(),
+            data_len: cmd.len() as u64,
+            cmd,
+        }
+    }).collect()
+}
+
+#[test]
+fn test_fixtures() {
+    let dir = fixtures_dir();
+    let fixtures: toml::Table = std::fs::read_to_string(dir.join("fixtures.toml")).unwrap().parse().unwrap();
+    assert!(!fixtures.is_empty());
+    for (name, fixture) in &fixtures {
+        let fixture = fixture.as_table().unwrap();
+        let log_path = dir.join(format!("{}.log", name));
+        let expected = expected_entries(fixture);
+        let synthetic = fixture.get("synthetic").and_then(|synthetic| synthetic.as_bool()).unwrap_or(false);
+        let kernel = fixture.get("kernel").and_then(|kernel| kernel.as_str());
+        assert!(synthetic != kernel.is_some(), "{} must be synthetic or name the kernel it was captured on", name);
+
+        let reader = LogReader::open(&log_path).unwrap();
+        assert_eq!(reader.sector_size() as u64, int(fixture, "sector_size"), "{}", name);
+        assert_eq!(reader.nr_entries(), expected.len() as u64, "{}", name);
+        let read: Vec<LogWriteEntry> = reader.map(|entry| entry.unwrap().entry).collect();
+        assert_eq!(read, expected, "{}", name);
+        let bytes = std::fs::read(&log_path).unwrap();
+        let in_memory: Vec<LogWriteEntry> = Entries::new(&bytes).unwrap().map(|entry| entry.unwrap().entry).collect();
+        assert_eq!(in_memory, expected, "{}", name);
+
+        let image_path = std::env::temp_dir().join(format!("log-write-fixture-{}-{}.img", std::process::id(), name));
+        OpenOptions::new().write(true).create(true).truncate(true).open(&image_path).unwrap()
+            .set_len(int(fixture, "image_size")).unwrap();
+        let mut log = Log::open(log_path.as_path(), image_path.as_path()).unwrap();
+        let progress = log.replay(None, &AtomicBool::new(false)).unwrap();
+        log.fsync_replay_file().unwrap();
+        let image = std::fs::read(&image_path).unwrap();
+        std::fs::remove_file(&image_path).unwrap();
+        assert_eq!(progress.entries_replayed, expected.len() as u64, "{}", name);
+        assert_eq!(image.len() as u64, int(fixture, "image_size"), "{}", name);
+        assert_eq!(crc32c(&image) as u64, int(fixture, "image_crc32c"), "{}", name);
+    }
+}